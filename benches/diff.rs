@@ -0,0 +1,145 @@
+//! Benchmarks for the matcher, the differ and the applier.
+//!
+//! By default these run against the avian binaries in `tests/`.  To run them
+//! against a bigger corpus, point `RSDIFF_BENCH_CORPUS` at a directory of
+//! consecutive builds of the same artifact (e.g. successive Firefox `libxul.so`
+//! files); files are sorted by name and each is diffed against the next.
+
+#[macro_use]
+extern crate criterion;
+extern crate rsdiff;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use criterion::Criterion;
+
+use rsdiff::diff::{Differ, Index, MatchIter};
+use rsdiff::index::{AutoIndex, SmallIndex};
+use rsdiff::format::bsdiff;
+
+fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+struct Pair {
+    name: String,
+    old: Vec<u8>,
+    new: Vec<u8>,
+}
+
+fn corpus() -> Vec<Pair> {
+    let paths = match env::var_os("RSDIFF_BENCH_CORPUS") {
+        Some(dir) => {
+            let mut paths = fs::read_dir(dir).unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.is_file())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        }
+        None => vec![
+            PathBuf::from("tests/avian_linux"),
+            PathBuf::from("tests/avian_pr_linux"),
+        ],
+    };
+
+    paths.windows(2).map(|w| Pair {
+        name: format!("{}", w[1].file_name().unwrap().to_string_lossy()),
+        old: load(&w[0]).unwrap(),
+        new: load(&w[1]).unwrap(),
+    }).collect()
+}
+
+fn bench_index_compute(c: &mut Criterion) {
+    for pair in corpus() {
+        let old = pair.old;
+        c.bench_function(&format!("Index::compute {}", pair.name), move |b| {
+            b.iter(|| Index::compute(old.clone()))
+        });
+    }
+}
+
+fn bench_match_iter(c: &mut Criterion) {
+    for pair in corpus() {
+        let index = Index::compute(pair.old);
+        let new = pair.new;
+        c.bench_function(&format!("MatchIter {}", pair.name), move |b| {
+            b.iter(|| MatchIter::from(&index, &new).count())
+        });
+    }
+}
+
+fn bench_generate_full_patch(c: &mut Criterion) {
+    for pair in corpus() {
+        let index = Index::compute(pair.old);
+        let new = pair.new;
+        c.bench_function(&format!("generate_full_patch {}", pair.name), move |b| {
+            b.iter(|| bsdiff::generate_full_patch(&index, &new))
+        });
+    }
+}
+
+fn bench_apply(c: &mut Criterion) {
+    for pair in corpus() {
+        let patch = bsdiff::generate_full_patch(&Index::compute(pair.old.clone()), &pair.new);
+        let old = pair.old;
+        let new_len = pair.new.len();
+        c.bench_function(&format!("apply_patch {}", pair.name), move |b| {
+            b.iter(|| {
+                let mut out = Vec::with_capacity(new_len);
+                bsdiff::apply_patch(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+                out
+            })
+        });
+    }
+}
+
+/// A few hundred lines of config-file-like text, and an edit of it.
+fn small_pair() -> (Vec<u8>, Vec<u8>) {
+    let old = (0..120).map(|i| format!("option_{} = {}\n", i, i * 37 % 101)).collect::<String>();
+    let new = old.replace("option_5", "setting_5").replace(" = 12\n", " = 13\n");
+    (old.into_bytes(), new.into_bytes())
+}
+
+/// Indexing and diffing a small file from scratch, as a service generating
+/// lots of tiny patches does.
+fn bench_small_inputs(c: &mut Criterion) {
+    let (old, new) = small_pair();
+    c.bench_function("small diff Index", move |b| {
+        b.iter(|| {
+            let mut patch = Vec::new();
+            Differ::new(&Index::compute(old.clone())).run(&new, &mut patch).unwrap();
+            patch
+        })
+    });
+
+    let (old, new) = small_pair();
+    c.bench_function("small diff SmallIndex", move |b| {
+        b.iter(|| {
+            let mut patch = Vec::new();
+            Differ::new(&SmallIndex::compute(old.clone())).run(&new, &mut patch).unwrap();
+            patch
+        })
+    });
+
+    let (old, new) = small_pair();
+    c.bench_function("small diff AutoIndex", move |b| {
+        b.iter(|| {
+            let mut patch = Vec::new();
+            Differ::new(&AutoIndex::compute(old.clone())).run(&new, &mut patch).unwrap();
+            patch
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_index_compute, bench_match_iter, bench_generate_full_patch, bench_apply, bench_small_inputs
+}
+criterion_main!(benches);
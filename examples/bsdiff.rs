@@ -1,11 +1,12 @@
 extern crate rsdiff;
 
-use std::path::{Path, PathBuf};
-use std::fs::{self, File};
-use std::io::{self, Read, Write, Cursor};
-use std::{env, fmt};
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::env;
 
-use rsdiff::diff::{Cache, Index};
+use rsdiff::diff::Index;
+use rsdiff::index::FsCache;
 use rsdiff::format::bsdiff::generate_full_patch;
 
 fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
@@ -14,50 +15,6 @@ fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     Ok(contents)
 }
 
-struct FileCache {
-    path: PathBuf
-}
-
-impl FileCache {
-    fn new(path: PathBuf) -> FileCache {
-        fs::create_dir_all(&path).unwrap();
-        FileCache {
-            path: path
-        }
-    }
-}
-
-struct Hex<'a>(pub &'a [u8]);
-
-impl<'a> fmt::Display for Hex<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for byte in self.0.iter() {
-            try!(write!(f, "{:02x}", byte));
-        }
-        Ok(())
-    }
-}
-
-impl<'a> Cache for &'a mut FileCache {
-    type Read = File;
-    type Write = File;
-
-    fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>> {
-        match File::open(self.path.join(format!("{}", Hex(digest)))) {
-            Ok(read) =>
-                Ok(Some(read)),
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
-                Ok(None),
-            Err(e) =>
-                Err(e)
-        }
-    }
-
-    fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write> {
-        File::create(self.path.join(format!("{}", Hex(digest))))
-    }
-}
-
 fn main() {
     let args = env::args().collect::<Vec<_>>();
 
@@ -68,9 +25,9 @@ fn main() {
     let old = load(&args[1]).unwrap();
     let new = load(&args[2]).unwrap();
 
-    let mut cache = FileCache::new(PathBuf::from(".cache"));
+    let cache = FsCache::new(".cache").unwrap();
 
-    let old_index = Index::from_cache_or_compute(&mut cache, old).unwrap();
+    let old_index = Index::from_cache_or_compute(&cache, old).unwrap();
 
     let patch_data = generate_full_patch(&old_index, &new);
 
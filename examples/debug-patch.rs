@@ -5,7 +5,7 @@ use std::fs::File;
 use std::io::{self, Read};
 use std::env;
 
-use rsdiff::format::bsdiff::Header;
+use rsdiff::inspect;
 
 fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     let mut contents = Vec::new();
@@ -14,16 +14,17 @@ fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
 }
 
 fn main() {
-    let ref arg = env::args().collect::<Vec<_>>()[1];
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let json = args.iter().any(|a| a == "--json");
+    let ref path = args.iter().find(|a| *a != "--json").expect("usage: debug-patch [--json] <patch>");
 
-    let patch = load(arg).unwrap();
+    let patch = load(path).unwrap();
+    let dump = inspect::dump(&patch).unwrap();
 
-    println!("size: {}", patch.len());
-    let h = Header::read(&patch).unwrap();
-    println!("{:?}", h);
-    println!("extra size: {}",
-        patch.len()
-            - 32
-            - h.compressed_commands_size as usize
-            - h.compressed_delta_size as usize);
+    let stdout = io::stdout();
+    if json {
+        dump.write_json(stdout.lock()).unwrap();
+    } else {
+        dump.write_text(stdout.lock()).unwrap();
+    }
 }
@@ -0,0 +1,41 @@
+extern crate rsdiff;
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read};
+use std::time::Instant;
+
+use rsdiff::diff::{Index, DiffStat};
+use rsdiff::index::FmIndex;
+
+fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+fn seconds(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9
+}
+
+fn main() {
+    let a = load("tests/avian_linux").unwrap();
+    let b = load("tests/avian_pr_linux").unwrap();
+
+    let start = Instant::now();
+    let index = Index::compute(a.clone());
+    println!("Index:   built in {:.2}s, {} bytes", seconds(start), index.memory_usage());
+
+    let start = Instant::now();
+    let stat = DiffStat::from(&index, &b);
+    println!("Index:   matched in {:.2}s: {:?}", seconds(start), stat);
+
+    let start = Instant::now();
+    let fm = FmIndex::compute(a);
+    println!("FmIndex: built in {:.2}s, {} bytes", seconds(start), fm.memory_usage());
+
+    let start = Instant::now();
+    let stat = DiffStat::from(&fm, &b);
+    println!("FmIndex: matched in {:.2}s: {:?}", seconds(start), stat);
+}
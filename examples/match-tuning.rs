@@ -0,0 +1,60 @@
+//! Measures how `MatchOptions` affect command count and patch size.
+//!
+//! Usage: match-tuning [<old> <new>]
+//!
+//! Defaults to the avian binaries in `tests/`.
+
+extern crate rsdiff;
+
+use std::env;
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read};
+
+use rsdiff::diff::{Index, MatchIter, MatchOptions, PatchOptions, Effort, DEFAULT_MISMATCH_BUDGET};
+use rsdiff::diff::auto::{self, ALL_ENGINES};
+
+fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let (old_path, new_path) = match args.len() {
+        0 => ("tests/avian_linux".to_string(), "tests/avian_pr_linux".to_string()),
+        2 => (args[0].clone(), args[1].clone()),
+        _ => panic!("usage: match-tuning [<old> <new>]"),
+    };
+
+    let index = Index::compute(load(old_path).unwrap());
+    let new = load(new_path).unwrap();
+
+    print!("{:>8} {:>9} {:>8} {:>10}", "budget", "lookahead", "effort", "commands");
+    for engine in ALL_ENGINES {
+        print!(" {:>16}", format!("{:?}", engine));
+    }
+    println!("");
+
+    let mut settings = Vec::new();
+    for &budget in &[DEFAULT_MISMATCH_BUDGET, 16, 64] {
+        for &lookahead in &[0, 1, 2] {
+            settings.push(MatchOptions::new().mismatch_budget(budget).lookahead(lookahead));
+        }
+        settings.push(MatchOptions::new().mismatch_budget(budget).effort(Effort::Optimal));
+    }
+
+    for matching in settings {
+        let commands = MatchIter::with_options(&index, &new, matching).count();
+
+        print!("{:>8} {:>9} {:>8} {:>10}", matching.mismatch_budget, matching.lookahead,
+            format!("{:?}", matching.effort), commands);
+        for &engine in ALL_ENGINES {
+            let options = PatchOptions::new().matching(matching);
+            let patch = auto::generate_with(engine, &index, &new, &options).unwrap();
+            print!(" {:>16}", patch.len());
+        }
+        println!("");
+    }
+}
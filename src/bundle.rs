@@ -0,0 +1,264 @@
+//! Bundles: several independent patches packed into one container, each
+//! addressable by name (e.g. one patch per partition of a system update).
+//!
+//! A bundle is the magic `RSDBUN01`, a little-endian `u32` entry count, then
+//! a table of contents with one record per entry:
+//!
+//! ```text
+//! name length (u16), name (UTF-8), patch offset (u64), patch length (u64)
+//! ```
+//!
+//! followed by the patches themselves.  Offsets count from the end of the
+//! table of contents.  Each patch is a complete patch in any format
+//! `apply_any` understands.
+
+use std::io::{self, Read, Write, Seek};
+
+use format::wire;
+use patch::{apply_any, apply_from_slice};
+
+pub const MAGIC: &'static [u8] = b"RSDBUN01";
+
+/// Whether `data` looks like a bundle.
+pub fn is_match(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Collects named patches and writes them out as a bundle.
+#[derive(Debug, Clone, Default)]
+pub struct BundleBuilder {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl BundleBuilder {
+    pub fn new() -> BundleBuilder {
+        BundleBuilder::default()
+    }
+
+    /// Adds `patch` under `name`.  Entries keep the order they're added in.
+    pub fn entry<S: Into<String>>(mut self, name: S, patch: Vec<u8>) -> BundleBuilder {
+        self.entries.push((name.into(), patch));
+        self
+    }
+
+    /// Writes the bundle.  Fails with `InvalidInput` if two entries share a
+    /// name, or a name is longer than 65535 bytes.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (i, &(ref name, _)) in self.entries.iter().enumerate() {
+            if name.len() > u16::max_value() as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "Bundle entry name is {} bytes long", name.len())));
+            }
+            if self.entries[..i].iter().any(|&(ref other, _)| other == name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "Duplicate bundle entry {:?}", name)));
+            }
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+
+        let mut buf = [0u8; 8];
+        wire::write_u32(&mut buf[..4], self.entries.len() as u32);
+        header.extend_from_slice(&buf[..4]);
+
+        let mut offset = 0u64;
+        for &(ref name, ref patch) in &self.entries {
+            wire::write_u16(&mut buf[..2], name.len() as u16);
+            header.extend_from_slice(&buf[..2]);
+            header.extend_from_slice(name.as_bytes());
+
+            wire::write_u64(&mut buf, offset);
+            header.extend_from_slice(&buf);
+            wire::write_u64(&mut buf, patch.len() as u64);
+            header.extend_from_slice(&buf);
+
+            offset += patch.len() as u64;
+        }
+
+        writer.write_all(&header)?;
+        for &(_, ref patch) in &self.entries {
+            writer.write_all(patch)?;
+        }
+        Ok(())
+    }
+
+    /// Like `write_to`, into a fresh buffer.
+    pub fn build(&self) -> io::Result<Vec<u8>> {
+        let mut bundle = Vec::new();
+        self.write_to(&mut bundle)?;
+        Ok(bundle)
+    }
+}
+
+/// A bundle parsed from memory.  Only the table of contents is read up
+/// front; each patch stays where it is in the buffer.
+#[derive(Debug, Clone)]
+pub struct Bundle<'a> {
+    entries: Vec<Entry<'a>>,
+}
+
+/// One named patch within a `Bundle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub patch: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    /// Applies this entry's patch with `apply_any`.
+    pub fn apply<OldRS, NewW>(&self, old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        apply_any(self.patch, old, new)
+    }
+
+    /// Applies this entry's patch with `apply_from_slice`.
+    pub fn apply_from_slice<NewW: Write>(&self, old: &[u8], new: NewW) -> io::Result<()> {
+        apply_from_slice(self.patch, old, new)
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated bundle table of contents")
+}
+
+/// Splits `n` bytes off the front of `data`.
+fn take<'a>(data: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(truncated());
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Ok(head)
+}
+
+impl<'a> Bundle<'a> {
+    /// Reads the table of contents of the bundle in `data`, checking that
+    /// every entry lies within it and that names are unique.
+    pub fn parse(data: &'a [u8]) -> io::Result<Bundle<'a>> {
+        if !is_match(data) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad bundle magic"));
+        }
+
+        let mut rest = &data[MAGIC.len()..];
+        let count = wire::read_u32(take(&mut rest, 4)?);
+
+        let mut toc = Vec::new();
+        for _ in 0..count {
+            let name_len = wire::read_u16(take(&mut rest, 2)?) as usize;
+            let name = ::std::str::from_utf8(take(&mut rest, name_len)?).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "Bundle entry name isn't UTF-8")
+            })?;
+            let offset = wire::read_u64(take(&mut rest, 8)?);
+            let len = wire::read_u64(take(&mut rest, 8)?);
+
+            if toc.iter().any(|&(other, _, _)| other == name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Duplicate bundle entry {:?}", name)));
+            }
+            toc.push((name, offset, len));
+        }
+
+        let body = rest;
+        let mut entries = Vec::with_capacity(toc.len());
+        for (name, offset, len) in toc {
+            let end = offset.checked_add(len);
+            if end.map_or(true, |end| end > body.len() as u64) {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+                    "Bundle entry {:?} runs past the end of the bundle", name)));
+            }
+
+            entries.push(Entry {
+                name: name,
+                patch: &body[offset as usize .. (offset + len) as usize],
+            });
+        }
+
+        Ok(Bundle { entries: entries })
+    }
+
+    /// The entry called `name`, or a `NotFound` error.
+    pub fn entry(&self, name: &str) -> io::Result<Entry<'a>> {
+        match self.entries.iter().find(|e| e.name == name) {
+            Some(entry) => Ok(*entry),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!(
+                "No bundle entry {:?}", name))),
+        }
+    }
+
+    /// Every entry, in the order they were added.
+    pub fn entries(&self) -> &[Entry<'a>] {
+        &self.entries
+    }
+
+    pub fn names(&self) -> Vec<&'a str> {
+        self.entries.iter().map(|e| e.name).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::*;
+    use diff::Index;
+    use format::linear_diff;
+
+    fn linear_patch(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&Index::compute(old.to_vec()), new, &mut patch).unwrap();
+        patch
+    }
+
+    #[test]
+    fn test_bundle_roundtrip() {
+        let system = linear_patch(b"system image, version 1", b"system image, version 2");
+        let vendor = linear_patch(b"vendor blob 1234", b"vendor blob 12345678");
+
+        let bundle = BundleBuilder::new()
+            .entry("system", system.clone())
+            .entry("vendor", vendor.clone())
+            .entry("empty", Vec::new())
+            .build()
+            .unwrap();
+
+        let parsed = Bundle::parse(&bundle).unwrap();
+        assert_eq!(parsed.names(), vec!["system", "vendor", "empty"]);
+        assert_eq!(parsed.entry("vendor").unwrap().patch, &vendor[..]);
+        assert!(parsed.entry("empty").unwrap().patch.is_empty());
+
+        let mut out = Vec::new();
+        parsed.entry("system").unwrap().apply(Cursor::new(&b"system image, version 1"[..]), &mut out).unwrap();
+        assert_eq!(out, b"system image, version 2");
+
+        let mut out = Vec::new();
+        parsed.entry("vendor").unwrap().apply_from_slice(b"vendor blob 1234", &mut out).unwrap();
+        assert_eq!(out, b"vendor blob 12345678");
+
+        assert_eq!(parsed.entry("boot").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_bundle_errors() {
+        let err = BundleBuilder::new()
+            .entry("a", Vec::new())
+            .entry("a", Vec::new())
+            .build()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let bundle = BundleBuilder::new()
+            .entry("system", linear_patch(b"old", b"new"))
+            .build()
+            .unwrap();
+
+        assert_eq!(Bundle::parse(b"NOTABUNDLE").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        for len in 0..bundle.len() {
+            assert!(Bundle::parse(&bundle[..len]).is_err());
+        }
+        assert!(Bundle::parse(&bundle).is_ok());
+    }
+}
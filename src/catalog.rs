@@ -0,0 +1,635 @@
+//! Catalogs: signed manifests of a set of published patches, so an updater
+//! can work out which patches take it from the version it has to the one it
+//! wants.
+//!
+//! Each entry names a patch by the SHA-1 of the file it applies to and the
+//! SHA-1 of the file it produces, with its size, format and path.  The
+//! binary layout is:
+//!
+//! ```text
+//! magic "RSDCAT01", entry count (u32), entries,
+//! signature length (u16), signature
+//! ```
+//!
+//! where each entry is:
+//!
+//! ```text
+//! from digest (20 bytes), to digest (20 bytes), patch size (u64),
+//! format name length (u8), format name (UTF-8), path length (u16), path (UTF-8)
+//! ```
+//!
+//! All integers are little-endian.  The signature covers everything before
+//! its length.  The JSON form (with the `json` feature) carries the same
+//! entries, for other tools; its signature covers the text of the `entries`
+//! array.
+//!
+//! Signing is pluggable through `Signer` and `Verifier`.  An updater should
+//! check catalogs against a public key, so that it can't itself forge one:
+//! `Ed25519Signer` and `Ed25519Verifier` do that, with the `ed25519`
+//! feature.  `HmacSha1` is only a shared-key MAC.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use sha1::Sha1;
+
+use format::source_digest::{self, SourceDigest};
+use format::wire;
+use patch;
+
+pub const MAGIC: &'static [u8] = b"RSDCAT01";
+
+/// Whether `data` looks like a binary catalog.
+pub fn is_match(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// One published patch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CatalogEntry {
+    /// SHA-1 of the old file the patch applies to.
+    pub from: [u8; 20],
+    /// SHA-1 of the new file it produces.
+    pub to: [u8; 20],
+    pub size: u64,
+    /// Name of the patch's format.
+    pub format: String,
+    /// Where the patch is published, relative to the catalog.
+    pub path: String,
+}
+
+/// Signs catalogs as they're written.
+pub trait Signer {
+    fn sign(&self, message: &[u8]) -> Vec<u8>;
+}
+
+/// Checks catalogs' signatures as they're read.
+pub trait Verifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// No signature at all: signs with nothing, and only accepts catalogs with
+/// an empty signature.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unsigned;
+
+impl Signer for Unsigned {
+    fn sign(&self, _: &[u8]) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+impl Verifier for Unsigned {
+    fn verify(&self, _: &[u8], signature: &[u8]) -> bool {
+        signature.is_empty()
+    }
+}
+
+/// HMAC-SHA1 with a key shared between whoever publishes the catalog and
+/// the updaters reading it.  This is a MAC, not a signature: anyone who can
+/// check a catalog can also make one, so the key has to be as well kept on
+/// every updater as on the publisher.  Use `Ed25519Signer` and
+/// `Ed25519Verifier` (or another public-key scheme through `Signer` and
+/// `Verifier`) unless that holds.
+#[derive(Debug, Clone)]
+pub struct HmacSha1 {
+    key: Vec<u8>,
+}
+
+const HMAC_BLOCK: usize = 64;
+
+impl HmacSha1 {
+    pub fn new(key: &[u8]) -> HmacSha1 {
+        let key = if key.len() > HMAC_BLOCK { sha1(&[key]).to_vec() } else { key.to_vec() };
+        HmacSha1 { key: key }
+    }
+
+    fn mac(&self, message: &[u8]) -> [u8; 20] {
+        let mut inner_pad = [0x36u8; HMAC_BLOCK];
+        let mut outer_pad = [0x5cu8; HMAC_BLOCK];
+        for (i, &b) in self.key.iter().enumerate() {
+            inner_pad[i] ^= b;
+            outer_pad[i] ^= b;
+        }
+        let inner = sha1(&[&inner_pad[..], message]);
+        sha1(&[&outer_pad[..], &inner[..]])
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    for part in parts {
+        sha1.update(part);
+    }
+    sha1.digest().bytes()
+}
+
+impl Signer for HmacSha1 {
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.mac(message).to_vec()
+    }
+}
+
+impl Verifier for HmacSha1 {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let expected = self.mac(message);
+        // Compared in full, so the time taken doesn't leak how much matched.
+        signature.len() == expected.len() &&
+            signature.iter().zip(&expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+/// Ed25519 signatures (RFC 8032), made with the publisher's private key.
+#[cfg(feature = "ed25519")]
+pub struct Ed25519Signer {
+    key_pair: ed25519_compact::KeyPair,
+}
+
+#[cfg(feature = "ed25519")]
+impl Ed25519Signer {
+    /// The signer for the key pair generated from the 32-byte `seed`, which
+    /// is the whole of the private key.
+    pub fn from_seed(seed: &[u8; 32]) -> Ed25519Signer {
+        Ed25519Signer { key_pair: ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new(*seed)) }
+    }
+
+    /// The public key to give updaters' `Ed25519Verifier`s.
+    pub fn public_key(&self) -> [u8; 32] {
+        *self.key_pair.pk
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl Signer for Ed25519Signer {
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.key_pair.sk.sign(message, None).to_vec()
+    }
+}
+
+/// Checks Ed25519 signatures against the publisher's public key, which is
+/// all an updater needs to hold.
+#[cfg(feature = "ed25519")]
+pub struct Ed25519Verifier {
+    public_key: ed25519_compact::PublicKey,
+}
+
+#[cfg(feature = "ed25519")]
+impl Ed25519Verifier {
+    pub fn new(public_key: &[u8; 32]) -> Ed25519Verifier {
+        Ed25519Verifier { public_key: ed25519_compact::PublicKey::new(*public_key) }
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl Verifier for Ed25519Verifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match ed25519_compact::Signature::from_slice(signature) {
+            Ok(signature) => self.public_key.verify(message, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// A set of published patches.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Catalog {
+    entries: Vec<CatalogEntry>,
+}
+
+impl Catalog {
+    pub fn new() -> Catalog {
+        Catalog::default()
+    }
+
+    pub fn entry(mut self, entry: CatalogEntry) -> Catalog {
+        self.entries.push(entry);
+        self
+    }
+
+    pub fn entries(&self) -> &[CatalogEntry] {
+        &self.entries
+    }
+
+    /// Catalogs the patches in `dir`, which must be named for the digests
+    /// they go between, as `<from>-<to>` in hex plus any extension.  Other
+    /// files are left out.  Each path is the file name after `url_prefix`.
+    ///
+    /// Fails with `InvalidData` if a patch is in an unknown format, or has
+    /// a source digest that disagrees with its name.
+    pub fn from_dir<P: AsRef<Path>>(dir: P, url_prefix: &str) -> io::Result<Catalog> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir.as_ref())? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+
+        let mut catalog = Catalog::new();
+        for name in names {
+            let (from, to) = match parse_name(&name) {
+                Some(digests) => digests,
+                None => continue,
+            };
+
+            let data = fs::read(dir.as_ref().join(&name))?;
+            let format = patch::format_name(&data).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} isn't a patch in a known format", name)))?;
+            if source_digest::is_match(&data) && SourceDigest::read_header(&data)?.digest != from {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} records a source digest other than the one in its name", name)));
+            }
+
+            catalog.entries.push(CatalogEntry {
+                from: from,
+                to: to,
+                size: data.len() as u64,
+                format: format.to_string(),
+                path: format!("{}{}", url_prefix, name),
+            });
+        }
+        Ok(catalog)
+    }
+
+    /// The patches to apply, in order, to get from `from` to `to` with the
+    /// least downloaded, or `None` if there's no way there.  Getting from a
+    /// version to itself takes no patches.
+    pub fn find_path(&self, from: &[u8; 20], to: &[u8; 20]) -> Option<Vec<&CatalogEntry>> {
+        let mut patches_from: HashMap<[u8; 20], Vec<usize>> = HashMap::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            patches_from.entry(entry.from).or_insert_with(Vec::new).push(i);
+        }
+
+        // Dijkstra over the versions, by total patch size.
+        let mut best: HashMap<[u8; 20], (u64, Option<usize>)> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        best.insert(*from, (0, None));
+        queue.push(Reverse((0u64, *from)));
+
+        while let Some(Reverse((cost, version))) = queue.pop() {
+            if &version == to {
+                break;
+            }
+            if best.get(&version).map_or(false, |&(known, _)| known < cost) {
+                continue;
+            }
+            for &i in patches_from.get(&version).map_or(&[][..], |patches| &patches[..]) {
+                let entry = &self.entries[i];
+                let next = cost.saturating_add(entry.size);
+                if best.get(&entry.to).map_or(true, |&(known, _)| next < known) {
+                    best.insert(entry.to, (next, Some(i)));
+                    queue.push(Reverse((next, entry.to)));
+                }
+            }
+        }
+
+        best.get(to)?;
+        let mut path = Vec::new();
+        let mut version = *to;
+        while let Some(&(_, Some(i))) = best.get(&version) {
+            path.push(&self.entries[i]);
+            version = self.entries[i].from;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Writes the binary form, signed by `signer`.
+    pub fn write_to<W: Write>(&self, mut writer: W, signer: &Signer) -> io::Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        wire::write_u32_to(&mut buf, self.entries.len() as u32)?;
+
+        for entry in &self.entries {
+            if entry.format.len() > u8::max_value() as usize || entry.path.len() > u16::max_value() as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "Catalog entry {:?} has too long a format name or path", entry.path)));
+            }
+            buf.extend_from_slice(&entry.from);
+            buf.extend_from_slice(&entry.to);
+            wire::write_u64_to(&mut buf, entry.size)?;
+            wire::write_u8_to(&mut buf, entry.format.len() as u8)?;
+            buf.extend_from_slice(entry.format.as_bytes());
+            wire::write_u16_to(&mut buf, entry.path.len() as u16)?;
+            buf.extend_from_slice(entry.path.as_bytes());
+        }
+
+        let signature = signer.sign(&buf);
+        if signature.len() > u16::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Catalog signature is too long"));
+        }
+        wire::write_u16_to(&mut buf, signature.len() as u16)?;
+        buf.extend_from_slice(&signature);
+
+        writer.write_all(&buf)
+    }
+
+    /// Reads the binary form, failing with `InvalidData` unless `verifier`
+    /// accepts its signature.
+    pub fn read(data: &[u8], verifier: &Verifier) -> io::Result<Catalog> {
+        if !is_match(data) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad catalog magic"));
+        }
+
+        let mut r = &data[MAGIC.len()..];
+        let count = wire::read_u32_from(&mut r)?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let mut from = [0u8; 20];
+            let mut to = [0u8; 20];
+            r.read_exact(&mut from)?;
+            r.read_exact(&mut to)?;
+            let size = wire::read_u64_from(&mut r)?;
+            let format_len = wire::read_u8_from(&mut r)? as usize;
+            let format = read_string(&mut r, format_len)?;
+            let path_len = wire::read_u16_from(&mut r)? as usize;
+            let path = read_string(&mut r, path_len)?;
+
+            entries.push(CatalogEntry { from: from, to: to, size: size, format: format, path: path });
+        }
+
+        let signed = &data[..data.len() - r.len()];
+        let signature_len = wire::read_u16_from(&mut r)? as usize;
+        if r.len() != signature_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad catalog signature length"));
+        }
+        if !verifier.verify(signed, r) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Catalog signature doesn't verify"));
+        }
+
+        Ok(Catalog { entries: entries })
+    }
+
+    /// Writes the JSON form: an object with the `entries` and a hex
+    /// `signature` of the text of the `entries` array.
+    #[cfg(feature = "json")]
+    pub fn write_json<W: Write>(&self, mut w: W, signer: &Signer) -> io::Result<()> {
+        let entries = self.entries.iter().map(JsonEntry::from).collect::<Vec<_>>();
+        let entries = serde_json::to_string(&entries)?;
+        let signature = hex(&signer.sign(entries.as_bytes()));
+
+        let entries = serde_json::value::RawValue::from_string(entries)?;
+        serde_json::to_writer(&mut w, &JsonCatalog { entries: entries, signature: signature })?;
+        writeln!(w)
+    }
+
+    /// Reads the JSON form, failing with `InvalidData` unless `verifier`
+    /// accepts the signature of the `entries` text exactly as it appears.
+    #[cfg(feature = "json")]
+    pub fn read_json(data: &[u8], verifier: &Verifier) -> io::Result<Catalog> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        let catalog: JsonCatalog = serde_json::from_slice(data)?;
+        let signature = parse_hex(&catalog.signature).ok_or_else(|| invalid("Bad catalog signature"))?;
+        if !verifier.verify(catalog.entries.get().as_bytes(), &signature) {
+            return Err(invalid("Catalog signature doesn't verify"));
+        }
+
+        let entries: Vec<JsonEntry> = serde_json::from_str(catalog.entries.get())?;
+        let entries = entries.into_iter().map(|entry| {
+            Ok(CatalogEntry {
+                from: parse_digest(&entry.from).ok_or_else(|| invalid("Bad catalog digest"))?,
+                to: parse_digest(&entry.to).ok_or_else(|| invalid("Bad catalog digest"))?,
+                size: entry.size,
+                format: entry.format,
+                path: entry.path,
+            })
+        }).collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Catalog { entries: entries })
+    }
+}
+
+/// The JSON form of a catalog.  The entries are kept as text, since that's
+/// what the signature covers.
+#[cfg(feature = "json")]
+#[derive(Serialize, Deserialize)]
+struct JsonCatalog {
+    entries: Box<serde_json::value::RawValue>,
+    signature: String,
+}
+
+/// A `CatalogEntry` in JSON, with the digests in hex.
+#[cfg(feature = "json")]
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    from: String,
+    to: String,
+    size: u64,
+    format: String,
+    path: String,
+}
+
+#[cfg(feature = "json")]
+impl<'a> From<&'a CatalogEntry> for JsonEntry {
+    fn from(entry: &'a CatalogEntry) -> JsonEntry {
+        JsonEntry {
+            from: hex(&entry.from),
+            to: hex(&entry.to),
+            size: entry.size,
+            format: entry.format.clone(),
+            path: entry.path.clone(),
+        }
+    }
+}
+
+/// Splits a file name of the form `<from>-<to>[.ext]` into its digests.
+fn parse_name(name: &str) -> Option<([u8; 20], [u8; 20])> {
+    let stem = name.split('.').next().unwrap_or("");
+    let mut parts = stem.splitn(2, '-');
+    let from = parse_digest(parts.next()?)?;
+    let to = parse_digest(parts.next()?)?;
+    Some((from, to))
+}
+
+fn parse_digest(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut digest = [0u8; 20];
+    digest.copy_from_slice(&parse_hex(hex)?);
+    Some(digest)
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()).collect()
+}
+
+#[cfg(any(test, feature = "json"))]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_string<R: Read>(r: R, len: usize) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated catalog entry"));
+    }
+    String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Catalog entry isn't UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use diff::{Differ, Index, PatchOptions};
+
+    fn digest(data: &[u8]) -> [u8; 20] {
+        sha1(&[data])
+    }
+
+    fn entry(from: u8, to: u8, size: u64) -> CatalogEntry {
+        CatalogEntry {
+            from: [from; 20],
+            to: [to; 20],
+            size: size,
+            format: "BSDIFF40".to_string(),
+            path: format!("{}-{}.patch", from, to),
+        }
+    }
+
+    #[test]
+    fn test_find_path() {
+        let catalog = Catalog::new()
+            .entry(entry(1, 2, 100))
+            .entry(entry(2, 3, 100))
+            .entry(entry(1, 3, 500))
+            .entry(entry(3, 4, 10))
+            .entry(entry(5, 1, 10));
+
+        let path = catalog.find_path(&[1; 20], &[4; 20]).unwrap();
+        assert_eq!(path.iter().map(|e| &e.path[..]).collect::<Vec<_>>(), vec!["1-2.patch", "2-3.patch", "3-4.patch"]);
+
+        assert_eq!(catalog.find_path(&[2; 20], &[2; 20]), Some(vec![]));
+        assert_eq!(catalog.find_path(&[4; 20], &[1; 20]), None);
+        assert_eq!(catalog.find_path(&[9; 20], &[1; 20]), None);
+
+        // A long chain, with a costly shortcut from every version.
+        let mut catalog = Catalog::new();
+        for i in 0..200u8 {
+            catalog = catalog.entry(entry(i, i + 1, 1)).entry(entry(i, 200, 1000));
+        }
+        assert_eq!(catalog.find_path(&[0; 20], &[200; 20]).unwrap().len(), 200);
+        assert_eq!(catalog.find_path(&[199; 20], &[200; 20]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_signed_roundtrip() {
+        let catalog = Catalog::new().entry(entry(1, 2, 100)).entry(entry(2, 3, 7));
+        let key = HmacSha1::new(b"secret");
+
+        let mut data = Vec::new();
+        catalog.write_to(&mut data, &key).unwrap();
+        assert_eq!(Catalog::read(&data, &key).unwrap(), catalog);
+
+        assert!(Catalog::read(&data, &HmacSha1::new(b"other")).is_err());
+        assert!(Catalog::read(&data, &Unsigned).is_err());
+
+        let mut tampered = data.clone();
+        tampered[MAGIC.len() + 4 + 40] ^= 1;
+        assert!(Catalog::read(&tampered, &key).is_err());
+
+        let mut unsigned = Vec::new();
+        catalog.write_to(&mut unsigned, &Unsigned).unwrap();
+        assert_eq!(Catalog::read(&unsigned, &Unsigned).unwrap(), catalog);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_json_roundtrip() {
+        let catalog = Catalog::new().entry(entry(1, 2, 100)).entry(entry(2, 3, 7));
+        let key = HmacSha1::new(b"secret");
+
+        let mut json = Vec::new();
+        catalog.write_json(&mut json, &key).unwrap();
+        let text = String::from_utf8(json.clone()).unwrap();
+        assert!(text.starts_with("{\"entries\":[{\"from\":\"0101"));
+        assert!(text.contains("\"path\":\"2-3.patch\""));
+        assert_eq!(Catalog::read_json(&json, &key).unwrap(), catalog);
+
+        assert!(Catalog::read_json(&json, &HmacSha1::new(b"other")).is_err());
+        let tampered = text.replace("\"size\":7", "\"size\":8");
+        assert_ne!(tampered, text);
+        assert!(Catalog::read_json(tampered.as_bytes(), &key).is_err());
+
+        // Whitespace is part of the signed text too.
+        let spaced = text.replace("},{", "}, {");
+        assert_eq!(Catalog::read_json(spaced.as_bytes(), &key).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(Catalog::read_json(b"{\"entries\":[]}", &Unsigned).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "ed25519")]
+    fn test_ed25519() {
+        // RFC 8032, test 1.
+        let seed = parse_hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60").unwrap();
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&seed);
+        let signer = Ed25519Signer::from_seed(&secret);
+        assert_eq!(hex(&signer.public_key()), "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        assert_eq!(hex(&signer.sign(b"")),
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b");
+
+        let catalog = Catalog::new().entry(entry(1, 2, 100));
+        let mut data = Vec::new();
+        catalog.write_to(&mut data, &signer).unwrap();
+        let verifier = Ed25519Verifier::new(&signer.public_key());
+        assert_eq!(Catalog::read(&data, &verifier).unwrap(), catalog);
+
+        let other = Ed25519Verifier::new(&Ed25519Signer::from_seed(&[7; 32]).public_key());
+        assert!(Catalog::read(&data, &other).is_err());
+        assert!(Catalog::read(&data, &Unsigned).is_err());
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // RFC 2202, test case 2.
+        let mac = HmacSha1::new(b"Jefe").sign(b"what do ya want for nothing?");
+        assert_eq!(hex(&mac), "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+    }
+
+    #[test]
+    fn test_from_dir() {
+        let dir: PathBuf = ::std::env::temp_dir().join(format!("rsdiff-test-catalog-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let v1 = b"this is a test 12345678 test".to_vec();
+        let v2 = b"this is really a cool uftu 12345678 uftu".to_vec();
+        let mut patch = Vec::new();
+        Differ::new(&Index::compute(v1.clone()))
+            .options(PatchOptions::default().source_digest(true))
+            .run(&v2, &mut patch)
+            .unwrap();
+
+        let name = format!("{}-{}.patch", hex(&digest(&v1)), hex(&digest(&v2)));
+        fs::write(dir.join(&name), &patch).unwrap();
+        fs::write(dir.join("README"), b"not a patch").unwrap();
+
+        let catalog = Catalog::from_dir(&dir, "patches/").unwrap();
+        assert_eq!(catalog.entries().len(), 1);
+        assert_eq!(catalog.entries()[0].path, format!("patches/{}", name));
+        assert_eq!(catalog.entries()[0].size, patch.len() as u64);
+        assert_eq!(catalog.find_path(&digest(&v1), &digest(&v2)).unwrap().len(), 1);
+
+        // A name that disagrees with the patch's source digest.
+        fs::write(dir.join(format!("{}-{}.patch", hex(&digest(&v2)), hex(&digest(&v1)))), &patch).unwrap();
+        assert!(Catalog::from_dir(&dir, "").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Where the crate's worker threads come from, and how many it may start,
+//! for services that already run their own executor and don't want the
+//! crate oversubscribing the machine behind its back.
+//!
+//! Work that splits into independent jobs (`Differ::diff_many_parallel`,
+//! `ApplyOptions::parallel_output`) runs on an `Executor`: the one in the
+//! call's options if set, else the one given to `set_executor`, else a
+//! scoped thread per job.  Wrapping a rayon pool takes a few lines:
+//!
+//! ```ignore
+//! #[derive(Debug)]
+//! struct Rayon(rayon::ThreadPool);
+//!
+//! impl Executor for Rayon {
+//!     fn run_all<'a>(&self, jobs: Vec<Job<'a>>) {
+//!         self.0.scope(|s| for job in jobs { s.spawn(move |_| job()) });
+//!     }
+//! }
+//! ```
+//!
+//! Threads that hand data to each other as they go (bsdiff's compression
+//! pipeline, and section prefetching when applying) could deadlock waiting
+//! for a slot in a pool, so they're always threads of their own.  The limit
+//! set with `set_max_threads` caps those too, along with the number of jobs
+//! a call hands its executor.
+
+use std::fmt;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// One unit of work for an `Executor`.
+pub type Job<'a> = Box<FnOnce() + Send + 'a>;
+
+/// Runs jobs, possibly at the same time.
+pub trait Executor: fmt::Debug + Send + Sync {
+    /// Runs every one of `jobs` to completion before returning, passing on
+    /// any panic.  The jobs may coordinate with each other, but never wait
+    /// on one another, so running them one after the other is allowed.
+    fn run_all<'a>(&self, jobs: Vec<Job<'a>>);
+}
+
+/// The default `Executor`: a scoped thread per job.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScopedThreads;
+
+impl Executor for ScopedThreads {
+    fn run_all<'a>(&self, jobs: Vec<Job<'a>>) {
+        thread::scope(|scope| {
+            let threads = jobs.into_iter().map(|job| scope.spawn(move || job())).collect::<Vec<_>>();
+            for thread in threads {
+                if let Err(panic) = thread.join() {
+                    panic::resume_unwind(panic);
+                }
+            }
+        })
+    }
+}
+
+static MAX_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+static EXECUTOR: Mutex<Option<Arc<Executor>>> = Mutex::new(None);
+
+/// Caps the threads (or executor jobs) any one call starts on top of the
+/// calling thread; 0, the default, means no cap.  Calls asking for more get
+/// this many, and a bsdiff pipeline that can't have at least two runs on
+/// the calling thread alone.
+pub fn set_max_threads(max: usize) {
+    MAX_THREADS.store(max, Ordering::Relaxed);
+}
+
+/// The cap set with `set_max_threads`, or 0 for none.
+pub fn max_threads() -> usize {
+    MAX_THREADS.load(Ordering::Relaxed)
+}
+
+/// Runs jobs on `executor` wherever a call's options don't name one.
+/// `None` goes back to scoped threads.
+pub fn set_executor(executor: Option<Arc<Executor>>) {
+    *EXECUTOR.lock().unwrap() = executor;
+}
+
+/// `requested` threads, cut down to `max_threads`.
+pub(crate) fn limit(requested: usize) -> usize {
+    limit_to(requested, max_threads())
+}
+
+/// `requested` threads, cut down to `max` unless that's 0.
+fn limit_to(requested: usize, max: usize) -> usize {
+    match max {
+        0 => requested,
+        max => ::std::cmp::min(requested, max),
+    }
+}
+
+/// The executor for a call whose options name `executor`.
+pub(crate) fn executor(executor: &Option<Arc<Executor>>) -> Arc<Executor> {
+    if let Some(ref executor) = *executor {
+        return executor.clone();
+    }
+    match *EXECUTOR.lock().unwrap() {
+        Some(ref executor) => executor.clone(),
+        None => Arc::new(ScopedThreads),
+    }
+}
+
+/// Runs `job(0)` to `job(count - 1)` on `executor`, returning their results
+/// in order.
+pub(crate) fn run<T, F>(executor: &Executor, count: usize, job: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(usize) -> T + Sync
+{
+    let results = (0..count).map(|_| Mutex::new(None)).collect::<Vec<_>>();
+    {
+        let job = &job;
+        let results = &results;
+        let jobs = (0..count)
+            .map(|i| Box::new(move || *results[i].lock().unwrap() = Some(job(i))) as Job)
+            .collect();
+        executor.run_all(jobs);
+    }
+    results.into_iter()
+        .map(|result| result.into_inner().unwrap().expect("executor returned without running every job"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs jobs one at a time on the calling thread.
+    #[derive(Debug)]
+    struct Inline(AtomicUsize);
+
+    impl Executor for Inline {
+        fn run_all<'a>(&self, jobs: Vec<Job<'a>>) {
+            for job in jobs {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                job();
+            }
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run(&ScopedThreads, 4, |i| i * 10), vec![0, 10, 20, 30]);
+
+        let inline = Inline(AtomicUsize::new(0));
+        assert_eq!(run(&inline, 3, |i| i + 1), vec![1, 2, 3]);
+        assert_eq!(inline.0.load(Ordering::Relaxed), 3);
+
+        let chosen: Arc<Executor> = Arc::new(Inline(AtomicUsize::new(0)));
+        assert!(Arc::ptr_eq(&executor(&Some(chosen.clone())), &chosen));
+
+        // The cap itself is global, so it's left alone here: tests run in
+        // parallel and some count the jobs they hand an executor.
+        assert_eq!((limit_to(8, 2), limit_to(1, 2)), (2, 1));
+        assert_eq!(limit_to(8, 0), 8);
+    }
+}
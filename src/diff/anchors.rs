@@ -0,0 +1,174 @@
+//! Long-distance anchors, for sections that moved between versions and were
+//! edited along the way.
+//!
+//! The old file is sampled at every multiple of `MatchOptions::anchor_block`
+//! bytes, and each sampled block is looked for anywhere in the new file with
+//! a rolling hash.  Each hit pins down the diagonal (old offset minus new
+//! offset) of a section that moved as a whole.  Where the index can't find
+//! a long enough exact match (relocated code, say, whose addresses changed
+//! every few bytes), `MatchIter` tries the nearest anchor's diagonal before
+//! giving up on the bytes as unmatched.
+
+use std::collections::HashMap;
+
+/// How far (in blocks) from an anchor its diagonal is still tried.
+const ANCHOR_REACH: usize = 4;
+
+const HASH_BASE: u64 = 0x100000001b3;
+
+fn hash(block: &[u8]) -> u64 {
+    block.iter().fold(0u64, |h, &b| h.wrapping_mul(HASH_BASE).wrapping_add(b as u64))
+}
+
+/// Where sampled blocks of the old file turned up in the new one.
+#[derive(Debug, Clone)]
+pub struct Anchors {
+    block_size: usize,
+
+    /// `(new offset, diagonal)`, ordered by new offset.
+    anchors: Vec<(usize, isize)>,
+}
+
+impl Anchors {
+    pub fn find(old: &[u8], new: &[u8], block_size: usize) -> Anchors {
+        assert!(block_size > 0);
+
+        let mut anchors = Vec::new();
+        if old.len() < block_size || new.len() < block_size {
+            return Anchors {
+                block_size: block_size,
+                anchors: anchors,
+            };
+        }
+
+        let mut sampled = HashMap::new();
+        for (n, block) in old.chunks(block_size).enumerate() {
+            if block.len() == block_size {
+                sampled.entry(hash(block)).or_insert(n * block_size);
+            }
+        }
+
+        // The weight of the byte leaving the window.
+        let top = (1..block_size).fold(1u64, |p, _| p.wrapping_mul(HASH_BASE));
+
+        let mut h = hash(&new[..block_size]);
+        let mut i = 0;
+        loop {
+            if let Some(&old_offset) = sampled.get(&h) {
+                if old[old_offset..old_offset + block_size] == new[i..i + block_size] {
+                    anchors.push((i, old_offset as isize - i as isize));
+
+                    // Nothing more to learn inside this block; start afresh
+                    // after it.
+                    i += block_size;
+                    if i + block_size > new.len() {
+                        break;
+                    }
+                    h = hash(&new[i..i + block_size]);
+                    continue;
+                }
+            }
+
+            if i + block_size >= new.len() {
+                break;
+            }
+            h = h.wrapping_sub((new[i] as u64).wrapping_mul(top))
+                .wrapping_mul(HASH_BASE)
+                .wrapping_add(new[i + block_size] as u64);
+            i += 1;
+        }
+
+        Anchors {
+            block_size: block_size,
+            anchors: anchors,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.anchors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.anchors.is_empty()
+    }
+
+    /// The diagonal of the anchor nearest `new_offset`, if one is close
+    /// enough to be worth trying.
+    pub fn diagonal_near(&self, new_offset: usize) -> Option<isize> {
+        let p = match self.anchors.binary_search_by_key(&new_offset, |&(at, _)| at) {
+            Ok(p) => return Some(self.anchors[p].1),
+            Err(p) => p,
+        };
+
+        let before = if p > 0 { Some(self.anchors[p - 1]) } else { None };
+        let after = self.anchors.get(p).cloned();
+
+        let distance = |&(at, _): &(usize, isize)| {
+            if at > new_offset { at - new_offset } else { new_offset - at }
+        };
+        let nearest = match (before, after) {
+            (Some(b), Some(a)) => if distance(&b) <= distance(&a) { b } else { a },
+            (Some(b), None) => b,
+            (None, Some(a)) => a,
+            (None, None) => return None,
+        };
+
+        if distance(&nearest) <= ANCHOR_REACH * self.block_size {
+            Some(nearest.1)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::{Index, MatchIter, MatchOptions};
+    use test_util::noise;
+
+    #[test]
+    fn test_find_anchors() {
+        let old = noise(1, 64 * 1024);
+
+        let mut new = noise(2, 30000);
+        new.extend_from_slice(&old[40000..50000]);
+        new.extend_from_slice(&noise(3, 5000));
+
+        let anchors = Anchors::find(&old, &new, 4096);
+        assert_eq!(anchors.len(), 2);
+        assert_eq!(anchors.diagonal_near(32000), Some(40000 - 30000));
+        assert_eq!(anchors.diagonal_near(100), None);
+
+        assert!(Anchors::find(&old[..100], &new, 4096).is_empty());
+    }
+
+    #[test]
+    fn test_anchored_matches() {
+        let old = noise(1, 64 * 1024);
+
+        // A section moved a long way, with every 6th byte changed, as
+        // relocated addresses would be: too often for any exact match to
+        // reach the usual minimum, except in one untouched stretch.
+        let mut new = noise(2, 1000);
+        let moved = new.len();
+        new.extend_from_slice(&old[50000..62000]);
+        for i in (moved..moved + 3000).chain(moved + 9000..new.len()).filter(|i| i % 6 == 0) {
+            new[i] = new[i].wrapping_add(1);
+        }
+
+        let index = Index::compute(old.clone());
+        let matched = |options: MatchOptions| MatchIter::with_options(&index, &new, options)
+            .map(|m| m.matched.len())
+            .sum::<usize>();
+
+        let plain = matched(MatchOptions::new());
+        let anchored = matched(MatchOptions::new().anchor_block(4096));
+        assert!(anchored > plain + 4000, "{} vs {}", anchored, plain);
+
+        let covered = MatchIter::with_options(&index, &new, MatchOptions::new().anchor_block(4096))
+            .map(|m| m.matched.len() + m.unmatched_suffix)
+            .sum::<usize>();
+        assert_eq!(covered, new.len());
+    }
+}
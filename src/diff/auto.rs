@@ -0,0 +1,384 @@
+//! Picking the patch engine that gives the smallest patch.
+//!
+//! Every engine's output starts with its own magic, so `patch::apply_any`
+//! can apply whichever one wins without being told.
+
+use std::io::{self, Write};
+
+use diff::{DeltaMode, Match, MatchIter, Matcher, PatchOptions};
+#[cfg(feature = "zstd")]
+use diff::Compression;
+use format::bsdiff;
+#[cfg(feature = "zstd")]
+use format::{linear_diff, zstd_patch_from};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// Classic BSDIFF40, with bzip2-compressed sections.
+    Bsdiff,
+
+    /// linear_diff with a zstd-compressed body (`LINDIFZ2`).  With
+    /// `Compression::None` it writes plain, uncompressed linear_diff
+    /// (`LINDIF02`) instead, which `inspect` reports as `linear_diff` and
+    /// which applies without the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    LinearDiffZstd,
+
+    /// The new file compressed by zstd with the old one as its dictionary
+    /// (see `format::zstd_patch_from`).  Matches aren't used; zstd finds its
+    /// own.  There is no uncompressed form, so `Compression::None` is the
+    /// fastest level, and exclude and literal ranges can't be honored.
+    #[cfg(feature = "zstd")]
+    ZstdPatchFrom,
+}
+
+/// Every engine, in the order `generate_smallest` breaks ties.
+#[cfg(feature = "zstd")]
+pub const ALL_ENGINES: &'static [Engine] = &[
+    Engine::Bsdiff,
+    Engine::LinearDiffZstd,
+    Engine::ZstdPatchFrom,
+];
+
+#[cfg(not(feature = "zstd"))]
+pub const ALL_ENGINES: &'static [Engine] = &[
+    Engine::Bsdiff,
+];
+
+/// The engines whose patches are made of copy and extra commands, which
+/// `patch::transcode` and `reread::Plan` can write and `inspect` can list.
+#[cfg(feature = "zstd")]
+pub const STEP_ENGINES: &'static [Engine] = &[
+    Engine::Bsdiff,
+    Engine::LinearDiffZstd,
+];
+
+#[cfg(not(feature = "zstd"))]
+pub const STEP_ENGINES: &'static [Engine] = &[
+    Engine::Bsdiff,
+];
+
+#[cfg(feature = "zstd")]
+pub(crate) fn zstd_level(compression: Compression) -> i32 {
+    match compression {
+        // Never used by linear_diff, where `None` skips compression
+        // altogether; zstd patch-from has no such option.
+        Compression::None | Compression::Fastest => 1,
+        Compression::Default => 3,
+        Compression::Best => 19,
+    }
+}
+
+/// The error for writing an `Engine::ZstdPatchFrom` patch from steps rather
+/// than from the old file.
+#[cfg(feature = "zstd")]
+pub(crate) fn patch_from_needs_old() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+        "zstd patch-from patches are written from the old file, not from another patch's commands")
+}
+
+/// Fails with `InvalidInput` unless `engine`'s format can record
+/// `options.delta_mode`.  Only bsdiff's header has room for it; linear_diff
+/// and zstd patch-from patches are always `DeltaMode::Subtract`.
+pub(crate) fn check_delta_mode(engine: Engine, options: &PatchOptions) -> io::Result<()> {
+    let name: Option<&str> = match engine {
+        Engine::Bsdiff => None,
+        #[cfg(feature = "zstd")]
+        Engine::LinearDiffZstd => Some("linear_diff"),
+        #[cfg(feature = "zstd")]
+        Engine::ZstdPatchFrom => Some("zstd patch-from"),
+    };
+
+    match name {
+        Some(name) if options.delta_mode != DeltaMode::Subtract =>
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} patches can't hold {:?} deltas", name, options.delta_mode))),
+        _ => Ok(()),
+    }
+}
+
+/// Fails with `InvalidInput` unless `engine` can write a patch with
+/// `options`: `check_delta_mode`, and no restricted ranges for
+/// `Engine::ZstdPatchFrom`.
+pub(crate) fn check_options(engine: Engine, options: &PatchOptions) -> io::Result<()> {
+    check_delta_mode(engine, options)?;
+
+    #[cfg(feature = "zstd")]
+    {
+        if engine == Engine::ZstdPatchFrom && options.restricts_ranges() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "zstd patch-from patches can't honor exclude or literal ranges"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Generates a patch with a single engine.
+pub fn generate_with<M: Matcher>(engine: Engine, old: &M, new: &[u8], options: &PatchOptions) -> io::Result<Vec<u8>> {
+    let mut patch = Vec::new();
+    write_with(engine, old, new, options, &mut patch)?;
+    Ok(patch)
+}
+
+/// Like `generate_with`, writing the patch to `writer`.
+pub fn write_with<M: Matcher, W: Write>(engine: Engine, old: &M, new: &[u8], options: &PatchOptions, writer: W)
+    -> io::Result<()>
+{
+    write_found_matches(engine, old.data(), new, MatchIter::with_options(old, new, options.matching), options, writer)
+}
+
+/// Like `write_with`, with `matches` as `MatchIter` would find them, before
+/// `options` constrains them.
+pub(crate) fn write_found_matches<I, W>(engine: Engine, old: &[u8], new: &[u8], matches: I, options: &PatchOptions,
+    writer: W) -> io::Result<()>
+    where
+        I: Iterator<Item = Match>,
+        W: Write
+{
+    check_options(engine, options)?;
+
+    match engine {
+        Engine::Bsdiff =>
+            bsdiff::write_found_matches(old, new, matches, options, writer),
+        #[cfg(feature = "zstd")]
+        Engine::LinearDiffZstd if options.restricts_ranges() =>
+            write_linear_diff(old, new, options.restrict_ranges(matches.collect()), options, writer),
+        #[cfg(feature = "zstd")]
+        Engine::LinearDiffZstd =>
+            write_linear_diff(old, new, matches, options, writer),
+        #[cfg(feature = "zstd")]
+        Engine::ZstdPatchFrom =>
+            zstd_patch_from::generate_patch(old, new, zstd_level(options.compression), writer),
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn write_linear_diff<I, W>(old: &[u8], new: &[u8], matches: I, options: &PatchOptions, writer: W) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Match>,
+        W: Write
+{
+    if options.compression == Compression::None {
+        linear_diff::generate_patch_for_matches(old, new, matches, writer)
+    } else {
+        linear_diff::generate_compressed_patch_for_matches(old, new, matches, zstd_level(options.compression), writer)
+    }
+}
+
+/// Tries each of `engines` in turn and returns the smallest patch, along
+/// with the engine that produced it.  Ties go to the earlier engine.
+/// Engines that can't write a patch with `options` (say, a delta mode only
+/// bsdiff records) are skipped.
+///
+/// Fails with `InvalidInput` if `engines` is empty, or if none of them can
+/// write a patch with `options`.
+pub fn generate_smallest<M: Matcher>(engines: &[Engine], old: &M, new: &[u8], options: &PatchOptions)
+    -> io::Result<(Engine, Vec<u8>)>
+{
+    let mut best: Option<(Engine, Vec<u8>)> = None;
+
+    for engine in usable_engines(engines, options)? {
+        let patch = generate_with(engine, old, new, options)?;
+
+        let better = match best {
+            Some((_, ref b)) => patch.len() < b.len(),
+            None => true,
+        };
+
+        if better {
+            best = Some((engine, patch));
+        }
+    }
+
+    Ok(best.unwrap())
+}
+
+/// Like `generate_smallest`, but picks the engine by trying each on just
+/// `sample_len` bytes from the middle of `new` (diffed against the whole
+/// old file), then writes the whole patch with the winner only.  This is
+/// much quicker with many engines or big files, at the risk of picking an
+/// engine that only wins on the sample.
+pub fn generate_smallest_sampled<M: Matcher>(engines: &[Engine], old: &M, new: &[u8], options: &PatchOptions,
+    sample_len: usize) -> io::Result<(Engine, Vec<u8>)>
+{
+    if new.len() <= sample_len {
+        return generate_smallest(engines, old, new, options);
+    }
+
+    let start = (new.len() - sample_len) / 2;
+    let sample = &new[start..start + sample_len];
+
+    let mut best: Option<(Engine, usize)> = None;
+    for engine in usable_engines(engines, options)? {
+        let len = generate_with(engine, old, sample, options)?.len();
+        if best.map_or(true, |(_, b)| len < b) {
+            best = Some((engine, len));
+        }
+    }
+
+    let engine = best.unwrap().0;
+    Ok((engine, generate_with(engine, old, new, options)?))
+}
+
+/// The `engines` that `check_options` allows, failing as
+/// `generate_smallest` documents if there are none.
+fn usable_engines(engines: &[Engine], options: &PatchOptions) -> io::Result<Vec<Engine>> {
+    if engines.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No engines to choose from"));
+    }
+
+    let mut first_err = None;
+    let mut usable = Vec::new();
+    for &engine in engines {
+        match check_options(engine, options) {
+            Ok(()) => usable.push(engine),
+            Err(err) => { first_err.get_or_insert(err); }
+        }
+    }
+
+    match first_err {
+        Some(err) if usable.is_empty() => Err(err),
+        _ => Ok(usable),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use diff::Index;
+    use patch::apply_any;
+    use test_util::noise;
+
+    #[test]
+    fn test_generate_smallest() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let (engine, patch) = generate_smallest(ALL_ENGINES, &index, &new[..], &PatchOptions::default()).unwrap();
+
+        for &other in ALL_ENGINES {
+            let other_patch = generate_with(other, &index, &new[..], &PatchOptions::default()).unwrap();
+            assert!(patch.len() <= other_patch.len(), "{:?} beat {:?}", other, engine);
+        }
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(&new[..], &out[..]);
+    }
+
+    #[test]
+    fn test_generate_smallest_skips_unusable() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let err = generate_smallest(&[], &index, &new[..], &PatchOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = generate_smallest_sampled(&[], &index, &new[..], &PatchOptions::default(), 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Only bsdiff can record xor deltas, so it's picked whatever else is
+        // on offer.
+        let xor = PatchOptions::default().delta_mode(DeltaMode::Xor);
+        let (engine, _) = generate_smallest(ALL_ENGINES, &index, &new[..], &xor).unwrap();
+        assert_eq!(engine, Engine::Bsdiff);
+
+        #[cfg(feature = "zstd")]
+        {
+            let err = generate_smallest(&[Engine::LinearDiffZstd], &index, &new[..], &xor).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_generate_smallest_sampled() {
+        let old = noise(1, 50000);
+        let mut new = old.clone();
+        for i in 0..50 {
+            new[i * 1000] ^= 0x55;
+        }
+        new.extend_from_slice(&noise(2, 3000));
+        let index = Index::compute(old.clone());
+        let options = PatchOptions::default();
+
+        // A sample as big as the file is no sample at all.
+        assert_eq!(generate_smallest_sampled(ALL_ENGINES, &index, &new, &options, new.len()).unwrap(),
+            generate_smallest(ALL_ENGINES, &index, &new, &options).unwrap());
+
+        let (engine, patch) = generate_smallest_sampled(ALL_ENGINES, &index, &new, &options, 4096).unwrap();
+        assert_eq!(patch, generate_with(engine, &index, &new, &options).unwrap());
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert!(out == new);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_patch_from() {
+        use patch::{apply_from_slice, transcode};
+
+        let old = noise(3, 20000);
+        let mut new = old.clone();
+        new[7000..7010].copy_from_slice(b"0123456789");
+        let index = Index::compute(old.clone());
+
+        let patch = generate_with(Engine::ZstdPatchFrom, &index, &new, &PatchOptions::default()).unwrap();
+        assert!(patch.starts_with(zstd_patch_from::MAGIC));
+        assert!(patch.len() < 1000, "{}", patch.len());
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert!(out == new);
+        let mut out = Vec::new();
+        apply_from_slice(&patch, &old, &mut out).unwrap();
+        assert!(out == new);
+
+        // Neither way round is there a list of commands to transcode.
+        let plain = generate_with(Engine::Bsdiff, &index, &new, &PatchOptions::default()).unwrap();
+        let err = transcode(&plain, Vec::new(), Engine::ZstdPatchFrom, &PatchOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = transcode(&patch, Vec::new(), Engine::Bsdiff, &PatchOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let summary = ::inspect::summarize(&patch).unwrap();
+        assert_eq!((summary.format.as_str(), summary.new_size), (zstd_patch_from::NAME, new.len() as u64));
+
+        // Excluded ranges would still be referenced, so the engine refuses
+        // them, and `generate_smallest` passes it over.
+        let excluded = PatchOptions::default().exclude_ranges(vec![0..100]);
+        let err = generate_with(Engine::ZstdPatchFrom, &index, &new, &excluded).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let (engine, _) = generate_smallest(ALL_ENGINES, &index, &new, &excluded).unwrap();
+        assert!(engine != Engine::ZstdPatchFrom);
+    }
+
+    #[test]
+    fn test_delta_mode_needs_bsdiff() {
+        use patch::transcode;
+
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+        let xor = PatchOptions::default().delta_mode(DeltaMode::Xor);
+
+        let plain = generate_with(Engine::Bsdiff, &index, &new[..], &PatchOptions::default()).unwrap();
+        for &engine in ALL_ENGINES {
+            let written = generate_with(engine, &index, &new[..], &xor);
+            let transcoded = transcode(&plain, Vec::new(), engine, &xor);
+            if engine == Engine::Bsdiff {
+                assert!(written.is_ok());
+                // The steps are subtraction deltas, which bsdiff can't
+                // re-encode either.
+                assert_eq!(transcoded.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            } else {
+                assert_eq!(written.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+                assert_eq!(transcoded.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            }
+        }
+    }
+}
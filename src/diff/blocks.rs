@@ -0,0 +1,133 @@
+//! Block-aligned matching, for flash storage that wants writes aligned to
+//! its erase blocks.
+//!
+//! The new file is cut into blocks of `MatchOptions::block_size` bytes, and
+//! each block becomes either a copy (a delta against one stretch of the old
+//! file) or a replacement (extra bytes), so that every command's output
+//! starts on a block boundary.  The old offsets tried for each block come
+//! from the matches found without alignment, so this costs little beyond the
+//! usual search; the patch grows by whatever the blocks' edges drag in.
+
+use std::cmp::min;
+
+use diff::{Delta, Match, Matcher};
+
+/// Quantizes `matches`, which must cover `new` in order, to blocks of
+/// `block_size` bytes.
+///
+/// A block is copied from the old offset (along any match overlapping it)
+/// that agrees with it on the most bytes, as long as that's at least half of
+/// them; otherwise it's replaced outright.  Consecutive blocks copied along
+/// the same diagonal share a command.
+pub fn align<M: Matcher>(old: &M, new: &[u8], block_size: usize, matches: &[Match]) -> Vec<Match> {
+    assert!(block_size > 0);
+    let old_data = old.data();
+
+    // The new-file range of each match, and its diagonal: the old offset
+    // minus the new one.
+    let mut diagonals = Vec::new();
+    let mut pos = 0;
+    for m in matches {
+        if m.matched.len() > 0 {
+            diagonals.push((pos, pos + m.matched.len(), m.matched.old_offset as isize - pos as isize));
+        }
+        pos += m.matched.len() + m.unmatched_suffix;
+    }
+
+    let mut aligned = Vec::new();
+    let mut current = Match::default();
+    let mut current_diagonal = None;
+    let mut first = 0;
+
+    let mut start = 0;
+    while start < new.len() {
+        let end = min(start + block_size, new.len());
+        while first < diagonals.len() && diagonals[first].1 <= start {
+            first += 1;
+        }
+
+        let best = diagonals[first..].iter()
+            .take_while(|&&(begin, _, _)| begin < end)
+            .filter_map(|&(_, _, diagonal)| {
+                let old_start = start as isize + diagonal;
+                if old_start < 0 || old_start as usize + (end - start) > old_data.len() {
+                    return None;
+                }
+                let old_block = &old_data[old_start as usize .. old_start as usize + (end - start)];
+                let same = old_block.iter().zip(&new[start..end]).filter(|&(a, b)| a == b).count();
+                Some((diagonal, same))
+            })
+            .max_by_key(|&(_, same)| same);
+
+        match best {
+            Some((diagonal, same)) if same * 2 >= end - start => {
+                if current_diagonal == Some(diagonal) && current.unmatched_suffix == 0 {
+                    current.matched.lower_delta_len += end - start;
+                } else {
+                    if current.matched.len() > 0 || current.unmatched_suffix > 0 {
+                        aligned.push(current);
+                    }
+
+                    // The whole block goes in the delta: its exact stretches
+                    // are just zeros there.
+                    current = Match {
+                        matched: Delta {
+                            old_offset: (start as isize + diagonal) as usize,
+                            lower_delta_len: end - start,
+                            mid_exact_len: 0,
+                            upper_delta_len: 0,
+                        },
+                        unmatched_suffix: 0,
+                    };
+                    current_diagonal = Some(diagonal);
+                }
+            }
+            _ => current.unmatched_suffix += end - start,
+        }
+
+        start = end;
+    }
+
+    if current.matched.len() > 0 || current.unmatched_suffix > 0 {
+        aligned.push(current);
+    }
+
+    aligned
+}
+
+#[cfg(test)]
+mod tests {
+    use diff::{Index, MatchIter, MatchOptions};
+    use format::linear_diff;
+
+    #[test]
+    fn test_block_aligned_matches() {
+        let old = (0..8192u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+
+        let mut new = b"a short new header".to_vec();
+        new.extend_from_slice(&old[100..3000]);
+        new.extend_from_slice(b"something new in the middle");
+        new.extend_from_slice(&old[4000..8000]);
+        new[2000] ^= 0xff;
+
+        let index = Index::compute(old.clone());
+        let options = MatchOptions::new().block_size(256);
+        let matches = MatchIter::with_options(&index, &new, options).collect::<Vec<_>>();
+
+        let mut pos = 0;
+        for m in &matches {
+            assert_eq!(pos % 256, 0, "{:?}", matches);
+            pos += m.matched.len();
+            assert!(pos % 256 == 0 || pos == new.len(), "{:?}", matches);
+            pos += m.unmatched_suffix;
+        }
+        assert_eq!(pos, new.len());
+        assert!(matches.iter().any(|m| m.matched.len() > 0));
+
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch_with(&index, &new, options, &mut patch).unwrap();
+        let mut out = Vec::new();
+        linear_diff::apply_patch_from_slice(&patch[..], &old, &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+}
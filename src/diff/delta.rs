@@ -0,0 +1,172 @@
+//! How the delta bytes of a patch are worked out from the old and new
+//! files.
+//!
+//! Classic bsdiff subtracts each old byte from the new one (mod 256), and
+//! that's what every format does unless its header records otherwise.
+//! Whatever the encoding, a byte that's unchanged must encode as zero: the
+//! formats count on it for copies and runs of zeros, and compressors do
+//! well on them.
+
+use std::io::{self, Write};
+use std::cmp::min;
+
+use diff::write_fill;
+use patch::add_delta;
+
+/// An encoding of delta bytes: `encode` works out the delta byte for an
+/// old and a new byte, and `decode` recovers the new byte.
+pub trait DeltaEncoder {
+    fn encode(&self, old: u8, new: u8) -> u8;
+
+    fn decode(&self, old: u8, delta: u8) -> u8;
+
+    /// Writes the delta of `new` against `old`, which must be the same
+    /// length.
+    fn write_delta<W: Write>(&self, mut w: W, old: &[u8], new: &[u8]) -> io::Result<()> {
+        assert_eq!(old.len(), new.len());
+        let mut buf = [0u8; 1024];
+        let mut written = 0;
+        while written < old.len() {
+            let to_write = min(buf.len(), old.len() - written);
+            for i in 0..to_write {
+                buf[i] = self.encode(old[i + written], new[i + written]);
+            }
+
+            w.write_all(&buf[..to_write])?;
+            written += to_write;
+        }
+        Ok(())
+    }
+
+    /// Writes the delta of `count` unchanged bytes.
+    fn write_zeros<W: Write>(&self, w: W, count: u64) -> io::Result<()> {
+        write_fill(w, 0, count)
+    }
+}
+
+/// Bytewise subtraction, as in classic bsdiff.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Subtract;
+
+impl DeltaEncoder for Subtract {
+    fn encode(&self, old: u8, new: u8) -> u8 {
+        new.wrapping_sub(old)
+    }
+
+    fn decode(&self, old: u8, delta: u8) -> u8 {
+        old.wrapping_add(delta)
+    }
+}
+
+/// Bytewise XOR.  Some secure-boot verifiers can XOR and hash in
+/// hardware, so patches in this mode are cheaper for them to check.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Xor;
+
+impl DeltaEncoder for Xor {
+    fn encode(&self, old: u8, new: u8) -> u8 {
+        new ^ old
+    }
+
+    fn decode(&self, old: u8, delta: u8) -> u8 {
+        old ^ delta
+    }
+}
+
+/// Which `DeltaEncoder` a patch uses, as `PatchOptions` chooses it and
+/// patch headers record it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DeltaMode {
+    Subtract,
+    Xor,
+}
+
+impl Default for DeltaMode {
+    fn default() -> DeltaMode {
+        DeltaMode::Subtract
+    }
+}
+
+impl DeltaEncoder for DeltaMode {
+    fn encode(&self, old: u8, new: u8) -> u8 {
+        match *self {
+            DeltaMode::Subtract => Subtract.encode(old, new),
+            DeltaMode::Xor => Xor.encode(old, new),
+        }
+    }
+
+    fn decode(&self, old: u8, delta: u8) -> u8 {
+        match *self {
+            DeltaMode::Subtract => Subtract.decode(old, delta),
+            DeltaMode::Xor => Xor.decode(old, delta),
+        }
+    }
+
+    fn write_delta<W: Write>(&self, w: W, old: &[u8], new: &[u8]) -> io::Result<()> {
+        match *self {
+            DeltaMode::Subtract => Subtract.write_delta(w, old, new),
+            DeltaMode::Xor => Xor.write_delta(w, old, new),
+        }
+    }
+}
+
+/// XORs `delta` into `buf`, the `Xor` counterpart of `patch::add_delta`.
+pub(crate) fn xor_delta(buf: &mut [u8], delta: &[u8]) {
+    assert_eq!(buf.len(), delta.len());
+    for (b, &d) in buf.iter_mut().zip(delta) {
+        *b ^= d;
+    }
+}
+
+/// Combines old bytes in `buf` with `delta` as `mode` says, leaving the new
+/// bytes in `buf`.
+pub(crate) fn apply_delta(mode: DeltaMode, buf: &mut [u8], delta: &[u8]) {
+    match mode {
+        DeltaMode::Subtract => add_delta(buf, delta),
+        DeltaMode::Xor => xor_delta(buf, delta),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subtract() {
+        let old = (0..3000u32).map(|i| (i * 31) as u8).collect::<Vec<_>>();
+        let new = (0..3000u32).map(|i| (i * 17 + 3) as u8).collect::<Vec<_>>();
+
+        let mut delta = Vec::new();
+        Subtract.write_delta(&mut delta, &old, &new).unwrap();
+        assert_eq!(delta.len(), old.len());
+
+        let decoded = old.iter().zip(&delta).map(|(&o, &d)| Subtract.decode(o, d)).collect::<Vec<_>>();
+        assert_eq!(decoded, new);
+
+        let mut unchanged = Vec::new();
+        Subtract.write_delta(&mut unchanged, &old, &old).unwrap();
+        let mut zeros = Vec::new();
+        Subtract.write_zeros(&mut zeros, old.len() as u64).unwrap();
+        assert_eq!(unchanged, zeros);
+    }
+
+    #[test]
+    fn test_xor() {
+        let old = b"the quick brown fox".to_vec();
+        let new = b"the quick green fox".to_vec();
+
+        for &mode in &[DeltaMode::Subtract, DeltaMode::Xor] {
+            let mut delta = Vec::new();
+            mode.write_delta(&mut delta, &old, &new).unwrap();
+            assert_eq!(delta.iter().filter(|&&d| d != 0).count(), 3, "{:?}", mode);
+
+            let mut buf = old.clone();
+            apply_delta(mode, &mut buf, &delta);
+            assert_eq!(buf, new, "{:?}", mode);
+        }
+
+        assert_eq!(Xor.encode(0x0F, 0xF0), 0xFF);
+        assert_eq!(Subtract.encode(0x0F, 0xF0), 0xE1);
+    }
+}
@@ -0,0 +1,964 @@
+use std::io::{self, Write, Seek};
+use std::cell::Cell;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use diff::{exclude, DeltaMode, DiffStat, Index, Match, MatchIter, Matcher, MatchOptions, Recorded};
+use diff::auto::{self, Engine};
+use format::bsdiff;
+use format::sink::Scratch;
+use concurrency::{self, Executor};
+#[cfg(feature = "sha1")]
+use format::block_hashes;
+#[cfg(feature = "sha1")]
+use format::source_digest::SourceDigest;
+
+/// How hard to squeeze each compressed section of the patch.
+///
+/// For the classic format this maps directly onto the bzip2 block size:
+/// `Fastest` uses 100k blocks, `Best` uses 900k blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Store everything as is, for data that won't compress anyway
+    /// (encrypted or random).  Bsdiff patches get raw sections, recorded in
+    /// a `BsdiffCodecs` header.  `Engine::LinearDiffZstd` writes a plain
+    /// `LINDIF02` linear_diff patch rather than a `LINDIFZ2` one: a different
+    /// format, which applies without the `zstd` feature.
+    /// `Engine::ZstdPatchFrom` can't skip compression and uses the fastest
+    /// level instead.
+    None,
+    Fastest,
+    Default,
+    Best,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::Best
+    }
+}
+
+/// How one section of a bsdiff patch is compressed.  Each codec but `None`
+/// needs the cargo feature of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SectionCodec {
+    None,
+    #[cfg(feature = "bzip2")]
+    Bzip2,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl SectionCodec {
+    /// The codec sections get unless told otherwise: bzip2, or without the
+    /// `bzip2` feature zstd, or failing that none at all.
+    #[cfg(feature = "bzip2")]
+    pub const DEFAULT: SectionCodec = SectionCodec::Bzip2;
+
+    #[cfg(all(not(feature = "bzip2"), feature = "zstd"))]
+    pub const DEFAULT: SectionCodec = SectionCodec::Zstd;
+
+    #[cfg(not(any(feature = "bzip2", feature = "zstd")))]
+    pub const DEFAULT: SectionCodec = SectionCodec::None;
+}
+
+/// The codec for each of the three sections of a bsdiff patch.
+///
+/// All bzip2 (the default, with the `bzip2` feature) gives a classic
+/// BSDIFF40 patch; anything else is written as `BsdiffCodecs`, whose header
+/// records the three codecs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SectionCodecs {
+    pub commands: SectionCodec,
+    pub delta: SectionCodec,
+    pub extra: SectionCodec,
+}
+
+impl SectionCodecs {
+    /// The same codec for every section.
+    pub fn all(codec: SectionCodec) -> SectionCodecs {
+        SectionCodecs {
+            commands: codec,
+            delta: codec,
+            extra: codec,
+        }
+    }
+}
+
+impl Default for SectionCodecs {
+    fn default() -> SectionCodecs {
+        SectionCodecs::all(SectionCodec::DEFAULT)
+    }
+}
+
+/// How `bsdiff::write_full_patch` spreads its work over threads: matching
+/// stays on the calling thread, one thread turns matches into commands and
+/// section bytes, and `compressor_threads` threads compress the sections,
+/// all connected by bounded queues.  Worth it when compression (bzip2
+/// especially) is the bottleneck; the patch is the same either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pipeline {
+    /// From 1 (every section compressed on one thread) to 3 (one thread per
+    /// section); anything else is clamped.
+    pub compressor_threads: usize,
+
+    /// How many matches, or chunks of section data, may wait between stages.
+    pub queue_depth: usize,
+}
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline {
+            compressor_threads: 3,
+            queue_depth: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PatchOptions {
+    pub compression: Compression,
+
+    /// Buffer compressed sections in `scratch` files rather than in memory.
+    /// Useful for very large patches.
+    pub spill_to_temp_files: bool,
+
+    /// Where spilled sections go: `TempFiles::new()` if unset.
+    pub scratch: Option<Arc<Scratch>>,
+
+    /// Runs `Differ::diff_many_parallel`'s workers; see `concurrency`.
+    pub executor: Option<Arc<Executor>>,
+
+    /// How matches between the old and new files are found.
+    pub matching: MatchOptions,
+
+    /// How each section of a bsdiff patch is compressed.
+    pub codecs: SectionCodecs,
+
+    /// If set, `Differ::run` wraps the patch with the SHA-1 of each block of
+    /// this many bytes of the new file; see `format::block_hashes`.
+    #[cfg(feature = "sha1")]
+    pub block_hashes: Option<u32>,
+
+    /// Whether `Differ::run` records the old file's size and SHA-1 at the
+    /// start of the patch, for `patch::verify_source`.
+    #[cfg(feature = "sha1")]
+    pub source_digest: bool,
+
+    /// How long `Differ::run` may spend matching before it falls back to
+    /// looking up only every `FALLBACK_STRIDE`th position of the rest of the
+    /// new file.  The patch is still complete and correct, just bigger where
+    /// matches are missed.  `Differ::run_with_stat` reports how much of the
+    /// new file was diffed that way.
+    pub deadline: Option<Duration>,
+
+    /// If set, bsdiff patches are written by a pipeline of threads; see
+    /// `Pipeline`.  Ignored when `spill_to_temp_files` is set.
+    pub pipeline: Option<Pipeline>,
+
+    /// Drop matches as needed so that bsdiff patches read the old file
+    /// front to back, and mark them as such; see `diff::sequential`.  Such
+    /// a patch can be applied with `patch::apply_sequential` to an old file
+    /// that can't seek.
+    pub sequential_old_access: bool,
+
+    /// How delta bytes are worked out from the old and new files; see
+    /// `DeltaMode`.  Anything but `Subtract` is recorded in a `BsdiffCodecs`
+    /// header, so it needs the bsdiff engine: linear_diff has nowhere to say
+    /// so.
+    pub delta_mode: DeltaMode,
+
+    /// Ranges of the old file that patches never copy from, such as
+    /// per-device signature blocks; the new bytes they'd have matched are
+    /// sent as is.  See `diff::exclude`.  Command boundaries no longer line
+    /// up with `MatchOptions::block_size` where they cut a match.
+    pub exclude_ranges: Vec<Range<u64>>,
+
+    /// Ranges of the new file that patches only ever write as extra bytes,
+    /// never from the old file, such as a signature placeholder that's
+    /// filled in after applying.  See `diff::exclude::literal`.
+    pub literal_ranges: Vec<Range<u64>>,
+}
+
+impl PatchOptions {
+    pub fn new() -> PatchOptions {
+        Default::default()
+    }
+
+    pub fn compression(mut self, compression: Compression) -> PatchOptions {
+        self.compression = compression;
+        self
+    }
+
+    pub fn spill_to_temp_files(mut self, spill: bool) -> PatchOptions {
+        self.spill_to_temp_files = spill;
+        self
+    }
+
+    pub fn scratch(mut self, scratch: Arc<Scratch>) -> PatchOptions {
+        self.scratch = Some(scratch);
+        self
+    }
+
+    pub fn executor(mut self, executor: Arc<Executor>) -> PatchOptions {
+        self.executor = Some(executor);
+        self
+    }
+
+    pub fn matching(mut self, matching: MatchOptions) -> PatchOptions {
+        self.matching = matching;
+        self
+    }
+
+    pub fn codecs(mut self, codecs: SectionCodecs) -> PatchOptions {
+        self.codecs = codecs;
+        self
+    }
+
+    #[cfg(feature = "sha1")]
+    pub fn block_hashes(mut self, block_size: u32) -> PatchOptions {
+        self.block_hashes = Some(block_size);
+        self
+    }
+
+    #[cfg(feature = "sha1")]
+    pub fn source_digest(mut self, source_digest: bool) -> PatchOptions {
+        self.source_digest = source_digest;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> PatchOptions {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn pipeline(mut self, pipeline: Pipeline) -> PatchOptions {
+        self.pipeline = Some(pipeline);
+        self
+    }
+
+    pub fn sequential_old_access(mut self, sequential: bool) -> PatchOptions {
+        self.sequential_old_access = sequential;
+        self
+    }
+
+    pub fn delta_mode(mut self, mode: DeltaMode) -> PatchOptions {
+        self.delta_mode = mode;
+        self
+    }
+
+    pub fn exclude_ranges(mut self, ranges: Vec<Range<u64>>) -> PatchOptions {
+        self.exclude_ranges = ranges;
+        self
+    }
+
+    pub fn literal_ranges(mut self, ranges: Vec<Range<u64>>) -> PatchOptions {
+        self.literal_ranges = ranges;
+        self
+    }
+
+    /// Whether matches have to be rewritten by `diff::exclude` before they
+    /// go in a patch.
+    pub(crate) fn restricts_ranges(&self) -> bool {
+        !self.exclude_ranges.is_empty() || !self.literal_ranges.is_empty()
+    }
+
+    /// `matches` with `exclude_ranges` and `literal_ranges` applied.
+    pub(crate) fn restrict_ranges(&self, mut matches: Vec<Match>) -> Vec<Match> {
+        if !self.exclude_ranges.is_empty() {
+            matches = exclude::exclude(&matches, &self.exclude_ranges);
+        }
+        if !self.literal_ranges.is_empty() {
+            matches = exclude::literal(&matches, &self.literal_ranges);
+        }
+        matches
+    }
+
+    /// The codecs bsdiff sections are actually written with: `codecs`,
+    /// unless `compression` is `None`.
+    pub fn section_codecs(&self) -> SectionCodecs {
+        if self.compression == Compression::None {
+            SectionCodecs::all(SectionCodec::None)
+        } else {
+            self.codecs
+        }
+    }
+}
+
+/// Generates patches from a pre-computed index of the old file (either an
+/// `Index` or an `FmIndex`).
+///
+/// This is the main entry point for diffing:
+///
+/// ```
+/// use rsdiff::diff::{Differ, Index, Compression};
+/// use rsdiff::diff::auto::Engine;
+///
+/// let index = Index::compute(b"this is a test".to_vec());
+///
+/// let mut patch = Vec::new();
+/// Differ::new(&index)
+///     .compression(Compression::Fastest)
+///     .format(Engine::LinearDiffZstd)
+///     .run(b"this is really a test", &mut patch)
+///     .unwrap();
+/// ```
+///
+/// By default the output is a classic BSDIFF40 patch.  Each match found by
+/// `MatchIter` turns into one command, and the command, delta and extra
+/// bytes are each fed into their own bzip2 stream.  Once the whole new file
+/// has been processed, the header (which records the compressed sizes of the
+/// first two streams) is written, followed by the three streams in order.
+pub struct Differ<'a, M: 'a + Matcher = Index> {
+    index: &'a M,
+    options: PatchOptions,
+    engine: Engine,
+    progress: Option<&'a (Fn(u64, u64) + Sync)>,
+}
+
+impl<'a, M: Matcher> Differ<'a, M> {
+    pub fn new(index: &'a M) -> Differ<'a, M> {
+        Differ {
+            index: index,
+            options: PatchOptions::default(),
+            engine: Engine::Bsdiff,
+            progress: None,
+        }
+    }
+
+    pub fn options(mut self, options: PatchOptions) -> Differ<'a, M> {
+        self.options = options;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Differ<'a, M> {
+        self.options.compression = compression;
+        self
+    }
+
+    /// Which patch format `run` produces.  Defaults to `Engine::Bsdiff`.
+    pub fn format(mut self, engine: Engine) -> Differ<'a, M> {
+        self.engine = engine;
+        self
+    }
+
+    /// Has `run` call `progress(done, total)` as it works through the new
+    /// file, about a hundred times in all, finishing with `done == total`.
+    pub fn progress(mut self, progress: &'a (Fn(u64, u64) + Sync)) -> Differ<'a, M> {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Writes a patch from the old file to `new` in the configured format.
+    pub fn run<W: Write>(&self, new: &[u8], writer: W) -> io::Result<()> {
+        self.run_until(new, writer, self.deadline(), None, &mut Vec::new()).map(|_| ())
+    }
+
+    /// Like `run`, returning the `DiffStat` for the diff, including how much
+    /// of it was done at reduced effort after the deadline.  The stats are
+    /// counted from the matches the patch is written from, so they cost no
+    /// extra matching.
+    pub fn run_with_stat<W: Write>(&self, new: &[u8], writer: W) -> io::Result<DiffStat> {
+        let mut stat = DiffStat::default();
+        self.run_counted(new, writer, &mut stat, &mut Vec::new())?;
+        Ok(stat)
+    }
+
+    /// Like `run_with_stat`, adding the stats to `stat`, and assembling the
+    /// patch in `scratch` if it has to be wrapped in an envelope.
+    fn run_counted<W: Write>(&self, new: &[u8], writer: W, stat: &mut DiffStat, scratch: &mut Vec<u8>)
+        -> io::Result<()>
+    {
+        let reduced = self.run_until(new, writer, self.deadline(), Some(&mut *stat), scratch)?;
+        stat.reduced_effort_bytes += reduced;
+        Ok(())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.options.deadline.map(|limit| Instant::now() + limit)
+    }
+
+    /// Runs the diff, counting its matches in `stat` if given, and returns
+    /// how many bytes of `new` were matched at reduced effort.  A patch
+    /// that gets an envelope is put together in `scratch` first.
+    #[cfg_attr(not(feature = "sha1"), allow(unused_variables))]
+    fn run_until<W: Write>(&self, new: &[u8], writer: W, deadline: Option<Instant>, stat: Option<&mut DiffStat>,
+        scratch: &mut Vec<u8>) -> io::Result<u64>
+    {
+        #[cfg(feature = "sha1")]
+        {
+            if self.options.block_hashes.is_some() || self.options.source_digest {
+                scratch.clear();
+                let reduced = self.run_unwrapped(new, &mut *scratch, deadline, stat)?;
+
+                let wrapped;
+                let mut patch = &scratch[..];
+                if let Some(block_size) = self.options.block_hashes {
+                    let mut buf = Vec::new();
+                    block_hashes::write_patch(patch, new, block_size, &mut buf)?;
+                    wrapped = buf;
+                    patch = &wrapped;
+                }
+
+                if self.options.source_digest {
+                    SourceDigest::compute(self.index.data()).write_patch(patch, writer)?;
+                    return Ok(reduced);
+                }
+
+                let mut writer = writer;
+                writer.write_all(patch)?;
+                return Ok(reduced);
+            }
+        }
+
+        self.run_unwrapped(new, writer, deadline, stat)
+    }
+
+    fn run_unwrapped<W: Write>(&self, new: &[u8], writer: W, deadline: Option<Instant>, stat: Option<&mut DiffStat>)
+        -> io::Result<u64>
+    {
+        match deadline {
+            Some(deadline) => {
+                let matcher = DeadlineMatcher::new(self.index, new, deadline);
+                self.write_with(&matcher, new, writer, stat)?;
+                Ok(matcher.reduced_effort_bytes())
+            }
+            None => self.write_with(self.index, new, writer, stat).map(|_| 0),
+        }
+    }
+
+    fn write_with<N: Matcher, W: Write>(&self, matcher: &N, new: &[u8], writer: W, stat: Option<&mut DiffStat>)
+        -> io::Result<()>
+    {
+        match self.progress {
+            Some(progress) => {
+                let total = new.len() as u64;
+                let matcher = ProgressMatcher {
+                    inner: matcher,
+                    total: total,
+                    next_report: Cell::new(0),
+                    progress: progress,
+                };
+
+                self.write_matching(&matcher, new, writer, stat)?;
+                progress(total, total);
+                Ok(())
+            }
+            None => self.write_matching(matcher, new, writer, stat),
+        }
+    }
+
+    fn write_matching<N: Matcher, W: Write>(&self, matcher: &N, new: &[u8], writer: W, stat: Option<&mut DiffStat>)
+        -> io::Result<()>
+    {
+        let matches = MatchIter::with_options(matcher, new, self.options.matching);
+        match stat {
+            Some(stat) => auto::write_found_matches(self.engine, matcher.data(), new, Recorded::new(matches, stat),
+                &self.options, writer),
+            None => auto::write_found_matches(self.engine, matcher.data(), new, matches, &self.options, writer),
+        }
+    }
+
+    /// Runs the diff for each of `targets`, writing each patch to the
+    /// writer paired with it, and returns the `DiffStat`s of all of them
+    /// added together.  Stops at the first error.
+    ///
+    /// The index and options are shared by every target, so a batch against
+    /// one old file costs no more setup than a single diff.  Targets are
+    /// taken from the iterator one at a time, and the buffer an envelope's
+    /// patch is put together in is reused from one to the next.
+    pub fn diff_many<'t, I, W>(&self, targets: I) -> io::Result<DiffStat>
+        where
+            I: IntoIterator<Item=(&'t [u8], W)>,
+            W: Write
+    {
+        let mut stat = DiffStat::default();
+        let mut scratch = Vec::new();
+        for (new, writer) in targets {
+            self.run_counted(new, writer, &mut stat, &mut scratch)?;
+        }
+        Ok(stat)
+    }
+
+    /// Like `diff_many`, with `threads` workers taking targets off the
+    /// shared iterator, run on the options' executor and capped by
+    /// `concurrency::max_threads`.  After an error, the remaining targets
+    /// are skipped; which of them were already written is unspecified.
+    pub fn diff_many_parallel<'t, I, W>(&self, targets: I, threads: usize) -> io::Result<DiffStat>
+        where
+            I: IntoIterator<Item=(&'t [u8], W)>,
+            I::IntoIter: Send,
+            W: Write + Send,
+            M: Sync
+    {
+        assert!(threads > 0, "need at least one thread");
+
+        let queue = Mutex::new(targets.into_iter());
+        let failed = AtomicBool::new(false);
+
+        let executor = concurrency::executor(&self.options.executor);
+        let workers = concurrency::run(&*executor, concurrency::limit(threads), |_| -> io::Result<DiffStat> {
+            let mut stat = DiffStat::default();
+            let mut scratch = Vec::new();
+            while !failed.load(Ordering::Relaxed) {
+                let next = queue.lock().unwrap().next();
+                let (new, writer) = match next {
+                    Some(target) => target,
+                    None => break,
+                };
+
+                if let Err(err) = self.run_counted(new, writer, &mut stat, &mut scratch) {
+                    failed.store(true, Ordering::Relaxed);
+                    return Err(err);
+                }
+            }
+            Ok(stat)
+        });
+
+        let mut total = DiffStat::default();
+        for worker in workers {
+            total.merge(&worker?);
+        }
+        Ok(total)
+    }
+
+    pub fn write_to<W: Write>(&self, new: &[u8], writer: W) -> io::Result<()> {
+        bsdiff::write_full_patch(self.index, new, &self.options, writer)
+    }
+
+    /// Like `write_to`, but streams each section directly into `writer` and
+    /// seeks back to fill in the header, so memory use doesn't grow with the
+    /// size of the patch.
+    pub fn write_to_seekable<W: Write+Seek>(&self, new: &[u8], writer: W) -> io::Result<()> {
+        bsdiff::write_full_patch_seekable(self.index, new, &self.options, writer)
+    }
+}
+
+/// Reports progress from inside the diff loop: every `longest_match` call is
+/// handed the rest of the new file, so its length says how far along we are.
+struct ProgressMatcher<'a, M: 'a> {
+    inner: &'a M,
+    total: u64,
+    next_report: Cell<u64>,
+    progress: &'a (Fn(u64, u64) + Sync),
+}
+
+impl<'a, M: Matcher> Matcher for ProgressMatcher<'a, M> {
+    fn data(&self) -> &[u8] {
+        self.inner.data()
+    }
+
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        let done = self.total - buf.len() as u64;
+
+        if done >= self.next_report.get() {
+            (self.progress)(done, self.total);
+            self.next_report.set(done + self.total / 100 + 1);
+        }
+
+        self.inner.longest_match(buf)
+    }
+}
+
+/// Once the deadline passes, `longest_match` only looks up every
+/// `FALLBACK_STRIDE`th position of the new file.
+pub const FALLBACK_STRIDE: u64 = 32;
+
+/// Enforces `PatchOptions::deadline`: `MatchIter` looks up every position
+/// it can't cover with a match, so skipping most lookups (answering them
+/// with an empty match) is what costs the time.  A match found at one of
+/// the remaining positions is still extended backwards and forwards as
+/// usual, so long matches are mostly recovered anyway.
+struct DeadlineMatcher<'a, M: 'a> {
+    inner: &'a M,
+    total: u64,
+    deadline: Instant,
+    calls: Cell<u32>,
+
+    /// Where in the new file the deadline passed, once it has.
+    passed_at: Cell<Option<u64>>,
+}
+
+impl<'a, M: Matcher> DeadlineMatcher<'a, M> {
+    fn new(inner: &'a M, new: &[u8], deadline: Instant) -> DeadlineMatcher<'a, M> {
+        DeadlineMatcher {
+            inner: inner,
+            total: new.len() as u64,
+            deadline: deadline,
+            calls: Cell::new(0),
+            passed_at: Cell::new(None),
+        }
+    }
+
+    fn reduced_effort_bytes(&self) -> u64 {
+        self.passed_at.get().map_or(0, |at| self.total - at)
+    }
+}
+
+impl<'a, M: Matcher> Matcher for DeadlineMatcher<'a, M> {
+    fn data(&self) -> &[u8] {
+        self.inner.data()
+    }
+
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        let pos = self.total - buf.len() as u64;
+
+        if self.passed_at.get().is_none() {
+            // Reading the clock every time would cost more than the lookups.
+            let calls = self.calls.get();
+            self.calls.set(calls.wrapping_add(1));
+            if calls % 256 == 0 && Instant::now() >= self.deadline {
+                info_event!("diff deadline passed at {} of {} bytes", pos, self.total);
+                self.passed_at.set(Some(pos));
+            }
+        }
+
+        if self.passed_at.get().is_some() && pos % FALLBACK_STRIDE != 0 {
+            let end = self.inner.data().len();
+            return end..end;
+        }
+
+        self.inner.longest_match(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use format::bsdiff::apply_patch;
+    use format::sink::InMemory;
+    use test_util::noise;
+
+    #[test]
+    fn test_differ_compression_levels() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        for &compression in &[Compression::None, Compression::Fastest, Compression::Default, Compression::Best] {
+            let mut patch = Vec::new();
+            Differ::new(&index)
+                .options(PatchOptions::new().compression(compression))
+                .write_to(&new[..], &mut patch)
+                .unwrap();
+
+            let mut out = Vec::new();
+            apply_patch(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+
+            assert_eq!(&new[..], &out[..]);
+        }
+    }
+
+    #[test]
+    fn test_differ_uncompressed() {
+        use patch::apply_any;
+        use format::PatchFormat;
+        use format::bsdiff::Header;
+        #[cfg(feature = "zstd")]
+        use format::linear_diff::{LinearDiff, LinearDiffZstd};
+
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        for &engine in auto::STEP_ENGINES {
+            let mut patch = Vec::new();
+            Differ::new(&index)
+                .compression(Compression::None)
+                .format(engine)
+                .run(&new[..], &mut patch)
+                .unwrap();
+
+            match engine {
+                Engine::Bsdiff =>
+                    assert_eq!(Header::read(&patch).unwrap().codecs, SectionCodecs::all(SectionCodec::None)),
+                // Not zstd at all: the plain format, under its own name.
+                #[cfg(feature = "zstd")]
+                Engine::LinearDiffZstd => {
+                    assert!(LinearDiff::is_match(&patch) && !LinearDiffZstd::is_match(&patch));
+                    assert_eq!(::inspect::summarize(&patch).unwrap().format, LinearDiff::NAME);
+                }
+                #[cfg(feature = "zstd")]
+                Engine::ZstdPatchFrom => unreachable!(),
+            }
+
+            // Extra bytes are stored verbatim.
+            assert!(patch.windows(12).any(|w| w == b"eally a cool"));
+
+            let mut out = Vec::new();
+            apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+            assert_eq!(&new[..], &out[..]);
+        }
+    }
+
+    #[test]
+    fn test_differ_run() {
+        use std::sync::Mutex;
+        use patch::apply_any;
+
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let reports = Mutex::new(Vec::new());
+        let progress = |done: u64, total: u64| reports.lock().unwrap().push((done, total));
+
+        for &engine in auto::ALL_ENGINES {
+            reports.lock().unwrap().clear();
+
+            let mut patch = Vec::new();
+            Differ::new(&index)
+                .compression(Compression::Fastest)
+                .format(engine)
+                .progress(&progress)
+                .run(&new[..], &mut patch)
+                .unwrap();
+
+            let mut out = Vec::new();
+            apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+            assert_eq!(&new[..], &out[..]);
+
+            let reports = reports.lock().unwrap();
+            assert_eq!(reports.last(), Some(&(new.len() as u64, new.len() as u64)));
+            assert!(reports.windows(2).all(|w| w[0].0 <= w[1].0));
+        }
+    }
+
+    #[test]
+    fn test_differ_exclude_ranges() {
+        let old = noise(11, 20000);
+        let mut new = old.clone();
+        new[3000] ^= 1;
+        let index = Index::compute(old.clone());
+
+        // Patches from the excluded ranges' contents on another device.
+        let excluded = vec![1000..1500, 8000..8100, 19990..20000];
+        let mut device = old.clone();
+        for r in &excluded {
+            for b in &mut device[r.start as usize .. r.end as usize] {
+                *b = !*b;
+            }
+        }
+
+        let options = PatchOptions::new().exclude_ranges(excluded.clone());
+        for &engine in auto::STEP_ENGINES {
+            for &compression in &[Compression::Default, Compression::None] {
+                let mut patch = Vec::new();
+                Differ::new(&index)
+                    .options(options.clone().compression(compression))
+                    .format(engine)
+                    .run(&new, &mut patch)
+                    .unwrap();
+
+                let mut out = Vec::new();
+                ::patch::apply_any(&patch, Cursor::new(&device), &mut out).unwrap();
+                assert!(out == new, "{:?} {:?}", engine, compression);
+            }
+        }
+
+        let mut patch = Vec::new();
+        Differ::new(&index).options(options.sequential_old_access(true)).run(&new, &mut patch).unwrap();
+        let mut out = Vec::new();
+        ::patch::apply_sequential(&patch, &device[..], &mut out).unwrap();
+        assert!(out == new);
+    }
+
+    #[test]
+    fn test_differ_literal_ranges() {
+        let old = noise(13, 20000);
+        let mut new = old.clone();
+        new[3000] ^= 1;
+        let index = Index::compute(old.clone());
+
+        // A placeholder that happens to match the old file.
+        let placeholder = 5000..5256u64;
+        let options = PatchOptions::new().literal_ranges(vec![placeholder.clone()]);
+        for &engine in auto::STEP_ENGINES {
+            let mut patch = Vec::new();
+            Differ::new(&index).options(options.clone()).format(engine).run(&new, &mut patch).unwrap();
+
+            let mut out = Vec::new();
+            ::patch::apply_any(&patch, Cursor::new(&old), &mut out).unwrap();
+            assert!(out == new, "{:?}", engine);
+
+            let dump = ::inspect::dump(&patch).unwrap();
+            for c in &dump.commands {
+                let delta = c.new_offset .. c.new_offset + c.delta_len;
+                assert!(delta.end <= placeholder.start || delta.start >= placeholder.end,
+                    "{:?}: {:?} overlaps the placeholder", engine, c);
+            }
+            assert!(dump.summary.extra_bytes >= 256, "{:?}", engine);
+        }
+    }
+
+    #[test]
+    fn test_differ_spill_matches_in_memory() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let mut in_memory = Vec::new();
+        Differ::new(&index).write_to(&new[..], &mut in_memory).unwrap();
+
+        let mut spilled = Vec::new();
+        Differ::new(&index)
+            .options(PatchOptions::new().spill_to_temp_files(true))
+            .write_to(&new[..], &mut spilled)
+            .unwrap();
+
+        assert_eq!(in_memory, spilled);
+
+        let mut spilled = Vec::new();
+        Differ::new(&index)
+            .options(PatchOptions::new().spill_to_temp_files(true).scratch(Arc::new(InMemory)))
+            .write_to(&new[..], &mut spilled)
+            .unwrap();
+
+        assert_eq!(in_memory, spilled);
+    }
+
+    #[test]
+    fn test_differ_diff_many() {
+        use patch::apply_any;
+
+        let old = b"this is a test 12345678 test, and some more text to match against";
+        let index = Index::compute(old.to_vec());
+
+        let targets = (0..6).map(|i| {
+            let mut new = old.to_vec();
+            new[i * 7] = b'!';
+            new.extend_from_slice(format!(" target {}", i).as_bytes());
+            new
+        }).collect::<Vec<_>>();
+
+        let differ = Differ::new(&index).compression(Compression::Fastest);
+
+        let mut patches = vec![Vec::new(); targets.len()];
+        let stat = differ.diff_many(targets.iter().map(|t| &t[..]).zip(patches.iter_mut())).unwrap();
+
+        let mut expected = DiffStat::default();
+        for (new, patch) in targets.iter().zip(&patches) {
+            let mut out = Vec::new();
+            apply_any(patch, Cursor::new(&old[..]), &mut out).unwrap();
+            assert_eq!(&out, new);
+            expected.merge(&DiffStat::from(&index, new));
+        }
+        assert_eq!(stat, expected);
+
+        let mut parallel = vec![Vec::new(); targets.len()];
+        let parallel_stat = differ.diff_many_parallel(
+            targets.iter().map(|t| &t[..]).zip(parallel.iter_mut()), 3).unwrap();
+        assert_eq!(parallel, patches);
+        assert_eq!(parallel_stat, stat);
+
+        // On the caller's own executor, which here runs the workers in turn.
+        #[derive(Debug)]
+        struct Inline(AtomicUsize);
+
+        impl Executor for Inline {
+            fn run_all<'a>(&self, jobs: Vec<concurrency::Job<'a>>) {
+                for job in jobs {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                    job();
+                }
+            }
+        }
+
+        let executor = Arc::new(Inline(AtomicUsize::new(0)));
+        let differ = differ.options(PatchOptions::new().compression(Compression::Fastest).executor(executor.clone()));
+        let mut inline = vec![Vec::new(); targets.len()];
+        differ.diff_many_parallel(targets.iter().map(|t| &t[..]).zip(inline.iter_mut()), 3).unwrap();
+        assert_eq!(inline, patches);
+        assert_eq!(executor.0.load(Ordering::Relaxed), 3);
+
+        // Targets are pulled one at a time, so none past a failure are.
+        struct Failing(bool);
+
+        impl Write for Failing {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 { Err(io::Error::new(io::ErrorKind::Other, "full")) } else { Ok(buf.len()) }
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let pulled = Cell::new(0);
+        let err = differ.diff_many(targets.iter().map(|t| {
+            pulled.set(pulled.get() + 1);
+            (&t[..], Failing(pulled.get() == 2))
+        })).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(pulled.get(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_differ_diff_many_envelopes() {
+        let old = b"this is a test 12345678 test, and some more text to match against";
+        let index = Index::compute(old.to_vec());
+        let targets = vec![old[10..].to_vec(), b"something else".to_vec(), old.to_vec()];
+
+        let differ = Differ::new(&index).options(PatchOptions::new().block_hashes(16).source_digest(true));
+        let mut patches = vec![Vec::new(); targets.len()];
+        differ.diff_many(targets.iter().map(|t| &t[..]).zip(patches.iter_mut())).unwrap();
+
+        for (new, patch) in targets.iter().zip(&patches) {
+            let mut alone = Vec::new();
+            differ.run(new, &mut alone).unwrap();
+            assert_eq!(&alone, patch);
+        }
+    }
+
+    #[test]
+    fn test_differ_deadline() {
+        use patch::apply_any;
+
+        let old = (0..100000u32).map(|i| (i * 7919 % 65521) as u8).collect::<Vec<_>>();
+        let mut new = old.clone();
+        for i in (0..new.len()).filter(|i| i % 1000 == 0) {
+            new[i] = b'!';
+        }
+        let index = Index::compute(old.clone());
+
+        let stat = Differ::new(&index).run_with_stat(&new, &mut Vec::new()).unwrap();
+        assert_eq!(stat.reduced_effort_bytes(), 0);
+
+        // Already past, so it's all done at reduced effort.
+        let differ = Differ::new(&index).options(PatchOptions::new().deadline(Duration::from_secs(0)));
+        let mut patch = Vec::new();
+        let stat = differ.run_with_stat(&new, &mut patch).unwrap();
+        assert_eq!(stat.reduced_effort_bytes(), new.len() as u64);
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn test_differ_seekable_matches_in_memory() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let mut in_memory = Vec::new();
+        Differ::new(&index).write_to(&new[..], &mut in_memory).unwrap();
+
+        let mut seekable = Cursor::new(b"prefix".to_vec());
+        seekable.set_position(6);
+        Differ::new(&index).write_to_seekable(&new[..], &mut seekable).unwrap();
+
+        assert_eq!(seekable.position(), 6 + in_memory.len() as u64);
+        assert_eq!(&seekable.get_ref()[..6], b"prefix");
+        assert_eq!(&seekable.get_ref()[6..], &in_memory[..]);
+    }
+}
@@ -0,0 +1,210 @@
+//! Predicting the size of a patch without compressing anything.
+//!
+//! We run the matcher as usual, but instead of writing the command, delta
+//! and extra streams we feed them into simple entropy models.  The delta and
+//! command streams are dominated by runs of zeros, which bzip2 squashes to
+//! almost nothing, so those models charge each run a single symbol plus the
+//! bits needed for its length.  The extra stream is modelled with plain
+//! order-0 entropy.
+
+use std::f64;
+
+use diff::{Matcher, MatchIter};
+use format::bsdiff::Command;
+
+/// Rough fixed cost of a bzip2 stream: stream header, one block header and
+/// the end-of-stream marker.
+const STREAM_OVERHEAD: u64 = 40;
+
+const HEADER_SIZE: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SizeEstimate {
+    pub command_count: u64,
+
+    /// Estimated compressed size of each section, in bytes.
+    pub commands: u64,
+    pub delta: u64,
+    pub extra: u64,
+
+    /// Uncompressed size of the extra section: bytes of the new file that
+    /// didn't match anything in the old file.
+    pub extra_raw: u64,
+}
+
+impl SizeEstimate {
+    /// Estimated size of the whole patch, including the header.
+    pub fn total(&self) -> u64 {
+        HEADER_SIZE + self.commands + self.delta + self.extra
+    }
+}
+
+struct Histogram {
+    counts: [u64; 257],
+    total: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: [0; 257],
+            total: 0,
+        }
+    }
+
+    fn add(&mut self, symbol: usize) {
+        self.counts[symbol] += 1;
+        self.total += 1;
+    }
+
+    /// Order-0 entropy of everything added so far, in bits.
+    fn entropy_bits(&self) -> f64 {
+        let total = self.total as f64;
+        self.counts.iter()
+            .filter(|&&c| c > 0)
+            .map(|&c| {
+                let c = c as f64;
+                -c * (c / total).log2()
+            })
+            .sum()
+    }
+}
+
+/// Models a stream where zeros come in long runs.  Each run counts as a
+/// single symbol (256) plus the bits to encode its length.
+struct RunModel {
+    hist: Histogram,
+    run: u64,
+    run_bits: f64,
+}
+
+const RUN_SYMBOL: usize = 256;
+
+impl RunModel {
+    fn new() -> RunModel {
+        RunModel {
+            hist: Histogram::new(),
+            run: 0,
+            run_bits: 0.0,
+        }
+    }
+
+    fn add_zeros(&mut self, count: u64) {
+        self.run += count;
+    }
+
+    fn add(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if b == 0 {
+                self.run += 1;
+            } else {
+                self.end_run();
+                self.hist.add(b as usize);
+            }
+        }
+    }
+
+    fn end_run(&mut self) {
+        if self.run > 0 {
+            self.hist.add(RUN_SYMBOL);
+            self.run_bits += (self.run as f64 + 1.0).log2();
+            self.run = 0;
+        }
+    }
+
+    fn estimate(mut self) -> u64 {
+        self.end_run();
+        ((self.hist.entropy_bits() + self.run_bits) / 8.0).ceil() as u64 + STREAM_OVERHEAD
+    }
+}
+
+/// Estimates the size of the classic-format patch that `Differ` would
+/// generate for `new`, without compressing anything.
+pub fn estimate_patch_size<M: Matcher>(old: &M, new: &[u8]) -> SizeEstimate {
+    let old_data = old.data();
+
+    let mut commands = RunModel::new();
+    let mut delta = RunModel::new();
+    let mut extra = Histogram::new();
+
+    let mut command_count = 0;
+    let mut buf = [0u8; 24];
+    let mut i = 0;
+
+    let mut it = MatchIter::from(old, new).peekable();
+
+    while let Some(m) = it.next() {
+        let mm = &m.matched;
+        let next_old_offset = it.peek()
+            .map(|m| m.matched.old_offset)
+            .unwrap_or(mm.old_offset + mm.len());
+
+        Command {
+            bytewise_add_size: mm.len() as u64,
+            extra_append_size: m.unmatched_suffix as u64,
+            oldfile_seek_offset: next_old_offset as i64 - (mm.old_offset + mm.len()) as i64,
+        }.write_to(&mut buf[..]).unwrap();
+        commands.add(&buf);
+        command_count += 1;
+
+        let lower = &old_data[mm.lower_delta_range()];
+        for (o, n) in lower.iter().zip(&new[i .. i + mm.lower_delta_len]) {
+            delta.add(&[n.wrapping_sub(*o)]);
+        }
+
+        delta.add_zeros(mm.mid_exact_len as u64);
+
+        let upper = &old_data[mm.upper_delta_range()];
+        for (o, n) in upper.iter().zip(&new[i + mm.lower_delta_len + mm.mid_exact_len .. i + mm.len()]) {
+            delta.add(&[n.wrapping_sub(*o)]);
+        }
+
+        let extra_begin = i + mm.len();
+        let extra_end = extra_begin + m.unmatched_suffix;
+        for &b in &new[extra_begin .. extra_end] {
+            extra.add(b as usize);
+        }
+
+        i = extra_end;
+    }
+
+    SizeEstimate {
+        command_count: command_count,
+        commands: commands.estimate(),
+        delta: delta.estimate(),
+        extra: (extra.entropy_bits() / 8.0).ceil() as u64 + STREAM_OVERHEAD,
+        extra_raw: extra.total,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::Index;
+
+    #[test]
+    fn test_estimate_identical() {
+        let old = (0..10000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+        let index = Index::compute(old.clone());
+
+        let estimate = estimate_patch_size(&index, &old);
+
+        assert_eq!(estimate.command_count, 1);
+        assert_eq!(estimate.extra_raw, 0);
+        assert!(estimate.total() < 200, "{:?}", estimate);
+    }
+
+    #[test]
+    fn test_estimate_unrelated() {
+        let old = vec![0u8; 1000];
+        let new = (0..10000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+        let index = Index::compute(old);
+
+        let estimate = estimate_patch_size(&index, &new);
+
+        assert_eq!(estimate.extra_raw, new.len() as u64);
+        assert!(estimate.extra > 5000, "{:?}", estimate);
+        assert!(estimate.extra <= new.len() as u64 + STREAM_OVERHEAD, "{:?}", estimate);
+    }
+}
@@ -0,0 +1,187 @@
+//! Keeping regions of the old file out of patches, for bytes that differ
+//! from one device to the next (signature blocks, serial numbers, version
+//! strings) and so can't be copied from, and regions of the new file out of
+//! every command but extra bytes.
+//!
+//! Matching runs as usual; `exclude` then cuts the excluded old bytes out of
+//! each match, and the new bytes they would have produced are sent as extra
+//! bytes instead.  A match that happened to land in an excluded region isn't
+//! looked for elsewhere, so the patch can be bigger than it needs to be.
+//! `literal` does the same for ranges of the new file.
+
+use std::cmp::{max, min};
+use std::ops::Range;
+
+use diff::{Delta, Match};
+
+/// Rewrites `matches`, which must cover the new file in order, so that none
+/// of them reads old bytes in `ranges` (which may overlap, and come in any
+/// order).
+///
+/// Each match is split around the excluded bytes it covers.  The new bytes
+/// those would have produced are appended to the `unmatched_suffix` of the
+/// piece before them, or of the previous match, or go in a leading empty
+/// match if there's neither.
+pub fn exclude(matches: &[Match], ranges: &[Range<u64>]) -> Vec<Match> {
+    let ranges = normalize(ranges);
+    split(matches, |old_start, _, len| allowed(old_start, len, &ranges))
+}
+
+/// Like `exclude`, for `ranges` of the new file: those bytes all end up as
+/// extra bytes, so that they can be overwritten after the patch is applied
+/// (a signature filled in on the device, say) without any command having
+/// depended on them.
+pub fn literal(matches: &[Match], ranges: &[Range<u64>]) -> Vec<Match> {
+    let ranges = normalize(ranges);
+    split(matches, |_, new_start, len| allowed(new_start, len, &ranges))
+}
+
+/// Splits each of `matches` into the pieces `pieces(old_start, new_start,
+/// len)` keeps, given as ranges from its start, turning the rest into extra
+/// bytes.
+fn split<F>(matches: &[Match], mut pieces: F) -> Vec<Match>
+    where F: FnMut(usize, usize, usize) -> Vec<(usize, usize)>
+{
+    let mut out: Vec<Match> = Vec::with_capacity(matches.len());
+    let mut new_pos = 0;
+    for m in matches {
+        let len = m.matched.len();
+
+        // Bytes of the match accounted for so far.
+        let mut done = 0;
+        for (a, b) in pieces(m.matched.old_offset, new_pos, len) {
+            add_extra(&mut out, a - done);
+            out.push(Match {
+                matched: sub_delta(&m.matched, a, b),
+                unmatched_suffix: 0,
+            });
+            done = b;
+        }
+        add_extra(&mut out, len - done + m.unmatched_suffix);
+        new_pos += len + m.unmatched_suffix;
+    }
+    out
+}
+
+/// `ranges` sorted, merged and as `usize`s.
+fn normalize(ranges: &[Range<u64>]) -> Vec<Range<usize>> {
+    let mut sorted = ranges.iter()
+        .filter(|r| r.start < r.end)
+        .map(|r| to_usize(r.start) .. to_usize(r.end))
+        .collect::<Vec<_>>();
+    sorted.sort_by_key(|r| r.start);
+
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(sorted.len());
+    for r in sorted {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = max(last.end, r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+fn to_usize(x: u64) -> usize {
+    min(x, usize::max_value() as u64) as usize
+}
+
+/// The parts of the `len` bytes from `start` outside the (sorted, merged)
+/// `excluded` ranges, relative to `start`.
+fn allowed(start: usize, len: usize, excluded: &[Range<usize>]) -> Vec<(usize, usize)> {
+    let end = start + len;
+    let mut pieces = Vec::new();
+    let mut pos = start;
+
+    // The first excluded range that ends after `start`.
+    let first = match excluded.binary_search_by_key(&start, |r| r.end) {
+        Ok(k) => k + 1,
+        Err(k) => k,
+    };
+    for r in excluded[first..].iter().take_while(|r| r.start < end) {
+        if r.start > pos {
+            pieces.push((pos - start, r.start - start));
+        }
+        pos = max(pos, r.end);
+    }
+    if pos < end {
+        pieces.push((pos - start, len));
+    }
+    pieces
+}
+
+/// The part of `d` from `from` to `to` bytes into it.
+fn sub_delta(d: &Delta, from: usize, to: usize) -> Delta {
+    let overlap = |start: usize, end: usize| min(to, end).saturating_sub(max(from, start));
+    let mid_start = d.lower_delta_len;
+    let upper_start = mid_start + d.mid_exact_len;
+
+    Delta {
+        old_offset: d.old_offset + from,
+        lower_delta_len: overlap(0, mid_start),
+        mid_exact_len: overlap(mid_start, upper_start),
+        upper_delta_len: overlap(upper_start, d.len()),
+    }
+}
+
+fn add_extra(out: &mut Vec<Match>, len: usize) {
+    if len == 0 {
+        return;
+    }
+    match out.last_mut() {
+        Some(last) => last.unmatched_suffix += len,
+        None => out.push(Match { matched: Delta::default(), unmatched_suffix: len }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta(old_offset: usize, lower: usize, mid: usize, upper: usize) -> Delta {
+        Delta { old_offset: old_offset, lower_delta_len: lower, mid_exact_len: mid, upper_delta_len: upper }
+    }
+
+    #[test]
+    fn test_exclude_splits_matches() {
+        let matches = vec![
+            Match { matched: delta(100, 2, 10, 3), unmatched_suffix: 4 },
+            Match { matched: delta(0, 0, 20, 0), unmatched_suffix: 0 },
+            Match { matched: delta(200, 0, 8, 0), unmatched_suffix: 1 },
+        ];
+        let excluded = exclude(&matches, &[105..108, 10..12, 11..14, 195..300]);
+
+        assert_eq!(excluded, vec![
+            Match { matched: delta(100, 2, 3, 0), unmatched_suffix: 3 },
+            Match { matched: delta(108, 0, 4, 3), unmatched_suffix: 4 },
+            Match { matched: delta(0, 0, 10, 0), unmatched_suffix: 4 },
+            Match { matched: delta(14, 0, 6, 0), unmatched_suffix: 9 },
+        ]);
+
+        let len = |ms: &[Match]| ms.iter().map(|m| m.matched.len() + m.unmatched_suffix).sum::<usize>();
+        assert_eq!(len(&excluded), len(&matches));
+
+        assert_eq!(exclude(&matches[..1], &[0..1000]), vec![
+            Match { matched: Delta::default(), unmatched_suffix: 19 },
+        ]);
+        assert_eq!(exclude(&matches, &[]), matches);
+    }
+
+    #[test]
+    fn test_literal_splits_matches() {
+        // Covering new bytes 0..19, 19..39 and 39..48.
+        let matches = vec![
+            Match { matched: delta(100, 2, 10, 3), unmatched_suffix: 4 },
+            Match { matched: delta(0, 0, 20, 0), unmatched_suffix: 0 },
+            Match { matched: delta(200, 0, 8, 0), unmatched_suffix: 1 },
+        ];
+        let literal = literal(&matches, &[5..8, 17..21, 40..42]);
+
+        assert_eq!(literal, vec![
+            Match { matched: delta(100, 2, 3, 0), unmatched_suffix: 3 },
+            Match { matched: delta(108, 0, 4, 3), unmatched_suffix: 6 },
+            Match { matched: delta(2, 0, 18, 0), unmatched_suffix: 0 },
+            Match { matched: delta(200, 0, 1, 0), unmatched_suffix: 2 },
+            Match { matched: delta(203, 0, 5, 0), unmatched_suffix: 1 },
+        ]);
+    }
+}
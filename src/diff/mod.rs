@@ -0,0 +1,844 @@
+use std::io::{self, Write};
+use std::cmp::{min, max};
+use std::ops::Range;
+use std::mem;
+use std::vec;
+
+pub mod anchors;
+pub mod auto;
+pub mod blocks;
+pub mod exclude;
+pub mod optimal;
+#[cfg(feature = "sha1")]
+pub mod reread;
+pub mod sequential;
+pub mod special;
+pub mod text;
+mod delta;
+mod differ;
+mod estimate;
+mod recommend;
+mod similarity;
+
+pub use self::delta::{DeltaEncoder, DeltaMode, Subtract, Xor};
+pub(crate) use self::delta::apply_delta;
+pub use self::differ::{Differ, PatchOptions, Pipeline, Compression, SectionCodec, SectionCodecs, FALLBACK_STRIDE};
+pub use self::estimate::{estimate_patch_size, SizeEstimate};
+pub use self::recommend::{recommend, Recommendation, Threshold};
+pub use self::similarity::{similarity, similarity_sampled, SIMILARITY_MIN_MATCH};
+
+pub use index::{Cache, Index, Matcher};
+use index::longest_prefix;
+use self::anchors::Anchors;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DiffStat {
+    match_count: usize,
+    match_length_sum: u64,
+    partial_match_count: usize,
+    partial_match_length_sum: u64,
+
+    /// Bytes of the new file matched at reduced effort after
+    /// `PatchOptions::deadline` passed.
+    reduced_effort_bytes: u64,
+
+    /// Bytes of the new file the matches cover.
+    new_len: u64,
+
+    forward_seeks: u64,
+    backward_seeks: u64,
+    seek_distance_sum: u64,
+    seek_histogram: Vec<u64>,
+}
+
+/// Counts a seek of `seek` bytes in `histogram`: entry 0 counts seeks of 0
+/// bytes, entry `k` those of `2^(k-1)` up to `2^k - 1` bytes, either way.
+pub(crate) fn record_seek(histogram: &mut Vec<u64>, seek: i64) {
+    let bucket = 64 - seek.wrapping_abs().leading_zeros() as usize;
+    if histogram.len() <= bucket {
+        histogram.resize(bucket + 1, 0);
+    }
+    histogram[bucket] += 1;
+}
+
+/// Mismatches allowed while extending a match, unless configured otherwise.
+pub const DEFAULT_MISMATCH_BUDGET: usize = 8;
+
+/// How many bytes (exact or not) a match along an anchor's diagonal has to
+/// line up to be taken.
+const MIN_ANCHORED_MATCH: usize = 16;
+
+/// How hard `MatchIter` works to find a good sequence of matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Effort {
+    /// Take the longest match at each step (after any `lookahead`).
+    Normal,
+
+    /// Search for the sequence of matches with the smallest estimated
+    /// encoding; see `optimal`.  Far slower, for patches that are generated
+    /// once and downloaded many times.
+    Optimal,
+}
+
+/// Tuning for how `MatchIter` finds and extends matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MatchOptions {
+    /// How many mismatched bytes an exact match may be extended across, in
+    /// either direction.  Extension stops once the budget runs out, and is
+    /// cut back to the longest stretch that's still at least half matching.
+    ///
+    /// Mismatches inside a match cost a non-zero delta byte each, which
+    /// compresses far better than starting a new command, so a larger budget
+    /// helps on inputs like executables where small changes (relocated
+    /// addresses, say) are scattered through otherwise identical code.
+    pub mismatch_budget: usize,
+
+    /// Lazy matching, as in LZ parsers: before taking a match, look this many
+    /// positions further on, and skip ahead if a longer match starts there.
+    /// Greedy matching (0) is the default; 1 or 2 is usually plenty, since
+    /// each extra position costs another index lookup.
+    pub lookahead: usize,
+
+    pub effort: Effort,
+
+    /// If non-zero, every command's output starts on a multiple of this many
+    /// bytes of the new file, and is a whole number of blocks long (bar the
+    /// last); see `blocks`.  Flash devices can then write each command
+    /// straight to its erase blocks, at some cost in patch size.
+    pub block_size: usize,
+
+    /// If non-zero, blocks of this many bytes sampled across the old file
+    /// are looked for throughout the new one, and where the index finds no
+    /// long enough exact match, the diagonal of the nearest such anchor is
+    /// tried too; see `anchors`.  This picks up sections that moved and were
+    /// edited too densely for exact matching, at the cost of a pass over both
+    /// files up front.  4096 is a reasonable size.
+    pub anchor_block: usize,
+}
+
+impl MatchOptions {
+    pub fn new() -> MatchOptions {
+        MatchOptions::default()
+    }
+
+    pub fn mismatch_budget(mut self, budget: usize) -> MatchOptions {
+        self.mismatch_budget = budget;
+        self
+    }
+
+    pub fn lookahead(mut self, lookahead: usize) -> MatchOptions {
+        self.lookahead = lookahead;
+        self
+    }
+
+    pub fn effort(mut self, effort: Effort) -> MatchOptions {
+        self.effort = effort;
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> MatchOptions {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn anchor_block(mut self, anchor_block: usize) -> MatchOptions {
+        self.anchor_block = anchor_block;
+        self
+    }
+}
+
+impl Default for MatchOptions {
+    fn default() -> MatchOptions {
+        MatchOptions {
+            mismatch_budget: DEFAULT_MISMATCH_BUDGET,
+            lookahead: 0,
+            effort: Effort::Normal,
+            block_size: 0,
+            anchor_block: 0,
+        }
+    }
+}
+
+fn partial_match_length(a: &[u8], b: &[u8], budget: usize) -> usize {
+    let mut cur_matches = 0;
+    let mut last_good_i = 0;
+    let mut i = 0;
+
+    let len = min(a.len(), b.len());
+
+    while (i - cur_matches < budget) && i < len {
+        if cur_matches >= i / 2 {
+            last_good_i = i;
+        }
+
+        if a[i] == b[i] {
+            cur_matches += 1;
+        }
+
+        i += 1;
+    }
+
+    last_good_i
+}
+
+fn reverse_partial_match_length(a: &[u8], b: &[u8], budget: usize) -> usize {
+    let mut cur_matches = 0;
+    let mut last_good_i = 0;
+    let mut i = 0;
+
+    let len = min(a.len(), b.len());
+
+    while (i - cur_matches < budget) && i < len {
+        if cur_matches >= i / 2 {
+            last_good_i = i;
+        }
+
+        if a[len - i - 1] == b[len - i - 1] {
+            cur_matches += 1;
+        }
+
+        i += 1;
+    }
+
+    last_good_i
+}
+
+impl DiffStat {
+    pub fn from<M: Matcher>(old: &M, new: &[u8]) -> DiffStat {
+        DiffStat::with_options(old, new, MatchOptions::default())
+    }
+
+    /// Like `from`, finding matches as `options` says.
+    pub fn with_options<M: Matcher>(old: &M, new: &[u8], options: MatchOptions) -> DiffStat {
+        let mut stat = DiffStat::default();
+        Recorded::new(MatchIter::with_options(old, new, options), &mut stat).count();
+        stat
+    }
+
+    /// Counts `m`, the next match.  `old_pos` is where the last match left
+    /// off in the old file.
+    fn record(&mut self, m: &Match, old_pos: &mut i64) {
+        self.new_len += (m.matched.len() + m.unmatched_suffix) as u64;
+
+        // Where the old file is read from next, as a bsdiff command stream
+        // would seek there.
+        if m.matched.len() > 0 {
+            let seek = m.matched.old_offset as i64 - *old_pos;
+            if seek > 0 {
+                self.forward_seeks += 1;
+            } else if seek < 0 {
+                self.backward_seeks += 1;
+            }
+            self.seek_distance_sum += seek.wrapping_abs() as u64;
+            record_seek(&mut self.seek_histogram, seek);
+            *old_pos = (m.matched.old_offset + m.matched.len()) as i64;
+        }
+
+        let m = &m.matched;
+        self.match_count += 1;
+        self.match_length_sum += m.mid_exact_len as u64;
+
+        self.partial_match_length_sum += (m.upper_delta_len + m.lower_delta_len) as u64;
+        if m.upper_delta_len + m.lower_delta_len > 0 {
+            self.partial_match_count += 1;
+        }
+    }
+
+    /// Bytes of the new file that were matched at reduced effort, because
+    /// `PatchOptions::deadline` had passed.  Only `Differ::run_with_stat`
+    /// (and the batch methods built on it) fill this in.
+    pub fn reduced_effort_bytes(&self) -> u64 {
+        self.reduced_effort_bytes
+    }
+
+    /// Seeks forward in the old file between commands.  These and the
+    /// other seek figures are for the commands as matched, before
+    /// `PatchOptions::exclude_ranges`, `literal_ranges` or
+    /// `sequential_old_access` change them.
+    pub fn forward_seeks(&self) -> u64 {
+        self.forward_seeks
+    }
+
+    /// Seeks back in the old file between commands, which are slow on
+    /// devices that read their storage front to back.
+    pub fn backward_seeks(&self) -> u64 {
+        self.backward_seeks
+    }
+
+    /// Bytes of the old file skipped over by seeks, either way.
+    pub fn seek_distance(&self) -> u64 {
+        self.seek_distance_sum
+    }
+
+    /// How many seeks covered each distance, bucketed as in
+    /// `patch::ApplyStats::seek_histogram`.
+    pub fn seek_histogram(&self) -> &[u64] {
+        &self.seek_histogram
+    }
+
+    /// Average bytes of the new file per command, or 0 with no commands.
+    pub fn mean_command_len(&self) -> f64 {
+        if self.match_count == 0 {
+            0.0
+        } else {
+            self.new_len as f64 / self.match_count as f64
+        }
+    }
+
+    /// Adds `other`'s counts to these, as for a batch of diffs.
+    pub fn merge(&mut self, other: &DiffStat) {
+        self.match_count += other.match_count;
+        self.match_length_sum += other.match_length_sum;
+        self.partial_match_count += other.partial_match_count;
+        self.partial_match_length_sum += other.partial_match_length_sum;
+        self.reduced_effort_bytes += other.reduced_effort_bytes;
+        self.new_len += other.new_len;
+        self.forward_seeks += other.forward_seeks;
+        self.backward_seeks += other.backward_seeks;
+        self.seek_distance_sum += other.seek_distance_sum;
+
+        if self.seek_histogram.len() < other.seek_histogram.len() {
+            self.seek_histogram.resize(other.seek_histogram.len(), 0);
+        }
+        for (total, count) in self.seek_histogram.iter_mut().zip(&other.seek_histogram) {
+            *total += count;
+        }
+    }
+}
+
+/// Passes matches through, counting each one in a `DiffStat` on the way, so
+/// a patch and its stats come from a single matching pass.
+pub(crate) struct Recorded<'s, I> {
+    inner: I,
+    stat: &'s mut DiffStat,
+    old_pos: i64,
+}
+
+impl<'s, I> Recorded<'s, I> {
+    pub(crate) fn new(inner: I, stat: &'s mut DiffStat) -> Recorded<'s, I> {
+        Recorded {
+            inner: inner,
+            stat: stat,
+            old_pos: 0,
+        }
+    }
+}
+
+impl<'s, I: Iterator<Item = Match>> Iterator for Recorded<'s, I> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        let m = self.inner.next()?;
+        self.stat.record(&m, &mut self.old_pos);
+        Some(m)
+    }
+}
+
+/// A stretch of the new file lined up against the old file at
+/// `old_offset`, for encoding as bytewise differences.
+///
+/// The stretch is `len()` bytes long, and splits into three parts: an exact
+/// match (`mid_exact_len` bytes, all the same in both files) with up to
+/// `lower_delta_len` and `upper_delta_len` bytes on either side where the
+/// exact match was extended across mismatches.  Most of the bytes there
+/// match too, but some don't.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Delta {
+    /// Where the stretch starts in the old file.
+    pub old_offset: usize,
+    pub lower_delta_len: usize,
+    pub mid_exact_len: usize,
+    pub upper_delta_len: usize,
+}
+
+impl Delta {
+    /// The old-file bytes before the exact match.
+    pub fn lower_delta_range(&self) -> Range<usize> {
+        self.old_offset .. self.old_offset + self.lower_delta_len
+    }
+
+    /// The old-file bytes after the exact match.
+    pub fn upper_delta_range(&self) -> Range<usize> {
+        self.old_offset + self.lower_delta_len + self.mid_exact_len .. self.old_offset + self.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lower_delta_len + self.mid_exact_len + self.upper_delta_len
+    }
+}
+
+/// One step of a diff: `matched.len()` bytes of the new file encoded as a
+/// delta against the old file, then `unmatched_suffix` bytes that didn't
+/// match anything and go in the patch verbatim.
+///
+/// A sequence of matches covers the new file in order, without gaps: each
+/// one starts where the last one's unmatched suffix ends.  The first may
+/// have an empty `matched`, if the new file starts with unmatched bytes.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Match {
+    pub matched: Delta,
+    pub unmatched_suffix: usize,
+}
+
+/// The matches between an old file and a new one, as the built-in patch
+/// formats encode them; see `Match`.  It's an ordinary iterator, for custom
+/// patch formats and analysis tools to build on.
+///
+/// With `Effort::Normal` and no `block_size`, matches are found lazily as
+/// the iterator is advanced.  Otherwise they're all worked out on creation.
+pub struct MatchIter<'a, M: 'a + Matcher = Index> {
+    old: &'a M,
+    new: &'a [u8],
+    options: MatchOptions,
+    i: usize,
+    last_delta: Delta,
+    last_end: usize,
+
+    /// With `Effort::Optimal` or a `block_size`, every match is worked out
+    /// up front.
+    planned: Option<vec::IntoIter<Match>>,
+
+    anchors: Option<Anchors>,
+}
+
+/// The matches between `old` and `new`, with default options.
+pub fn matches<'a, M: Matcher>(old: &'a M, new: &'a [u8]) -> MatchIter<'a, M> {
+    MatchIter::from(old, new)
+}
+
+/// The matches between `old` and `new`, found as `options` says.
+pub fn matches_with_options<'a, M: Matcher>(old: &'a M, new: &'a [u8], options: MatchOptions) -> MatchIter<'a, M> {
+    MatchIter::with_options(old, new, options)
+}
+
+impl<'a, M: Matcher> MatchIter<'a, M> {
+    pub fn from(old: &'a M, new: &'a [u8]) -> MatchIter<'a, M> {
+        MatchIter::with_options(old, new, MatchOptions::default())
+    }
+
+    pub fn with_options(old: &'a M, new: &'a [u8], options: MatchOptions) -> MatchIter<'a, M> {
+        MatchIter::with_options_from_offset(old, new, options, 0)
+    }
+
+    /// The matches covering `new[start..]`, for diffing a new file in
+    /// chunks.  Matches never reach back before `start`, or past the end of
+    /// `new`, so splitting a new file at `a < b < ...` and chaining
+    /// `from_offset(old, &new[..b], a)` for each chunk gives matches for the
+    /// whole file, which any of the formats can write.  Matches that would
+    /// have crossed a boundary are cut there instead; the rest are the same
+    /// as for the whole file.
+    ///
+    /// The bytes before `start` aren't matched at all.  They're still there
+    /// to line up anchors (see `MatchOptions::anchor_block`) with the rest
+    /// of the file.
+    pub fn from_offset(old: &'a M, new: &'a [u8], start: usize) -> MatchIter<'a, M> {
+        MatchIter::with_options_from_offset(old, new, MatchOptions::default(), start)
+    }
+
+    pub fn with_options_from_offset(old: &'a M, new: &'a [u8], options: MatchOptions, start: usize)
+        -> MatchIter<'a, M>
+    {
+        assert!(start <= new.len(), "start is past the end of the new file");
+
+        MatchIter {
+            old: old,
+            new: new,
+            options: options,
+            i: start,
+            last_delta: Default::default(),
+            last_end: start,
+            // Planned matches don't depend on anything before the start.
+            planned: if options.block_size > 0 {
+                let unaligned = MatchIter::with_options(old, &new[start..], options.block_size(0)).collect::<Vec<_>>();
+                Some(blocks::align(old, &new[start..], options.block_size, &unaligned).into_iter())
+            } else {
+                match options.effort {
+                    Effort::Normal => None,
+                    Effort::Optimal => Some(optimal::parse(old, &new[start..], &options).into_iter()),
+                }
+            },
+            anchors: if options.anchor_block > 0 && options.block_size == 0 && options.effort == Effort::Normal {
+                Some(Anchors::find(old.data(), new, options.anchor_block))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl<'a, M: Matcher> MatchIter<'a, M> {
+    /// The longest match starting within `options.lookahead` positions after
+    /// `self.i`, and how far after, if any beats the `len` bytes matched at
+    /// `self.i` itself.
+    fn lookahead(&self, len: usize) -> (usize, Option<Range<usize>>) {
+        let mut best_len = len;
+        let mut best = (0, None);
+
+        for skip in 1..self.options.lookahead + 1 {
+            if self.i + skip >= self.new.len() {
+                break;
+            }
+
+            let m = self.old.longest_match(&self.new[self.i + skip..]);
+            if m.len() > best_len {
+                best_len = m.len();
+                best = (skip, Some(m));
+            }
+        }
+
+        best
+    }
+
+    /// A match along the diagonal of the anchor nearest `self.i`, if that
+    /// lines up at least `MIN_ANCHORED_MATCH` bytes (allowing for
+    /// mismatches).  The range returned is the exact match at `self.i`,
+    /// which may well be empty.
+    fn anchored_match(&self) -> Option<Range<usize>> {
+        let diagonal = self.anchors.as_ref()?.diagonal_near(self.i)?;
+        let old_data = self.old.data();
+
+        let start = self.i as isize + diagonal;
+        if start < 0 || start as usize >= old_data.len() {
+            return None;
+        }
+        let start = start as usize;
+
+        let exact = longest_prefix(&old_data[start..], &self.new[self.i..]);
+        let partial = partial_match_length(
+            &old_data[start + exact..],
+            &self.new[self.i + exact..],
+            self.options.mismatch_budget);
+
+        if exact + partial >= MIN_ANCHORED_MATCH {
+            Some(start..start + exact)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, M: Matcher> Iterator for MatchIter<'a, M> {
+    type Item = Match;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(ref mut planned) = self.planned {
+            return planned.next();
+        }
+
+        while self.i < self.new.len() {
+            let mut m = self.old.longest_match(&self.new[self.i..]);
+
+            // println!("i {} match {:?}", self.i, m);
+
+            let anchored = if m.len() < 8 { self.anchored_match() } else { None };
+
+            if m.len() >= 8 || anchored.is_some() {
+                if let Some(anchored) = anchored {
+                    m = anchored;
+                } else {
+                    let (skip, better) = self.lookahead(m.len());
+                    if let Some(better) = better {
+                        self.i += skip;
+                        m = better;
+                    }
+                }
+
+                let pml = partial_match_length(
+                    &self.old.data()[m.end..],
+                    &self.new[self.i + m.len()..],
+                    self.options.mismatch_budget);
+
+                let rpml = reverse_partial_match_length(
+                    &self.old.data()[..m.start],
+                    &self.new[self.last_end..self.i],
+                    self.options.mismatch_budget);
+
+                let begin = self.i - rpml;
+
+                let last_end = self.last_end;
+                self.last_end = self.i + m.len() + pml;
+
+                self.i += max(1, m.len() + pml) as usize;
+
+                let last_delta = mem::replace(&mut self.last_delta, Delta {
+                    old_offset: m.start - rpml,
+                    lower_delta_len: rpml,
+                    mid_exact_len: m.len(),
+                    upper_delta_len: pml,
+                });
+
+                // Emit the previous match, or the unmatched bytes before the
+                // first one.
+                if begin > last_end || last_delta.len() > 0 {
+                    return Some(Match {
+                        matched: last_delta,
+                        unmatched_suffix: begin - last_end,
+                    });
+                }
+            } else {
+                self.i += max(1, m.len()) as usize;
+            }
+        }
+
+        self.i = min(self.i, self.new.len());
+
+        if self.i > self.last_end || self.last_delta.len() > 0 {
+            let suffix = self.i - self.last_end;
+            self.last_end = self.i;
+            return Some(Match {
+                matched: mem::replace(&mut self.last_delta, Default::default()),
+                unmatched_suffix: suffix,
+            });
+        }
+
+        None
+    }
+}
+
+/// Writes `count` copies of `byte`.
+pub fn write_fill<W: Write>(mut w: W, byte: u8, count: u64) -> io::Result<()> {
+    let buf = [byte; 1024];
+    let mut written = 0;
+    while written < count {
+        let s = w.write(&buf[..min(buf.len() as u64, (count - written)) as usize])?;
+        written += s as u64;
+        // println!("write zero {}", s);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::noise;
+
+    #[test]
+    fn test_index_simple_match() {
+        let index = Index::compute(Vec::from(&b"this is a test"[..]));
+
+        let matches = MatchIter::from(&index, b"this is a test").collect::<Vec<_>>();
+
+        assert_eq!(matches, vec![
+            Match {
+                matched: Delta {
+                    old_offset: 0,
+                    lower_delta_len: 0,
+                    mid_exact_len: 14,
+                    upper_delta_len: 0,
+                }, unmatched_suffix: 0
+            }
+        ]);
+    }
+
+    #[test]
+    fn test_unmatched_prefix() {
+        let index = Index::compute(Vec::from(&b"0123456789abcdef"[..]));
+
+        let matches = MatchIter::from(&index, b"xyz0123456789abcdef").collect::<Vec<_>>();
+
+        assert_eq!(matches, vec![
+            Match {
+                matched: Delta::default(),
+                unmatched_suffix: 3
+            },
+            Match {
+                matched: Delta {
+                    old_offset: 0,
+                    lower_delta_len: 0,
+                    mid_exact_len: 16,
+                    upper_delta_len: 0,
+                },
+                unmatched_suffix: 0
+            }
+        ]);
+    }
+
+    #[test]
+    fn test_diff_stat_seeks() {
+        use std::io::Cursor;
+        use format::bsdiff;
+        use patch::{ApplyStats, PatchApplier};
+
+        let old = noise(5, 4000);
+        let mut new = old[2000..3000].to_vec();
+        new.extend_from_slice(&old[..1000]);
+        new.extend_from_slice(&old[3000..]);
+        let index = Index::compute(old.clone());
+
+        let stat = DiffStat::from(&index, &new);
+        assert_eq!((stat.forward_seeks(), stat.backward_seeks()), (2, 1));
+        assert_eq!(stat.seek_distance(), 2000 + 3000 + 2000);
+        assert_eq!(stat.mean_command_len(), 1000.0);
+
+        // The same seeks as applying the patch sees.
+        let mut applied = ApplyStats::new();
+        let patch = bsdiff::generate_full_patch(&index, &new);
+        PatchApplier::new().apply_observed(&patch, Cursor::new(&old), &mut Vec::new(), &mut applied).unwrap();
+        assert_eq!(stat.seek_histogram(), &applied.seek_histogram[..]);
+
+        let mut twice = stat.clone();
+        twice.merge(&stat);
+        assert_eq!(twice.backward_seeks(), 2);
+        assert_eq!(twice.seek_histogram().iter().sum::<u64>(), 6);
+        assert_eq!(twice.mean_command_len(), 1000.0);
+    }
+
+    #[test]
+    fn test_matches_contract() {
+        let old = b"the quick brown fox jumps over the lazy dog; the quick brown fox sleeps";
+        let new = b"a quick brown cat jumps over the lazy dog! the quick brown fox sleeps. the end";
+        let index = Index::compute(old.to_vec());
+
+        let mut i = 0;
+        for m in matches(&index, &new[..]) {
+            let mm = &m.matched;
+            let exact = mm.old_offset + mm.lower_delta_len;
+            assert_eq!(&old[exact..exact + mm.mid_exact_len],
+                &new[i + mm.lower_delta_len..i + mm.lower_delta_len + mm.mid_exact_len]);
+            assert_eq!(mm.lower_delta_range().len() + mm.mid_exact_len + mm.upper_delta_range().len(), mm.len());
+            i += mm.len() + m.unmatched_suffix;
+        }
+        assert_eq!(i, new.len());
+    }
+
+    #[test]
+    fn test_mismatch_budget() {
+        // Pseudo-random "code", with every 16th byte changed, as relocating
+        // addresses in an executable would.
+        let old = noise(12345, 16384);
+
+        let mut new = old.clone();
+        for i in (7..new.len()).filter(|i| i % 16 == 7) {
+            new[i] = new[i].wrapping_add(1);
+        }
+
+        let index = Index::compute(old.clone());
+        let count = |budget| MatchIter::with_options(&index, &new, MatchOptions::new().mismatch_budget(budget)).count();
+
+        assert_eq!(MatchIter::from(&index, &new).count(), count(DEFAULT_MISMATCH_BUDGET));
+        assert!(count(64) * 4 < count(DEFAULT_MISMATCH_BUDGET),
+            "{} vs {} commands", count(64), count(DEFAULT_MISMATCH_BUDGET));
+
+        // Every byte is still covered, whatever the budget.
+        for &budget in &[0, 1, 8, 64] {
+            let covered = MatchIter::with_options(&index, &new, MatchOptions::new().mismatch_budget(budget))
+                .map(|m| m.matched.len() + m.unmatched_suffix)
+                .sum::<usize>();
+            assert_eq!(covered, new.len());
+        }
+    }
+
+    #[test]
+    fn test_lookahead() {
+        let old = b"ABCDEFGHIJ----BCDEFGHIJKLMNOPQRSTUVWXYZ0123";
+        let new = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123";
+        let index = Index::compute(old.to_vec());
+
+        // Greedily, the short match at the start splits the long one after it.
+        let greedy = MatchIter::with_options(&index, &new[..], MatchOptions::new().mismatch_budget(0))
+            .map(|m| m.matched.mid_exact_len)
+            .collect::<Vec<_>>();
+        assert_eq!(greedy, vec![10, 20]);
+
+        let lazy = MatchIter::with_options(&index, &new[..], MatchOptions::new().mismatch_budget(0).lookahead(2))
+            .collect::<Vec<_>>();
+
+        let covered = lazy.iter().map(|m| m.matched.len() + m.unmatched_suffix).sum::<usize>();
+        assert_eq!(covered, new.len());
+        assert!(lazy.iter().any(|m| m.matched.mid_exact_len == 29), "{:?}", lazy);
+    }
+
+    #[test]
+    fn test_match_iter_from_offset() {
+        let old = b"the quick brown fox jumps over the lazy dog";
+        let new = b"xx the quick brown fox jumps over the lazy cat";
+        let index = Index::compute(old.to_vec());
+
+        assert_eq!(MatchIter::from_offset(&index, &new[..], 0).collect::<Vec<_>>(),
+            MatchIter::from(&index, &new[..]).collect::<Vec<_>>());
+
+        // Splitting inside the match cuts it at the boundary, without
+        // reaching back across it.
+        for start in 0..new.len() + 1 {
+            let matches = MatchIter::from_offset(&index, &new[..], start).collect::<Vec<_>>();
+            let covered = matches.iter().map(|m| m.matched.len() + m.unmatched_suffix).sum::<usize>();
+            assert_eq!(covered, new.len() - start, "from {}", start);
+        }
+
+        let tail = MatchIter::from_offset(&index, &new[..], 13).collect::<Vec<_>>();
+        assert_eq!(tail[0].matched.old_offset, 10);
+        assert_eq!(tail[0].matched.lower_delta_len, 0);
+    }
+
+    #[test]
+    fn test_chunked_matches_apply() {
+        use std::io::Cursor;
+        use format::bsdiff;
+        use patch::apply_any;
+
+        let old = noise(7, 20000);
+        let mut new = old[6000..16000].to_vec();
+        new.extend_from_slice(b"fresh bytes");
+        new.extend_from_slice(&old[..5000]);
+        for i in (0..new.len()).step_by(300) {
+            new[i] ^= 0xFF;
+        }
+        let index = Index::compute(old.clone());
+
+        for &options in &[MatchOptions::new(), MatchOptions::new().effort(Effort::Optimal), MatchOptions::new().block_size(512)] {
+            let bounds = [0, 1, 4096, 4100, 10005, 12345, new.len()];
+            let matches = bounds.windows(2)
+                .flat_map(|w| MatchIter::with_options_from_offset(&index, &new[..w[1]], options, w[0]))
+                .collect::<Vec<_>>();
+
+            let mut patch = Vec::new();
+            bsdiff::write_patch_for_matches(&old, &new, matches, &PatchOptions::default(), &mut patch).unwrap();
+
+            let mut out = Vec::new();
+            apply_any(&patch, Cursor::new(&old), &mut out).unwrap();
+            assert!(out == new, "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn test_index_slightly_less_simple_match() {
+        let index = Index::compute(Vec::from(&b"this is a test 12345678 test"[..]));
+
+        println!("index:");
+        for (i, &offset) in index.offsets.iter().enumerate() {
+            println!("  {}:  {}: {:?}", i, offset, ::std::str::from_utf8(&index.data[offset..]).unwrap());
+        }
+
+        println!("");
+
+        let matches = MatchIter::from(&index,
+            b"this is really a cool uftu 12345678 uftu")
+        .collect::<Vec<_>>();
+
+        assert_eq!(matches, vec![
+            Match {
+                matched: Delta {
+                    old_offset: 0,
+                    lower_delta_len: 0,
+                    mid_exact_len: 8,
+                    upper_delta_len: 1
+                },
+                unmatched_suffix: 16
+            },
+            // NOTE: the following definitely seems sub-optimal.
+            // We can probably do a better job here.
+            Match {
+                matched: Delta {
+                    old_offset: 13,
+                    lower_delta_len: 1,
+                    mid_exact_len: 10,
+                    upper_delta_len: 1
+                },
+                unmatched_suffix: 3
+            }
+        ]);
+    }
+}
\ No newline at end of file
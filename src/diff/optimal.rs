@@ -0,0 +1,281 @@
+//! Optimal parsing: picking the sequence of matches that minimises the
+//! estimated size of the patch, rather than the longest match at each step.
+//!
+//! The candidates are diagonals: alignments of the new file against the old
+//! one, each found by an index lookup and then followed onwards for as long
+//! as it's worth it, across mismatches as well as matches.  Walking the new
+//! file a byte at a time, the cheapest encoding of everything so far is
+//! kept for each way the byte can be covered: as a literal (extra) byte, or
+//! by a command on one of the diagonals being followed, which either
+//! continues from the byte before or starts here.  That's a shortest path
+//! through the graph of every such choice, found by dynamic programming, so
+//! a match is cut short, run across a gap of mismatches or given up for a
+//! literal wherever that's cheaper overall.
+//!
+//! Costs are rough encoded sizes, in bits, in the spirit of `estimate`:
+//! each command has a fixed overhead, and each byte it covers is nearly
+//! free if it matches and more expensive than a literal if it doesn't.
+//! `MatchOptions::mismatch_budget` caps how many mismatches in a row a
+//! command may run across.
+//!
+//! The index is searched at every position, bar those inside a match of
+//! `LONG_MATCH` bytes or more, rather than once per match as in
+//! `MatchIter`, so this is much slower, and keeps a few words of state per
+//! byte of the new file.
+
+use std::f64;
+
+use diff::{Delta, Match, MatchOptions, Matcher};
+
+/// Matches shorter than this aren't worth following a new diagonal for.
+const MIN_MATCH: usize = 8;
+
+/// A match this long is taken to be as good as any that starts inside it,
+/// and the index isn't searched again until it ends.  This keeps long runs
+/// of identical data from taking quadratic time.
+const LONG_MATCH: usize = 256;
+
+/// How many diagonals are followed at once.  When a new one is found, the
+/// one with the most expensive open command makes way.
+const MAX_DIAGONALS: usize = 8;
+
+/// A command, after compression.  Sizes and seeks vary, so commands don't
+/// compress to nearly as little as the runs of zeros around them.
+const COMMAND_BITS: f64 = 64.0;
+
+/// A byte of the extra section.
+const LITERAL_BITS: f64 = 8.0;
+
+/// A zero in the delta section: a byte that matches.
+const MATCH_BITS: f64 = 0.125;
+
+/// A non-zero byte in the delta section.  Dearer than a literal, since it
+/// breaks up the runs of zeros around it as well, so commands don't start
+/// or end on one.
+const MISMATCH_BITS: f64 = 10.0;
+
+/// How the cheapest encoding of the new file up to a position ends.
+#[derive(Clone, Copy)]
+enum Step {
+    Literal,
+    /// A command on `diagonal`, from `start` up to the position.
+    Command { start: usize, diagonal: isize },
+}
+
+/// A diagonal being followed, with the cheapest encoding of the new file so
+/// far that ends in a command on it that's still open.
+struct Open {
+    diagonal: isize,
+    cost: f64,
+    start: usize,
+    /// Mismatches at the end of the open command.
+    mismatch_run: usize,
+}
+
+/// The cheapest sequence of matches covering `new`, in the same shape that
+/// `MatchIter` produces.
+pub fn parse<M: Matcher>(old: &M, new: &[u8], options: &MatchOptions) -> Vec<Match> {
+    let old_data = old.data();
+    let n = new.len();
+
+    // `cost[p]` is the cheapest encoding of `new[..p]` that ends on a
+    // boundary between commands and literals, and `steps[p - 1]` how it
+    // ends.
+    let mut cost = vec![0.0f64; n + 1];
+    let mut steps = Vec::with_capacity(n);
+    let mut open: Vec<Open> = Vec::new();
+    let mut search_from = 0;
+
+    for p in 0..n {
+        let old_at = |diagonal: isize| {
+            let o = p as isize + diagonal;
+            if o >= 0 && (o as usize) < old_data.len() { Some(old_data[o as usize]) } else { None }
+        };
+
+        if p >= search_from {
+            let m = old.longest_match(&new[p..]);
+            if m.len() >= LONG_MATCH {
+                search_from = p + m.len();
+            }
+            if m.len() >= MIN_MATCH {
+                let diagonal = m.start as isize - p as isize;
+                if !open.iter().any(|o| o.diagonal == diagonal) {
+                    if open.len() == MAX_DIAGONALS {
+                        let worst = (0..open.len())
+                            .max_by(|&a, &b| open[a].cost.partial_cmp(&open[b].cost).unwrap())
+                            .unwrap();
+                        open.swap_remove(worst);
+                    }
+                    open.push(Open { diagonal: diagonal, cost: f64::INFINITY, start: p, mismatch_run: 0 });
+                }
+            }
+        }
+
+        // Extend every open command over `new[p]`, or start one here.
+        let fresh = cost[p] + COMMAND_BITS;
+        for o in &mut open {
+            match old_at(o.diagonal) {
+                Some(b) if b == new[p] => {
+                    if fresh <= o.cost {
+                        o.cost = fresh;
+                        o.start = p;
+                    }
+                    o.cost += MATCH_BITS;
+                    o.mismatch_run = 0;
+                }
+                Some(_) if o.mismatch_run < options.mismatch_budget => {
+                    o.cost += MISMATCH_BITS;
+                    o.mismatch_run += 1;
+                }
+                _ => o.cost = f64::INFINITY,
+            }
+        }
+        open.retain(|o| o.cost.is_finite());
+
+        let mut best = (cost[p] + LITERAL_BITS, Step::Literal);
+        for o in &open {
+            if o.cost < best.0 {
+                best = (o.cost, Step::Command { start: o.start, diagonal: o.diagonal });
+            }
+        }
+        cost[p + 1] = best.0;
+        steps.push(best.1);
+    }
+
+    // Walk back from the end to recover the path, then turn it around.
+    let mut path = Vec::new();
+    let mut p = n;
+    while p > 0 {
+        match steps[p - 1] {
+            Step::Literal => {
+                path.push((p - 1, None));
+                p -= 1;
+            }
+            Step::Command { start, diagonal } => {
+                path.push((start, Some(diagonal)));
+                p = start;
+            }
+        }
+    }
+    path.reverse();
+
+    let mut matches = Vec::new();
+    let mut current = Match::default();
+
+    for (i, &(start, diagonal)) in path.iter().enumerate() {
+        let end = path.get(i + 1).map_or(n, |&(next, _)| next);
+        match diagonal {
+            Some(diagonal) => {
+                if current.matched.len() > 0 || current.unmatched_suffix > 0 {
+                    matches.push(current);
+                }
+                current = Match {
+                    matched: command_delta(old_data, new, start, end, diagonal),
+                    unmatched_suffix: 0,
+                };
+            }
+            None => current.unmatched_suffix += end - start,
+        }
+    }
+
+    if current.matched.len() > 0 || current.unmatched_suffix > 0 {
+        matches.push(current);
+    }
+
+    matches
+}
+
+/// `new[start..end]` lined up against the old file along `diagonal`, split
+/// around its longest exact run.
+fn command_delta(old: &[u8], new: &[u8], start: usize, end: usize, diagonal: isize) -> Delta {
+    let old_offset = (start as isize + diagonal) as usize;
+    let old = &old[old_offset..old_offset + (end - start)];
+    let new = &new[start..end];
+
+    let (mut exact, mut run) = (0..0, 0);
+    for i in 0..new.len() {
+        if old[i] == new[i] {
+            run += 1;
+            if run > exact.len() {
+                exact = i + 1 - run .. i + 1;
+            }
+        } else {
+            run = 0;
+        }
+    }
+
+    Delta {
+        old_offset: old_offset,
+        lower_delta_len: exact.start,
+        mid_exact_len: exact.len(),
+        upper_delta_len: new.len() - exact.end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::{Index, MatchIter, Effort};
+    use test_util::noise;
+
+    fn covered(matches: &[Match]) -> usize {
+        matches.iter().map(|m| m.matched.len() + m.unmatched_suffix).sum()
+    }
+
+    #[test]
+    fn test_optimal_skips_short_match() {
+        let old = b"ABCDEFGHIJ----BCDEFGHIJKLMNOPQRSTUVWXYZ0123";
+        let new = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123";
+        let index = Index::compute(old.to_vec());
+
+        let options = MatchOptions::new().mismatch_budget(0).effort(Effort::Optimal);
+        let matches = MatchIter::with_options(&index, &new[..], options).collect::<Vec<_>>();
+
+        assert_eq!(covered(&matches), new.len());
+        assert_eq!(matches.iter().map(|m| m.matched.mid_exact_len).collect::<Vec<_>>(), vec![0, 29]);
+    }
+
+    #[test]
+    fn test_optimal_covers_everything() {
+        let old = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+
+        let mut new = b"a fresh prefix".to_vec();
+        new.extend_from_slice(&old[100..2000]);
+        new.extend_from_slice(b"something new in the middle");
+        new.extend_from_slice(&old[1500..4000]);
+
+        let index = Index::compute(old.clone());
+        let matches = parse(&index, &new, &MatchOptions::default());
+
+        assert_eq!(covered(&matches), new.len());
+        assert_eq!(matches[0].matched.len(), 0);
+        assert_eq!(matches[0].unmatched_suffix, 14);
+    }
+
+    #[test]
+    fn test_optimal_bridges_mismatches() {
+        // Scattered single-byte changes, as relocated addresses make.
+        let old = noise(5, 8192);
+        let mut new = old.clone();
+        for i in (100..new.len()).step_by(200) {
+            new[i] ^= 0x40;
+        }
+        let index = Index::compute(old.clone());
+
+        // Running across each mismatch beats a new command per stretch.
+        let matches = parse(&index, &new, &MatchOptions::new().mismatch_budget(1));
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].matched.len(), new.len());
+
+        // Without the budget to, the stretches are separate commands, and
+        // the changed bytes go in as literals between them.
+        let matches = parse(&index, &new, &MatchOptions::new().mismatch_budget(0));
+        assert_eq!(covered(&matches), new.len());
+        assert_eq!(matches.len(), (100..new.len()).step_by(200).count() + 1);
+        let mut pos = 0;
+        for m in &matches {
+            assert_eq!((m.matched.old_offset, m.matched.mid_exact_len), (pos, m.matched.len()));
+            pos += m.matched.len() + m.unmatched_suffix;
+        }
+    }
+}
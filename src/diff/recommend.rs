@@ -0,0 +1,97 @@
+//! Whether a patch is worth sending at all, for updater services deciding
+//! between a patch and the whole new file.
+//!
+//! A sampled `similarity` rules out unrelated files quickly; for the rest,
+//! `estimate_patch_size` predicts the patch, which has to come out small
+//! enough next to the new file to be worth the client's trouble.
+
+use diff::{estimate_patch_size, similarity_sampled, Matcher, SizeEstimate};
+
+/// How many positions of the new file `recommend` samples for similarity.
+const SIMILARITY_SAMPLES: usize = 1024;
+
+/// When `recommend` prefers a patch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Threshold {
+    /// New files of this many bytes or fewer are always sent whole.
+    pub min_file_size: u64,
+
+    /// Below this similarity (see `similarity`), the patch isn't estimated.
+    pub min_similarity: f64,
+
+    /// The biggest patch worth sending, as a fraction of the new file's size.
+    pub max_patch_ratio: f64,
+}
+
+impl Default for Threshold {
+    fn default() -> Threshold {
+        Threshold {
+            min_file_size: 0,
+            min_similarity: 0.1,
+            max_patch_ratio: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Recommendation {
+    /// Send a patch, expected to come out about this big.
+    Patch(SizeEstimate),
+
+    /// Send the new file as is.
+    FullFile,
+}
+
+/// Whether to send a patch from the old file behind `index` to `new`, or
+/// `new` itself, going by `threshold`.  The estimate is for a classic
+/// bsdiff patch, and is compared against the uncompressed new file.
+pub fn recommend<M: Matcher>(index: &M, new: &[u8], threshold: Threshold) -> Recommendation {
+    if new.len() as u64 <= threshold.min_file_size {
+        return Recommendation::FullFile;
+    }
+
+    if similarity_sampled(index, new, SIMILARITY_SAMPLES) < threshold.min_similarity {
+        return Recommendation::FullFile;
+    }
+
+    let estimate = estimate_patch_size(index, new);
+    if estimate.total() as f64 <= new.len() as f64 * threshold.max_patch_ratio {
+        Recommendation::Patch(estimate)
+    } else {
+        Recommendation::FullFile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::Index;
+    use test_util::noise;
+
+    #[test]
+    fn test_recommend() {
+        let old = noise(1, 20000);
+        let index = Index::compute(old.clone());
+
+        let mut new = old.clone();
+        new[500] ^= 1;
+        new[15000] ^= 0x80;
+        match recommend(&index, &new, Threshold::default()) {
+            Recommendation::Patch(estimate) => assert!(estimate.total() < 1000, "{:?}", estimate),
+            r => panic!("{:?}", r),
+        }
+
+        assert_eq!(recommend(&index, &noise(2, 20000), Threshold::default()), Recommendation::FullFile);
+
+        // Similar enough to estimate, but too much of it is new.
+        let mut mostly_new = old[..5000].to_vec();
+        mostly_new.extend_from_slice(&noise(3, 15000));
+        let threshold = Threshold { min_similarity: 0.0, ..Threshold::default() };
+        assert_eq!(recommend(&index, &mostly_new, threshold), Recommendation::FullFile);
+
+        let threshold = Threshold { min_file_size: new.len() as u64, ..Threshold::default() };
+        assert_eq!(recommend(&index, &new, threshold), Recommendation::FullFile);
+    }
+}
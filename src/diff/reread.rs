@@ -0,0 +1,361 @@
+//! Diffing in two passes, so the old file needn't stay in memory while the
+//! patch is written.
+//!
+//! `Plan::compute` does the matching, which needs the index and so the whole
+//! old file in memory, but keeps only the matches it finds and a SHA-1 of
+//! each block of the old file.  Once it returns, the index can be dropped.
+//! `Plan::write_patch` then reads back just the parts of the old file that
+//! the deltas cover, from any `Read + Seek`, checking each block against its
+//! digest as it goes, so an old file that changed in between can't produce
+//! a patch that quietly doesn't apply.
+//!
+//! While writing, only one block of the old file and one match's delta are
+//! held at a time, on top of the patch itself (which bsdiff buffers anyway,
+//! to learn its section sizes), so peak memory is the larger of the index
+//! and the patch, rather than both together.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::cmp::min;
+use std::slice;
+
+use sha1::Sha1;
+
+use diff::{Match, MatchIter, Matcher, PatchOptions};
+#[cfg(feature = "zstd")]
+use diff::Compression;
+use diff::auto::Engine;
+#[cfg(feature = "zstd")]
+use diff::auto::{patch_from_needs_old, zstd_level};
+use format::bsdiff;
+#[cfg(feature = "zstd")]
+use format::linear_diff;
+use format::block_hashes::{self, BlockHashes};
+use format::source_digest::SourceDigest;
+use patch::Step;
+
+/// Block size of the old file's digest table.
+pub const REREAD_BLOCK_SIZE: u32 = block_hashes::DEFAULT_BLOCK_SIZE;
+
+/// The matches for one diff, worked out ahead of writing the patch.
+pub struct Plan {
+    matches: Vec<Match>,
+    new_len: u64,
+    options: PatchOptions,
+    /// Block hashes of the old file, in the same layout as the block hash
+    /// envelope uses for new files.
+    old_blocks: BlockHashes,
+    source_digest: Option<SourceDigest>,
+}
+
+impl Plan {
+    /// Matches `new` against `old` with `options`, honouring its matching
+    /// options and excluded and literal ranges.  Deadlines and progress
+    /// reporting aren't supported.
+    pub fn compute<M: Matcher>(old: &M, new: &[u8], options: &PatchOptions) -> Plan {
+        Plan::with_block_size(old, new, options, REREAD_BLOCK_SIZE)
+    }
+
+    pub fn with_block_size<M: Matcher>(old: &M, new: &[u8], options: &PatchOptions, block_size: u32) -> Plan {
+        let matches = MatchIter::with_options(old, new, options.matching).collect();
+
+        Plan {
+            matches: options.restrict_ranges(matches),
+            new_len: new.len() as u64,
+            options: options.clone(),
+            old_blocks: BlockHashes::compute(old.data(), block_size),
+            source_digest: if options.source_digest { Some(SourceDigest::compute(old.data())) } else { None },
+        }
+    }
+
+    pub fn old_len(&self) -> u64 {
+        self.old_blocks.new_size
+    }
+
+    pub fn matches(&self) -> &[Match] {
+        &self.matches
+    }
+
+    /// Writes the patch of `new` in `engine`'s format, reading the old file
+    /// back from `old`.  `new` must be the file the plan was computed for.
+    ///
+    /// Fails with `InvalidInput` if `new` isn't the length it was when
+    /// planned, and with `InvalidData` if a block of `old` that's read no
+    /// longer matches the file the plan was computed against.
+    /// `Engine::ZstdPatchFrom` needs the whole old file rather than a plan,
+    /// and fails with `InvalidInput`.
+    pub fn write_patch<R, W>(&self, engine: Engine, old: R, new: &[u8], mut writer: W) -> io::Result<()>
+        where R: Read + Seek, W: Write
+    {
+        if new.len() as u64 != self.new_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Plan was computed for a {}-byte new file, not {} bytes", self.new_len, new.len())));
+        }
+
+        let wrapped = self.options.block_hashes.is_some() || self.source_digest.is_some();
+        if !wrapped {
+            return write_steps(engine, self.steps(old, new), self.new_len, &self.options, writer);
+        }
+
+        let mut patch = Vec::new();
+        write_steps(engine, self.steps(old, new), self.new_len, &self.options, &mut patch)?;
+
+        if let Some(block_size) = self.options.block_hashes {
+            let mut wrapped = Vec::new();
+            block_hashes::write_patch(&patch, new, block_size, &mut wrapped)?;
+            patch = wrapped;
+        }
+
+        match self.source_digest {
+            Some(ref digest) => digest.write_patch(&patch, writer),
+            None => writer.write_all(&patch),
+        }
+    }
+
+    fn steps<'a, R: Read + Seek>(&'a self, old: R, new: &'a [u8]) -> Steps<'a, R> {
+        Steps {
+            matches: self.matches.iter(),
+            old: BlockReader::new(old, &self.old_blocks),
+            new: new,
+            pos: 0,
+            extra: None,
+        }
+    }
+}
+
+fn write_steps<I, W>(engine: Engine, steps: I, new_len: u64, options: &PatchOptions, writer: W) -> io::Result<()>
+    where I: Iterator<Item = io::Result<Step>>, W: Write
+{
+    match engine {
+        Engine::Bsdiff => bsdiff::write_steps(steps, new_len, options, writer),
+        #[cfg(feature = "zstd")]
+        Engine::LinearDiffZstd if options.compression == Compression::None =>
+            linear_diff::write_steps(steps, new_len, writer),
+        #[cfg(feature = "zstd")]
+        Engine::LinearDiffZstd =>
+            linear_diff::write_compressed_steps(steps, new_len, zstd_level(options.compression), writer),
+        #[cfg(feature = "zstd")]
+        Engine::ZstdPatchFrom => Err(patch_from_needs_old()),
+    }
+}
+
+/// The steps of the patch, worked out a match at a time as they're written.
+/// The matches must cover `new` exactly, which its length being the planned
+/// one ensures.
+struct Steps<'a, R> {
+    matches: slice::Iter<'a, Match>,
+    old: BlockReader<'a, R>,
+    new: &'a [u8],
+    /// How much of `new` the steps so far cover.
+    pos: usize,
+    /// The extra bytes after the last delta or copy returned.
+    extra: Option<Step>,
+}
+
+impl<'a, R: Read + Seek> Steps<'a, R> {
+    fn matched(&mut self, m: &Match) -> io::Result<Step> {
+        let mm = &m.matched;
+        let new = &self.new[self.pos..self.pos + mm.len()];
+
+        // Only the bytes around the exact match can differ.
+        let mut delta = vec![0u8; mm.len()];
+        let upper_start = mm.lower_delta_len + mm.mid_exact_len;
+        self.old.read_delta(mm.old_offset as u64, &new[..mm.lower_delta_len], &mut delta[..mm.lower_delta_len])?;
+        self.old.read_delta((mm.old_offset + upper_start) as u64, &new[upper_start..], &mut delta[upper_start..])?;
+
+        if delta.iter().all(|&b| b == 0) {
+            Ok(Step::Copy { old_offset: mm.old_offset as u64, len: mm.len() as u64 })
+        } else {
+            Ok(Step::Delta { old_offset: mm.old_offset as u64, delta: delta })
+        }
+    }
+}
+
+impl<'a, R: Read + Seek> Iterator for Steps<'a, R> {
+    type Item = io::Result<Step>;
+
+    fn next(&mut self) -> Option<io::Result<Step>> {
+        if let Some(extra) = self.extra.take() {
+            return Some(Ok(extra));
+        }
+
+        while let Some(m) = self.matches.next() {
+            let matched = if m.matched.len() > 0 {
+                match self.matched(m) {
+                    Ok(step) => Some(step),
+                    Err(e) => return Some(Err(e)),
+                }
+            } else {
+                None
+            };
+            self.pos += m.matched.len();
+
+            let extra = if m.unmatched_suffix > 0 {
+                Some(Step::Extra(self.new[self.pos..self.pos + m.unmatched_suffix].to_vec()))
+            } else {
+                None
+            };
+            self.pos += m.unmatched_suffix;
+
+            match (matched, extra) {
+                (Some(step), extra) => {
+                    self.extra = extra;
+                    return Some(Ok(step));
+                }
+                (None, Some(extra)) => return Some(Ok(extra)),
+                (None, None) => {}
+            }
+        }
+
+        None
+    }
+}
+
+/// Reads the old file a block at a time, checking each against its digest.
+struct BlockReader<'a, R> {
+    old: R,
+    blocks: &'a BlockHashes,
+    /// The last block read, and its index.
+    block: Vec<u8>,
+    block_index: Option<u64>,
+}
+
+impl<'a, R: Read + Seek> BlockReader<'a, R> {
+    fn new(old: R, blocks: &'a BlockHashes) -> BlockReader<'a, R> {
+        BlockReader {
+            old: old,
+            blocks: blocks,
+            block: Vec::new(),
+            block_index: None,
+        }
+    }
+
+    fn load(&mut self, index: u64) -> io::Result<()> {
+        if self.block_index == Some(index) {
+            return Ok(());
+        }
+        self.block_index = None;
+
+        let block_size = self.blocks.block_size as u64;
+        let start = index * block_size;
+        let len = min(block_size, self.blocks.new_size - start) as usize;
+
+        self.block.resize(len, 0);
+        self.old.seek(SeekFrom::Start(start))?;
+        self.old.read_exact(&mut self.block)?;
+
+        let mut sha1 = Sha1::new();
+        sha1.update(&self.block);
+        if sha1.digest().bytes() != self.blocks.digests[index as usize] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Old file changed since the diff was planned (block {})", index)));
+        }
+
+        self.block_index = Some(index);
+        Ok(())
+    }
+
+    /// Fills `delta` with the bytewise difference between `new` and the old
+    /// file from `offset`.
+    fn read_delta(&mut self, mut offset: u64, mut new: &[u8], mut delta: &mut [u8]) -> io::Result<()> {
+        let block_size = self.blocks.block_size as u64;
+
+        while !new.is_empty() {
+            let index = offset / block_size;
+            self.load(index)?;
+
+            let start = (offset - index * block_size) as usize;
+            let n = min(new.len(), self.block.len() - start);
+            for ((d, &x), &o) in delta[..n].iter_mut().zip(&new[..n]).zip(&self.block[start..start + n]) {
+                *d = x.wrapping_sub(o);
+            }
+
+            offset += n as u64;
+            new = &new[n..];
+            delta = &mut { delta }[n..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use diff::Index;
+    use diff::auto::STEP_ENGINES;
+    use patch::apply_any;
+
+    fn pair() -> (Vec<u8>, Vec<u8>) {
+        let old = (0..20000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+        let mut new = old[5000..15000].to_vec();
+        new.extend_from_slice(b"something new");
+        new.extend_from_slice(&old[..3000]);
+        for i in (0..new.len()).step_by(500) {
+            new[i] ^= 0x55;
+        }
+        (old, new)
+    }
+
+    fn apply(patch: &[u8], old: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        apply_any(patch, Cursor::new(old), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_reread_roundtrip() {
+        let (old, new) = pair();
+        let options = PatchOptions::default();
+
+        let plan = {
+            let index = Index::compute(old.clone());
+            Plan::with_block_size(&index, &new, &options, 1024)
+        };
+        assert_eq!(plan.old_len(), old.len() as u64);
+
+        for &engine in STEP_ENGINES {
+            let mut patch = Vec::new();
+            plan.write_patch(engine, Cursor::new(&old[..]), &new, &mut patch).unwrap();
+            assert_eq!(apply(&patch, &old), new, "{:?}", engine);
+        }
+    }
+
+    #[test]
+    fn test_reread_envelopes() {
+        let (old, new) = pair();
+        let options = PatchOptions::default().block_hashes(4096).source_digest(true);
+
+        let plan = Plan::compute(&Index::compute(old.clone()), &new, &options);
+        let mut patch = Vec::new();
+        plan.write_patch(Engine::Bsdiff, Cursor::new(&old[..]), &new, &mut patch).unwrap();
+
+        assert!(patch.starts_with(::format::source_digest::MAGIC));
+        assert_eq!(apply(&patch, &old), new);
+    }
+
+    #[test]
+    fn test_reread_detects_changed_old() {
+        let (old, new) = pair();
+        let plan = Plan::with_block_size(&Index::compute(old.clone()), &new, &PatchOptions::default(), 1024);
+
+        // Change a byte in a block the deltas read from.
+        let mm = plan.matches().iter().map(|m| &m.matched).find(|mm| mm.upper_delta_len > 0).unwrap();
+        let mut changed = old.clone();
+        changed[mm.upper_delta_range().start] ^= 1;
+
+        let err = plan.write_patch(Engine::Bsdiff, Cursor::new(&changed[..]), &new, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_reread_wrong_new() {
+        let (old, new) = pair();
+        let plan = Plan::compute(&Index::compute(old.clone()), &new, &PatchOptions::default());
+
+        for wrong in &[&new[..new.len() - 1], &[&new[..], b"!"].concat()[..], &[]] {
+            let err = plan.write_patch(Engine::Bsdiff, Cursor::new(&old[..]), wrong, &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Matches that read the old file front to back, for targets where seeking
+//! backwards is slow (spinning disks, some flash) or impossible (a pipe).
+//!
+//! The usual matches jump around the old file wherever the new one moved
+//! things.  `monotonic` keeps the subset whose old ranges come in order and
+//! don't overlap, covering as many new bytes as possible, and turns the rest
+//! into extra bytes.  Every seek between commands is then forward, at the
+//! cost of a bigger patch where content was reordered.
+
+use std::cmp::max;
+
+use diff::Match;
+
+/// Rewrites `matches`, which must cover the new file in order, so that each
+/// one starts in the old file no earlier than the previous one ended.
+///
+/// The matches kept are those with the most matched bytes between them (a
+/// weighted longest increasing subsequence); the others, and whatever
+/// unmatched bytes follow them, are appended to the previous kept match's
+/// `unmatched_suffix`.  Anything before the first kept match goes in a
+/// leading empty match.
+pub fn monotonic(matches: &[Match]) -> Vec<Match> {
+    let keep = best_chain(matches);
+
+    let mut monotonic = Vec::new();
+    let mut current = Match::default();
+    for (m, &kept) in matches.iter().zip(&keep) {
+        if kept {
+            if current.matched.len() > 0 || current.unmatched_suffix > 0 {
+                monotonic.push(current);
+            }
+            current = m.clone();
+        } else {
+            current.unmatched_suffix += m.matched.len() + m.unmatched_suffix;
+        }
+    }
+    if current.matched.len() > 0 || current.unmatched_suffix > 0 {
+        monotonic.push(current);
+    }
+    monotonic
+}
+
+/// Which of `matches` make up the heaviest chain of non-empty matches whose
+/// old ranges are in order.
+fn best_chain(matches: &[Match]) -> Vec<bool> {
+    let mut ends = matches.iter()
+        .filter(|m| m.matched.len() > 0)
+        .map(|m| m.matched.old_offset + m.matched.len())
+        .collect::<Vec<_>>();
+    ends.sort();
+    ends.dedup();
+
+    // For each match: the weight of the best chain ending with it, and the
+    // match before it there.
+    let mut best = vec![(0u64, None); matches.len()];
+    let mut tree = MaxTree::new(ends.len());
+
+    for (j, m) in matches.iter().enumerate() {
+        let len = m.matched.len();
+        if len == 0 {
+            continue;
+        }
+        // Chains ending at or before this match's start in the old file.
+        let before = match ends.binary_search(&m.matched.old_offset) {
+            Ok(k) => k + 1,
+            Err(k) => k,
+        };
+        let (weight, prev) = tree.prefix_max(before);
+        best[j] = (weight + len as u64, prev);
+
+        let end = ends.binary_search(&(m.matched.old_offset + len)).unwrap();
+        tree.update(end, best[j].0, j);
+    }
+
+    let mut keep = vec![false; matches.len()];
+    let mut last = tree.prefix_max(ends.len()).1;
+    while let Some(j) = last {
+        keep[j] = true;
+        last = best[j].1;
+    }
+    keep
+}
+
+/// A Fenwick tree of the best chain (by weight) ending at each old offset.
+struct MaxTree {
+    nodes: Vec<(u64, Option<usize>)>,
+}
+
+impl MaxTree {
+    fn new(size: usize) -> MaxTree {
+        MaxTree { nodes: vec![(0, None); size + 1] }
+    }
+
+    /// The best chain among the first `count` positions.
+    fn prefix_max(&self, count: usize) -> (u64, Option<usize>) {
+        let mut best = (0, None);
+        let mut i = count;
+        while i > 0 {
+            if self.nodes[i].0 > best.0 {
+                best = self.nodes[i];
+            }
+            i &= i - 1;
+        }
+        best
+    }
+
+    fn update(&mut self, pos: usize, weight: u64, index: usize) {
+        let mut i = pos + 1;
+        while i < self.nodes.len() {
+            let node = &mut self.nodes[i];
+            if weight > node.0 {
+                *node = (weight, Some(index));
+            }
+            i += i & i.wrapping_neg();
+        }
+    }
+}
+
+/// How far `matches` seek backwards in the old file, in total.
+pub fn backward_seek_bytes(matches: &[Match]) -> u64 {
+    let mut pos = 0usize;
+    let mut total = 0u64;
+    for m in matches.iter().filter(|m| m.matched.len() > 0) {
+        total += pos.saturating_sub(m.matched.old_offset) as u64;
+        pos = max(pos, m.matched.old_offset + m.matched.len());
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::{Delta, Index, MatchIter};
+    use test_util::noise;
+
+    fn exact(old_offset: usize, len: usize, unmatched_suffix: usize) -> Match {
+        Match {
+            matched: Delta { old_offset: old_offset, lower_delta_len: 0, mid_exact_len: len, upper_delta_len: 0 },
+            unmatched_suffix: unmatched_suffix,
+        }
+    }
+
+    fn covered(matches: &[Match]) -> usize {
+        matches.iter().map(|m| m.matched.len() + m.unmatched_suffix).sum()
+    }
+
+    #[test]
+    fn test_monotonic_keeps_heaviest_chain() {
+        let matches = vec![exact(100, 10, 2), exact(0, 50, 0), exact(60, 5, 1), exact(120, 8, 0)];
+        let monotonic = monotonic(&matches);
+
+        assert_eq!(monotonic, vec![
+            Match { matched: Delta::default(), unmatched_suffix: 12 },
+            exact(0, 50, 0),
+            exact(60, 5, 1),
+            exact(120, 8, 0),
+        ]);
+        assert_eq!(covered(&monotonic), covered(&matches));
+        assert_eq!(backward_seek_bytes(&monotonic), 0);
+    }
+
+    #[test]
+    fn test_monotonic_moved_blocks() {
+        let old = noise(1, 40000);
+        let mut new = Vec::new();
+        new.extend_from_slice(&old[30000..40000]);
+        new.extend_from_slice(&old[0..10000]);
+        new.extend_from_slice(&old[20000..30000]);
+        new.extend_from_slice(&old[10000..20000]);
+
+        let index = Index::compute(old);
+        let matches = MatchIter::from(&index, &new).collect::<Vec<_>>();
+        assert!(backward_seek_bytes(&matches) > 0);
+
+        let monotonic = monotonic(&matches);
+        assert_eq!(backward_seek_bytes(&monotonic), 0);
+        assert_eq!(covered(&monotonic), new.len());
+
+        let matched = monotonic.iter().map(|m| m.matched.len()).sum::<usize>();
+        assert!(matched >= 20000, "only {} bytes matched", matched);
+    }
+}
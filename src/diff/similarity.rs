@@ -0,0 +1,94 @@
+//! Cheap measures of how well a new file would diff against an old one, for
+//! picking which of several old versions to generate a patch from.
+
+use diff::Matcher;
+
+/// Matches shorter than this don't count towards similarity: short ones turn
+/// up by chance, and don't save much in a patch anyway.
+pub const SIMILARITY_MIN_MATCH: usize = 32;
+
+/// The fraction of `candidate` covered by matches of at least
+/// `SIMILARITY_MIN_MATCH` bytes against `old`, from 0 to 1.  An empty
+/// candidate counts as fully similar.
+///
+/// This walks `candidate` greedily, as `MatchIter` does, but without
+/// extending matches or producing anything, so it's a good deal cheaper than
+/// diffing.
+pub fn similarity<M: Matcher>(old: &M, candidate: &[u8]) -> f64 {
+    if candidate.is_empty() {
+        return 1.0;
+    }
+
+    let mut covered = 0;
+    let mut i = 0;
+
+    while i < candidate.len() {
+        let len = old.longest_match(&candidate[i..]).len();
+        if len >= SIMILARITY_MIN_MATCH {
+            covered += len;
+            i += len;
+        } else {
+            i += 1;
+        }
+    }
+
+    covered as f64 / candidate.len() as f64
+}
+
+/// Like `similarity`, estimated from `samples` evenly spaced positions: the
+/// fraction of them where a long enough match starts.  Takes `samples`
+/// lookups, however big `candidate` is.
+pub fn similarity_sampled<M: Matcher>(old: &M, candidate: &[u8], samples: usize) -> f64 {
+    // Sampling wouldn't save anything.
+    if samples == 0 || samples >= candidate.len() {
+        return similarity(old, candidate);
+    }
+
+    let hits = (0..samples)
+        .map(|s| s * candidate.len() / samples)
+        .filter(|&p| old.longest_match(&candidate[p..]).len() >= SIMILARITY_MIN_MATCH)
+        .count();
+
+    hits as f64 / samples as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::Index;
+    use test_util::noise;
+
+    #[test]
+    fn test_similarity() {
+        let old = noise(1, 8192);
+        let index = Index::compute(old.clone());
+
+        assert_eq!(similarity(&index, &old), 1.0);
+        assert_eq!(similarity(&index, b""), 1.0);
+        assert!(similarity(&index, &noise(2, 4096)) < 0.01);
+
+        let mut half = old[..2048].to_vec();
+        half.extend_from_slice(&noise(3, 2048));
+        let score = similarity(&index, &half);
+        assert!(score > 0.45 && score < 0.55, "{}", score);
+
+        let sampled = similarity_sampled(&index, &half, 200);
+        assert!(sampled > 0.4 && sampled < 0.6, "{}", sampled);
+        assert_eq!(similarity_sampled(&index, &half, 0), score);
+    }
+
+    #[test]
+    fn test_similarity_picks_closest_base() {
+        let new = noise(4, 4096);
+
+        let mut close = new.clone();
+        close[100] ^= 1;
+        close[3000] ^= 1;
+        let mut far = new[..1000].to_vec();
+        far.extend_from_slice(&noise(5, 3096));
+
+        let bases = vec![Index::compute(noise(6, 4096)), Index::compute(far), Index::compute(close)];
+        let scores = bases.iter().map(|b| similarity(b, &new)).collect::<Vec<_>>();
+        assert!(scores[2] > scores[1] && scores[1] > scores[0], "{:?}", scores);
+    }
+}
@@ -0,0 +1,208 @@
+//! Patches that don't depend on diffing against an old file.
+//!
+//! These are useful as fallbacks: an identity patch leaves the old file
+//! as-is, and an idempotent patch forces the full new content regardless of
+//! what the old file contains.
+//!
+//! Diffs where one side is empty don't need an index either.  Diffing to an
+//! empty new file gives a patch with a valid header and no commands, and
+//! diffing from an empty old file gives one command of nothing but extra
+//! bytes.  `is_trivial` says when that's the case, and `write_trivial_patch`
+//! writes such a patch with any `PatchOptions`, skipping the suffix sort
+//! (which for an empty new file would be all the work).
+
+use std::io;
+use std::ops::Range;
+
+use diff::{Differ, Matcher, PatchOptions};
+use diff::auto::Engine;
+use format::PatchFormat;
+
+pub fn write_identity_patch<F: PatchFormat, W: io::Write>(size: u64, patch: W) -> io::Result<()> {
+    F::write_identity_patch(size, patch)
+}
+
+pub fn write_idempotent_patch<F: PatchFormat, W: io::Write>(desired_output: &[u8], patch: W) -> io::Result<()> {
+    F::write_idempotent_patch(desired_output, patch)
+}
+
+pub fn generate_identity_patch<F: PatchFormat>(size: u64) -> Vec<u8> {
+    let mut patch = Vec::new();
+    write_identity_patch::<F, _>(size, &mut patch).unwrap();
+    patch
+}
+
+pub fn generate_idempotent_patch<F: PatchFormat>(desired_output: &[u8]) -> Vec<u8> {
+    let mut patch = Vec::new();
+    write_idempotent_patch::<F, _>(desired_output, &mut patch).unwrap();
+    patch
+}
+
+/// A `Matcher` that never finds anything, standing in for an index where
+/// there's nothing to match.
+pub struct NoIndex<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> NoIndex<'a> {
+    pub fn new(old: &'a [u8]) -> NoIndex<'a> {
+        NoIndex { data: old }
+    }
+}
+
+impl<'a> Matcher for NoIndex<'a> {
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    fn longest_match(&self, _buf: &[u8]) -> Range<usize> {
+        self.data.len()..self.data.len()
+    }
+}
+
+/// Whether diffing `old` against `new` can't find any matches, because one
+/// of them is empty.
+pub fn is_trivial(old: &[u8], new: &[u8]) -> bool {
+    old.is_empty() || new.is_empty()
+}
+
+/// Writes the patch `Differ::run` would for a trivial diff (see
+/// `is_trivial`), without indexing `old`.  The result is the same as with an
+/// index: no commands for an empty `new`, and all extra bytes for an empty
+/// `old`.  Fails with `InvalidInput` if the diff isn't trivial.
+pub fn write_trivial_patch<W: io::Write>(engine: Engine, old: &[u8], new: &[u8], options: &PatchOptions, writer: W)
+    -> io::Result<()>
+{
+    if !is_trivial(old, new) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Diff isn't trivial, and needs an index"));
+    }
+
+    Differ::new(&NoIndex::new(old))
+        .options(options.clone())
+        .format(engine)
+        .run(new, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    #[cfg(feature = "bzip2")]
+    use format::bsdiff::Bsdiff;
+    use format::bsdiff::BsdiffCodecs;
+    use format::linear_diff::LinearDiff;
+    #[cfg(feature = "zstd")]
+    use format::linear_diff::LinearDiffZstd;
+    use diff::Index;
+    use diff::auto::STEP_ENGINES;
+    use inspect::summarize;
+    use patch::apply_any;
+
+    fn assert_identity<F: PatchFormat>() {
+        let buf = b"this is a test";
+        let patch = generate_identity_patch::<F>(buf.len() as u64);
+
+        let mut new = Vec::new();
+        F::apply_patch(&patch, Cursor::new(&buf[..]), &mut new).unwrap();
+
+        assert_eq!(&buf[..], &new[..]);
+    }
+
+    fn assert_idempotent<F: PatchFormat>() {
+        let buf = b"this is a test";
+        let patch = generate_idempotent_patch::<F>(buf);
+
+        for example in &["", "this is a test", "1234", "\0"] {
+            let mut new = Vec::new();
+            F::apply_patch(&patch, Cursor::new(example.as_bytes()), &mut new).unwrap();
+
+            assert_eq!(&buf[..], &new[..]);
+        }
+    }
+
+    #[test]
+    fn test_identity_patch() {
+        #[cfg(feature = "bzip2")]
+        assert_identity::<Bsdiff>();
+        assert_identity::<BsdiffCodecs>();
+        assert_identity::<LinearDiff>();
+        #[cfg(feature = "zstd")]
+        assert_identity::<LinearDiffZstd>();
+    }
+
+    #[test]
+    fn test_idempotent_patch() {
+        #[cfg(feature = "bzip2")]
+        assert_idempotent::<Bsdiff>();
+        assert_idempotent::<BsdiffCodecs>();
+        assert_idempotent::<LinearDiff>();
+        #[cfg(feature = "zstd")]
+        assert_idempotent::<LinearDiffZstd>();
+    }
+
+    #[test]
+    fn test_empty_new() {
+        let old = b"this is a test";
+
+        for &engine in STEP_ENGINES {
+            let mut patch = Vec::new();
+            write_trivial_patch(engine, old, b"", &PatchOptions::default(), &mut patch).unwrap();
+
+            let mut indexed = Vec::new();
+            Differ::new(&Index::compute(old.to_vec())).format(engine).run(b"", &mut indexed).unwrap();
+            assert_eq!(patch, indexed, "{:?}", engine);
+
+            let summary = summarize(&patch).unwrap();
+            assert_eq!((summary.new_size, summary.command_count), (0, 0), "{:?}", engine);
+
+            let mut new = Vec::new();
+            apply_any(&patch, Cursor::new(&old[..]), &mut new).unwrap();
+            assert!(new.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_empty_old() {
+        let new = b"this is a test";
+
+        for &engine in STEP_ENGINES {
+            let mut patch = Vec::new();
+            write_trivial_patch(engine, b"", new, &PatchOptions::default(), &mut patch).unwrap();
+
+            let mut indexed = Vec::new();
+            Differ::new(&Index::compute(Vec::new())).format(engine).run(new, &mut indexed).unwrap();
+            assert_eq!(patch, indexed, "{:?}", engine);
+
+            let summary = summarize(&patch).unwrap();
+            assert_eq!(summary.command_count, 1, "{:?}", engine);
+            assert_eq!((summary.delta_bytes, summary.extra_bytes), (0, new.len() as u64), "{:?}", engine);
+
+            let mut out = Vec::new();
+            apply_any(&patch, Cursor::new(&b""[..]), &mut out).unwrap();
+            assert_eq!(&out[..], &new[..]);
+        }
+    }
+
+    #[cfg(feature = "sha1")]
+    #[test]
+    fn test_trivial_patch_options() {
+        let old = b"this is a test";
+        let options = PatchOptions::new().source_digest(true).block_hashes(4);
+
+        let mut patch = Vec::new();
+        write_trivial_patch(Engine::Bsdiff, old, b"", &options, &mut patch).unwrap();
+
+        let mut indexed = Vec::new();
+        Differ::new(&Index::compute(old.to_vec())).options(options).run(b"", &mut indexed).unwrap();
+        assert_eq!(patch, indexed);
+    }
+
+    #[test]
+    fn test_nontrivial() {
+        let mut patch = Vec::new();
+        let err = write_trivial_patch(Engine::Bsdiff, b"a", b"b", &PatchOptions::default(), &mut patch).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(patch.is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+//! An optional envelope around a patch in any format, carrying the SHA-1 of
+//! each block of the new file.  Appliers check every block as it's written,
+//! so corruption is caught at the block where it happens rather than at the
+//! end, and an interrupted apply can check what it already wrote before
+//! carrying on (see `resume_apply`).
+//!
+//! Layout (integers little-endian):
+//!
+//! ```text
+//! magic "RSDBLH01", block size (u32), new file size (u64), patch length (u64),
+//! patch, one 20-byte SHA-1 per block of the new file
+//! ```
+//!
+//! The hashes come last, so that the wrapped patch's own header sits at a
+//! fixed offset near the start, where `patch::can_apply` can find it.
+
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::cmp::min;
+
+use sha1::Sha1;
+
+use format::{self, wire};
+use patch::apply_any;
+
+pub const MAGIC: &'static [u8] = b"RSDBLH01";
+
+/// Bytes before the wrapped patch.
+pub const HEADER_SIZE: usize = 8 + 4 + 8 + 8;
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 64 * 1024;
+
+/// The most `verified_prefix` reads at once.
+const VERIFY_BUFFER_SIZE: u32 = 64 * 1024;
+
+pub fn is_match(patch: &[u8]) -> bool {
+    patch.starts_with(MAGIC)
+}
+
+fn sha1_digest(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    sha1.digest().bytes()
+}
+
+/// The block hashes of a new file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHashes {
+    pub block_size: u32,
+    pub new_size: u64,
+    pub digests: Vec<[u8; 20]>,
+}
+
+impl BlockHashes {
+    pub fn compute(new: &[u8], block_size: u32) -> BlockHashes {
+        assert!(block_size > 0, "block_size must be non-zero");
+
+        BlockHashes {
+            block_size: block_size,
+            new_size: new.len() as u64,
+            digests: new.chunks(block_size as usize).map(sha1_digest).collect(),
+        }
+    }
+
+    /// Splits a wrapped patch into its hashes and the patch inside.
+    pub fn read(patch: &[u8]) -> io::Result<(BlockHashes, &[u8])> {
+        if !is_match(patch) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad block hash magic"));
+        }
+        if patch.len() < HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated block hash header"));
+        }
+
+        let block_size = wire::read_u32(&patch[8..12]);
+        let new_size = wire::read_u64(&patch[12..20]);
+        let patch_len = wire::read_u64(&patch[20..28]);
+
+        if block_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Block hash block size is zero"));
+        }
+
+        let count = new_size / block_size as u64 + (new_size % block_size as u64 != 0) as u64;
+        let rest = &patch[HEADER_SIZE..];
+        let expected = count.checked_mul(20).and_then(|table| table.checked_add(patch_len));
+        match expected {
+            Some(expected) if expected == rest.len() as u64 => {}
+            Some(expected) if expected > rest.len() as u64 =>
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated block-hashed patch")),
+            Some(_) =>
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Trailing bytes after block hashes")),
+            None =>
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad block hash header sizes")),
+        }
+
+        let (inner, table) = rest.split_at(patch_len as usize);
+        format::check_wrapped(patch, inner)?;
+        let digests = table.chunks(20).map(|chunk| {
+            let mut digest = [0u8; 20];
+            digest.copy_from_slice(chunk);
+            digest
+        }).collect();
+
+        Ok((BlockHashes {
+            block_size: block_size,
+            new_size: new_size,
+            digests: digests,
+        }, inner))
+    }
+
+    /// Writes `patch` wrapped with these hashes.
+    pub fn write_patch<W: Write>(&self, patch: &[u8], mut writer: W) -> io::Result<()> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..8].copy_from_slice(MAGIC);
+        wire::write_u32(&mut header[8..12], self.block_size);
+        wire::write_u64(&mut header[12..20], self.new_size);
+        wire::write_u64(&mut header[20..28], patch.len() as u64);
+
+        writer.write_all(&header)?;
+        writer.write_all(patch)?;
+        for digest in &self.digests {
+            writer.write_all(digest)?;
+        }
+        Ok(())
+    }
+
+    fn block_len(&self, index: usize) -> usize {
+        min(self.block_size as u64, self.new_size - index as u64 * self.block_size as u64) as usize
+    }
+
+    /// How many bytes at the start of `output` are blocks matching their
+    /// hashes.  Always a whole number of blocks, or the whole new file.
+    pub fn verified_prefix<R: Read>(&self, mut output: R) -> io::Result<u64> {
+        // Blocks are hashed a piece at a time, since the block size comes
+        // from the patch and may be anything up to 4GiB.
+        let mut buf = vec![0u8; min(self.block_size, VERIFY_BUFFER_SIZE) as usize];
+        let mut verified = 0;
+
+        for (i, digest) in self.digests.iter().enumerate() {
+            let block_len = self.block_len(i);
+            let mut sha1 = Sha1::new();
+
+            let mut got = 0;
+            while got < block_len {
+                let want = min(buf.len(), block_len - got);
+                match output.read(&mut buf[..want])? {
+                    0 => return Ok(verified),
+                    n => {
+                        sha1.update(&buf[..n]);
+                        got += n;
+                    }
+                }
+            }
+
+            if sha1.digest().bytes() != *digest {
+                break;
+            }
+            verified += block_len as u64;
+        }
+
+        Ok(verified)
+    }
+
+    /// Wraps `new` so that each block of output is checked before it's
+    /// passed on.
+    pub fn verifier<'a, W: Write>(&'a self, new: W) -> BlockVerifier<'a, W> {
+        BlockVerifier {
+            hashes: self,
+            inner: new,
+            block: Vec::new(),
+            index: 0,
+            skip: 0,
+        }
+    }
+}
+
+/// Passes output on to `inner` a whole block at a time, failing with
+/// `InvalidData` as soon as a block doesn't match its hash, so corrupt
+/// blocks never reach `inner`.
+pub struct BlockVerifier<'a, W> {
+    hashes: &'a BlockHashes,
+    inner: W,
+    block: Vec<u8>,
+    index: usize,
+    /// Blocks before this output offset are checked, but not passed on:
+    /// they're already there from an earlier attempt.
+    skip: u64,
+}
+
+impl<'a, W: Write> BlockVerifier<'a, W> {
+    fn offset(&self) -> u64 {
+        self.index as u64 * self.hashes.block_size as u64
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        if sha1_digest(&self.block) != self.hashes.digests[self.index] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Block {} of the output (offset {}) doesn't match its hash", self.index, self.offset())));
+        }
+
+        if self.offset() >= self.skip {
+            self.inner.write_all(&self.block)?;
+        }
+
+        self.index += 1;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Checks that the output came to the full size of the new file, and
+    /// returns `inner`.
+    pub fn finish(self) -> io::Result<W> {
+        if self.index < self.hashes.digests.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+                "Output ended at {} bytes, expected {}",
+                self.offset() + self.block.len() as u64, self.hashes.new_size)));
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<'a, W: Write> Write for BlockVerifier<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.index >= self.hashes.digests.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Output runs past the {} byte new file", self.hashes.new_size)));
+        }
+
+        let len = self.hashes.block_len(self.index);
+        let take = min(buf.len(), len - self.block.len());
+        self.block.extend_from_slice(&buf[..take]);
+
+        if self.block.len() == len {
+            self.finish_block()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Wraps `patch` with the block hashes of `new`, the file it produces.
+pub fn write_patch<W: Write>(patch: &[u8], new: &[u8], block_size: u32, writer: W) -> io::Result<()> {
+    BlockHashes::compute(new, block_size).write_patch(patch, writer)
+}
+
+/// Applies a block-hashed patch to `new`, which may already hold part of
+/// the output from an earlier, interrupted attempt.  Leading blocks that
+/// match their hashes are kept and not rewritten; returns how many bytes
+/// were kept.
+///
+/// Anything in `new` past the end of the new file is left alone, so callers
+/// reusing a file should truncate it to `BlockHashes::new_size` afterwards.
+pub fn resume_apply<OldRS, NewRWS>(patch: &[u8], old: OldRS, mut new: NewRWS) -> io::Result<u64>
+    where
+        OldRS: Read+Seek,
+        NewRWS: Read+Write+Seek
+{
+    let (hashes, inner) = BlockHashes::read(patch)?;
+
+    new.seek(SeekFrom::Start(0))?;
+    let kept = hashes.verified_prefix(&mut new)?;
+    new.seek(SeekFrom::Start(kept))?;
+
+    let mut verifier = hashes.verifier(&mut new);
+    verifier.skip = kept;
+    apply_any(inner, old, &mut verifier)?;
+    verifier.finish()?.flush()?;
+
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read};
+
+    use super::*;
+    use diff::{Differ, Index, PatchOptions};
+    use patch::{apply_from_slice, can_apply, validate};
+
+    const OLD: &'static [u8] = b"the quick brown fox jumps over the lazy dog, again and again and again";
+    const NEW: &'static [u8] = b"the quick brown cat jumps over the lazy dog, again and again, and then stops";
+
+    fn wrapped_patch() -> Vec<u8> {
+        let index = Index::compute(OLD.to_vec());
+        let mut patch = Vec::new();
+        Differ::new(&index)
+            .options(PatchOptions::new().block_hashes(16))
+            .run(NEW, &mut patch)
+            .unwrap();
+        patch
+    }
+
+    #[test]
+    fn test_block_hashes_roundtrip() {
+        let patch = wrapped_patch();
+        assert!(is_match(&patch));
+        assert_eq!(can_apply(&patch[..::patch::CAN_APPLY_BYTES]), Ok(()));
+
+        let (hashes, inner) = BlockHashes::read(&patch).unwrap();
+        assert_eq!(hashes, BlockHashes::compute(NEW, 16));
+        assert_eq!(validate(&patch, OLD).unwrap().format, validate(inner, OLD).unwrap().format);
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(OLD), &mut out).unwrap();
+        assert_eq!(out, NEW);
+
+        let mut out = Vec::new();
+        apply_from_slice(&patch, OLD, &mut out).unwrap();
+        assert_eq!(out, NEW);
+
+        for len in 0..patch.len() {
+            assert!(apply_any(&patch[..len], Cursor::new(OLD), &mut Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_block_hashes_stop_at_bad_block() {
+        let (mut hashes, inner) = {
+            let patch = wrapped_patch();
+            let (hashes, inner) = BlockHashes::read(&patch).unwrap();
+            (hashes, inner.to_vec())
+        };
+        hashes.digests[2][0] ^= 1;
+
+        let mut patch = Vec::new();
+        hashes.write_patch(&inner, &mut patch).unwrap();
+
+        let mut out = Vec::new();
+        let err = apply_any(&patch, Cursor::new(OLD), &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(out, &NEW[..32]);
+    }
+
+    #[test]
+    fn test_huge_header_sizes() {
+        let (_, inner) = {
+            let patch = wrapped_patch();
+            let (hashes, inner) = BlockHashes::read(&patch).unwrap();
+            (hashes, inner.to_vec())
+        };
+
+        // The block count would overflow if rounded up by adding.
+        let hashes = BlockHashes { block_size: 16, new_size: !0, digests: vec![] };
+        let mut patch = Vec::new();
+        hashes.write_patch(&inner, &mut patch).unwrap();
+        let err = BlockHashes::read(&patch).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A single 4GiB block is checked without buffering all of it.
+        let hashes = BlockHashes { block_size: !0, new_size: !0u32 as u64, digests: vec![[0; 20]] };
+        let mut patch = Vec::new();
+        hashes.write_patch(&inner, &mut patch).unwrap();
+        let (hashes, _) = BlockHashes::read(&patch).unwrap();
+        assert_eq!(hashes.verified_prefix(io::repeat(0).take(1 << 20)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_resume_apply() {
+        let patch = wrapped_patch();
+
+        // Three good blocks, then a torn write.
+        let mut partial = NEW[..48].to_vec();
+        partial.extend_from_slice(b"garbage from before the crash");
+        let mut new = Cursor::new(partial);
+
+        assert_eq!(resume_apply(&patch, Cursor::new(OLD), &mut new).unwrap(), 48);
+        assert_eq!(&new.get_ref()[..NEW.len()], NEW);
+
+        let mut new = Cursor::new(NEW.to_vec());
+        assert_eq!(resume_apply(&patch, Cursor::new(OLD), &mut new).unwrap(), NEW.len() as u64);
+        assert_eq!(new.into_inner(), NEW);
+
+        let mut new = Cursor::new(Vec::new());
+        assert_eq!(resume_apply(&patch, Cursor::new(OLD), &mut new).unwrap(), 0);
+        assert_eq!(new.into_inner(), NEW);
+    }
+}
@@ -1,28 +1,228 @@
-use std::io::{self, Read, Write, Seek, Cursor, BufReader};
+use std::io::{self, Read, Write, Seek, SeekFrom, Cursor, BufWriter};
+use std::borrow::Borrow;
 use std::cmp::{min, max, Ordering};
 use std::ops::Range;
 use std::{mem, str};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::fs::File;
+use std::path::Path;
 
-use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
+#[cfg(feature = "bzip2")]
 use bzip2::write::BzEncoder;
+#[cfg(feature = "bzip2")]
 use bzip2::bufread::BzDecoder;
+#[cfg(feature = "bzip2")]
 use bzip2;
+#[cfg(feature = "zstd")]
+use zstd;
 
 use diff::{
-    Index,
-    write_delta,
-    write_zeros,
+    Differ,
+    Matcher,
+    apply_delta,
+    DeltaEncoder,
+    DeltaMode,
     MatchIter,
+    Match,
+    Compression,
+    PatchOptions,
+    Pipeline,
+    SectionCodec,
+    SectionCodecs,
 };
+use diff::sequential;
+use index::AutoIndex;
+use diff::special;
+use diff::auto::Engine;
+use concurrency;
+#[cfg(feature = "zstd")]
+use diff::auto::zstd_level;
 
 use patch::{
-    read_paired_bufs,
-    read_size_from,
+    apply_to_path,
+    write_atomically,
+    ApplyError,
+    ApplyOptions,
+    ApplyObserver,
+    CommandEvent,
+    copy_old_prefix,
+    old_slice,
+    read_paired_bufs_in,
+    read_size_from_in,
+    BufferConfig,
+    DecodeMode,
+    SequentialOld,
+    Scratch,
+    Step,
+    Unsupported,
+    read_step_bytes,
 };
 
-#[derive(Debug)]
+use format::{FeatureSet, PatchFormat};
+use format::wire::is_negative_zero;
+pub use format::wire::{read_offset, write_offset};
+use format::sink::{self, SectionSink, ScratchFile, TempFiles, CountingWriter};
+
+const CLASSIC_MAGIC: &'static [u8] = b"BSDIFF40";
+const CLASSIC_NAME: &'static str = "BSDIFF40";
+
+/// The classic BSDIFF40 format: a fixed header followed by three
+/// bzip2-compressed sections (commands, delta and extra).
+#[cfg(feature = "bzip2")]
+pub struct Bsdiff;
+
+#[cfg(feature = "bzip2")]
+impl PatchFormat for Bsdiff {
+    const MAGIC: &'static [u8] = CLASSIC_MAGIC;
+    const NAME: &'static str = CLASSIC_NAME;
+
+    // Plenty of tools write BSDIFF40, not all of them tidily.
+    const DECODE_MODE: DecodeMode = DecodeMode::Lenient;
+
+    fn apply_patch<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        apply_patch(patch, old, new)
+    }
+
+    fn write_identity_patch<W: Write>(size: u64, patch: W) -> io::Result<()> {
+        write_identity_patch(size, patch)
+    }
+
+    fn write_idempotent_patch<W: Write>(desired_output: &[u8], patch: W) -> io::Result<()> {
+        write_idempotent_patch(desired_output, patch)
+    }
+}
+
+/// BSDIFF40 with a choice of codec for each section.  The header grows by
+/// eight bytes: one codec per section, a byte of flags, then four reserved
+/// zeros.
+pub struct BsdiffCodecs;
+
+impl BsdiffCodecs {
+    /// What identity and idempotent patches use: commands are small and
+    /// varied, so they're left alone.
+    pub const CODECS: SectionCodecs = SectionCodecs {
+        commands: SectionCodec::None,
+        delta: SectionCodec::DEFAULT,
+        #[cfg(feature = "zstd")]
+        extra: SectionCodec::Zstd,
+        #[cfg(not(feature = "zstd"))]
+        extra: SectionCodec::DEFAULT,
+    };
+}
+
+impl PatchFormat for BsdiffCodecs {
+    const MAGIC: &'static [u8] = b"BSDIFFC1";
+    const NAME: &'static str = "bsdiff+codecs";
+
+    fn apply_patch<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        apply_patch_with_mode(patch, old, new, &BufferConfig::default(), BsdiffCodecs::DECODE_MODE)
+    }
+
+    fn write_identity_patch<W: Write>(size: u64, patch: W) -> io::Result<()> {
+        write_identity_patch_with(size, BsdiffCodecs::CODECS, patch)
+    }
+
+    fn write_idempotent_patch<W: Write>(desired_output: &[u8], patch: W) -> io::Result<()> {
+        write_idempotent_patch_with(desired_output, BsdiffCodecs::CODECS, patch)
+    }
+}
+
+/// Either a `Bsdiff` or a `BsdiffCodecs` patch.  Classic patches match even
+/// without the `bzip2` feature, so that reading them fails with a useful
+/// error.
+pub fn is_match(patch: &[u8]) -> bool {
+    patch.starts_with(CLASSIC_MAGIC) || BsdiffCodecs::is_match(patch)
+}
+
+/// The `NAME` of `patch`'s format, which must be one `is_match` accepts.
+pub fn format_name(patch: &[u8]) -> &'static str {
+    if BsdiffCodecs::is_match(patch) { BsdiffCodecs::NAME } else { CLASSIC_NAME }
+}
+
+/// Whether `read_steps` can decode `patch`, which must be one `is_match`
+/// accepts.
+pub(crate) fn has_steps(patch: &[u8]) -> bool {
+    Header::read(patch).map_or(true, |header| header.delta_mode == DeltaMode::Subtract)
+}
+
+/// How many bytes of the old file `patch` copies, if that's all it does: a
+/// single command adding an all-zero delta section to the start of the old
+/// file, as `generate_identity_patch` writes.  The delta section is
+/// decompressed to check, up to its first non-zero byte.  In strict `mode`,
+/// trailing data in any section is an error, as it would be when applying.
+pub fn identity_len(patch: &[u8], mode: DecodeMode) -> io::Result<Option<u64>> {
+    identity_len_within(patch, mode, u64::max_value())
+}
+
+/// Like `identity_len`, but gives up without decompressing the delta
+/// section if the patch would copy more than `max_len` bytes.
+fn identity_len_within(patch: &[u8], mode: DecodeMode, max_len: u64) -> io::Result<Option<u64>> {
+    let header = Header::read(patch)?;
+    if header.new_file_size > max_len {
+        return Ok(None);
+    }
+
+    let (header, mut commands, mut delta, mut extra) = open_sections(patch, mode)?;
+
+    let cmd = match commands.next() {
+        Some(cmd) => cmd?,
+        None => return Ok(None),
+    };
+    if commands.next().is_some() || cmd.extra_append_size > 0 || cmd.bytewise_add_size != header.new_file_size {
+        return Ok(None);
+    }
+
+    let mut buf = vec![0u8; PREFETCH_CHUNK];
+    let mut left = cmd.bytewise_add_size;
+    while left > 0 {
+        let n = delta.read(&mut buf[..min(left, PREFETCH_CHUNK as u64) as usize])?;
+        if n == 0 || buf[..n].iter().any(|&b| b != 0) {
+            return Ok(None);
+        }
+        left -= n as u64;
+    }
+
+    check_section_end(commands.get_mut(), "commands", mode)?;
+    check_section_end(&mut delta, "delta", mode)?;
+    check_section_end(&mut extra, "extra", mode)?;
+
+    Ok(Some(cmd.bytewise_add_size))
+}
+
+/// Largest output `apply_patch` checks for being a plain copy before
+/// applying it as usual.
+const IDENTITY_SCAN_MAX: u64 = 1 << 20;
+
+/// The `DECODE_MODE` of `patch`'s format.
+pub(crate) fn default_mode(patch: &[u8]) -> DecodeMode {
+    if BsdiffCodecs::is_match(patch) { BsdiffCodecs::DECODE_MODE } else { DecodeMode::Lenient }
+}
+
+/// Bytes in the header of a classic BSDIFF40 patch: the magic, then the
+/// compressed command and delta section sizes and the new file's size.
+pub const CLASSIC_HEADER_SIZE: usize = 32;
+
+/// Bytes in the header of a `BsdiffCodecs` patch: the classic fields, a
+/// codec byte per section, a flags byte and four reserved zeros.
+pub const CODECS_HEADER_SIZE: usize = 40;
+
+/// The fixed-size header at the start of every bsdiff patch.  Sizes are
+/// stored as bsdiff's sign-and-magnitude 64-bit integers (see
+/// `write_offset`), so none may be over `i64::max_value()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Header {
-    // NOTE: there's a non-stored field: magic (always b"BSDIFF40")
+    // NOTE: there's a non-stored field: magic (b"BSDIFF40" when every codec
+    // is bzip2, b"BSDIFFC1" otherwise)
 
     pub compressed_commands_size: u64,
     pub compressed_delta_size: u64,
@@ -31,35 +231,453 @@ pub struct Header {
     // remainder of the patch file, after the compressed "delta" data.
 
     pub new_file_size: u64,
+
+    pub codecs: SectionCodecs,
+
+    /// The commands never seek backwards in the old file.  Only a
+    /// `BsdiffCodecs` header can say so.
+    pub sequential_old_access: bool,
+
+    /// How the delta section combines with the old file.  Only a
+    /// `BsdiffCodecs` header can have anything but `Subtract`.
+    pub delta_mode: DeltaMode,
+}
+
+/// Header flag: the commands never seek backwards in the old file.
+const SEQUENTIAL_OLD_ACCESS: u8 = 1;
+
+/// Header flag: deltas are `DeltaMode::Xor`.
+const XOR_DELTA: u8 = 2;
+
+/// Whether the flags byte and the reserved bytes after it are ones this
+/// version of rsdiff understands.
+fn known_flags(buf: &[u8]) -> bool {
+    buf[35] & !(SEQUENTIAL_OLD_ACCESS | XOR_DELTA) == 0 && buf[36..40].iter().all(|&b| b == 0)
+}
+
+/// The flags byte for a header with `sequential_old_access` and
+/// `delta_mode`.
+fn header_flags(sequential_old_access: bool, delta_mode: DeltaMode) -> u8 {
+    let mut flags = 0;
+    if sequential_old_access {
+        flags |= SEQUENTIAL_OLD_ACCESS;
+    }
+    if delta_mode == DeltaMode::Xor {
+        flags |= XOR_DELTA;
+    }
+    flags
+}
+
+fn codec_byte(codec: SectionCodec) -> u8 {
+    match codec {
+        SectionCodec::None => 0,
+        #[cfg(feature = "bzip2")]
+        SectionCodec::Bzip2 => 1,
+        #[cfg(feature = "zstd")]
+        SectionCodec::Zstd => 2,
+    }
+}
+
+fn read_codec(byte: u8) -> io::Result<SectionCodec> {
+    match byte {
+        0 => Ok(SectionCodec::None),
+        #[cfg(feature = "bzip2")]
+        1 => Ok(SectionCodec::Bzip2),
+        #[cfg(not(feature = "bzip2"))]
+        1 => Err(missing_codec("bzip2")),
+        #[cfg(feature = "zstd")]
+        2 => Ok(SectionCodec::Zstd),
+        #[cfg(not(feature = "zstd"))]
+        2 => Err(missing_codec("zstd")),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown section codec {}", byte))),
+    }
+}
+
+#[cfg(not(all(feature = "bzip2", feature = "zstd")))]
+fn missing_codec(feature: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!(
+        "Patch has {} sections, but rsdiff was built without the `{}` feature", feature, feature))
+}
+
+/// The features needed to decode a section stored with codec `byte`, or
+/// `None` for a codec this version of rsdiff has never heard of.
+fn codec_features(byte: u8) -> Option<FeatureSet> {
+    match byte {
+        0 => Some(FeatureSet::default()),
+        1 => Some(FeatureSet { bzip2: true, ..FeatureSet::default() }),
+        2 => Some(FeatureSet { zstd: true, ..FeatureSet::default() }),
+        _ => None,
+    }
+}
+
+/// Like `Header::required_features`, straight from the header bytes, so
+/// that codecs this build leaves out can still be named.
+pub(crate) fn header_features(buf: &[u8]) -> Result<FeatureSet, Unsupported> {
+    if buf.starts_with(CLASSIC_MAGIC) {
+        return Ok(FeatureSet { bzip2: true, ..FeatureSet::default() });
+    }
+    if buf.len() < CODECS_HEADER_SIZE {
+        return Err(Unsupported::Truncated { needed: CODECS_HEADER_SIZE });
+    }
+    if !known_flags(buf) {
+        return Err(Unsupported::UnknownFormat);
+    }
+
+    let mut features = FeatureSet::default();
+    for &byte in &buf[32..35] {
+        match codec_features(byte) {
+            Some(needed) => features = features.union(&needed),
+            None => return Err(Unsupported::UnknownCodec(byte)),
+        }
+    }
+    Ok(features)
+}
+
+/// The codecs of a classic BSDIFF40 patch.
+#[cfg(feature = "bzip2")]
+fn classic_codecs() -> io::Result<SectionCodecs> {
+    Ok(SectionCodecs::all(SectionCodec::Bzip2))
+}
+
+#[cfg(not(feature = "bzip2"))]
+fn classic_codecs() -> io::Result<SectionCodecs> {
+    Err(missing_codec("bzip2"))
+}
+
+/// Whether `codecs` are those of a classic BSDIFF40 patch.
+#[cfg(feature = "bzip2")]
+fn is_classic(codecs: &SectionCodecs) -> bool {
+    *codecs == SectionCodecs::all(SectionCodec::Bzip2)
+}
+
+#[cfg(not(feature = "bzip2"))]
+fn is_classic(_: &SectionCodecs) -> bool {
+    false
+}
+
+/// Bytes in the header of a patch using `codecs`, with header `flags`.
+fn header_size(codecs: &SectionCodecs, flags: u8) -> u64 {
+    let size = if is_classic(codecs) && flags == 0 { CLASSIC_HEADER_SIZE } else { CODECS_HEADER_SIZE };
+    size as u64
 }
 
 impl Header {
+    /// A header for a patch of `new_file_size` bytes with `codecs`, whose
+    /// section sizes are still to be filled in.
+    pub fn new(new_file_size: u64, codecs: SectionCodecs) -> Header {
+        Header {
+            compressed_commands_size: 0,
+            compressed_delta_size: 0,
+            new_file_size: new_file_size,
+            codecs: codecs,
+            sequential_old_access: false,
+            delta_mode: DeltaMode::Subtract,
+        }
+    }
+
     pub fn read(buf: &[u8]) -> io::Result<Header> {
-        if &buf[0..8] != b"BSDIFF40" {
+        if buf.len() < CLASSIC_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated header"));
+        }
+
+        let (codecs, flags) = if &buf[0..8] == CLASSIC_MAGIC {
+            (classic_codecs()?, 0)
+        } else if &buf[0..8] == BsdiffCodecs::MAGIC {
+            if buf.len() < CODECS_HEADER_SIZE {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated header"));
+            }
+            if !known_flags(buf) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Reserved header bytes are set"));
+            }
+            let codecs = SectionCodecs {
+                commands: read_codec(buf[32])?,
+                delta: read_codec(buf[33])?,
+                extra: read_codec(buf[34])?,
+            };
+            (codecs, buf[35])
+        } else {
             return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad header: {}",
                 unsafe { ::std::str::from_utf8_unchecked(&buf[0..8]) } )));
-        }
+        };
 
         Ok(Header {
             compressed_commands_size: read_offset(&buf[8..8+8]) as u64,
             compressed_delta_size: read_offset(&buf[16..8+16]) as u64,
             new_file_size: read_offset(&buf[24..8+24]) as u64,
+            codecs: codecs,
+            sequential_old_access: flags & SEQUENTIAL_OLD_ACCESS != 0,
+            delta_mode: if flags & XOR_DELTA != 0 { DeltaMode::Xor } else { DeltaMode::Subtract },
         })
     }
 
+    /// The cargo features needed to decode this patch's sections.
+    pub fn required_features(&self) -> FeatureSet {
+        [self.codecs.commands, self.codecs.delta, self.codecs.extra].iter()
+            .filter_map(|&codec| codec_features(codec_byte(codec)))
+            .fold(FeatureSet::default(), |acc, f| acc.union(&f))
+    }
+
+    /// Bytes this header takes up in the patch.
+    pub fn encoded_size(&self) -> u64 {
+        header_size(&self.codecs, header_flags(self.sequential_old_access, self.delta_mode))
+    }
+
+    /// The magic this header starts with: BSDIFF40 for a classic patch,
+    /// BSDIFFC1 otherwise.
+    pub fn magic(&self) -> &'static [u8] {
+        if self.encoded_size() == CLASSIC_HEADER_SIZE as u64 { CLASSIC_MAGIC } else { BsdiffCodecs::MAGIC }
+    }
+
+    /// Size of the compressed extra section of a `patch_len` byte patch with
+    /// this header, or `None` if the other sections don't fit.
+    pub fn compressed_extra_size(&self, patch_len: u64) -> Option<u64> {
+        patch_len.checked_sub(self.encoded_size())
+            .and_then(|rest| rest.checked_sub(self.compressed_commands_size))
+            .and_then(|rest| rest.checked_sub(self.compressed_delta_size))
+    }
+
+    /// Writes the header, failing with `InvalidInput` if a size doesn't fit
+    /// the format.
     pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
-        let mut buf = [0u8; 8*4];
+        let max = i64::max_value() as u64;
+        if self.compressed_commands_size > max || self.compressed_delta_size > max || self.new_file_size > max {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bsdiff header sizes too big: {:?}", self)));
+        }
 
-        buf[0..8].copy_from_slice(b"BSDIFF40");
+        let mut buf = [0u8; CODECS_HEADER_SIZE];
+
+        if self.encoded_size() == CLASSIC_HEADER_SIZE as u64 {
+            buf[0..8].copy_from_slice(CLASSIC_MAGIC);
+        } else {
+            buf[0..8].copy_from_slice(BsdiffCodecs::MAGIC);
+            buf[32] = codec_byte(self.codecs.commands);
+            buf[33] = codec_byte(self.codecs.delta);
+            buf[34] = codec_byte(self.codecs.extra);
+            buf[35] = header_flags(self.sequential_old_access, self.delta_mode);
+        }
         write_offset(&mut buf[8..16], self.compressed_commands_size as i64);
         write_offset(&mut buf[16..24], self.compressed_delta_size as i64);
         write_offset(&mut buf[24..32], self.new_file_size as i64);
 
-        writer.write_all(&buf)
+        writer.write_all(&buf[..self.encoded_size() as usize])
+    }
+}
+
+/// Compresses one section with its codec.
+enum SectionWriter<W: Write> {
+    None(W),
+    #[cfg(feature = "bzip2")]
+    Bzip2(BzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::Encoder<W>),
+}
+
+impl<W: Write> SectionWriter<W> {
+    fn new(codec: SectionCodec, inner: W, compression: Compression) -> io::Result<SectionWriter<W>> {
+        Ok(match codec {
+            SectionCodec::None => SectionWriter::None(inner),
+            #[cfg(feature = "bzip2")]
+            SectionCodec::Bzip2 => SectionWriter::Bzip2(BzEncoder::new(inner, bzip2_compression(compression))),
+            #[cfg(feature = "zstd")]
+            SectionCodec::Zstd => SectionWriter::Zstd(zstd::stream::Encoder::new(inner, zstd_level(compression))?),
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            SectionWriter::None(w) => Ok(w),
+            #[cfg(feature = "bzip2")]
+            SectionWriter::Bzip2(w) => w.finish(),
+            #[cfg(feature = "zstd")]
+            SectionWriter::Zstd(w) => w.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for SectionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            SectionWriter::None(ref mut w) => w.write(buf),
+            #[cfg(feature = "bzip2")]
+            SectionWriter::Bzip2(ref mut w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            SectionWriter::Zstd(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            SectionWriter::None(ref mut w) => w.flush(),
+            #[cfg(feature = "bzip2")]
+            SectionWriter::Bzip2(ref mut w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            SectionWriter::Zstd(ref mut w) => w.flush(),
+        }
+    }
+}
+
+/// Decompresses one section of a patch in memory.
+pub(crate) enum Section<'a> {
+    None(Cursor<&'a [u8]>),
+    #[cfg(feature = "bzip2")]
+    Bzip2(BzDecoder<Cursor<&'a [u8]>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::Decoder<Cursor<&'a [u8]>>),
+}
+
+impl<'a> Section<'a> {
+    pub(crate) fn open(codec: SectionCodec, data: &'a [u8]) -> io::Result<Section<'a>> {
+        Ok(match codec {
+            SectionCodec::None => Section::None(Cursor::new(data)),
+            #[cfg(feature = "bzip2")]
+            SectionCodec::Bzip2 => Section::Bzip2(BzDecoder::new(Cursor::new(data))),
+            #[cfg(feature = "zstd")]
+            SectionCodec::Zstd => Section::Zstd(zstd::stream::Decoder::with_buffer(Cursor::new(data))?),
+        })
+    }
+
+    /// The compressed input, where the decoder lets us see it.
+    fn input(&self) -> Option<&Cursor<&'a [u8]>> {
+        match *self {
+            Section::None(ref r) => Some(r),
+            #[cfg(feature = "bzip2")]
+            Section::Bzip2(ref r) => Some(r.get_ref()),
+            #[cfg(feature = "zstd")]
+            Section::Zstd(_) => None,
+        }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// A section whose trailing data can be checked once it's been read to the
+/// end; see `check_section_end`.
+trait SectionEnd: Read {
+    /// Whether compressed input is left over after the end of the stream.
+    fn leftover_input(&self) -> bool;
+}
+
+impl<'a> SectionEnd for Section<'a> {
+    fn leftover_input(&self) -> bool {
+        self.input().map_or(false, |input| input.position() < input.get_ref().len() as u64)
+    }
+}
+
+/// Decompressed section data is handed over from a `PrefetchedSection`'s
+/// thread in chunks of this many bytes.
+const PREFETCH_CHUNK: usize = 64 * 1024;
+
+/// How many chunks a `PrefetchedSection`'s thread may decompress ahead.
+const PREFETCH_DEPTH: usize = 4;
+
+enum Prefetched {
+    Data(Vec<u8>),
+    End { leftover_input: bool },
+    Failed(io::Error),
+}
+
+/// A section decompressed on a background thread, which keeps up to
+/// `PREFETCH_DEPTH` chunks ahead of the reader in a bounded queue.  Dropping
+/// it stops the thread at its next chunk.
+struct PrefetchedSection {
+    chunks: Receiver<Prefetched>,
+    current: Cursor<Vec<u8>>,
+    leftover_input: bool,
+    done: bool,
+}
+
+impl PrefetchedSection {
+    fn spawn<'scope, 'env>(scope: &'scope thread::Scope<'scope, 'env>, codec: SectionCodec, data: &'env [u8])
+        -> PrefetchedSection
+    {
+        let (tx, rx) = mpsc::sync_channel(PREFETCH_DEPTH);
+
+        // The decoder is made on the thread itself, so it needn't be `Send`.
+        scope.spawn(move || {
+            let mut section = match Section::open(codec, data) {
+                Ok(section) => section,
+                Err(e) => {
+                    let _ = tx.send(Prefetched::Failed(e));
+                    return;
+                }
+            };
+
+            loop {
+                let mut chunk = vec![0u8; PREFETCH_CHUNK];
+                let next = match section.read(&mut chunk) {
+                    Ok(0) => Prefetched::End { leftover_input: section.leftover_input() },
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Prefetched::Data(chunk)
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Prefetched::Failed(e),
+                };
+
+                let last = match next {
+                    Prefetched::Data(_) => false,
+                    _ => true,
+                };
+                if tx.send(next).is_err() || last {
+                    return;
+                }
+            }
+        });
+
+        PrefetchedSection {
+            chunks: rx,
+            current: Cursor::new(Vec::new()),
+            leftover_input: false,
+            done: false,
+        }
+    }
+}
+
+impl Read for PrefetchedSection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() || self.done {
+                return Ok(n);
+            }
+
+            match self.chunks.recv() {
+                Ok(Prefetched::Data(chunk)) => self.current = Cursor::new(chunk),
+                Ok(Prefetched::End { leftover_input }) => {
+                    self.done = true;
+                    self.leftover_input = leftover_input;
+                }
+                Ok(Prefetched::Failed(e)) => {
+                    self.done = true;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.done = true;
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "bsdiff decoder thread stopped"));
+                }
+            }
+        }
+    }
+}
+
+impl SectionEnd for PrefetchedSection {
+    fn leftover_input(&self) -> bool {
+        self.leftover_input
+    }
+}
+
+impl<'a> Read for Section<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Section::None(ref mut r) => r.read(buf),
+            #[cfg(feature = "bzip2")]
+            Section::Bzip2(ref mut r) => r.read(buf),
+            #[cfg(feature = "zstd")]
+            Section::Zstd(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Command {
     pub bytewise_add_size: u64,
     pub extra_append_size: u64,
@@ -67,7 +685,19 @@ pub struct Command {
 }
 
 impl Command {
+    /// Writes the command as three of bsdiff's sign-and-magnitude integers
+    /// (see `write_offset`), failing with `InvalidInput` if a size is over
+    /// `i64::max_value()` or the seek is `i64::min_value()`, which would come
+    /// out as "negative zero".
     pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let max = i64::max_value() as u64;
+        if self.bytewise_add_size > max || self.extra_append_size > max {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bsdiff command sizes too big: {:?}", self)));
+        }
+        if self.oldfile_seek_offset == i64::min_value() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bsdiff command seek too big: {:?}", self)));
+        }
+
         let mut buf = [0u8; 8*3];
 
         write_offset(&mut buf[0..8], self.bytewise_add_size as i64);
@@ -79,46 +709,96 @@ impl Command {
 }
 
 pub struct CommandReader<R> {
-    inner: R
+    inner: R,
+    mode: DecodeMode,
+    first: bool,
+    /// The first command did nothing at all, so nothing may follow it.
+    first_empty: bool,
 }
 
 impl<R> CommandReader<R>
     where R: Read
 {
     pub fn new(inner: R) -> CommandReader<R> {
+        CommandReader::with_mode(inner, DecodeMode::Lenient)
+    }
+
+    /// In strict mode, commands with non-canonical offsets, negative sizes
+    /// or no output are errors; only the first may produce nothing, to seek
+    /// to where the first match starts or, if it's the only command, as
+    /// earlier versions wrote empty patches.
+    pub fn with_mode(inner: R, mode: DecodeMode) -> CommandReader<R> {
         CommandReader {
-            inner: inner
+            inner: inner,
+            mode: mode,
+            first: true,
+            first_empty: false,
         }
     }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+/// Writes a classic bsdiff command stream, as `CommandReader` reads it.
+pub struct CommandWriter<W> {
+    inner: W,
+    mode: DecodeMode,
+    first: bool,
 }
 
-pub fn read_offset(buf: &[u8]) -> i64 {
-    let mut y = (buf[7] & 0x7F) as i64;
+impl<W: Write> CommandWriter<W> {
+    pub fn new(inner: W) -> CommandWriter<W> {
+        CommandWriter::with_mode(inner, DecodeMode::Lenient)
+    }
+
+    /// In strict mode, commands a strict `CommandReader` would reject fail
+    /// with `InvalidInput` instead of being written.
+    pub fn with_mode(inner: W, mode: DecodeMode) -> CommandWriter<W> {
+        CommandWriter {
+            inner: inner,
+            mode: mode,
+            first: true,
+        }
+    }
 
-    for i in 0..7 {
-        y = y * 256;
-        y += buf[6 - i] as i64;
+    pub fn write(&mut self, cmd: &Command) -> io::Result<()> {
+        if self.mode == DecodeMode::Strict
+            && cmd.bytewise_add_size == 0 && cmd.extra_append_size == 0
+            && !(self.first && cmd.oldfile_seek_offset != 0)
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bsdiff command produces no output"));
+        }
+
+        cmd.write_to(&mut self.inner)?;
+        self.first = false;
+        Ok(())
     }
 
-    if (buf[7] & 0x80) != 0 {
-        y = -y;
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
     }
 
-    y
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
 }
 
-pub fn write_offset(buf: &mut [u8], x: i64) {
-    let mut y = if x < 0 { x.wrapping_neg() } else { x };
+fn check_command(buf: &[u8; 8*3], first: bool, after_empty: bool, mode: DecodeMode) -> io::Result<()> {
+    if buf.chunks(8).any(is_negative_zero) {
+        mode.reject("bsdiff command has a non-canonical offset")?;
+    }
 
-    for i in 0..8 {
-        buf[i] = y as u8;
-        y -= buf[i] as i64;
-        y /= 256;
+    if buf[7] & 0x80 != 0 || buf[15] & 0x80 != 0 {
+        mode.reject("bsdiff command has a negative size")?;
     }
 
-    if x < 0 {
-        buf[7] |= 0x80;
+    if after_empty || (buf[..16].iter().all(|&b| b == 0) && !first) {
+        mode.reject("bsdiff command produces no output")?;
     }
+
+    Ok(())
 }
 
 impl<R> Iterator for CommandReader<R>
@@ -133,10 +813,13 @@ impl<R> Iterator for CommandReader<R>
         while p < buf.len() {
             // println!("loop");
             match self.inner.read(&mut buf[p..]) {
-                Ok(0) => {
+                Ok(0) if p == 0 => {
                     // println!("1");
                     return None
                 }
+                Ok(0) => {
+                    return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated bsdiff command")))
+                }
                 Ok(size) => {
                     // println!("2 => {}", size);
                     p += size
@@ -148,6 +831,12 @@ impl<R> Iterator for CommandReader<R>
             }
         }
 
+        if let Err(e) = check_command(&buf, self.first, self.first_empty, self.mode) {
+            return Some(Err(e));
+        }
+        self.first_empty = self.first && buf.iter().all(|&b| b == 0);
+        self.first = false;
+
         Some(Ok(Command {
             bytewise_add_size: read_offset(&buf[0..8]) as u64,
             extra_append_size: read_offset(&buf[8..16]) as u64,
@@ -156,66 +845,162 @@ impl<R> Iterator for CommandReader<R>
     }
 }
 
-struct PatchWriter {
-    new_file_size: usize,
-    cmds: BzEncoder<Vec<u8>>,
-    delta: BzEncoder<Vec<u8>>,
-    extra: BzEncoder<Vec<u8>>,
+/// Buffers the three sections of a patch, counting the bytes that go into
+/// each so `finish_to` can check them against the commands and the header
+/// before anything is emitted.
+struct PatchWriter<S: SectionSink> {
+    new_file_size: u64,
+    codecs: SectionCodecs,
+    sequential_old_access: bool,
+    delta_mode: DeltaMode,
+    cmds: CountingWriter<SectionWriter<S>>,
+    delta: CountingWriter<SectionWriter<S>>,
+    extra: CountingWriter<SectionWriter<S>>,
+    expected: SectionSizes,
+}
+
+/// Uncompressed size of each section of a patch.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SectionSizes {
+    cmds: u64,
+    delta: u64,
+    extra: u64,
+}
+
+#[cfg(feature = "bzip2")]
+fn bzip2_compression(compression: Compression) -> bzip2::Compression {
+    match compression {
+        // Never actually used: `None` sections aren't bzip2'd.
+        Compression::None | Compression::Fastest => bzip2::Compression::Fastest,
+        Compression::Default => bzip2::Compression::Default,
+        Compression::Best => bzip2::Compression::Best,
+    }
+}
+
+impl PatchWriter<Vec<u8>> {
+    fn in_memory(new_file_size: u64, options: &PatchOptions) -> io::Result<PatchWriter<Vec<u8>>> {
+        PatchWriter::new(new_file_size, options, Vec::new(), Vec::new(), Vec::new())
+    }
+}
+
+impl PatchWriter<ScratchFile> {
+    fn spilled(new_file_size: u64, options: &PatchOptions) -> io::Result<PatchWriter<ScratchFile>> {
+        let default = TempFiles::new();
+        let scratch: &sink::Scratch = options.scratch.as_ref().map_or(&default, |s| &**s);
+        PatchWriter::new(new_file_size, options,
+            scratch.create()?,
+            scratch.create()?,
+            scratch.create()?)
+    }
 }
 
-impl PatchWriter {
-    fn new(new_file_size: usize) -> PatchWriter {
-        PatchWriter {
+impl<S: SectionSink> PatchWriter<S> {
+    fn new(new_file_size: u64, options: &PatchOptions, cmds: S, delta: S, extra: S) -> io::Result<PatchWriter<S>> {
+        let codecs = options.section_codecs();
+        let level = options.compression;
+        Ok(PatchWriter {
             new_file_size: new_file_size,
-            cmds: BzEncoder::new(Vec::new(), bzip2::Compression::Best),
-            delta: BzEncoder::new(Vec::new(), bzip2::Compression::Best),
-            extra: BzEncoder::new(Vec::new(), bzip2::Compression::Best),
-        }
+            codecs: codecs,
+            sequential_old_access: options.sequential_old_access,
+            delta_mode: options.delta_mode,
+            cmds: CountingWriter::new(SectionWriter::new(codecs.commands, cmds, level)?),
+            delta: CountingWriter::new(SectionWriter::new(codecs.delta, delta, level)?),
+            extra: CountingWriter::new(SectionWriter::new(codecs.extra, extra, level)?),
+            expected: SectionSizes::default(),
+        })
     }
 
-    fn finish(self) -> Vec<u8> {
-        let cmds = self.cmds.finish().unwrap();
-        let delta = self.delta.finish().unwrap();
-        let extra = self.extra.finish().unwrap();
+    fn finish_to<W: Write>(self, writer: W) -> io::Result<()> {
+        self.check_sizes()?;
+
+        let mut cmds = self.cmds.into_inner().finish()?;
+        let mut delta = self.delta.into_inner().finish()?;
+        let mut extra = self.extra.into_inner().finish()?;
+
+        let mut writer = CountingWriter::new(writer);
+
+        let header = Header {
+            compressed_commands_size: cmds.len(),
+            compressed_delta_size: delta.len(),
+            new_file_size: self.new_file_size,
+            codecs: self.codecs,
+            sequential_old_access: self.sequential_old_access,
+            delta_mode: self.delta_mode,
+        };
+        header.write_to(&mut writer)?;
+
+        cmds.copy_to(&mut writer)?;
+        delta.copy_to(&mut writer)?;
+        extra.copy_to(&mut writer)?;
+
+        let expected = header.encoded_size() + cmds.len() + delta.len() + extra.len();
+        if writer.count() != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "wrote {} bytes of bsdiff patch, but the header describes {}", writer.count(), expected)));
+        }
 
-        let mut patch = Vec::new();
+        Ok(())
+    }
 
-        Header {
-            compressed_commands_size: cmds.len() as u64,
-            compressed_delta_size: delta.len() as u64,
-            new_file_size: self.new_file_size as u64,
-        }.write_to(&mut patch).unwrap();
+    /// Makes sure every section got exactly the bytes its commands call for,
+    /// and that the commands produce as much output as the header says.
+    fn check_sizes(&self) -> io::Result<()> {
+        let written = SectionSizes {
+            cmds: self.cmds.count(),
+            delta: self.delta.count(),
+            extra: self.extra.count(),
+        };
+
+        if written != self.expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "bsdiff sections hold {:?}, but the commands call for {:?}", written, self.expected)));
+        }
 
-        patch.extend(&cmds);
-        patch.extend(&delta);
-        patch.extend(&extra);
+        let output = self.expected.delta + self.expected.extra;
+        if output != self.new_file_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "bsdiff commands produce {} bytes, but the header says {}", output, self.new_file_size)));
+        }
+
+        Ok(())
+    }
 
-        patch
+    fn write_delta_zeros(&mut self, count: u64) -> io::Result<()> {
+        self.delta_mode.write_zeros(&mut self.delta, count)
     }
 
-    fn write_delta_zeros(&mut self, count: usize) {
-        write_zeros(&mut self.delta, count as u64).unwrap();
+    fn write_delta(&mut self, old: &[u8], new: &[u8]) -> io::Result<()> {
+        self.delta_mode.write_delta(&mut self.delta, old, new)
     }
 
-    fn write_delta(&mut self, old: &[u8], new: &[u8]) {
-        write_delta(&mut self.delta, old, new).unwrap();
+    /// Writes delta bytes that have already been worked out.
+    fn write_raw_delta(&mut self, delta: &[u8]) -> io::Result<()> {
+        self.delta.write_all(delta)
     }
 
-    fn write_extra(&mut self, new: &[u8]) {
-        self.extra.write_all(new).unwrap();
-        // println!("write extra {}", new.len());
+    fn write_extra(&mut self, new: &[u8]) -> io::Result<()> {
+        self.extra.write_all(new)
     }
 
-    fn write_command(&mut self, cmd: &Command) {
-        cmd.write_to(&mut self.cmds).unwrap();
+    fn write_command(&mut self, cmd: &Command) -> io::Result<()> {
+        self.expected.cmds += 8*3;
+        self.expected.delta += cmd.bytewise_add_size;
+        self.expected.extra += cmd.extra_append_size;
+        cmd.write_to(&mut self.cmds)
     }
 }
 
+/// Applies bsdiff commands one at a time.  Output goes straight to `new`,
+/// so a write error surfaces from the command that caused it; callers that
+/// want it buffered wrap `new` in a `BufWriter` and flush it themselves.
 pub struct Patcher<DeltaR, ExtraR, OldRS, NewW> {
     delta: DeltaR,
     extra: ExtraR,
     old: OldRS,
     new: NewW,
+    scratch: Scratch,
+    written: u64,
+    delta_mode: DeltaMode,
 }
 
 impl<DeltaR, ExtraR, OldRS, NewW> Patcher<DeltaR, ExtraR, OldRS, NewW>
@@ -227,14 +1012,36 @@ impl<DeltaR, ExtraR, OldRS, NewW> Patcher<DeltaR, ExtraR, OldRS, NewW>
 {
 
     pub fn new(delta: DeltaR, extra: ExtraR, old: OldRS, new: NewW) -> Patcher<DeltaR, ExtraR, OldRS, NewW> {
+        Patcher::with_buffers(delta, extra, old, new, &BufferConfig::default())
+    }
+
+    pub fn with_buffers(delta: DeltaR, extra: ExtraR, old: OldRS, new: NewW, config: &BufferConfig)
+        -> Patcher<DeltaR, ExtraR, OldRS, NewW>
+    {
         Patcher {
             delta: delta,
             extra: extra,
             old: old,
             new: new,
+            scratch: Scratch::new(config),
+            written: 0,
+            delta_mode: DeltaMode::Subtract,
         }
     }
 
+    /// Combines deltas with the old file as `mode` says, rather than by
+    /// adding them.
+    pub fn delta_mode(mut self, mode: DeltaMode) -> Patcher<DeltaR, ExtraR, OldRS, NewW> {
+        self.delta_mode = mode;
+        self
+    }
+
+    /// Flushes `new` and returns it.
+    pub fn finish(mut self) -> io::Result<NewW> {
+        self.new.flush()?;
+        Ok(self.new)
+    }
+
     pub fn apply(&mut self, c: &Command) -> io::Result<()> {
         self.append_delta(c.bytewise_add_size)?;
         self.append_extra(c.extra_append_size)?;
@@ -244,107 +1051,803 @@ impl<DeltaR, ExtraR, OldRS, NewW> Patcher<DeltaR, ExtraR, OldRS, NewW>
 
     pub fn append_delta(&mut self, size: u64) -> io::Result<()> {
         let new = &mut self.new;
-        read_paired_bufs(size, &mut self.old, &mut self.delta, |o, d| {
-            for i in 0..o.len() {
-                o[i] = o[i].wrapping_add(d[i]);
-            }
+        let mode = self.delta_mode;
+        read_paired_bufs_in(&mut self.scratch, size, &mut self.old, &mut self.delta, |o, d| {
+            apply_delta(mode, o, d);
             new.write_all(&o)
-        })
+        })?;
+        self.written += size;
+        Ok(())
     }
 
     pub fn append_extra(&mut self, size: u64) -> io::Result<()> {
         let new = &mut self.new;
-        read_size_from(size, &mut self.extra, |e| {
+        read_size_from_in(&mut self.scratch, size, &mut self.extra, |e| {
             new.write_all(&e)
-        })
+        })?;
+        self.written += size;
+        Ok(())
     }
 
     pub fn seek_old(&mut self, size: i64) -> io::Result<()> {
         self.old.seek(io::SeekFrom::Current(size)).map(|_|())
     }
 
-    pub fn check_written_size(&self, _: u64) -> io::Result<()> {
-        // TODO: return an error if we haven't written the expected size to the output.
+    /// Fails unless the commands applied so far produced `expected` bytes.
+    pub fn check_written_size(&self, expected: u64) -> io::Result<()> {
+        if self.written != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "bsdiff commands produce {} bytes, but the header says {}", self.written, expected)));
+        }
         Ok(())
     }
 }
 
 pub fn generate_identity_patch(size: u64) -> Vec<u8> {
-    let mut w = PatchWriter::new(size as usize);
-
-    w.write_delta_zeros(size as usize);
-
-    w.write_command(&Command {
-        bytewise_add_size: size,
-        extra_append_size: 0,
-        oldfile_seek_offset: 0,
-    });
+    let mut patch = Vec::new();
+    write_identity_patch(size, &mut patch).unwrap();
+    patch
+}
 
-    w.finish()
+pub fn write_identity_patch<W: Write>(size: u64, writer: W) -> io::Result<()> {
+    write_identity_patch_with(size, SectionCodecs::default(), writer)
 }
 
-pub fn generate_idempotent_patch(desired_output: &[u8]) -> Vec<u8> {
-    let mut w = PatchWriter::new(desired_output.len());
+fn write_identity_patch_with<W: Write>(size: u64, codecs: SectionCodecs, writer: W) -> io::Result<()> {
+    let mut w = PatchWriter::in_memory(size, &PatchOptions::default().codecs(codecs))?;
 
-    w.write_extra(desired_output);
+    w.write_delta_zeros(size)?;
 
-    w.write_command(&Command {
-        bytewise_add_size: 0,
-        extra_append_size: desired_output.len() as u64,
-        oldfile_seek_offset: 0,
-    });
+    if size > 0 {
+        w.write_command(&Command {
+            bytewise_add_size: size,
+            extra_append_size: 0,
+            oldfile_seek_offset: 0,
+        })?;
+    }
 
-    w.finish()
+    w.finish_to(writer)
 }
 
-pub fn generate_full_patch(old: &Index, new: &[u8]) -> Vec<u8> {
-    let mut w = PatchWriter::new(new.len());
-
-    let mut i = 0;
+pub fn generate_idempotent_patch(desired_output: &[u8]) -> Vec<u8> {
+    let mut patch = Vec::new();
+    write_idempotent_patch(desired_output, &mut patch).unwrap();
+    patch
+}
 
-    let mut k = 0;
+pub fn write_idempotent_patch<W: Write>(desired_output: &[u8], writer: W) -> io::Result<()> {
+    write_idempotent_patch_with(desired_output, SectionCodecs::default(), writer)
+}
 
-    let mut it = MatchIter::from(old, new).peekable();
+fn write_idempotent_patch_with<W: Write>(desired_output: &[u8], codecs: SectionCodecs, writer: W) -> io::Result<()> {
+    let mut w = PatchWriter::in_memory(desired_output.len() as u64, &PatchOptions::default().codecs(codecs))?;
 
+    w.write_extra(desired_output)?;
 
-    while let Some(m) = it.next() {
+    if !desired_output.is_empty() {
+        w.write_command(&Command {
+            bytewise_add_size: 0,
+            extra_append_size: desired_output.len() as u64,
+            oldfile_seek_offset: 0,
+        })?;
+    }
 
-        if k % 1024 == 0 {
-            println!("{} / {} ({}%)", i, new.len(), i * 100 / new.len());
-        }
+    w.finish_to(writer)
+}
 
-        k += 1;
+/// Like `write_idempotent_patch`, but reads the desired output from `reader`
+/// to its end, so it needn't fit in memory or have a known length.  The
+/// compressed sections are spilled to `options.scratch` (or temporary files)
+/// while the header waits for the length.
+///
+/// Returns the length of the desired output.
+pub fn write_idempotent_patch_from_reader<R: Read, W: Write>(mut reader: R, options: &PatchOptions, writer: W)
+    -> io::Result<u64>
+{
+    let mut w = PatchWriter::spilled(0, options)?;
 
-        let mm = m.matched;
-        let next_old_offset = it.peek()
-            .map(|m| m.matched.old_offset)
-            .unwrap_or(mm.old_offset + mm.len());
+    let size = io::copy(&mut reader, &mut w.extra)?;
+    w.new_file_size = size;
 
+    if size > 0 {
         w.write_command(&Command {
-            bytewise_add_size: mm.len() as u64,
-            extra_append_size: m.unmatched_suffix as u64,
-            oldfile_seek_offset: next_old_offset as i64 - (mm.old_offset + mm.len()) as i64,
-        });
+            bytewise_add_size: 0,
+            extra_append_size: size,
+            oldfile_seek_offset: 0,
+        })?;
+    }
 
-        w.write_delta(
-            &old.data[mm.lower_delta_range()], 
-            &new[i .. i + mm.lower_delta_len]);
+    w.finish_to(writer)?;
+    Ok(size)
+}
 
-        w.write_delta_zeros(mm.mid_exact_len);
+pub fn generate_full_patch<M: Matcher>(old: &M, new: &[u8]) -> Vec<u8> {
+    let mut patch = Vec::new();
+    write_full_patch(old, new, &PatchOptions::default(), &mut patch).unwrap();
+    patch
+}
 
-        w.write_delta(
-            &old.data[mm.upper_delta_range()], 
-            &new[i + mm.lower_delta_len + mm.mid_exact_len .. i + mm.len()]);
+/// Writes a full patch to `writer`.
+///
+/// Unless `options.spill_to_temp_files` is set, the three compressed sections
+/// are held in memory until the whole new file has been processed.  With
+/// `options.pipeline`, matching, encoding and compression overlap on
+/// separate threads.
+///
+/// With `options.exclude_ranges`, `options.literal_ranges` or
+/// `options.sequential_old_access`, the matches are all found first and then
+/// cut down by `diff::exclude` or `sequential::monotonic`.
+pub fn write_full_patch<M: Matcher, W: Write>(old: &M, new: &[u8], options: &PatchOptions, writer: W) -> io::Result<()> {
+    write_found_matches(old.data(), new, MatchIter::with_options(old, new, options.matching), options, writer)
+}
+
+/// Like `write_full_patch`, with `matches` as `MatchIter` would find them,
+/// before `options` constrains them.
+pub(crate) fn write_found_matches<I, W>(old: &[u8], new: &[u8], matches: I, options: &PatchOptions, writer: W)
+    -> io::Result<()>
+    where
+        I: Iterator<Item = Match>,
+        W: Write
+{
+    if options.sequential_old_access || options.restricts_ranges() {
+        let matches = constrain(matches.collect(), options);
+        write_matches(old, new, matches.into_iter(), options, writer)
+    } else {
+        write_matches(old, new, matches, options, writer)
+    }
+}
+
+/// `matches`, with the ranges `options` rules out taken out, and cut down to
+/// ones that read the old file in order if it asks for that.
+fn constrain(matches: Vec<Match>, options: &PatchOptions) -> Vec<Match> {
+    let mut matches = options.restrict_ranges(matches);
+    if options.sequential_old_access {
+        matches = sequential::monotonic(&matches);
+    }
+    matches
+}
+
+fn write_matches<I, W>(old: &[u8], new: &[u8], matches: I, options: &PatchOptions, writer: W) -> io::Result<()>
+    where
+        I: Iterator<Item = Match>,
+        W: Write
+{
+    if options.spill_to_temp_files {
+        write_full_patch_with(old, new, matches, PatchWriter::spilled(new.len() as u64, options)?, writer)
+    } else if let Some(pipeline) = options.pipeline.filter(|_| concurrency::limit(2) == 2) {
+        write_full_patch_pipelined(old, new, matches, options, pipeline, writer)
+    } else {
+        write_full_patch_with(old, new, matches, PatchWriter::in_memory(new.len() as u64, options)?, writer)
+    }
+}
+
+/// Writes a patch from `old` to `new` made of `matches`, which must cover
+/// `new` in order (as `MatchIter` produces them), instead of searching for
+/// its own.  This is how front-ends like `diff::text` that find matches
+/// some other way get a patch the normal applier takes.
+pub fn write_patch_for_matches<W: Write>(old: &[u8], new: &[u8], matches: Vec<Match>, options: &PatchOptions, writer: W)
+    -> io::Result<()>
+{
+    write_matches(old, new, constrain(matches, options).into_iter(), options, writer)
+}
+
+/// Writes a patch from the file at `old_path` to the one at `new_path` to
+/// `patch_path`, as `Differ::run` would with `options` (so with any digests
+/// the options ask for).  The old file is read into memory and indexed by
+/// `AutoIndex`, so small files skip the suffix sort; the new one is mapped
+/// where the platform allows.  The patch goes to a temporary file first and
+/// is renamed into place once it's complete, so `patch_path` never holds
+/// half a patch.
+pub fn diff_files<P, Q, R>(old_path: P, new_path: Q, patch_path: R, options: &PatchOptions) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        R: AsRef<Path>
+{
+    // The index needs its own copy of the old file, but the new one is
+    // only ever read.
+    let old = read_file(old_path.as_ref())?;
+    let new = FileBytes::open(new_path.as_ref())?;
+
+    if special::is_trivial(&old, &new) {
+        return write_atomically(patch_path.as_ref(), None, |file| {
+            let mut writer = BufWriter::new(file);
+            special::write_trivial_patch(Engine::Bsdiff, &old, &new, options, &mut writer)?;
+            writer.flush()
+        });
+    }
+
+    let index = AutoIndex::compute(old);
+    let differ = Differ::new(&index).options(options.clone());
+
+    write_atomically(patch_path.as_ref(), None, |file| {
+        let mut writer = BufWriter::new(file);
+        differ.run(&new, &mut writer)?;
+        writer.flush()
+    })
+}
+
+fn write_full_patch_with<I, S, W>(old: &[u8], new: &[u8], matches: I, mut w: PatchWriter<S>, writer: W)
+    -> io::Result<()>
+    where
+        I: Iterator<Item = Match>,
+        S: SectionSink,
+        W: Write
+{
+    enter_span!("bsdiff::write_full_patch");
+
+    let mut i = 0;
+
+    let mut k = 0;
+
+    let mut it = with_leading_seek(matches).peekable();
+
+
+    while let Some(m) = it.next() {
+
+        if k % 1024 == 0 {
+            debug_event!("{} / {} ({}%)", i, new.len(), i * 100 / new.len().max(1));
+        }
+
+        k += 1;
+
+        w.write_command(&command_for(&m, it.peek()))?;
+
+        let mm = m.matched;
+
+        w.write_delta(
+            &old[mm.lower_delta_range()], 
+            &new[i .. i + mm.lower_delta_len])?;
+
+        w.write_delta_zeros(mm.mid_exact_len as u64)?;
+
+        w.write_delta(
+            &old[mm.upper_delta_range()], 
+            &new[i + mm.lower_delta_len + mm.mid_exact_len .. i + mm.len()])?;
 
         let extra_begin = i + mm.len();
         let extra_end = extra_begin + m.unmatched_suffix;
 
-        w.write_extra(&new[extra_begin .. extra_end]);
+        w.write_extra(&new[extra_begin .. extra_end])?;
 
         i = extra_end;
     }
 
-    w.finish()
+    info_event!("wrote {} commands for {} bytes of new data", k, new.len());
+
+    w.finish_to(writer)
+}
+
+/// Section data is handed to the compressor threads in chunks of about
+/// this many bytes.
+const PIPELINE_CHUNK: usize = 64 * 1024;
+
+/// The encoder stage of `write_full_patch_pipelined`: turns matches into
+/// section bytes and passes them on, a chunk at a time, to whichever
+/// compressor thread owns each section (0 commands, 1 delta, 2 extra).
+struct PipelineEncoder {
+    bufs: [Vec<u8>; 3],
+    compressors: Vec<SyncSender<(usize, Vec<u8>)>>,
+    written: [u64; 3],
+    expected: SectionSizes,
+    delta_mode: DeltaMode,
+}
+
+impl PipelineEncoder {
+    fn encode(&mut self, old: &[u8], new: &[u8], i: usize, m: &Match, next: Option<&Match>) -> io::Result<usize> {
+        let cmd = command_for(m, next);
+        self.expected.cmds += 8*3;
+        self.expected.delta += cmd.bytewise_add_size;
+        self.expected.extra += cmd.extra_append_size;
+        cmd.write_to(&mut self.bufs[0])?;
+        self.pass_on(0, PIPELINE_CHUNK)?;
+
+        let mm = &m.matched;
+        self.delta_mode.write_delta(&mut self.bufs[1], &old[mm.lower_delta_range()], &new[i .. i + mm.lower_delta_len])?;
+        self.delta_mode.write_zeros(&mut self.bufs[1], mm.mid_exact_len as u64)?;
+        self.delta_mode.write_delta(&mut self.bufs[1],
+            &old[mm.upper_delta_range()],
+            &new[i + mm.lower_delta_len + mm.mid_exact_len .. i + mm.len()])?;
+        self.pass_on(1, PIPELINE_CHUNK)?;
+
+        let extra_begin = i + mm.len();
+        let extra_end = extra_begin + m.unmatched_suffix;
+        self.bufs[2].extend_from_slice(&new[extra_begin .. extra_end]);
+        self.pass_on(2, PIPELINE_CHUNK)?;
+
+        Ok(extra_end)
+    }
+
+    /// Sends `section`'s buffered bytes on once there are at least `min`.
+    fn pass_on(&mut self, section: usize, min: usize) -> io::Result<()> {
+        if self.bufs[section].len() < min || self.bufs[section].is_empty() {
+            return Ok(());
+        }
+
+        let chunk = mem::replace(&mut self.bufs[section], Vec::with_capacity(PIPELINE_CHUNK));
+        self.written[section] += chunk.len() as u64;
+        self.compressors[section % self.compressors.len()].send((section, chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "bsdiff compressor thread stopped"))
+    }
+
+    fn finish(mut self) -> io::Result<(SectionSizes, SectionSizes)> {
+        for section in 0..3 {
+            self.pass_on(section, 0)?;
+        }
+        let written = SectionSizes {
+            cmds: self.written[0],
+            delta: self.written[1],
+            extra: self.written[2],
+        };
+        Ok((written, self.expected))
+    }
+}
+
+/// Like `write_full_patch_with` into in-memory sections, but with the
+/// commands and section bytes worked out on one thread and the sections
+/// compressed on others, while `matches` is drained on this one (so the
+/// matcher needn't be `Sync`).  The patch is byte for byte the same.
+fn write_full_patch_pipelined<I, W>(old: &[u8], new: &[u8], matches: I, options: &PatchOptions, pipeline: Pipeline, writer: W)
+    -> io::Result<()>
+    where
+        I: Iterator<Item = Match>,
+        W: Write
+{
+    enter_span!("bsdiff::write_full_patch_pipelined");
+
+    let codecs = options.section_codecs();
+    let section_codecs = [codecs.commands, codecs.delta, codecs.extra];
+    let level = options.compression;
+    // The encoder takes one of the threads.
+    let compressor_count = max(1, min(min(3, pipeline.compressor_threads), concurrency::limit(4) - 1));
+    let depth = max(1, pipeline.queue_depth);
+
+    let (sections, written, expected) = thread::scope(|scope| -> io::Result<_> {
+        let mut senders = Vec::new();
+        let mut compressors = Vec::new();
+        for c in 0..compressor_count {
+            let (tx, rx) = mpsc::sync_channel::<(usize, Vec<u8>)>(depth);
+            senders.push(tx);
+            compressors.push(scope.spawn(move || -> io::Result<Vec<(usize, Vec<u8>)>> {
+                let mut writers = Vec::new();
+                for section in (0..3).filter(|s| s % compressor_count == c) {
+                    writers.push((section, SectionWriter::new(section_codecs[section], Vec::new(), level)?));
+                }
+                for (section, chunk) in rx {
+                    let writer = &mut writers.iter_mut()
+                        .find(|w| w.0 == section)
+                        .expect("chunk sent to the wrong compressor").1;
+                    writer.write_all(&chunk)?;
+                }
+                writers.into_iter()
+                    .map(|(section, writer)| writer.finish().map(|out| (section, out)))
+                    .collect()
+            }));
+        }
+
+        let (match_tx, match_rx) = mpsc::sync_channel::<Match>(depth);
+        let encoder = scope.spawn(move || -> io::Result<(SectionSizes, SectionSizes)> {
+            let mut encoder = PipelineEncoder {
+                bufs: [Vec::new(), Vec::new(), Vec::new()],
+                compressors: senders,
+                written: [0; 3],
+                expected: SectionSizes::default(),
+                delta_mode: options.delta_mode,
+            };
+
+            let mut i = 0;
+            let mut pending: Option<Match> = None;
+            for m in match_rx {
+                if let Some(prev) = pending.take() {
+                    i = encoder.encode(old, new, i, &prev, Some(&m))?;
+                }
+                pending = Some(m);
+            }
+            if let Some(prev) = pending {
+                encoder.encode(old, new, i, &prev, None)?;
+            }
+            encoder.finish()
+        });
+
+        let mut k = 0;
+        for m in with_leading_seek(matches) {
+            k += 1;
+            // A closed queue means the encoder gave up; its error is
+            // picked up below.
+            if match_tx.send(m).is_err() {
+                break;
+            }
+        }
+        drop(match_tx);
+        info_event!("matched {} commands for {} bytes of new data", k, new.len());
+
+        let encoded = encoder.join().unwrap();
+
+        // A compressor's error comes first: the encoder only fails to send
+        // because one of them stopped.
+        let mut sections = vec![Vec::new(), Vec::new(), Vec::new()];
+        for compressor in compressors {
+            for (section, out) in compressor.join().unwrap()? {
+                sections[section] = out;
+            }
+        }
+
+        let (written, expected) = encoded?;
+        Ok((sections, written, expected))
+    })?;
+
+    if written != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "bsdiff sections hold {:?}, but the commands call for {:?}", written, expected)));
+    }
+    let output = expected.delta + expected.extra;
+    if output != new.len() as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "bsdiff commands produce {} bytes, but the header says {}", output, new.len())));
+    }
+
+    let header = Header {
+        compressed_commands_size: sections[0].len() as u64,
+        compressed_delta_size: sections[1].len() as u64,
+        new_file_size: new.len() as u64,
+        codecs: codecs,
+        sequential_old_access: options.sequential_old_access,
+        delta_mode: options.delta_mode,
+    };
+
+    let mut writer = writer;
+    header.write_to(&mut writer)?;
+    for section in &sections {
+        writer.write_all(section)?;
+    }
+    Ok(())
+}
+
+/// Appliers start reading the old file at offset 0, so if the first match
+/// starts anywhere else, an empty one goes before it to seek there.
+fn with_leading_seek<I: Iterator<Item = Match>>(matches: I) -> impl Iterator<Item = Match> {
+    let mut matches = matches.peekable();
+    let lead = match matches.peek() {
+        Some(first) if first.matched.len() > 0 && first.matched.old_offset != 0 => Some(Match::default()),
+        _ => None,
+    };
+    lead.into_iter().chain(matches)
+}
+
+fn command_for(m: &Match, next: Option<&Match>) -> Command {
+    let mm = &m.matched;
+    let next_old_offset = next
+        .map(|m| m.matched.old_offset)
+        .unwrap_or(mm.old_offset + mm.len());
+
+    Command {
+        bytewise_add_size: mm.len() as u64,
+        extra_append_size: m.unmatched_suffix as u64,
+        oldfile_seek_offset: next_old_offset as i64 - (mm.old_offset + mm.len()) as i64,
+    }
+}
+
+/// Rewrites a bsdiff patch with each section recompressed at `level`, with
+/// the codec it already has.
+pub(crate) fn recompress<W: Write>(patch: &[u8], level: Compression, mut writer: W) -> io::Result<()> {
+    let (header, command_data, delta_data, extra_data) = split_sections(patch)?;
+
+    let mut sections = Vec::new();
+    for &(codec, data) in &[
+        (header.codecs.commands, command_data),
+        (header.codecs.delta, delta_data),
+        (header.codecs.extra, extra_data),
+    ] {
+        let mut section = SectionWriter::new(codec, Vec::new(), level)?;
+        io::copy(&mut Section::open(codec, data)?, &mut section)?;
+        sections.push(section.finish()?);
+    }
+
+    let header = Header {
+        compressed_commands_size: sections[0].len() as u64,
+        compressed_delta_size: sections[1].len() as u64,
+        ..header
+    };
+    header.write_to(&mut writer)?;
+    for section in &sections {
+        writer.write_all(section)?;
+    }
+    Ok(())
+}
+
+/// Decodes a bsdiff patch into `Step`s, for `patch::transcode`.  Deltas
+/// that are all zeros become copies.  Steps hold `Subtract` deltas, so
+/// patches in any other `DeltaMode` are `InvalidInput`; see `has_steps`.
+pub(crate) fn read_steps(patch: &[u8]) -> io::Result<Vec<Step>> {
+    let (header, commands, mut delta, mut extra) = open_sections(patch, default_mode(patch))?;
+    if header.delta_mode != DeltaMode::Subtract {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Can't decode {:?} deltas into steps", header.delta_mode)));
+    }
+
+    let mut steps = Vec::new();
+    let mut old_pos = 0i64;
+    let mut output = 0u64;
+
+    for cmd in commands {
+        let cmd = cmd?;
+
+        if cmd.bytewise_add_size > 0 {
+            if old_pos < 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    "bsdiff command reads from before the start of the old file"));
+            }
+            let bytes = read_step_bytes(&mut delta, cmd.bytewise_add_size, "delta section")?;
+            push_delta_steps(&mut steps, old_pos as u64, bytes);
+        }
+
+        if cmd.extra_append_size > 0 {
+            steps.push(Step::Extra(read_step_bytes(&mut extra, cmd.extra_append_size, "extra section")?));
+        }
+
+        output = output.saturating_add(cmd.bytewise_add_size).saturating_add(cmd.extra_append_size);
+        old_pos = old_pos.wrapping_add(cmd.bytewise_add_size as i64).wrapping_add(cmd.oldfile_seek_offset);
+    }
+
+    if output != header.new_file_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "bsdiff commands produce {} bytes, but the header says {}", output, header.new_file_size)));
+    }
+
+    Ok(steps)
+}
+
+/// Zero runs in a delta at least this long become copies of their own.
+const COPY_RUN_MIN: usize = 64 * 1024;
+
+/// Pushes the steps for `delta` applied at `old_offset`: a copy if it is all
+/// zeros, otherwise deltas with any zero run of `COPY_RUN_MIN` bytes or more
+/// split out as a copy, so a mostly unchanged stretch can still be cloned.
+fn push_delta_steps(steps: &mut Vec<Step>, old_offset: u64, delta: Vec<u8>) {
+    if delta.iter().all(|&b| b == 0) {
+        steps.push(Step::Copy { old_offset: old_offset, len: delta.len() as u64 });
+        return;
+    }
+
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < delta.len() {
+        if delta[i] != 0 {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < delta.len() && delta[i] == 0 {
+            i += 1;
+        }
+        if i - start >= COPY_RUN_MIN {
+            runs.push((start, i));
+        }
+    }
+    if runs.is_empty() {
+        steps.push(Step::Delta { old_offset: old_offset, delta: delta });
+        return;
+    }
+
+    let mut pos = 0;
+    for (start, end) in runs {
+        if start > pos {
+            steps.push(Step::Delta { old_offset: old_offset + pos as u64, delta: delta[pos..start].to_vec() });
+        }
+        steps.push(Step::Copy { old_offset: old_offset + start as u64, len: (end - start) as u64 });
+        pos = end;
+    }
+    if pos < delta.len() {
+        steps.push(Step::Delta { old_offset: old_offset + pos as u64, delta: delta[pos..].to_vec() });
+    }
+}
+
+/// Writes `steps`, which add up to `new_len` bytes of output, as a bsdiff
+/// patch with the codecs from `options`.  Each delta or copy, with any extra
+/// bytes after it, becomes one command, and fills are spelled out in the
+/// extra section.  Only `DeltaMode::Subtract` can be written this way.
+///
+/// The steps are taken one at a time, so they can be worked out as they're
+/// written; the first error among them stops the patch.
+pub(crate) fn write_steps<I, B, W>(steps: I, new_len: u64, options: &PatchOptions, writer: W) -> io::Result<()>
+    where
+        I: Iterator<Item = io::Result<B>>,
+        B: Borrow<Step>,
+        W: Write
+{
+    if options.delta_mode != DeltaMode::Subtract {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Can't write steps as {:?} deltas without the old file", options.delta_mode)));
+    }
+
+    // The steps may well seek backwards.
+    let options = options.clone().sequential_old_access(false);
+    if options.spill_to_temp_files {
+        write_steps_with(steps, PatchWriter::spilled(new_len, &options)?, writer)
+    } else {
+        write_steps_with(steps, PatchWriter::in_memory(new_len, &options)?, writer)
+    }
+}
+
+fn write_steps_with<I, B, S, W>(steps: I, mut w: PatchWriter<S>, writer: W) -> io::Result<()>
+    where
+        I: Iterator<Item = io::Result<B>>,
+        B: Borrow<Step>,
+        S: SectionSink,
+        W: Write
+{
+    // The command being built, whose seek depends on where the next delta
+    // or copy starts.
+    let mut pending: Option<Command> = None;
+    let mut old_pos = 0i64;
+
+    for step in steps {
+        let step = step?;
+        let step: &Step = step.borrow();
+        let (old_offset, len) = match *step {
+            Step::Delta { old_offset, ref delta } => (old_offset, delta.len() as u64),
+            Step::Copy { old_offset, len } => (old_offset, len),
+            Step::Extra(ref extra) => {
+                w.write_extra(extra)?;
+                pending.get_or_insert_with(Command::default).extra_append_size += extra.len() as u64;
+                continue;
+            }
+            Step::Fill { byte, len } => {
+                let chunk = vec![byte; min(len, 64 * 1024) as usize];
+                let mut left = len;
+                while left > 0 {
+                    let n = min(left, chunk.len() as u64);
+                    w.write_extra(&chunk[..n as usize])?;
+                    left -= n;
+                }
+                pending.get_or_insert_with(Command::default).extra_append_size += len;
+                continue;
+            }
+        };
+        if len == 0 {
+            continue;
+        }
+
+        // bsdiff seeks are i64s; with both ends of the range in that, so is
+        // the seek between them.
+        let end = match old_offset.checked_add(len) {
+            Some(end) if end <= i64::max_value() as u64 => end,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Old file range at {} of {} bytes is past what bsdiff can seek to", old_offset, len))),
+        };
+
+        match *step {
+            Step::Delta { ref delta, .. } => w.write_raw_delta(delta)?,
+            _ => w.write_delta_zeros(len)?,
+        }
+
+        let seek = old_offset as i64 - old_pos;
+        pending = match pending {
+            Some(mut cmd) if cmd.extra_append_size == 0 && seek == 0 => {
+                cmd.bytewise_add_size += len;
+                Some(cmd)
+            }
+            Some(mut cmd) => {
+                cmd.oldfile_seek_offset = seek;
+                w.write_command(&cmd)?;
+                Some(Command { bytewise_add_size: len, ..Command::default() })
+            }
+            None => {
+                // Appliers start reading the old file at offset 0.
+                if seek != 0 {
+                    w.write_command(&Command { oldfile_seek_offset: seek, ..Command::default() })?;
+                }
+                Some(Command { bytewise_add_size: len, ..Command::default() })
+            }
+        };
+        old_pos = end as i64;
+    }
+
+    if let Some(cmd) = pending {
+        w.write_command(&cmd)?;
+    }
+
+    w.finish_to(writer)
+}
+
+/// Writes a full patch to a seekable `writer` without buffering any of the
+/// compressed sections.
+///
+/// Each section is compressed straight into `writer` in turn, with the
+/// matches found afresh for each one rather than kept, so memory use doesn't
+/// grow with the files (bar what `Effort::Optimal` or a `block_size` plan up
+/// front anyway).  That's three rounds of matching.  Exclude and literal
+/// ranges and sequential old access rewrite the match list as a whole, so
+/// with those it's worked out once and kept.  A placeholder header goes out
+/// first and is overwritten once the section sizes are known; `writer` is
+/// left positioned at the end of the patch.
+pub fn write_full_patch_seekable<M: Matcher, W: Write+Seek>(old: &M, new: &[u8], options: &PatchOptions, mut writer: W) -> io::Result<()> {
+    let constrained = if options.restricts_ranges() || options.sequential_old_access {
+        Some(constrain(MatchIter::with_options(old, new, options.matching).collect(), options))
+    } else {
+        None
+    };
+    let codecs = options.section_codecs();
+    let level = options.compression;
+
+    let start = writer.seek(SeekFrom::Current(0))?;
+    let flags = header_flags(options.sequential_old_access, options.delta_mode);
+    writer.write_all(&vec![0u8; header_size(&codecs, flags) as usize])?;
+
+    let compressed_commands_size = {
+        let mut cmds = SectionWriter::new(codecs.commands, CountingWriter::new(&mut writer), level)?;
+        let mut matches = seekable_matches(old, new, options, &constrained).peekable();
+        while let Some(m) = matches.next() {
+            command_for(&m, matches.peek()).write_to(&mut cmds)?;
+        }
+        cmds.finish()?.count()
+    };
+
+    let compressed_delta_size = {
+        let mut delta = SectionWriter::new(codecs.delta, CountingWriter::new(&mut writer), level)?;
+        let mut i = 0;
+        for m in seekable_matches(old, new, options, &constrained) {
+            let mm = &m.matched;
+
+            options.delta_mode.write_delta(&mut delta,
+                &old.data()[mm.lower_delta_range()],
+                &new[i .. i + mm.lower_delta_len])?;
+
+            options.delta_mode.write_zeros(&mut delta, mm.mid_exact_len as u64)?;
+
+            options.delta_mode.write_delta(&mut delta,
+                &old.data()[mm.upper_delta_range()],
+                &new[i + mm.lower_delta_len + mm.mid_exact_len .. i + mm.len()])?;
+
+            i += mm.len() + m.unmatched_suffix;
+        }
+        delta.finish()?.count()
+    };
+
+    let compressed_extra_size = {
+        let mut extra = SectionWriter::new(codecs.extra, CountingWriter::new(&mut writer), level)?;
+        let mut i = 0;
+        for m in seekable_matches(old, new, options, &constrained) {
+            let extra_begin = i + m.matched.len();
+            let extra_end = extra_begin + m.unmatched_suffix;
+            extra.write_all(&new[extra_begin .. extra_end])?;
+            i = extra_end;
+        }
+        extra.finish()?.count()
+    };
+
+    let end = writer.seek(SeekFrom::Current(0))?;
+
+    let header = Header {
+        compressed_commands_size: compressed_commands_size,
+        compressed_delta_size: compressed_delta_size,
+        new_file_size: new.len() as u64,
+        codecs: codecs,
+        sequential_old_access: options.sequential_old_access,
+        delta_mode: options.delta_mode,
+    };
+
+    let expected = header.encoded_size() + compressed_commands_size + compressed_delta_size + compressed_extra_size;
+    if end - start != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "wrote {} bytes of bsdiff patch, but the header describes {}", end - start, expected)));
+    }
+
+    writer.seek(SeekFrom::Start(start))?;
+    header.write_to(&mut writer)?;
+
+    writer.seek(SeekFrom::Start(end)).map(|_| ())
+}
+
+/// One pass over the matches for `write_full_patch_seekable`: `constrained`
+/// if it worked them out up front, otherwise found again.
+fn seekable_matches<'a, M: Matcher>(old: &'a M, new: &'a [u8], options: &PatchOptions,
+    constrained: &'a Option<Vec<Match>>) -> Box<Iterator<Item = Match> + 'a>
+{
+    match *constrained {
+        Some(ref matches) => Box::new(with_leading_seek(matches.iter().cloned())),
+        None => Box::new(with_leading_seek(MatchIter::with_options(old, new, options.matching))),
+    }
 }
 
 pub fn apply_patch<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
@@ -352,69 +1855,387 @@ pub fn apply_patch<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Resu
         OldRS: Read+Seek,
         NewW: Write
 {
-    let (header, body) = patch.split_at(32);
+    apply_patch_with_buffers(patch, old, new, &BufferConfig::default())
+}
 
-    let header = Header::read(&header)?;
+/// The header and the compressed commands, delta and extra sections.
+fn split_sections(patch: &[u8]) -> io::Result<(Header, &[u8], &[u8], &[u8])> {
+    let header = Header::read(patch)?;
+    let body = &patch[header.encoded_size() as usize..];
+
+    let sections_size = header.compressed_commands_size.checked_add(header.compressed_delta_size);
+    if sections_size.map_or(true, |size| size > body.len() as u64) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bsdiff sections run past the end of the patch"));
+    }
 
     let (command_data, rest) = body.split_at(header.compressed_commands_size as usize);
     let (delta_data, extra_data) = rest.split_at(header.compressed_delta_size as usize);
 
-    let command_stream = BzDecoder::new(Cursor::new(command_data));
+    Ok((header, command_data, delta_data, extra_data))
+}
+
+fn open_sections<'a>(patch: &'a [u8], mode: DecodeMode)
+    -> io::Result<(Header, CommandReader<Section<'a>>, Section<'a>, Section<'a>)>
+{
+    let (header, command_data, delta_data, extra_data) = split_sections(patch)?;
 
-    let commands = CommandReader::new(command_stream);
+    let commands = CommandReader::with_mode(Section::open(header.codecs.commands, command_data)?, mode);
 
-    let delta = BzDecoder::new(Cursor::new(delta_data));
-    let extra = BzDecoder::new(Cursor::new(extra_data));
+    let delta = Section::open(header.codecs.delta, delta_data)?;
+    let extra = Section::open(header.codecs.extra, extra_data)?;
 
-    let mut patcher = Patcher::new(delta, extra, old, new);
+    Ok((header, commands, delta, extra))
+}
 
-    for cmd in commands {
-        // println!("cmd {:?}", cmd);
-        patcher.apply(&(cmd?))?;
+/// In strict mode, fails if `section` has anything left in it: either
+/// decompressed bytes no command used, or data after the end of the
+/// compressed stream.
+fn check_section_end<R: SectionEnd>(section: &mut R, name: &str, mode: DecodeMode) -> io::Result<()> {
+    if !mode.is_strict() {
+        return Ok(());
+    }
+
+    let mut byte = [0u8; 1];
+    let unused = section.read(&mut byte)? != 0;
+    let leftover = section.leftover_input();
+
+    if unused || leftover {
+        mode.reject(&format!("trailing data in the bsdiff {} section", name))?;
+    }
+
+    Ok(())
+}
+
+/// Like `apply_patch`, but with scratch buffers sized by `config`.
+pub fn apply_patch_with_buffers<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW, config: &BufferConfig)
+    -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    apply_patch_with_mode(patch, old, new, config, default_mode(patch))
+}
+
+/// Like `apply_patch_with_buffers`, decoding in `mode`.
+pub fn apply_patch_with_mode<OldRS, NewW>(
+    patch: &[u8],
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: DecodeMode
+) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    apply_patch_observed(patch, old, new, config, mode, 0, &mut ())
+}
+
+/// Like `apply_patch_with_mode`, but with the delta section decompressed
+/// on a background thread if `threads` is at least 1, and the extra section
+/// on another if it's at least 2, each running a few chunks ahead of the
+/// main thread.  With bzip2 that hides most of the decompression time.
+/// `threads` of 0 is the same as `apply_patch_with_mode`.
+pub fn apply_patch_with_threads<OldRS, NewW>(
+    patch: &[u8],
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: DecodeMode,
+    threads: usize
+) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    apply_patch_observed(patch, old, new, config, mode, threads, &mut ())
+}
+
+/// Like `apply_patch_with_threads`, reporting each command to `observer`
+/// once it's been applied.
+pub fn apply_patch_observed<OldRS, NewW>(
+    patch: &[u8],
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: DecodeMode,
+    threads: usize,
+    observer: &mut ApplyObserver
+) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    // Identity patches are a straight copy; there's nothing to add.  Telling
+    // takes a pass over the delta section, which is wasted on a patch that
+    // turns out not to be one, so only small ones are checked.
+    if let Some(len) = identity_len_within(patch, mode, IDENTITY_SCAN_MAX)? {
+        copy_old_prefix(old, new, len)?;
+        observer.command(0, &CommandEvent { added: len, copied: 0, extra: 0, seek: Some(0) });
+        return Ok(());
+    }
+
+    let threads = concurrency::limit(threads);
+    if threads == 0 {
+        enter_span!("bsdiff::apply_patch");
+
+        let (header, commands, delta, extra) = open_sections(patch, mode)?;
+        let new = BufWriter::with_capacity(config.output_buffer_size, new);
+        let patcher = Patcher::with_buffers(delta, extra, old, new, config).delta_mode(header.delta_mode);
+        return apply_sections(&header, commands, patcher, mode, observer);
+    }
+
+    enter_span!("bsdiff::apply_patch_with_threads");
+
+    let (header, command_data, delta_data, extra_data) = split_sections(patch)?;
+    let commands = CommandReader::with_mode(Section::open(header.codecs.commands, command_data)?, mode);
+
+    // The sections are dropped before the scope ends, which stops their
+    // threads even if applying fails partway.
+    let new = BufWriter::with_capacity(config.output_buffer_size, new);
+    thread::scope(|scope| {
+        let delta = PrefetchedSection::spawn(scope, header.codecs.delta, delta_data);
+        if threads >= 2 {
+            let extra = PrefetchedSection::spawn(scope, header.codecs.extra, extra_data);
+            let patcher = Patcher::with_buffers(delta, extra, old, new, config).delta_mode(header.delta_mode);
+            apply_sections(&header, commands, patcher, mode, observer)
+        } else {
+            let extra = Section::open(header.codecs.extra, extra_data)?;
+            let patcher = Patcher::with_buffers(delta, extra, old, new, config).delta_mode(header.delta_mode);
+            apply_sections(&header, commands, patcher, mode, observer)
+        }
+    })
+}
+
+fn apply_sections<D, E, OldRS, NewW>(
+    header: &Header,
+    mut commands: CommandReader<Section>,
+    mut patcher: Patcher<D, E, OldRS, NewW>,
+    mode: DecodeMode,
+    observer: &mut ApplyObserver
+) -> io::Result<()>
+    where
+        D: SectionEnd,
+        E: SectionEnd,
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    let mut output_offset = 0;
+    let mut seek = 0i64;
+
+    for (i, cmd) in commands.by_ref().enumerate() {
+        let cmd = cmd.map_err(|e| ApplyError::wrap(e, i as u64, None, output_offset))?;
+        debug_event!("apply cmd: {:?}", cmd);
+        patcher.apply(&cmd).map_err(|e| ApplyError::wrap(e, i as u64, None, output_offset))?;
+        output_offset += cmd.bytewise_add_size + cmd.extra_append_size;
+
+        // A command's seek applies after it, so it's counted toward the
+        // next command that reads the old file.
+        observer.command(i as u64, &CommandEvent {
+            added: cmd.bytewise_add_size,
+            copied: 0,
+            extra: cmd.extra_append_size,
+            seek: if cmd.bytewise_add_size > 0 { Some(mem::replace(&mut seek, 0)) } else { None },
+        });
+        seek = seek.saturating_add(cmd.oldfile_seek_offset);
     }
 
     patcher.check_written_size(header.new_file_size)?;
 
+    check_section_end(commands.get_mut(), "commands", mode)?;
+    check_section_end(&mut patcher.delta, "delta", mode)?;
+    check_section_end(&mut patcher.extra, "extra", mode)?;
+
+    patcher.finish()?;
+
+    info_event!("applied patch producing {} bytes", header.new_file_size);
+
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::io::Cursor;
+/// Applies a patch written with `PatchOptions::sequential_old_access` to an
+/// old file that can only be read front to back, such as a pipe.  Seeks
+/// forward are done by reading and discarding.  `patch::apply_sequential`
+/// takes any patch, failing only once one actually seeks backwards.
+pub fn apply_patch_sequential<OldR, NewW>(patch: &[u8], old: OldR, new: NewW) -> io::Result<()>
+    where
+        OldR: Read,
+        NewW: Write
+{
+    let header = Header::read(patch)?;
+    if !header.sequential_old_access {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Patch wasn't written for sequential access to the old file"));
+    }
 
-    use super::*;
-    use diff::Index;
+    apply_patch_with_buffers(patch, SequentialOld::new(old, 0), new, &BufferConfig::default())
+}
+
+/// Applies the patch at `patch_path` (in any format `patch::apply_any`
+/// takes) to the file at `old_path`, writing the result to `new_path`; see
+/// `patch::apply_to_path`, which this reads (or, where the platform allows,
+/// maps) the patch for.
+pub fn patch_files<P, Q, R>(old_path: P, patch_path: Q, new_path: R, options: &ApplyOptions) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        R: AsRef<Path>
+{
+    let patch = FileBytes::open(patch_path.as_ref())?;
+    apply_to_path(&patch, old_path, new_path, options)
+}
 
-    fn assert_identity_encoding(tests: &[(i64)]) {
-        for test in tests {
-            let mut buf = [0u8; 8];
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
 
-            // println!("trying 0x{:x}", test);
-            
-            write_offset(&mut buf, *test);
-            let result = read_offset(&buf);
+/// The contents of a file, for `diff_files` and `patch_files`.  On unix a
+/// regular file is mapped rather than read, so only the pages actually
+/// touched get read in and nothing is copied; anywhere else, or if mapping
+/// fails, the file is read into memory.
+///
+/// Nothing stops another process changing a mapped file underneath us, and
+/// truncating it makes touching the lost pages fatal (`SIGBUS`), so this is
+/// only for files the caller owns.
+enum FileBytes {
+    #[cfg(unix)]
+    Mapped(*const u8, usize),
+    Read(Vec<u8>),
+}
 
-            // println!("  got 0x{:x}", result);
+impl FileBytes {
+    fn open(path: &Path) -> io::Result<FileBytes> {
+        let mut file = File::open(path)?;
 
-            assert_eq!(*test, result);
+        #[cfg(unix)]
+        {
+            if let Some(mapped) = FileBytes::map(&file) {
+                return Ok(mapped);
+            }
         }
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(FileBytes::Read(contents))
     }
 
-    #[test]
-    fn test_read_write_offset_roundtrip() {
-        assert_identity_encoding(&[
-            0, 1, -1, 2, -2, 3, -3,
-            127, -127, 128, -128, 129, -129,
-            255, -255, 256, -256, 257, -257,
-            16383, -16383, 16384, -16384, 16385, -16385,
-            65535, -65535, 65536, -65536, 65537, -65537,
-            0x7ffffffffffffffe,
-            0x7fffffffffffffff,
-            // -0x8000000000000000, // TODO: investigate breakage
-            -0x7fffffffffffffff,
-        ]);
+    /// Maps `file` read-only, or `None` if it can't be: it isn't a regular
+    /// file, is empty (which `mmap` rejects), or the system refuses.
+    #[cfg(unix)]
+    fn map(file: &File) -> Option<FileBytes> {
+        use std::os::unix::io::AsRawFd;
+        use std::ptr;
+
+        let len = match file.metadata() {
+            Ok(ref metadata) if metadata.is_file() && metadata.len() > 0 &&
+                metadata.len() <= usize::max_value() as u64 => metadata.len() as usize,
+            _ => return None,
+        };
+
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if addr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(FileBytes::Mapped(addr as *const u8, len))
+    }
+}
+
+impl ::std::ops::Deref for FileBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            #[cfg(unix)]
+            FileBytes::Mapped(addr, len) => unsafe { ::std::slice::from_raw_parts(addr, len) },
+            FileBytes::Read(ref contents) => contents,
+        }
+    }
+}
+
+impl Drop for FileBytes {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            if let FileBytes::Mapped(addr, len) = *self {
+                unsafe { libc::munmap(addr as *mut libc::c_void, len); }
+            }
+        }
+    }
+}
+
+/// Applies `patch` to an old file that's already in memory.  Deltas are added
+/// straight from `old`, skipping the copy through a `Read+Seek` stream.
+pub fn apply_patch_from_slice<NewW: Write>(patch: &[u8], old: &[u8], new: NewW) -> io::Result<()> {
+    apply_patch_from_slice_with_mode(patch, old, new, default_mode(patch))
+}
+
+/// Like `apply_patch_from_slice`, decoding in `mode`.
+pub fn apply_patch_from_slice_with_mode<NewW: Write>(patch: &[u8], old: &[u8], new: NewW, mode: DecodeMode)
+    -> io::Result<()>
+{
+    let (header, mut commands, mut delta, mut extra) = open_sections(patch, mode)?;
+
+    let config = BufferConfig::default();
+    let mut scratch = Scratch::new(&config);
+    let mut new = BufWriter::with_capacity(config.output_buffer_size, new);
+    let mut pos = 0u64;
+    let mut output_offset = 0;
+
+    for (i, cmd) in commands.by_ref().enumerate() {
+        let res = cmd.and_then(|cmd| {
+            let o = old_slice(old, pos, cmd.bytewise_add_size)?;
+            let mut done = 0;
+            read_size_from_in(&mut scratch, cmd.bytewise_add_size, &mut delta, |d| {
+                apply_delta(header.delta_mode, d, &o[done..done + d.len()]);
+                done += d.len();
+                new.write_all(d)
+            })?;
+            pos += cmd.bytewise_add_size;
+
+            read_size_from_in(&mut scratch, cmd.extra_append_size, &mut extra, |e| new.write_all(e))?;
+
+            // `pos` is within `old`, so it fits in an i64.
+            pos = match (pos as i64).checked_add(cmd.oldfile_seek_offset) {
+                Some(next) if next >= 0 => next as u64,
+                Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    "patch seeks before the start of the old file")),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    "patch seeks past the largest old file offset")),
+            };
+
+            Ok(cmd.bytewise_add_size + cmd.extra_append_size)
+        });
+
+        match res {
+            Ok(len) => output_offset += len,
+            Err(e) => return Err(ApplyError::wrap(e, i as u64, None, output_offset)),
+        }
     }
 
+    if output_offset != header.new_file_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "bsdiff commands produce {} bytes, but the header says {}", output_offset, header.new_file_size)));
+    }
+
+    check_section_end(commands.get_mut(), "commands", mode)?;
+    check_section_end(&mut delta, "delta", mode)?;
+    check_section_end(&mut extra, "extra", mode)?;
+
+    new.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use diff::Index;
+    use test_util::{noise, ShortReads};
+
     #[test]
     fn test_command_roundtrip() {
         let cmds = vec![
@@ -450,6 +2271,239 @@ mod tests {
         assert_eq!(cmds, result);
     }
 
+    #[test]
+    fn test_patch_writer_checks_sizes() {
+        let mut w = PatchWriter::in_memory(5, &PatchOptions::default()).unwrap();
+        w.write_command(&Command {
+            bytewise_add_size: 5,
+            extra_append_size: 0,
+            oldfile_seek_offset: 0,
+        }).unwrap();
+        w.write_delta_zeros(3).unwrap();
+
+        let mut patch = Vec::new();
+        assert!(w.finish_to(&mut patch).is_err());
+        assert!(patch.is_empty());
+
+        let mut w = PatchWriter::in_memory(6, &PatchOptions::default()).unwrap();
+        w.write_command(&Command {
+            bytewise_add_size: 0,
+            extra_append_size: 5,
+            oldfile_seek_offset: 0,
+        }).unwrap();
+        w.write_extra(b"abcde").unwrap();
+        assert!(w.finish_to(&mut patch).is_err());
+    }
+
+    #[test]
+    fn test_command_reader_strict() {
+        let mut encoded = Vec::new();
+        Command {
+            bytewise_add_size: 4,
+            extra_append_size: 0,
+            oldfile_seek_offset: 0,
+        }.write_to(&mut encoded).unwrap();
+
+        // Same command, with the seek offset written as "negative zero".
+        encoded[23] = 0x80;
+
+        let lenient = CommandReader::new(Cursor::new(&encoded[..])).next().unwrap().unwrap();
+        assert_eq!(lenient.oldfile_seek_offset, 0);
+
+        let err = CommandReader::with_mode(Cursor::new(&encoded[..]), DecodeMode::Strict).next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // An empty command is only let through as the whole patch, as
+        // earlier versions wrote empty patches.
+        let empty = [0u8; 48];
+        let mut lenient = CommandReader::new(Cursor::new(&empty[..]));
+        assert!(lenient.next().unwrap().is_ok());
+        assert!(lenient.next().unwrap().is_ok());
+        let mut strict = CommandReader::with_mode(Cursor::new(&empty[..]), DecodeMode::Strict);
+        assert!(strict.next().unwrap().is_ok());
+        assert!(strict.next().unwrap().is_err());
+
+        // A leading command that only seeks is how a patch starts mid-way
+        // through the old file; anywhere else it's an empty command.
+        let mut seeks = Vec::new();
+        let seek = Command {
+            bytewise_add_size: 0,
+            extra_append_size: 0,
+            oldfile_seek_offset: 12,
+        };
+        seek.write_to(&mut seeks).unwrap();
+        seek.write_to(&mut seeks).unwrap();
+
+        let mut strict = CommandReader::with_mode(Cursor::new(&seeks[..]), DecodeMode::Strict);
+        assert_eq!(strict.next().unwrap().unwrap(), seek);
+        assert!(strict.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_command_writer() {
+        let commands = [
+            Command { bytewise_add_size: 0, extra_append_size: 0, oldfile_seek_offset: 1000 },
+            Command { bytewise_add_size: 5, extra_append_size: 2, oldfile_seek_offset: -1005 },
+            Command { bytewise_add_size: 1 << 40, extra_append_size: 0, oldfile_seek_offset: i64::min_value() + 1 },
+        ];
+
+        let mut writer = CommandWriter::with_mode(Vec::new(), DecodeMode::Strict);
+        for cmd in &commands {
+            writer.write(cmd).unwrap();
+        }
+
+        // Only a leading seek may produce nothing.
+        let err = writer.write(&commands[0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let big = Command { bytewise_add_size: 1 << 63, ..Command::default() };
+        assert_eq!(writer.write(&big).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let far = Command { bytewise_add_size: 1, oldfile_seek_offset: i64::min_value(), ..Command::default() };
+        assert_eq!(writer.write(&far).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(far.write_to(&mut Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let encoded = writer.into_inner();
+        assert_eq!(encoded.len(), 24 * commands.len());
+        let read = CommandReader::with_mode(Cursor::new(&encoded[..]), DecodeMode::Strict)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, commands);
+    }
+
+    #[test]
+    #[cfg(all(feature = "bzip2", feature = "zstd"))]
+    fn test_section_codecs() {
+        let buf = b"this is a test 12345678 test";
+        let buf2 = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(buf.to_vec());
+
+        let codecs = [
+            SectionCodecs::default(),
+            SectionCodecs::all(SectionCodec::None),
+            SectionCodecs::all(SectionCodec::Zstd),
+            BsdiffCodecs::CODECS,
+        ];
+
+        for codecs in &codecs {
+            let options = PatchOptions::default().codecs(*codecs);
+
+            let mut patch = Vec::new();
+            write_full_patch(&index, &buf2[..], &options, &mut patch).unwrap();
+
+            let mut seekable = Cursor::new(Vec::new());
+            write_full_patch_seekable(&index, &buf2[..], &options, &mut seekable).unwrap();
+            assert_eq!(patch, seekable.into_inner());
+
+            let header = Header::read(&patch).unwrap();
+            assert_eq!(header.codecs, *codecs);
+            assert_eq!(Bsdiff::is_match(&patch), *codecs == SectionCodecs::default());
+            assert!(is_match(&patch));
+
+            let mut new = Vec::new();
+            apply_patch_with_mode(&patch, Cursor::new(&buf[..]), &mut new, &BufferConfig::default(),
+                DecodeMode::Strict).unwrap();
+            assert_eq!(&buf2[..], &new[..]);
+        }
+    }
+
+    #[test]
+    fn test_unknown_codec() {
+        let mut patch = Vec::new();
+        BsdiffCodecs::write_idempotent_patch(b"test", &mut patch).unwrap();
+        assert!(Header::read(&patch).is_ok());
+
+        patch[34] = 7;
+        assert!(Header::read(&patch).is_err());
+    }
+
+    #[test]
+    fn test_apply_from_slice_seek_overflow() {
+        let mut w = PatchWriter::in_memory(1, &PatchOptions::default()).unwrap();
+        w.write_delta_zeros(1).unwrap();
+        w.write_command(&Command { bytewise_add_size: 1, extra_append_size: 0, oldfile_seek_offset: i64::max_value() })
+            .unwrap();
+        let mut patch = Vec::new();
+        w.finish_to(&mut patch).unwrap();
+
+        // The seek is never used, so streaming apply doesn't mind it.
+        let mut out = Vec::new();
+        apply_patch(&patch, Cursor::new(b"a"), &mut out).unwrap();
+        assert_eq!(out, b"a");
+
+        let err = apply_patch_from_slice(&patch, b"a", &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_patcher_write_errors() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, "broken"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Nothing is held back to fail later, or never if the patcher is
+        // dropped without `finish`.
+        let mut patcher = Patcher::new(&[0u8; 4][..], &b"tail"[..], Cursor::new(&b"abcd"[..]), Broken);
+        assert!(patcher.append_delta(4).is_err());
+        assert!(patcher.append_extra(4).is_err());
+
+        let mut patcher = Patcher::new(&[1u8; 4][..], &b"tail"[..], Cursor::new(&b"abcd"[..]), Vec::new());
+        patcher.apply(&Command { bytewise_add_size: 4, extra_append_size: 4, oldfile_seek_offset: 0 }).unwrap();
+        patcher.check_written_size(8).unwrap();
+        assert_eq!(patcher.finish().unwrap(), b"bcdetail");
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut header = Header::new(1 << 40, SectionCodecs::all(SectionCodec::None));
+        header.compressed_commands_size = 24;
+        header.compressed_delta_size = 1000;
+        header.sequential_old_access = true;
+
+        let mut buf = Vec::new();
+        header.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), CODECS_HEADER_SIZE);
+        assert_eq!(&buf[..8], header.magic());
+        assert_eq!(Header::read(&buf).unwrap(), header);
+        assert_eq!(header.compressed_extra_size(2000), Some(2000 - 40 - 24 - 1000));
+        assert_eq!(header.compressed_extra_size(1000), None);
+
+        let patch = generate_identity_patch(10);
+        let read = Header::read(&patch).unwrap();
+        assert_eq!(read.compressed_extra_size(patch.len() as u64), Some(split_sections(&patch).unwrap().3.len() as u64));
+
+        header.new_file_size = 1 << 63;
+        assert_eq!(header.write_to(&mut Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_oversized_declared_sizes() {
+        // Sizes past 4 GB mustn't be truncated on 32-bit targets, where a
+        // section size of 4 GB + 1 would otherwise split the patch at byte 1.
+        let buf = b"this is a test";
+        let mut patch = Vec::new();
+        write_identity_patch_with(buf.len() as u64, SectionCodecs::all(SectionCodec::None), &mut patch).unwrap();
+
+        let big = (1u64 << 32) + 1;
+        for &field in &[8, 16] {
+            let mut bad = patch.clone();
+            write_offset(&mut bad[field..field + 8], big as i64);
+            let err = apply_patch(&bad, Cursor::new(buf), Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut bad = patch.clone();
+        write_offset(&mut bad[24..32], big as i64);
+        assert!(apply_patch(&bad, Cursor::new(buf), Vec::new()).is_err());
+        assert!(apply_patch_from_slice(&bad, buf, Vec::new()).is_err());
+    }
+
     #[test]
     fn test_identity_patch() {
         let buf = b"this is a test";
@@ -485,6 +2539,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_idempotent_patch_from_reader() {
+        let buf = (0..300000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<u8>>();
+
+        for options in &[PatchOptions::default(), PatchOptions::default().codecs(SectionCodecs::all(SectionCodec::None))] {
+            for &max in &[1, 1000] {
+                let mut patch = Vec::new();
+                let reader = ShortReads { inner: &buf[..], max: max };
+                let size = write_idempotent_patch_from_reader(reader, options, &mut patch).unwrap();
+                assert_eq!(size, buf.len() as u64);
+
+                let mut new = Vec::new();
+                apply_patch(&patch, Cursor::new(b"something else"), &mut new).unwrap();
+                assert!(new == buf);
+            }
+        }
+
+        let mut patch = Vec::new();
+        assert_eq!(write_idempotent_patch_from_reader(&b""[..], &PatchOptions::default(), &mut patch).unwrap(), 0);
+        let mut new = Vec::new();
+        apply_patch(&patch, Cursor::new(b"old"), &mut new).unwrap();
+        assert!(new.is_empty());
+    }
+
     #[test]
     fn test_simple_patch() {
         let buf = b"this is a test";
@@ -500,6 +2578,19 @@ mod tests {
         assert_eq!(&buf2[..], &new[..]);
     }
 
+    #[test]
+    fn test_patch_starting_mid_old_file() {
+        let buf = b"some header bytes, then 0123456789abcdef";
+        let buf2 = b"0123456789abcdef";
+        let index = Index::compute(buf.to_vec());
+        let patch = generate_full_patch(&index, &buf2[..]);
+
+        let mut new = Vec::new();
+        apply_patch(&patch, Cursor::new(&buf[..]), &mut new).unwrap();
+
+        assert_eq!(&buf2[..], &new[..]);
+    }
+
     #[test]
     fn test_full_patch() {
         let buf = b"this is a test 12345678 test";
@@ -515,4 +2606,253 @@ mod tests {
 
         assert_eq!(str::from_utf8(buf2).unwrap(), str::from_utf8(&new).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pipelined_patch() {
+        let old = noise(1, 300000);
+        let mut new = old[1000..].to_vec();
+        for i in (0..new.len()).filter(|i| i % 5000 < 40) {
+            new[i] = new[i].wrapping_add(i as u8);
+        }
+        new.extend_from_slice(&old[..90000]);
+
+        let index = Index::compute(old.clone());
+        let mut serial = Vec::new();
+        write_full_patch(&index, &new, &PatchOptions::default(), &mut serial).unwrap();
+
+        for &(compressor_threads, queue_depth) in &[(1, 1), (2, 4), (3, 64), (8, 0)] {
+            let options = PatchOptions::default().pipeline(Pipeline {
+                compressor_threads: compressor_threads,
+                queue_depth: queue_depth,
+            });
+
+            let mut pipelined = Vec::new();
+            write_full_patch(&index, &new, &options, &mut pipelined).unwrap();
+            assert!(pipelined == serial, "{} compressor threads", compressor_threads);
+        }
+
+        let mut out = Vec::new();
+        apply_patch(&serial, &mut Cursor::new(&old[..]), &mut out).unwrap();
+        assert!(out == new);
+    }
+
+    #[test]
+    fn test_apply_with_threads() {
+        let old = noise(7, 300000);
+        let mut new = old[..200000].to_vec();
+        for i in (0..new.len()).step_by(3000) {
+            new[i] ^= 0x55;
+        }
+        new.extend_from_slice(&old[150000..]);
+        new.extend_from_slice(b"some bytes the old file never had");
+
+        let index = Index::compute(old.clone());
+        let mut patch = Vec::new();
+        write_full_patch(&index, &new, &PatchOptions::default(), &mut patch).unwrap();
+
+        for threads in 0..4 {
+            let mut out = Vec::new();
+            apply_patch_with_threads(&patch, Cursor::new(&old[..]), &mut out, &BufferConfig::default(),
+                DecodeMode::Strict, threads).unwrap();
+            assert!(out == new, "{} threads", threads);
+        }
+
+        // Trailing data after the extra section is still caught when it's
+        // decompressed on another thread.
+        patch.push(0);
+        for threads in 0..3 {
+            let res = apply_patch_with_threads(&patch, Cursor::new(&old[..]), &mut Vec::new(),
+                &BufferConfig::default(), DecodeMode::Strict, threads);
+            assert!(res.is_err(), "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn test_seekable_matches_full_patch() {
+        use diff::{Effort, MatchOptions};
+
+        let old = noise(12, 50000);
+        let mut new = old[10000..].to_vec();
+        new.extend_from_slice(&noise(13, 2000));
+        for i in (0..new.len()).step_by(900) {
+            new[i] ^= 0x10;
+        }
+        let index = Index::compute(old.clone());
+
+        for options in &[
+            PatchOptions::default(),
+            PatchOptions::default().exclude_ranges(vec![20000..21000]),
+            PatchOptions::default().matching(MatchOptions::new().effort(Effort::Optimal)),
+        ] {
+            let mut patch = Vec::new();
+            write_full_patch(&index, &new, options, &mut patch).unwrap();
+
+            let mut seekable = Cursor::new(Vec::new());
+            write_full_patch_seekable(&index, &new, options, &mut seekable).unwrap();
+            assert!(seekable.into_inner() == patch);
+        }
+    }
+
+    #[test]
+    fn test_sequential_old_access() {
+        let old = noise(11, 100000);
+        let mut new = old[60000..].to_vec();
+        new.extend_from_slice(&old[..60000]);
+        for i in (0..new.len()).step_by(7000) {
+            new[i] ^= 0x55;
+        }
+
+        let index = Index::compute(old.clone());
+        let options = PatchOptions::default().sequential_old_access(true);
+        let mut patch = Vec::new();
+        write_full_patch(&index, &new, &options, &mut patch).unwrap();
+        assert!(Header::read(&patch).unwrap().sequential_old_access);
+
+        let mut seekable = Cursor::new(Vec::new());
+        write_full_patch_seekable(&index, &new, &options, &mut seekable).unwrap();
+        assert!(seekable.into_inner() == patch);
+
+        // `&[u8]` can't seek.
+        let mut out = Vec::new();
+        apply_patch_sequential(&patch, &old[..], &mut out).unwrap();
+        assert!(out == new);
+
+        let mut out = Vec::new();
+        apply_patch(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert!(out == new);
+
+        let mut plain = Vec::new();
+        write_full_patch(&index, &new, &PatchOptions::default(), &mut plain).unwrap();
+        assert!(!Header::read(&plain).unwrap().sequential_old_access);
+        assert!(apply_patch_sequential(&plain, &old[..], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_xor_delta_mode() {
+        use patch::{apply_any, transcode};
+
+        let old = (0..50000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<u8>>();
+        let mut new = old[20000..].to_vec();
+        new.extend_from_slice(&old[..20000]);
+        for i in (0..new.len()).step_by(100) {
+            new[i] = new[i].wrapping_add(3);
+        }
+
+        let index = Index::compute(old.clone());
+        let options = PatchOptions::default().delta_mode(DeltaMode::Xor);
+        let mut patch = Vec::new();
+        write_full_patch(&index, &new, &options, &mut patch).unwrap();
+
+        let header = Header::read(&patch).unwrap();
+        assert_eq!(header.delta_mode, DeltaMode::Xor);
+        assert_eq!(header.magic(), BsdiffCodecs::MAGIC);
+
+        let mut seekable = Cursor::new(Vec::new());
+        write_full_patch_seekable(&index, &new, &options, &mut seekable).unwrap();
+        assert!(seekable.into_inner() == patch);
+
+        let mut pipelined = Vec::new();
+        write_full_patch(&index, &new, &options.clone().pipeline(Pipeline::default()), &mut pipelined).unwrap();
+        assert!(pipelined == patch);
+
+        let mut out = Vec::new();
+        apply_patch(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert!(out == new);
+
+        let mut out = Vec::new();
+        apply_patch_from_slice(&patch, &old, &mut out).unwrap();
+        assert!(out == new);
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert!(out == new);
+
+        // The deltas differ from subtraction's wherever a byte changed.
+        let mut plain = Vec::new();
+        write_full_patch(&index, &new, &PatchOptions::default(), &mut plain).unwrap();
+        assert_eq!(Header::read(&plain).unwrap().delta_mode, DeltaMode::Subtract);
+        assert!(plain[CLASSIC_HEADER_SIZE..] != patch[CODECS_HEADER_SIZE..]);
+
+        // Steps are subtraction deltas, and can't be had without the old
+        // file.
+        let err = transcode(&patch, Vec::new(), Engine::Bsdiff, &PatchOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = transcode(&plain, Vec::new(), Engine::Bsdiff, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_large_identity_patch() {
+        let len = IDENTITY_SCAN_MAX + 1;
+        let old = (0..len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let patch = generate_identity_patch(len);
+
+        assert_eq!(identity_len(&patch, DecodeMode::Strict).unwrap(), Some(len));
+        assert_eq!(identity_len_within(&patch, DecodeMode::Strict, len - 1).unwrap(), None);
+
+        // Applied as an ordinary patch, without the check.
+        let mut out = Vec::new();
+        apply_patch(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert!(out == old);
+    }
+
+    #[test]
+    fn test_read_steps_splits_zero_runs() {
+        use patch::Step;
+
+        let mut delta = vec![1u8; 10];
+        delta.extend(vec![0u8; 100000]);
+        delta.extend(vec![2u8; 10]);
+        delta.extend(vec![0u8; 1000]);
+        let len = delta.len() as u64;
+        let steps = vec![Step::Delta { old_offset: 5, delta: delta }];
+
+        let mut patch = Vec::new();
+        write_steps(steps.iter().map(Ok), len, &PatchOptions::default(), &mut patch).unwrap();
+        let steps = read_steps(&patch).unwrap();
+        assert_eq!(steps.len(), 3);
+        assert!(steps[0] == Step::Delta { old_offset: 5, delta: vec![1; 10] });
+        assert!(steps[1] == Step::Copy { old_offset: 15, len: 100000 });
+        match steps[2] {
+            Step::Delta { old_offset: 100015, ref delta } => assert_eq!(delta.len(), 1010),
+            ref step => panic!("unexpected step {:?}", step),
+        }
+    }
+
+    #[test]
+    fn test_diff_and_patch_files() {
+        use std::fs;
+
+        let dir = ::std::env::temp_dir().join(format!("rsdiff-test-diff-files-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (old_path, new_path, patch_path, out_path) =
+            (dir.join("old"), dir.join("new"), dir.join("patch"), dir.join("out"));
+
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        fs::write(&old_path, &old[..]).unwrap();
+        fs::write(&new_path, &new[..]).unwrap();
+
+        let options = PatchOptions::new();
+        #[cfg(feature = "sha1")]
+        let options = options.source_digest(true);
+        diff_files(&old_path, &new_path, &patch_path, &options).unwrap();
+        patch_files(&old_path, &patch_path, &out_path, &ApplyOptions::new()).unwrap();
+        assert_eq!(fs::read(&out_path).unwrap(), &new[..]);
+
+        // An empty file can't be mapped, and is read instead.
+        fs::write(&new_path, b"").unwrap();
+        diff_files(&old_path, &new_path, &patch_path, &options).unwrap();
+        patch_files(&old_path, &patch_path, &out_path, &ApplyOptions::new()).unwrap();
+        assert_eq!(fs::read(&out_path).unwrap(), b"");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+
+        // A bad patch leaves nothing behind.
+        fs::write(&patch_path, b"not a patch").unwrap();
+        assert!(patch_files(&old_path, &patch_path, dir.join("out2"), &ApplyOptions::new()).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
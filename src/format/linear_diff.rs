@@ -1,22 +1,214 @@
-use std::io::{Read, Write, Seek};
+use std::io::{Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::ops::Range;
+use std::borrow::Borrow;
 use std::io;
 
+#[cfg(feature = "zstd")]
 use zstd;
-use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt, ByteOrder};
 
 use diff::{
-    Index,
-    write_delta,
-    write_zeros,
+    Matcher,
+    write_fill,
+    DeltaEncoder,
+    Subtract,
+    Match,
     MatchIter,
+    MatchOptions,
 };
 
 use patch::{
-    read_paired_bufs,
+    add_delta,
+    ApplyError,
+    ApplyObserver,
+    CommandEvent,
+    add_delta_from_slice,
+    check_old_range,
+    old_len,
+    old_slice,
+    read_paired_bufs_in,
     read_size_from,
+    read_size_from_in,
+    BufferConfig,
+    DecodeMode,
+    EmptyCommands,
+    Scratch,
+    Step,
+    read_step_bytes,
 };
 
+use format::PatchFormat;
+use format::wire;
+use format::sink::{CountingReader, CountingWriter};
+
+/// An uncompressed format where each command carries an absolute old-file
+/// offset, immediately followed by its own delta and extra bytes.
+pub struct LinearDiff;
+
+impl PatchFormat for LinearDiff {
+    const MAGIC: &'static [u8] = b"LINDIF02";
+    const NAME: &'static str = "linear_diff";
+
+    fn is_match(patch: &[u8]) -> bool {
+        patch.starts_with(LinearDiff::MAGIC) || patch.starts_with(V1_MAGIC)
+    }
+
+    fn apply_patch<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        apply_patch(patch, old, new)
+    }
+
+    fn write_identity_patch<W: Write>(size: u64, mut patch: W) -> io::Result<()> {
+        patch.write_all(LinearDiff::MAGIC)?;
+        write_identity_body(size, patch)
+    }
+
+    fn write_idempotent_patch<W: Write>(desired_output: &[u8], mut patch: W) -> io::Result<()> {
+        patch.write_all(LinearDiff::MAGIC)?;
+        write_idempotent_body(desired_output, patch)
+    }
+}
+
+/// `LinearDiff`, with everything after the magic compressed with zstd.
+#[cfg(feature = "zstd")]
+pub struct LinearDiffZstd;
+
+#[cfg(feature = "zstd")]
+impl PatchFormat for LinearDiffZstd {
+    const MAGIC: &'static [u8] = b"LINDIFZ2";
+    const NAME: &'static str = "linear_diff+zstd";
+
+    fn is_match(patch: &[u8]) -> bool {
+        patch.starts_with(LinearDiffZstd::MAGIC) || patch.starts_with(V1_ZSTD_MAGIC)
+    }
+
+    fn apply_patch<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        apply_compressed_patch(patch, old, new)
+    }
+
+    fn write_identity_patch<W: Write>(size: u64, mut patch: W) -> io::Result<()> {
+        patch.write_all(LinearDiffZstd::MAGIC)?;
+        let mut encoder = zstd::stream::Encoder::new(patch, DEFAULT_ZSTD_LEVEL)?;
+        write_identity_body(size, &mut encoder)?;
+        encoder.finish().map(|_| ())
+    }
+
+    fn write_idempotent_patch<W: Write>(desired_output: &[u8], mut patch: W) -> io::Result<()> {
+        patch.write_all(LinearDiffZstd::MAGIC)?;
+        let mut encoder = zstd::stream::Encoder::new(patch, DEFAULT_ZSTD_LEVEL)?;
+        write_idempotent_body(desired_output, &mut encoder)?;
+        encoder.finish().map(|_| ())
+    }
+}
+
+/// The magics from before copy commands were added.  Patches written with
+/// them are still read, but a command with `COPY_FLAG` set is an error there,
+/// as it always was, rather than a copy.
+const V1_MAGIC: &'static [u8] = b"LINDIF01";
+const V1_ZSTD_MAGIC: &'static [u8] = b"LINDIFZ1";
+
+/// Which revision of the format a patch's magic says it's in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    /// No magic at all, as the first releases wrote; read with
+    /// `apply_headerless_patch`.  Otherwise like `V1`.
+    V0,
+    /// `LINDIF01`: no copy commands.
+    V1,
+    /// `LINDIF02`: adds copy commands.
+    V2,
+}
+
+/// Whether `patch` is a `LinearDiffZstd` patch, whether or not this build
+/// can read it.
+pub(crate) fn is_compressed(patch: &[u8]) -> bool {
+    patch.starts_with(b"LINDIFZ2") || patch.starts_with(V1_ZSTD_MAGIC)
+}
+
+/// Either a `LinearDiff` or (with the `zstd` feature) a `LinearDiffZstd`
+/// patch.
+pub fn is_match(patch: &[u8]) -> bool {
+    #[cfg(feature = "zstd")]
+    {
+        if LinearDiffZstd::is_match(patch) {
+            return true;
+        }
+    }
+    LinearDiff::is_match(patch)
+}
+
+#[cfg(feature = "zstd")]
+const DEFAULT_ZSTD_LEVEL: i32 = 19;
+
+fn write_identity_body<W: Write>(size: u64, mut patch: W) -> io::Result<()> {
+    if size == 0 {
+        return Ok(());
+    }
+
+    Command {
+        old_offset: 0,
+        bytewise_add_size: size,
+        extra_append_size: 0,
+    }.write_to(&mut patch)?;
+
+    Subtract.write_zeros(&mut patch, size)
+}
+
+fn write_idempotent_body<W: Write>(desired_output: &[u8], mut patch: W) -> io::Result<()> {
+    if desired_output.is_empty() {
+        return Ok(());
+    }
+
+    Command {
+        old_offset: 0,
+        bytewise_add_size: 0,
+        extra_append_size: desired_output.len() as u64,
+    }.write_to(&mut patch)?;
+
+    patch.write_all(desired_output)
+}
+
+/// Reads the magic, which must be `current` or the older `v1`.
+fn read_magic<R: Read>(mut patch: R, current: &[u8], v1: &[u8]) -> io::Result<Version> {
+    let mut magic = [0u8; 8];
+    patch.read_exact(&mut magic)?;
+
+    if &magic[..] == current {
+        Ok(Version::V2)
+    } else if &magic[..] == v1 {
+        Ok(Version::V1)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "Bad linear_diff magic"))
+    }
+}
+
+/// `old_offset` value marking a fill command: `extra_append_size` copies of
+/// the byte stored in `bytewise_add_size`, with no data following.
+const FILL_OFFSET: u64 = !0;
+
+/// Runs of a single repeated byte at least this long are written as fill
+/// commands rather than as delta or extra bytes.
+const MIN_FILL_RUN: usize = 64;
+
+/// Bit set in `bytewise_add_size` marking a copy command: the rest is the
+/// length of a stretch of the old file that's carried over unchanged, with
+/// no delta bytes following.  (Extra bytes follow as usual.)  Only in
+/// `Version::V2` patches.
+const COPY_FLAG: u64 = 1 << 63;
+
+/// Identical stretches at least this long, inside a match that isn't
+/// identical throughout, get a copy command of their own.  Shorter ones stay
+/// in the delta as zeros, which is cheaper than two more commands.
+const MIN_COPY_RUN: usize = 64;
+
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Command {
     pub old_offset: u64,
     pub bytewise_add_size: u64,
@@ -24,121 +216,946 @@ pub struct Command {
 }
 
 impl Command {
-    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
-        let mut buf = [0u8; 8*3];
+    pub fn fill(byte: u8, len: u64) -> Command {
+        Command {
+            old_offset: FILL_OFFSET,
+            bytewise_add_size: byte as u64,
+            extra_append_size: len,
+        }
+    }
 
-        LittleEndian::write_u64(&mut buf[0..8], self.old_offset);
-        LittleEndian::write_u64(&mut buf[8..16], self.bytewise_add_size);
-        LittleEndian::write_u64(&mut buf[16..24], self.extra_append_size);
+    pub fn copy(old_offset: u64, len: u64, extra_append_size: u64) -> Command {
+        Command {
+            old_offset: old_offset,
+            bytewise_add_size: len | COPY_FLAG,
+            extra_append_size: extra_append_size,
+        }
+    }
 
-        writer.write_all(&buf)
+    /// The repeated byte, if this is a fill command.
+    pub fn fill_byte(&self) -> Option<u8> {
+        if self.old_offset == FILL_OFFSET {
+            Some(self.bytewise_add_size as u8)
+        } else {
+            None
+        }
+    }
+
+    /// How many bytes are copied unchanged from the old file, if this is a
+    /// copy command.
+    pub fn copy_len(&self) -> Option<u64> {
+        if self.fill_byte().is_none() && self.bytewise_add_size & COPY_FLAG != 0 {
+            Some(self.bytewise_add_size & !COPY_FLAG)
+        } else {
+            None
+        }
+    }
+
+    /// Bytes of delta that follow the command in the patch.
+    pub fn delta_len(&self) -> u64 {
+        if self.fill_byte().is_some() || self.copy_len().is_some() {
+            0
+        } else {
+            self.bytewise_add_size
+        }
+    }
+
+    /// Bytes of data (delta, then extra) that follow the command.  Fails if
+    /// that doesn't fit in a `u64`.
+    pub fn body_len(&self) -> io::Result<u64> {
+        match self.fill_byte() {
+            Some(_) => Ok(0),
+            None => checked_len(self.delta_len().checked_add(self.extra_append_size)),
+        }
     }
 
-    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Option<Command>> {
+    /// Bytes of output the command produces.  Fails if that doesn't fit in a
+    /// `u64`.
+    pub fn output_len(&self) -> io::Result<u64> {
+        checked_len(match (self.fill_byte(), self.copy_len()) {
+            (Some(_), _) => Some(self.extra_append_size),
+            (None, Some(len)) => len.checked_add(self.extra_append_size),
+            (None, None) => self.bytewise_add_size.checked_add(self.extra_append_size),
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
         let mut buf = [0u8; 8*3];
+        wire::write_u64s(&mut buf, &[self.old_offset, self.bytewise_add_size, self.extra_append_size]);
+        writer.write_all(&buf)
+    }
 
-        let mut p = 0;
-        while p < buf.len() {
-            // DANGER!!!!
-            // If we get `0` from the underlying stream, we assume EOF.
-            // Technically, this may not be true for things like network sockets.
-            // This code could do weird things in such an environment.
-            match reader.read(&mut buf[p..])? {
-                0 => return Ok(None),
-                size => p += size,
-            }
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Option<Command>> {
+        let mut buf = [0u8; 8*3];
+        if !wire::read_record(reader, &mut buf, "linear_diff command")? {
+            return Ok(None);
         }
 
-        let old_offset = LittleEndian::read_u64(&mut buf[0..8]);
-        let bytewise_add_size = LittleEndian::read_u64(&mut buf[8..16]);
-        let extra_append_size = LittleEndian::read_u64(&mut buf[16..24]);
+        let mut fields = [0u64; 3];
+        wire::read_u64s(&buf, &mut fields);
 
         Ok(Some(Command {
-            old_offset: old_offset,
-            bytewise_add_size: bytewise_add_size,
-            extra_append_size: extra_append_size,
+            old_offset: fields[0],
+            bytewise_add_size: fields[1],
+            extra_append_size: fields[2],
         }))
     }
+
+    /// Fails on copy commands in a `version` that doesn't have them, and on
+    /// lengths that overflow.  `empty` checks for commands that produce no
+    /// output.
+    fn check(&self, empty: &mut EmptyCommands, version: Version) -> io::Result<()> {
+        if version != Version::V2 && self.copy_len().is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("linear_diff copy command in a {} patch", match version {
+                    Version::V0 => "header-less",
+                    _ => "LINDIF01",
+                })));
+        }
+
+        self.body_len()?;
+        empty.check(self.output_len()?)
+    }
+}
+
+fn checked_len(len: Option<u64>) -> io::Result<u64> {
+    len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "linear_diff command length overflows"))
 }
 
-pub fn generate_full_patch<PatchW: Write>(old: &Index, new: &[u8], mut patch: PatchW) -> io::Result<()> {
-    // let mut patch = zstd::stream::Encoder::new(patch, 19).unwrap();
+pub fn generate_full_patch<M: Matcher, PatchW: Write>(old: &M, new: &[u8], patch: PatchW) -> io::Result<()> {
+    generate_full_patch_with(old, new, MatchOptions::default(), patch)
+}
+
+/// Like `generate_full_patch`, finding matches as `matching` says.
+pub fn generate_full_patch_with<M: Matcher, PatchW: Write>(old: &M, new: &[u8], matching: MatchOptions, mut patch: PatchW)
+    -> io::Result<()>
+{
+    patch.write_all(LinearDiff::MAGIC)?;
+    write_body(old.data(), new, MatchIter::with_options(old, new, matching), Subtract, patch)
+}
+
+/// Writes a patch from `old` to `new` made of `matches`, which must cover
+/// `new` in order, instead of searching for its own; see
+/// `bsdiff::write_patch_for_matches`.
+pub fn generate_patch_for_matches<I, PatchW>(old: &[u8], new: &[u8], matches: I, mut patch: PatchW) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Match>,
+        PatchW: Write
+{
+    patch.write_all(LinearDiff::MAGIC)?;
+    write_body(old, new, matches.into_iter(), Subtract, patch)
+}
+
+/// Like `generate_full_patch`, but compresses the patch body with zstd at the
+/// given `level`.
+#[cfg(feature = "zstd")]
+pub fn generate_compressed_patch<M: Matcher, PatchW: Write>(old: &M, new: &[u8], level: i32, patch: PatchW) -> io::Result<()> {
+    generate_compressed_patch_with(old, new, level, MatchOptions::default(), patch)
+}
+
+/// Like `generate_compressed_patch`, finding matches as `matching` says.
+#[cfg(feature = "zstd")]
+pub fn generate_compressed_patch_with<M: Matcher, PatchW: Write>(
+    old: &M,
+    new: &[u8],
+    level: i32,
+    matching: MatchOptions,
+    mut patch: PatchW
+) -> io::Result<()> {
+    patch.write_all(LinearDiffZstd::MAGIC)?;
+
+    let mut encoder = zstd::stream::Encoder::new(patch, level)?;
+    write_body(old.data(), new, MatchIter::with_options(old, new, matching), Subtract, &mut encoder)?;
+    encoder.finish().map(|_| ())
+}
+
+/// Like `generate_patch_for_matches`, compressing the body with zstd at the
+/// given `level`.
+#[cfg(feature = "zstd")]
+pub fn generate_compressed_patch_for_matches<I, PatchW>(
+    old: &[u8],
+    new: &[u8],
+    matches: I,
+    level: i32,
+    mut patch: PatchW
+) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Match>,
+        PatchW: Write
+{
+    patch.write_all(LinearDiffZstd::MAGIC)?;
+
+    let mut encoder = zstd::stream::Encoder::new(patch, level)?;
+    write_body(old, new, matches.into_iter(), Subtract, &mut encoder)?;
+    encoder.finish().map(|_| ())
+}
+
+/// Writes the commands for `matches`, with deltas encoded by `encoder`.  The
+/// format has nowhere to record an encoding, so every patch is written with
+/// `Subtract`, which is what `apply_patch` decodes.
+fn write_body<I, E, PatchW>(old: &[u8], new: &[u8], matches: I, encoder: E, patch: PatchW) -> io::Result<()>
+    where
+        I: Iterator<Item = Match>,
+        E: DeltaEncoder,
+        PatchW: Write
+{
+    enter_span!("linear_diff::write_body");
+
+    let mut patch = CountingWriter::new(patch);
+    let mut sizes = BodySizes::default();
 
     let mut i = 0;
 
     let mut k = 0;
 
-    for m in MatchIter::from(old, new) {
+    for m in matches {
 
         if k % 1024 == 0 {
-            println!("{} / {} ({}%)", i, new.len(), i * 100 / new.len());
+            debug_event!("{} / {} ({}%)", i, new.len(), i * 100 / new.len().max(1));
         }
 
         k += 1;
 
         let mm = m.matched;
 
-        let cmd = Command {
-            old_offset: mm.old_offset as u64,
-            bytewise_add_size: mm.len() as u64,
-            extra_append_size: m.unmatched_suffix as u64,
+        let extra_begin = i + mm.len();
+        let extra_end = extra_begin + m.unmatched_suffix;
+
+        let delta_runs = split_fills(&new[i .. extra_begin]);
+        let extra_runs = split_fills(&new[extra_begin .. extra_end]);
+
+        if delta_runs.iter().chain(&extra_runs).all(|&(_, fill)| fill.is_none()) {
+            write_delta_command(&mut patch, &mut sizes, &encoder, old, mm.old_offset,
+                &new[i .. extra_begin], &new[extra_begin .. extra_end])?;
+        } else {
+            for (r, fill) in delta_runs {
+                if let Some(byte) = fill {
+                    let cmd = Command::fill(byte, r.len() as u64);
+                    sizes.add(&cmd)?;
+                    cmd.write_to(&mut patch)?;
+                    continue;
+                }
+
+                write_delta_command(&mut patch, &mut sizes, &encoder, old, mm.old_offset + r.start,
+                    &new[i + r.start .. i + r.end], &[])?;
+            }
+
+            for (r, fill) in extra_runs {
+                if let Some(byte) = fill {
+                    let cmd = Command::fill(byte, r.len() as u64);
+                    sizes.add(&cmd)?;
+                    cmd.write_to(&mut patch)?;
+                    continue;
+                }
+
+                let cmd = Command {
+                    old_offset: 0,
+                    bytewise_add_size: 0,
+                    extra_append_size: r.len() as u64,
+                };
+                sizes.add(&cmd)?;
+                cmd.write_to(&mut patch)?;
+
+                patch.write_all(&new[extra_begin + r.start .. extra_begin + r.end])?;
+            }
+        }
+
+        i = extra_end;
+    }
+
+    info_event!("wrote {} commands for {} bytes of new data", k, new.len());
+
+    sizes.check(patch.count(), new.len() as u64)
+}
+
+/// Writes `new` as a delta against the same number of bytes of `old` from
+/// `old_offset`, encoded by `encoder`, followed by `extra`.  Where the two
+/// are identical (all of it, or a long enough stretch), the delta's zeros are
+/// left out in favour of a copy command.
+fn write_delta_command<E, W>(
+    mut patch: W,
+    sizes: &mut BodySizes,
+    encoder: &E,
+    old: &[u8],
+    old_offset: usize,
+    new: &[u8],
+    extra: &[u8]
+) -> io::Result<()>
+    where
+        E: DeltaEncoder,
+        W: Write
+{
+    let old = &old[old_offset .. old_offset + new.len()];
+
+    // Tagged with whether each one is a copy.
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < new.len() {
+        if old[i] != new[i] {
+            i += 1;
+            continue;
+        }
+
+        let mut j = i + 1;
+        while j < new.len() && old[j] == new[j] {
+            j += 1;
+        }
+
+        if j - i >= MIN_COPY_RUN || (i == 0 && j == new.len()) {
+            if start < i {
+                segments.push((start .. i, false));
+            }
+            segments.push((i .. j, true));
+            start = j;
+        }
+
+        i = j;
+    }
+
+    if start < new.len() || segments.is_empty() {
+        segments.push((start .. new.len(), false));
+    }
+
+    // The extra bytes go with the last command.
+    let last = segments.len() - 1;
+    for (n, (r, copy)) in segments.into_iter().enumerate() {
+        let extra_len = if n == last { extra.len() as u64 } else { 0 };
+
+        let cmd = if copy {
+            Command::copy((old_offset + r.start) as u64, r.len() as u64, extra_len)
+        } else {
+            Command {
+                old_offset: (old_offset + r.start) as u64,
+                bytewise_add_size: r.len() as u64,
+                extra_append_size: extra_len,
+            }
         };
 
-        println!("write cmd: {:?}", cmd);
+        debug_event!("write cmd: {:?}", cmd);
 
+        sizes.add(&cmd)?;
         cmd.write_to(&mut patch)?;
 
-        write_delta(
-            &mut patch,
-            &old.data[mm.old_offset .. mm.old_offset + mm.len()],
-            &new[i .. i + mm.len()])?;
+        if !copy {
+            encoder.write_delta(&mut patch, &old[r.clone()], &new[r])?;
+        }
+    }
 
-        let extra_begin = i + mm.len();
-        let extra_end = extra_begin + m.unmatched_suffix;
+    patch.write_all(extra)
+}
+
+/// Decodes a `LinearDiff` or `LinearDiffZstd` patch into `Step`s, for
+/// `patch::transcode`.
+pub(crate) fn read_steps(patch: &[u8]) -> io::Result<Vec<Step>> {
+    #[cfg(feature = "zstd")]
+    {
+        if LinearDiffZstd::is_match(patch) {
+            let version = read_magic(patch, LinearDiffZstd::MAGIC, V1_ZSTD_MAGIC)?;
+            return read_body_steps(zstd::stream::Decoder::new(&patch[LinearDiffZstd::MAGIC.len()..])?, version);
+        }
+    }
+
+    let version = read_magic(patch, LinearDiff::MAGIC, V1_MAGIC)?;
+    read_body_steps(&patch[LinearDiff::MAGIC.len()..], version)
+}
 
-        patch.write_all(&new[extra_begin .. extra_end]);
+fn read_body_steps<R: Read>(mut body: R, version: Version) -> io::Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    let mut empty = EmptyCommands::new(DecodeMode::Strict, LinearDiff::NAME);
 
-        i = extra_end;
+    while let Some(cmd) = Command::read_from(&mut body)? {
+        cmd.check(&mut empty, version)?;
+
+        if let Some(byte) = cmd.fill_byte() {
+            steps.push(Step::Fill { byte: byte, len: cmd.extra_append_size });
+            continue;
+        }
+
+        if let Some(len) = cmd.copy_len() {
+            steps.push(Step::Copy { old_offset: cmd.old_offset, len: len });
+        } else if cmd.delta_len() > 0 {
+            let delta = read_step_bytes(&mut body, cmd.delta_len(), "delta")?;
+            steps.push(Step::Delta { old_offset: cmd.old_offset, delta: delta });
+        }
+
+        if cmd.extra_append_size > 0 {
+            steps.push(Step::Extra(read_step_bytes(&mut body, cmd.extra_append_size, "extra data")?));
+        }
     }
 
-    // patch.finish();
+    Ok(steps)
+}
 
-    Ok(())
+/// Rewrites a `LinearDiffZstd` patch with its body compressed at `level`,
+/// keeping its magic.
+#[cfg(feature = "zstd")]
+pub(crate) fn recompress<W: Write>(patch: &[u8], level: i32, mut writer: W) -> io::Result<()> {
+    read_magic(patch, LinearDiffZstd::MAGIC, V1_ZSTD_MAGIC)?;
+    writer.write_all(&patch[..LinearDiffZstd::MAGIC.len()])?;
+
+    let mut body = zstd::stream::Decoder::new(&patch[LinearDiffZstd::MAGIC.len()..])?;
+    let mut encoder = zstd::stream::Encoder::new(writer, level)?;
+    io::copy(&mut body, &mut encoder)?;
+    encoder.finish().map(|_| ())
+}
+
+/// Writes `steps`, which add up to `new_len` bytes of output, as an
+/// uncompressed `LinearDiff` patch.  Like `bsdiff::write_steps`, the steps
+/// are taken one at a time.
+pub(crate) fn write_steps<I, B, W>(steps: I, new_len: u64, mut patch: W) -> io::Result<()>
+    where
+        I: Iterator<Item = io::Result<B>>,
+        B: Borrow<Step>,
+        W: Write
+{
+    patch.write_all(LinearDiff::MAGIC)?;
+    write_body_steps(steps, new_len, patch)
 }
 
-pub fn apply_patch<PatchR: Read, OldRS: Read+Seek, NewW: Write>(mut patch: PatchR, mut old: OldRS, mut new: NewW)
+/// Writes `steps` as a `LinearDiffZstd` patch compressed at `level`.
+#[cfg(feature = "zstd")]
+pub(crate) fn write_compressed_steps<I, B, W>(steps: I, new_len: u64, level: i32, mut patch: W) -> io::Result<()>
+    where
+        I: Iterator<Item = io::Result<B>>,
+        B: Borrow<Step>,
+        W: Write
+{
+    patch.write_all(LinearDiffZstd::MAGIC)?;
+
+    let mut encoder = zstd::stream::Encoder::new(patch, level)?;
+    write_body_steps(steps, new_len, &mut encoder)?;
+    encoder.finish().map(|_| ())
+}
+
+/// Each delta or copy takes the extra bytes right after it along in the
+/// same command, so it's held back until the next step shows whether there
+/// are any.
+fn write_body_steps<I, B, W>(steps: I, new_len: u64, patch: W) -> io::Result<()>
+    where
+        I: Iterator<Item = io::Result<B>>,
+        B: Borrow<Step>,
+        W: Write
+{
+    let mut patch = CountingWriter::new(patch);
+    let mut sizes = BodySizes::default();
+    let mut held: Option<B> = None;
+
+    for step in steps {
+        let step = step?;
+        let (extra, fill) = match *step.borrow() {
+            Step::Extra(ref extra) => (Some(&extra[..]), None),
+            Step::Fill { byte, len } => (None, Some(Command::fill(byte, len))),
+            _ => (None, None),
+        };
+
+        if let Some(extra) = extra {
+            write_step(&mut patch, &mut sizes, held.take().as_ref().map(Borrow::borrow), extra)?;
+            continue;
+        }
+        if let Some(held) = held.take() {
+            write_step(&mut patch, &mut sizes, Some(held.borrow()), &[])?;
+        }
+        match fill {
+            Some(cmd) => {
+                sizes.add(&cmd)?;
+                cmd.write_to(&mut patch)?;
+            }
+            None => held = Some(step),
+        }
+    }
+    if let Some(held) = held {
+        write_step(&mut patch, &mut sizes, Some(held.borrow()), &[])?;
+    }
+
+    sizes.check(patch.count(), new_len)
+}
+
+/// Writes one command for `step`, a delta or copy (or nothing), followed by
+/// `extra`.
+fn write_step<W: Write>(mut patch: W, sizes: &mut BodySizes, step: Option<&Step>, extra: &[u8]) -> io::Result<()> {
+    let extra_len = extra.len() as u64;
+    let (cmd, delta) = match step {
+        Some(&Step::Delta { old_offset, ref delta }) =>
+            (Command { old_offset: old_offset, bytewise_add_size: delta.len() as u64, extra_append_size: extra_len }, &delta[..]),
+        Some(&Step::Copy { old_offset, len }) => (Command::copy(old_offset, len, extra_len), &[][..]),
+        _ => (Command { old_offset: 0, bytewise_add_size: 0, extra_append_size: extra_len }, &[][..]),
+    };
+
+    sizes.add(&cmd)?;
+    cmd.write_to(&mut patch)?;
+    patch.write_all(delta)?;
+    patch.write_all(extra)
+}
+
+/// What the commands written so far add up to, for cross-checking against
+/// what actually went into the patch.
+#[derive(Default)]
+struct BodySizes {
+    patch: u64,
+    output: u64,
+}
+
+impl BodySizes {
+    fn add(&mut self, cmd: &Command) -> io::Result<()> {
+        self.patch += 8*3 + cmd.body_len()?;
+        self.output = checked_len(self.output.checked_add(cmd.output_len()?))?;
+        Ok(())
+    }
+
+    fn check(&self, written: u64, new_len: u64) -> io::Result<()> {
+        if written != self.patch {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "wrote {} bytes of linear_diff body, but the commands call for {}", written, self.patch)));
+        }
+
+        if self.output != new_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "linear_diff commands produce {} bytes, but the new file is {}", self.output, new_len)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits `buf` into runs of a single repeated byte at least `MIN_FILL_RUN`
+/// long (tagged with that byte) and the literal stretches between them.
+fn split_fills(buf: &[u8]) -> Vec<(Range<usize>, Option<u8>)> {
+    let mut runs = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i < buf.len() {
+        let byte = buf[i];
+        let mut j = i + 1;
+        while j < buf.len() && buf[j] == byte {
+            j += 1;
+        }
+
+        if j - i >= MIN_FILL_RUN {
+            if literal_start < i {
+                runs.push((literal_start .. i, None));
+            }
+            runs.push((i .. j, Some(byte)));
+            literal_start = j;
+        }
+
+        i = j;
+    }
+
+    if literal_start < buf.len() {
+        runs.push((literal_start .. buf.len(), None));
+    }
+
+    runs
+}
+
+/// Where the applier's output goes; lets fill commands be written either as
+/// plain bytes or as holes in a sparse file.
+trait Output: Write {
+    fn fill(&mut self, byte: u8, count: u64) -> io::Result<()> {
+        write_fill(self, byte, count)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl<W: Write> Output for BufWriter<W> {}
+
+/// Turns zero fills into seeks, so the output ends up as a sparse file on
+/// filesystems that support it.
+struct SparseOutput<W> {
+    inner: W,
+    hole: u64,
+}
+
+impl<W: Write+Seek> SparseOutput<W> {
+    fn skip_hole(&mut self) -> io::Result<()> {
+        if self.hole > 0 {
+            self.inner.seek(SeekFrom::Current(self.hole as i64))?;
+            self.hole = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write+Seek> Write for SparseOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.skip_hole()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write+Seek> Output for SparseOutput<W> {
+    fn fill(&mut self, byte: u8, count: u64) -> io::Result<()> {
+        if byte == 0 {
+            self.hole += count;
+            Ok(())
+        } else {
+            self.skip_hole()?;
+            write_fill(&mut self.inner, byte, count)
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Seeking alone doesn't extend the file, so a trailing hole needs its
+        // last byte written out.
+        if self.hole > 0 {
+            self.inner.seek(SeekFrom::Current(self.hole as i64 - 1))?;
+            self.inner.write_all(&[0])?;
+            self.hole = 0;
+        }
+        self.inner.flush()
+    }
+}
+
+pub fn apply_patch<PatchR: Read, OldRS: Read+Seek, NewW: Write>(patch: PatchR, old: OldRS, new: NewW)
+ -> io::Result<()>
+{
+    apply_patch_with_buffers(patch, old, new, &BufferConfig::default())
+}
+
+/// Like `apply_patch`, but with scratch buffers sized by `config`.
+pub fn apply_patch_with_buffers<PatchR: Read, OldRS: Read+Seek, NewW: Write>(
+    patch: PatchR,
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig
+) -> io::Result<()>
+{
+    apply_patch_with_mode(patch, old, new, config, LinearDiff::DECODE_MODE)
+}
+
+/// Like `apply_patch_with_buffers`, decoding in `mode`.
+pub fn apply_patch_with_mode<PatchR: Read, OldRS: Read+Seek, NewW: Write>(
+    patch: PatchR,
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: DecodeMode
+) -> io::Result<()>
+{
+    apply_patch_observed(patch, old, new, config, mode, &mut ())
+}
+
+/// Like `apply_patch_with_mode`, reporting each command to `observer` once
+/// it's been applied.
+pub fn apply_patch_observed<PatchR: Read, OldRS: Read+Seek, NewW: Write>(
+    mut patch: PatchR,
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: DecodeMode,
+    observer: &mut ApplyObserver
+) -> io::Result<()>
+{
+    let version = read_magic(&mut patch, LinearDiff::MAGIC, V1_MAGIC)?;
+    let new = BufWriter::with_capacity(config.output_buffer_size, new);
+    let patch = BufReader::with_capacity(config.buffer_size, patch);
+    apply_body(patch, Some(LinearDiff::MAGIC.len() as u64), old, new, config, mode, version, observer)
+}
+
+/// Like `apply_patch`, but runs of zeros are skipped over with seeks instead
+/// of written, leaving holes in `new`.  `new` should start out empty.
+pub fn apply_patch_sparse<PatchR: Read, OldRS: Read+Seek, NewW: Write+Seek>(mut patch: PatchR, old: OldRS, new: NewW)
  -> io::Result<()>
 {
-    // let mut patch = zstd::Decoder::new(patch).unwrap();
+    let config = BufferConfig::default();
+    let version = read_magic(&mut patch, LinearDiff::MAGIC, V1_MAGIC)?;
+    let new = SparseOutput {
+        inner: BufWriter::with_capacity(config.output_buffer_size, new),
+        hole: 0,
+    };
+    let patch = BufReader::with_capacity(config.buffer_size, patch);
+    apply_body(patch, Some(LinearDiff::MAGIC.len() as u64), old, new, &config, LinearDiff::DECODE_MODE, version, &mut ())
+}
 
-    while let Some(cmd) = Command::read_from(&mut patch)? {
-        old.seek(io::SeekFrom::Start(cmd.old_offset))?;
+/// Applies a linear_diff patch from before the format had a magic: the
+/// uncompressed commands start at the first byte, and there are no copy
+/// commands.  Nothing marks such a patch as one, so `patch::apply_any` never
+/// tries this; it's for callers who know their patches are that old.  Its
+/// commands are decoded leniently, as they were then.
+pub fn apply_headerless_patch<PatchR: Read, OldRS: Read+Seek, NewW: Write>(patch: PatchR, old: OldRS, new: NewW)
+ -> io::Result<()>
+{
+    let config = BufferConfig::default();
+    let new = BufWriter::with_capacity(config.output_buffer_size, new);
+    let patch = BufReader::with_capacity(config.buffer_size, patch);
+    apply_body(patch, Some(0), old, new, &config, DecodeMode::Lenient, Version::V0, &mut ())
+}
+
+#[cfg(feature = "zstd")]
+pub fn apply_compressed_patch<PatchR: Read, OldRS: Read+Seek, NewW: Write>(patch: PatchR, old: OldRS, new: NewW)
+ -> io::Result<()>
+{
+    apply_compressed_patch_with_mode(patch, old, new, &BufferConfig::default(), LinearDiffZstd::DECODE_MODE)
+}
+
+/// Like `apply_compressed_patch`, with scratch buffers sized by `config` and
+/// decoding in `mode`.
+#[cfg(feature = "zstd")]
+pub fn apply_compressed_patch_with_mode<PatchR: Read, OldRS: Read+Seek, NewW: Write>(
+    patch: PatchR,
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: DecodeMode
+) -> io::Result<()>
+{
+    apply_compressed_patch_observed(patch, old, new, config, mode, &mut ())
+}
+
+/// Like `apply_compressed_patch_with_mode`, reporting each command to
+/// `observer` once it's been applied.
+#[cfg(feature = "zstd")]
+pub fn apply_compressed_patch_observed<PatchR: Read, OldRS: Read+Seek, NewW: Write>(
+    mut patch: PatchR,
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: DecodeMode,
+    observer: &mut ApplyObserver
+) -> io::Result<()>
+{
+    let version = read_magic(&mut patch, LinearDiffZstd::MAGIC, V1_ZSTD_MAGIC)?;
+    let new = BufWriter::with_capacity(config.output_buffer_size, new);
+    apply_body(zstd::stream::Decoder::new(patch)?, None, old, new, config, mode, version, observer)
+}
+
+/// Runs every command in `patch`.  `patch_base` is the offset of the body
+/// within the patch, if the body isn't compressed, for error reports.
+fn apply_body<PatchR: Read, OldRS: Read+Seek, NewO: Output>(
+    patch: PatchR,
+    patch_base: Option<u64>,
+    mut old: OldRS,
+    mut new: NewO,
+    config: &BufferConfig,
+    mode: DecodeMode,
+    version: Version,
+    observer: &mut ApplyObserver
+) -> io::Result<()>
+{
+    let mut scratch = Scratch::new(config);
+    let mut empty = EmptyCommands::new(mode, LinearDiff::NAME);
+    let mut patch = CountingReader::new(patch);
+    let mut command = 0;
+    let mut output_offset = 0;
+    let mut old_pos = 0;
+    let old_len = old_len(&mut old)?;
+
+    loop {
+        let patch_offset = patch_base.map(|base| base + patch.count());
 
-        read_paired_bufs(cmd.bytewise_add_size, &mut old, &mut patch, |o, d| {
-            for i in 0..o.len() {
-                o[i] = o[i].wrapping_add(d[i]);
+        match apply_command(&mut patch, &mut old, old_len, &mut new, &mut scratch, &mut empty, version) {
+            Ok(Some(cmd)) => {
+                output_offset += cmd.output_len()?;
+                observer.command(command, &command_event(&cmd, &mut old_pos));
             }
+            Ok(None) => break,
+            Err(e) => return Err(ApplyError::wrap(e, command, patch_offset, output_offset)),
+        }
+
+        command += 1;
+    }
+
+    new.finish()
+}
+
+/// What `cmd` did, for an `ApplyObserver`.  `old_pos` is where the last
+/// command that read the old file left off.
+fn command_event(cmd: &Command, old_pos: &mut u64) -> CommandEvent {
+    let (added, copied) = match cmd.copy_len() {
+        Some(len) => (0, len),
+        None => (cmd.delta_len(), 0),
+    };
+
+    let seek = if added + copied > 0 {
+        let seek = cmd.old_offset.wrapping_sub(*old_pos) as i64;
+        *old_pos = cmd.old_offset + added + copied;
+        Some(seek)
+    } else {
+        None
+    };
+
+    CommandEvent {
+        added: added,
+        copied: copied,
+        extra: cmd.extra_append_size,
+        seek: seek,
+    }
+}
+
+/// Applies the next command, returning it, or `None` at the end of the
+/// patch.
+fn apply_command<PatchR: Read, OldRS: Read+Seek, NewO: Output>(
+    mut patch: PatchR,
+    mut old: OldRS,
+    old_len: u64,
+    new: &mut NewO,
+    scratch: &mut Scratch,
+    empty: &mut EmptyCommands,
+    version: Version
+) -> io::Result<Option<Command>>
+{
+    let cmd = match Command::read_from(&mut patch)? {
+        Some(cmd) => cmd,
+        None => return Ok(None),
+    };
+
+    cmd.check(empty, version)?;
+
+    if let Some(byte) = cmd.fill_byte() {
+        new.fill(byte, cmd.extra_append_size)?;
+        return Ok(Some(cmd));
+    }
+
+    if let Some(len) = cmd.copy_len() {
+        check_old_range(cmd.old_offset, len, old_len)?;
+        old.seek(io::SeekFrom::Start(cmd.old_offset))?;
+
+        read_size_from_in(scratch, len, &mut old, |o| {
             new.write_all(&o)
         })?;
+    } else {
+        if cmd.bytewise_add_size > 0 {
+            check_old_range(cmd.old_offset, cmd.bytewise_add_size, old_len)?;
+        }
+
+        old.seek(io::SeekFrom::Start(cmd.old_offset))?;
 
-        read_size_from(cmd.extra_append_size, &mut patch, |e| {
-            new.write_all(&e)
+        read_paired_bufs_in(scratch, cmd.bytewise_add_size, &mut old, &mut patch, |o, d| {
+            add_delta(o, d);
+            new.write_all(&o)
         })?;
     }
 
-    Ok(())
+    read_size_from_in(scratch, cmd.extra_append_size, &mut patch, |e| {
+        new.write_all(&e)
+    })?;
+
+    Ok(Some(cmd))
+}
+
+/// Applies a `LinearDiff` patch to an old file that's already in memory,
+/// adding deltas straight from `old`.
+pub fn apply_patch_from_slice<PatchR: Read, NewW: Write>(patch: PatchR, old: &[u8], new: NewW)
+ -> io::Result<()>
+{
+    apply_patch_from_slice_with_mode(patch, old, new, LinearDiff::DECODE_MODE)
+}
+
+/// Like `apply_patch_from_slice`, decoding in `mode`.
+pub fn apply_patch_from_slice_with_mode<PatchR: Read, NewW: Write>(mut patch: PatchR, old: &[u8], new: NewW, mode: DecodeMode)
+ -> io::Result<()>
+{
+    let version = read_magic(&mut patch, LinearDiff::MAGIC, V1_MAGIC)?;
+    apply_body_from_slice(BufReader::new(patch), Some(LinearDiff::MAGIC.len() as u64), old, new, mode, version)
+}
+
+/// Applies a `LinearDiffZstd` patch to an old file that's already in memory.
+#[cfg(feature = "zstd")]
+pub fn apply_compressed_patch_from_slice<PatchR: Read, NewW: Write>(patch: PatchR, old: &[u8], new: NewW)
+ -> io::Result<()>
+{
+    apply_compressed_patch_from_slice_with_mode(patch, old, new, LinearDiffZstd::DECODE_MODE)
+}
+
+/// Like `apply_compressed_patch_from_slice`, decoding in `mode`.
+#[cfg(feature = "zstd")]
+pub fn apply_compressed_patch_from_slice_with_mode<PatchR: Read, NewW: Write>(
+    mut patch: PatchR,
+    old: &[u8],
+    new: NewW,
+    mode: DecodeMode
+) -> io::Result<()>
+{
+    let version = read_magic(&mut patch, LinearDiffZstd::MAGIC, V1_ZSTD_MAGIC)?;
+    apply_body_from_slice(zstd::stream::Decoder::new(patch)?, None, old, new, mode, version)
+}
+
+fn apply_body_from_slice<PatchR: Read, NewW: Write>(
+    patch: PatchR,
+    patch_base: Option<u64>,
+    old: &[u8],
+    new: NewW,
+    mode: DecodeMode,
+    version: Version
+) -> io::Result<()>
+{
+    let config = BufferConfig::default();
+    let mut scratch = Scratch::new(&config);
+    let mut empty = EmptyCommands::new(mode, LinearDiff::NAME);
+    let mut new = BufWriter::with_capacity(config.output_buffer_size, new);
+    let mut patch = CountingReader::new(patch);
+    let mut command = 0;
+    let mut output_offset = 0;
+
+    loop {
+        let patch_offset = patch_base.map(|base| base + patch.count());
+
+        match apply_command_from_slice(&mut patch, old, &mut new, &mut scratch, &mut empty, version) {
+            Ok(Some(len)) => output_offset += len,
+            Ok(None) => break,
+            Err(e) => return Err(ApplyError::wrap(e, command, patch_offset, output_offset)),
+        }
+
+        command += 1;
+    }
+
+    new.flush()
+}
+
+fn apply_command_from_slice<PatchR: Read, NewW: Write>(
+    mut patch: PatchR,
+    old: &[u8],
+    new: &mut BufWriter<NewW>,
+    scratch: &mut Scratch,
+    empty: &mut EmptyCommands,
+    version: Version
+) -> io::Result<Option<u64>>
+{
+    let cmd = match Command::read_from(&mut patch)? {
+        Some(cmd) => cmd,
+        None => return Ok(None),
+    };
+
+    cmd.check(empty, version)?;
+
+    if let Some(byte) = cmd.fill_byte() {
+        new.fill(byte, cmd.extra_append_size)?;
+        return Ok(Some(cmd.extra_append_size));
+    }
+
+    if let Some(len) = cmd.copy_len() {
+        new.write_all(old_slice(old, cmd.old_offset, len)?)?;
+    } else {
+        let o = old_slice(old, cmd.old_offset, cmd.bytewise_add_size)?;
+        add_delta_from_slice(scratch, o, &mut patch, |d| new.write_all(d))?;
+    }
+
+    read_size_from_in(scratch, cmd.extra_append_size, &mut patch, |e| {
+        new.write_all(&e)
+    })?;
+
+    cmd.output_len().map(Some)
 }
 
 pub fn print_patch<PatchR: Read>(mut patch: PatchR)
  -> io::Result<()>
 {
-    // let mut patch = zstd::Decoder::new(patch).unwrap();
+    read_magic(&mut patch, LinearDiff::MAGIC, V1_MAGIC)?;
 
     while let Some(cmd) = Command::read_from(&mut patch)? {
         println!("read {:?}", cmd);
 
-        read_size_from(cmd.bytewise_add_size, &mut patch, |_| {Ok(())})?;
-        read_size_from(cmd.extra_append_size, &mut patch, |_| {Ok(())})?;
+        read_size_from(cmd.body_len()?, &mut patch, |_| {Ok(())})?;
     }
 
     Ok(())
@@ -159,7 +1176,7 @@ mod tests {
         let mut patch = Vec::new();
         generate_full_patch(&index, &new[..], &mut patch).unwrap();
         
-        print_patch(Cursor::new(&patch));
+        print_patch(Cursor::new(&patch)).unwrap();
         
         let mut computed = Vec::new();
         apply_patch(Cursor::new(patch), Cursor::new(old), &mut computed).unwrap();
@@ -183,4 +1200,142 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_old_offset_out_of_range() {
+        let old = b"this is a test 12345678 test";
+
+        let mut patch = LinearDiff::MAGIC.to_vec();
+        Command {
+            old_offset: old.len() as u64 - 4,
+            bytewise_add_size: 8,
+            extra_append_size: 0,
+        }.write_to(&mut patch).unwrap();
+        patch.extend_from_slice(&[0; 8]);
+
+        let err = apply_patch(&patch[..], Cursor::new(&old[..]), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("only 28 bytes long"), "{}", err);
+
+        let err = apply_patch_from_slice(&patch[..], &old[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_fill_commands() {
+        let old = b"header 12345678 stuff".to_vec();
+
+        let mut new = b"header 12345678 stuf".to_vec();
+        new.extend(vec![0u8; 5000]);
+        new.extend_from_slice(b"trailer");
+        new.extend(vec![0xffu8; 300]);
+        new.extend(vec![0u8; 1000]);
+
+        let index = Index::compute(old.clone());
+        let mut patch = Vec::new();
+        generate_full_patch(&index, &new, &mut patch).unwrap();
+        assert!(patch.len() < 500);
+
+        let mut computed = Vec::new();
+        apply_patch(Cursor::new(&patch), Cursor::new(&old), &mut computed).unwrap();
+        assert_eq!(new, computed);
+
+        let mut computed = Vec::new();
+        apply_patch_from_slice(Cursor::new(&patch), &old, &mut computed).unwrap();
+        assert_eq!(new, computed);
+
+        let mut sparse = Cursor::new(Vec::new());
+        apply_patch_sparse(Cursor::new(&patch), Cursor::new(&old), &mut sparse).unwrap();
+        assert_eq!(new, sparse.into_inner());
+    }
+
+    #[test]
+    fn test_copy_commands() {
+        let old = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+
+        // One stretch carried over as-is, and one with a few bytes changed
+        // in its middle.
+        let mut new = b"new header".to_vec();
+        new.extend_from_slice(&old[100..1100]);
+        new.extend_from_slice(b"something else");
+        let changed = new.len() + 500;
+        new.extend_from_slice(&old[2000..3000]);
+        new[changed] ^= 0xff;
+        new[changed + 3] ^= 0xff;
+
+        let index = Index::compute(old.clone());
+        let mut patch = Vec::new();
+        generate_full_patch(&index, &new, &mut patch).unwrap();
+
+        let mut body = &patch[8..];
+        let mut copied = 0;
+        let mut delta = 0;
+        while let Some(cmd) = Command::read_from(&mut body).unwrap() {
+            copied += cmd.copy_len().unwrap_or(0);
+            delta += cmd.delta_len();
+            body = &body[cmd.body_len().unwrap() as usize..];
+        }
+        assert!(copied >= 1900, "{} bytes copied", copied);
+        assert!(delta < 100, "{} bytes of delta", delta);
+
+        let mut computed = Vec::new();
+        apply_patch(Cursor::new(&patch), Cursor::new(&old), &mut computed).unwrap();
+        assert_eq!(new, computed);
+
+        let mut computed = Vec::new();
+        apply_patch_from_slice(Cursor::new(&patch), &old, &mut computed).unwrap();
+        assert_eq!(new, computed);
+
+        // A copy past the end of the old file is caught like any other.
+        let mut bad = LinearDiff::MAGIC.to_vec();
+        Command::copy(4000, 200, 0).write_to(&mut bad).unwrap();
+        assert!(apply_patch(&bad[..], Cursor::new(&old), &mut Vec::new()).is_err());
+        assert!(apply_patch_from_slice(&bad[..], &old, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_headerless_patches() {
+        let old = b"this is a test 12345678 test";
+
+        // As the first releases wrote them: commands from the start.
+        let mut patch = Vec::new();
+        Command {
+            old_offset: 15,
+            bytewise_add_size: 8,
+            extra_append_size: 4,
+        }.write_to(&mut patch).unwrap();
+        patch.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        patch.extend_from_slice(b" end");
+
+        let mut computed = Vec::new();
+        apply_headerless_patch(&patch[..], Cursor::new(&old[..]), &mut computed).unwrap();
+        assert_eq!(&computed[..], b"12345679 end");
+
+        // There's no magic to go by.
+        assert!(!is_match(&patch));
+        let err = apply_patch(&patch[..], Cursor::new(&old[..]), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut copy = Vec::new();
+        Command::copy(0, 4, 0).write_to(&mut copy).unwrap();
+        let err = apply_headerless_patch(&copy[..], Cursor::new(&old[..]), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_compressed_patches() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let mut patch = Vec::new();
+        generate_compressed_patch(&index, &new[..], 3, &mut patch).unwrap();
+        assert!(LinearDiffZstd::is_match(&patch));
+
+        let mut computed = Vec::new();
+        apply_compressed_patch(Cursor::new(patch), Cursor::new(&old[..]), &mut computed).unwrap();
+
+        assert_eq!(&new[..], &computed[..]);
+    }
 }
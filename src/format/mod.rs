@@ -1,2 +1,284 @@
+use std::io::{self, Read, Write, Seek};
+use std::cmp::min;
+
+use patch::{DecodeMode, Unsupported};
+
 pub mod bsdiff;
-pub mod linear_diff;
\ No newline at end of file
+#[cfg(feature = "sha1")]
+pub mod block_hashes;
+#[cfg(feature = "sha1")]
+pub mod source_digest;
+pub mod linear_diff;
+pub mod multi_source;
+pub mod sink;
+pub mod wire;
+#[cfg(feature = "zstd")]
+pub mod zstd_patch_from;
+
+/// A patch container format, identified by the magic bytes at the very start
+/// of every patch it produces.
+pub trait PatchFormat {
+    /// Magic bytes that every patch in this format begins with.
+    const MAGIC: &'static [u8];
+
+    /// Human-readable name, used in error messages.
+    const NAME: &'static str;
+
+    /// How strictly patches are decoded unless the caller asks otherwise.
+    const DECODE_MODE: DecodeMode = DecodeMode::Strict;
+
+    fn apply_patch<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write;
+
+    /// Writes a patch that reproduces the first `size` bytes of the old file.
+    fn write_identity_patch<W: Write>(size: u64, patch: W) -> io::Result<()>;
+
+    /// Writes a patch that always produces `desired_output`, regardless of
+    /// the contents of the old file.
+    fn write_idempotent_patch<W: Write>(desired_output: &[u8], patch: W) -> io::Result<()>;
+
+    fn is_match(patch: &[u8]) -> bool {
+        patch.starts_with(Self::MAGIC)
+    }
+}
+
+/// The formats `patch::apply_any` tells apart by magic, once any envelopes
+/// have been unwrapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// `BSDIFF40` or `BSDIFFC1`.
+    Bsdiff,
+    LinearDiff,
+    #[cfg(feature = "zstd")]
+    LinearDiffZstd,
+    MultiSource,
+    #[cfg(feature = "zstd")]
+    ZstdPatchFrom,
+}
+
+/// Every `Format` this build can apply, in the order `Format::detect` tries
+/// them.
+pub(crate) const FORMATS: &'static [Format] = &[
+    Format::Bsdiff,
+    Format::LinearDiff,
+    #[cfg(feature = "zstd")]
+    Format::LinearDiffZstd,
+    Format::MultiSource,
+    #[cfg(feature = "zstd")]
+    Format::ZstdPatchFrom,
+];
+
+impl Format {
+    /// The format whose magic `patch` starts with, if any.
+    pub(crate) fn detect(patch: &[u8]) -> Option<Format> {
+        FORMATS.iter().cloned().find(|format| format.is_match(patch))
+    }
+
+    pub(crate) fn is_match(self, patch: &[u8]) -> bool {
+        match self {
+            Format::Bsdiff => bsdiff::is_match(patch),
+            Format::LinearDiff => linear_diff::LinearDiff::is_match(patch),
+            #[cfg(feature = "zstd")]
+            Format::LinearDiffZstd => linear_diff::LinearDiffZstd::is_match(patch),
+            Format::MultiSource => multi_source::MultiSource::is_match(patch),
+            #[cfg(feature = "zstd")]
+            Format::ZstdPatchFrom => zstd_patch_from::is_match(patch),
+        }
+    }
+
+    /// The format's name, for `patch`, which it must match.  (Classic and
+    /// codec bsdiff patches are named apart.)
+    pub(crate) fn name(self, patch: &[u8]) -> &'static str {
+        match self {
+            Format::Bsdiff => bsdiff::format_name(patch),
+            Format::LinearDiff => linear_diff::LinearDiff::NAME,
+            #[cfg(feature = "zstd")]
+            Format::LinearDiffZstd => linear_diff::LinearDiffZstd::NAME,
+            Format::MultiSource => multi_source::MultiSource::NAME,
+            #[cfg(feature = "zstd")]
+            Format::ZstdPatchFrom => zstd_patch_from::NAME,
+        }
+    }
+
+    /// The `DECODE_MODE` of the format, for `patch`, which it must match.
+    pub(crate) fn default_mode(self, patch: &[u8]) -> DecodeMode {
+        match self {
+            Format::Bsdiff => bsdiff::default_mode(patch),
+            Format::LinearDiff => linear_diff::LinearDiff::DECODE_MODE,
+            #[cfg(feature = "zstd")]
+            Format::LinearDiffZstd => linear_diff::LinearDiffZstd::DECODE_MODE,
+            Format::MultiSource => multi_source::MultiSource::DECODE_MODE,
+            #[cfg(feature = "zstd")]
+            Format::ZstdPatchFrom => DecodeMode::Strict,
+        }
+    }
+}
+
+/// Names of the formats this build can apply, for error messages.
+pub(crate) fn known_names() -> Vec<&'static str> {
+    let mut names = Vec::new();
+    #[cfg(feature = "bzip2")]
+    names.push(bsdiff::Bsdiff::NAME);
+    names.push(bsdiff::BsdiffCodecs::NAME);
+    names.push(linear_diff::LinearDiff::NAME);
+    #[cfg(feature = "zstd")]
+    names.push(linear_diff::LinearDiffZstd::NAME);
+    names.push(multi_source::MultiSource::NAME);
+    #[cfg(feature = "zstd")]
+    names.push(zstd_patch_from::NAME);
+    names
+}
+
+/// The cargo feature `patch` needs, if it's in a format this build leaves
+/// out.  (Bsdiff patches name their codecs in the header, and are caught
+/// when that's read.)
+pub(crate) fn missing_feature(patch: &[u8]) -> Option<&'static str> {
+    if !cfg!(feature = "zstd") && (linear_diff::is_compressed(patch) || patch.starts_with(PATCH_FROM_MAGIC)) {
+        Some("zstd")
+    } else {
+        None
+    }
+}
+
+/// The magic of `zstd_patch_from` patches, kept here so that builds
+/// without zstd recognize them too.
+const PATCH_FROM_MAGIC: &'static [u8] = b"RSDZPF01";
+
+/// The optional cargo features a patch needs in order to be applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FeatureSet {
+    pub bzip2: bool,
+    pub zstd: bool,
+    pub sha1: bool,
+}
+
+impl FeatureSet {
+    /// The features this build of rsdiff was compiled with.
+    pub fn available() -> FeatureSet {
+        FeatureSet {
+            bzip2: cfg!(feature = "bzip2"),
+            zstd: cfg!(feature = "zstd"),
+            sha1: cfg!(feature = "sha1"),
+        }
+    }
+
+    pub fn union(&self, other: &FeatureSet) -> FeatureSet {
+        FeatureSet {
+            bzip2: self.bzip2 || other.bzip2,
+            zstd: self.zstd || other.zstd,
+            sha1: self.sha1 || other.sha1,
+        }
+    }
+
+    /// The features in `self` that aren't in `other`.
+    pub fn difference(&self, other: &FeatureSet) -> FeatureSet {
+        FeatureSet {
+            bzip2: self.bzip2 && !other.bzip2,
+            zstd: self.zstd && !other.zstd,
+            sha1: self.sha1 && !other.sha1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == FeatureSet::default()
+    }
+
+    /// Cargo feature names, in a fixed order.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.bzip2 {
+            names.push("bzip2");
+        }
+        if self.zstd {
+            names.push("zstd");
+        }
+        if self.sha1 {
+            names.push("sha1");
+        }
+        names
+    }
+}
+
+/// The features needed to apply `patch`, judging only by its first few
+/// bytes.  Codecs are read as raw header bytes, so this works for patches
+/// that this build couldn't decode.
+pub(crate) fn required_features(patch: &[u8]) -> Result<FeatureSet, Unsupported> {
+    if patch.len() < 8 {
+        return Err(Unsupported::Truncated { needed: 8 });
+    }
+
+    // Source digest, normalizer and block hash envelopes.
+    let sha1 = FeatureSet { sha1: true, ..FeatureSet::default() };
+    if patch.starts_with(b"RSDSRC01") {
+        return envelope_features(patch, 36, sha1);
+    }
+    if patch.starts_with(b"RSDNRM01") {
+        return envelope_features(patch, 28, FeatureSet::default());
+    }
+    if patch.starts_with(b"RSDBLH01") {
+        return envelope_features(patch, 28, sha1);
+    }
+
+    if bsdiff::is_match(patch) {
+        bsdiff::header_features(patch)
+    } else if linear_diff::is_compressed(patch) || patch.starts_with(PATCH_FROM_MAGIC) {
+        Ok(FeatureSet { zstd: true, ..FeatureSet::default() })
+    } else if linear_diff::LinearDiff::is_match(patch) || multi_source::MultiSource::is_match(patch) {
+        Ok(FeatureSet::default())
+    } else {
+        Err(Unsupported::UnknownFormat)
+    }
+}
+
+/// Envelope magics, outermost first.  A patch carries each envelope at most
+/// once, nested in this order.
+const ENVELOPES: [&'static [u8]; 3] = [b"RSDSRC01", b"RSDNRM01", b"RSDBLH01"];
+
+/// Whether `patch` starts with any of the envelope magics.
+pub(crate) fn is_envelope(patch: &[u8]) -> bool {
+    envelope_position(patch).is_some()
+}
+
+fn envelope_position(patch: &[u8]) -> Option<usize> {
+    ENVELOPES.iter().position(|magic| patch.starts_with(magic))
+}
+
+/// Whether `inner`, the patch wrapped by the envelope at the start of
+/// `outer`, is bare or in an envelope that may go inside that one.  Checking
+/// this wherever an envelope is unwrapped bounds how deep they nest, however
+/// the patch was put together.
+fn may_wrap(outer: &[u8], inner: &[u8]) -> bool {
+    match (envelope_position(outer), envelope_position(inner)) {
+        (Some(outer), Some(inner)) => inner > outer,
+        _ => true,
+    }
+}
+
+/// Fails with `InvalidData` unless `may_wrap(outer, inner)`.
+pub(crate) fn check_wrapped(outer: &[u8], inner: &[u8]) -> io::Result<()> {
+    if may_wrap(outer, inner) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData,
+            "Patch envelopes are repeated or nested out of order"))
+    }
+}
+
+/// The features needed for an envelope with a `header_size`-byte header,
+/// followed by the patch it wraps: the envelope's own `features`, plus the
+/// wrapped patch's.
+fn envelope_features(patch: &[u8], header_size: usize, features: FeatureSet) -> Result<FeatureSet, Unsupported> {
+    let inner = &patch[min(patch.len(), header_size)..];
+    if !may_wrap(patch, inner) {
+        return Err(Unsupported::UnknownFormat);
+    }
+
+    match required_features(inner) {
+        Ok(inner) => Ok(inner.union(&features)),
+        Err(Unsupported::Truncated { needed }) => Err(Unsupported::Truncated { needed: needed + header_size }),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,520 @@
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use diff::{Matcher, DeltaEncoder, Subtract};
+use index::longest_prefix;
+
+use patch::{
+    add_delta,
+    check_old_range,
+    old_len,
+    read_paired_bufs_in,
+    read_size_from_in,
+    ApplyError,
+    BufferConfig,
+    DecodeMode,
+    EmptyCommands,
+    Scratch,
+};
+
+use format::PatchFormat;
+use format::sink::CountingReader;
+use format::wire;
+
+/// Like `LinearDiff`, but each command also names which of several old files
+/// ("sources") its bytes come from.  The special source `SELF_SOURCE` refers
+/// to the output written so far, LZ77-style.
+pub struct MultiSource;
+
+impl PatchFormat for MultiSource {
+    const MAGIC: &'static [u8] = b"LINDIFM1";
+    const NAME: &'static str = "multi_source";
+
+    /// Applies a patch against a single source, which gets source id 0.
+    fn apply_patch<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        apply_patch(patch, &mut [old], new)
+    }
+
+    fn write_identity_patch<W: Write>(size: u64, mut patch: W) -> io::Result<()> {
+        patch.write_all(MultiSource::MAGIC)?;
+
+        if size == 0 {
+            return Ok(());
+        }
+
+        Command {
+            source: 0,
+            old_offset: 0,
+            bytewise_add_size: size,
+            extra_append_size: 0,
+        }.write_to(&mut patch)?;
+
+        Subtract.write_zeros(&mut patch, size)
+    }
+
+    fn write_idempotent_patch<W: Write>(desired_output: &[u8], mut patch: W) -> io::Result<()> {
+        patch.write_all(MultiSource::MAGIC)?;
+
+        if desired_output.is_empty() {
+            return Ok(());
+        }
+
+        Command {
+            source: 0,
+            old_offset: 0,
+            bytewise_add_size: 0,
+            extra_append_size: desired_output.len() as u64,
+        }.write_to(&mut patch)?;
+
+        patch.write_all(desired_output)
+    }
+}
+
+/// Matches shorter than this are written as extra bytes.
+const MIN_MATCH: usize = 8;
+
+/// Source id for copies from earlier in the new file itself; `old_offset` is
+/// then an offset into the output.  These copies are always exact, so no
+/// delta bytes follow the command.
+pub const SELF_SOURCE: u64 = !0;
+
+/// How far back into the output a `SELF_SOURCE` copy may reach.
+pub const WINDOW: usize = 1 << 20;
+
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Command {
+    pub source: u64,
+    pub old_offset: u64,
+    pub bytewise_add_size: u64,
+    pub extra_append_size: u64,
+}
+
+impl Command {
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut buf = [0u8; 8*4];
+        wire::write_u64s(&mut buf, &[self.source, self.old_offset, self.bytewise_add_size, self.extra_append_size]);
+        writer.write_all(&buf)
+    }
+
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Option<Command>> {
+        let mut buf = [0u8; 8*4];
+        if !wire::read_record(reader, &mut buf, "multi_source command")? {
+            return Ok(None);
+        }
+
+        let mut fields = [0u64; 4];
+        wire::read_u64s(&buf, &mut fields);
+
+        Ok(Some(Command {
+            source: fields[0],
+            old_offset: fields[1],
+            bytewise_add_size: fields[2],
+            extra_append_size: fields[3],
+        }))
+    }
+}
+
+/// Writes a patch producing `new` from any mix of `sources`; a source's id
+/// in the patch is its position in the slice.
+///
+/// At each point the longest match across all sources wins, so chunks can
+/// be pulled from whichever old file has them.  Repeats within the last
+/// `WINDOW` bytes of `new` are also considered, as `SELF_SOURCE` copies.
+pub fn generate_full_patch<M: Matcher, PatchW: Write>(sources: &[&M], new: &[u8], mut patch: PatchW) -> io::Result<()> {
+    patch.write_all(MultiSource::MAGIC)?;
+
+    let mut history = SelfMatcher::new(new);
+    let mut pending = None;
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i < new.len() {
+        let best = sources.iter()
+            .enumerate()
+            .map(|(source, m)| (source as u64, m.longest_match(&new[i..])))
+            .chain(history.longest_match(i).map(|range| (SELF_SOURCE, range)))
+            .max_by_key(|&(_, ref range)| range.len());
+
+        let next = match best {
+            Some((source, ref range)) if range.len() >= MIN_MATCH => {
+                write_command(sources, new, &Subtract, pending, literal_start .. i, &mut patch)?;
+
+                pending = Some((source, range.start, i));
+                literal_start = i + range.len();
+                i + range.len()
+            }
+            _ => i + 1,
+        };
+
+        history.insert(i .. next);
+        i = next;
+    }
+
+    write_command(sources, new, &Subtract, pending, literal_start .. new.len(), &mut patch)
+}
+
+/// Finds earlier occurrences of the upcoming bytes of `new`, keyed by the
+/// `MIN_MATCH` bytes starting at each position seen so far.
+struct SelfMatcher<'a> {
+    new: &'a [u8],
+    positions: HashMap<&'a [u8], usize>,
+}
+
+impl<'a> SelfMatcher<'a> {
+    fn new(new: &'a [u8]) -> SelfMatcher<'a> {
+        SelfMatcher {
+            new: new,
+            positions: HashMap::new(),
+        }
+    }
+
+    fn key(&self, pos: usize) -> Option<&'a [u8]> {
+        let new = self.new;
+        if pos + MIN_MATCH <= new.len() {
+            Some(&new[pos .. pos + MIN_MATCH])
+        } else {
+            None
+        }
+    }
+
+    fn insert(&mut self, range: Range<usize>) {
+        for pos in range {
+            if let Some(key) = self.key(pos) {
+                self.positions.insert(key, pos);
+            }
+        }
+    }
+
+    /// The longest earlier match for `new[pos..]`.  It may overlap `pos`,
+    /// which the applier handles by copying forward a byte at a time.
+    fn longest_match(&self, pos: usize) -> Option<Range<usize>> {
+        let start = match self.key(pos).and_then(|key| self.positions.get(key)) {
+            Some(&start) if pos - start <= WINDOW => start,
+            _ => return None,
+        };
+
+        let len = longest_prefix(&self.new[start..], &self.new[pos..]);
+        Some(start .. start + len)
+    }
+}
+
+/// Writes the command for a match (`source`, old offset and new offset),
+/// with its delta encoded by `encoder`, followed by the `extra` range of
+/// `new`.  Like `LinearDiff`, the format only holds `Subtract` deltas.
+fn write_command<M: Matcher, E: DeltaEncoder, PatchW: Write>(
+    sources: &[&M],
+    new: &[u8],
+    encoder: &E,
+    matched: Option<(u64, usize, usize)>,
+    extra: Range<usize>,
+    mut patch: PatchW
+) -> io::Result<()> {
+    let (source, old_offset, new_offset) = matched.unwrap_or((0, 0, extra.start));
+    let len = extra.start - new_offset;
+
+    if len == 0 && extra.len() == 0 {
+        return Ok(());
+    }
+
+    Command {
+        source: source,
+        old_offset: old_offset as u64,
+        bytewise_add_size: len as u64,
+        extra_append_size: extra.len() as u64,
+    }.write_to(&mut patch)?;
+
+    if len > 0 && source != SELF_SOURCE {
+        encoder.write_delta(
+            &mut patch,
+            &sources[source as usize].data()[old_offset .. old_offset + len],
+            &new[new_offset .. extra.start])?;
+    }
+
+    patch.write_all(&new[extra])
+}
+
+/// Applies a multi-source patch, reading each command's bytes from
+/// `sources[command.source]`.
+pub fn apply_patch<PatchR: Read, OldRS: Read+Seek, NewW: Write>(patch: PatchR, sources: &mut [OldRS], new: NewW)
+ -> io::Result<()>
+{
+    apply_patch_with_mode(patch, sources, new, MultiSource::DECODE_MODE)
+}
+
+/// Like `apply_patch`, decoding in `mode`.
+pub fn apply_patch_with_mode<PatchR: Read, OldRS: Read+Seek, NewW: Write>(
+    mut patch: PatchR,
+    sources: &mut [OldRS],
+    new: NewW,
+    mode: DecodeMode
+) -> io::Result<()>
+{
+    let config = BufferConfig::default();
+    let mut scratch = Scratch::new(&config);
+
+    let mut magic = [0u8; 8];
+    patch.read_exact(&mut magic)?;
+    if &magic[..] != MultiSource::MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad multi_source magic"));
+    }
+
+    let mut patch = CountingReader::new(BufReader::with_capacity(config.buffer_size, patch));
+    let mut new = History::new(BufWriter::with_capacity(config.output_buffer_size, new));
+    let old_lens = sources.iter_mut().map(old_len).collect::<io::Result<Vec<u64>>>()?;
+
+    let mut empty = EmptyCommands::new(mode, MultiSource::NAME);
+    let mut command = 0;
+    let mut output_offset = 0u64;
+
+    loop {
+        let patch_offset = MultiSource::MAGIC.len() as u64 + patch.count();
+
+        match apply_command(&mut patch, sources, &old_lens, &mut new, &mut scratch, &mut empty) {
+            Ok(Some(len)) => output_offset = output_offset.saturating_add(len),
+            Ok(None) => break,
+            Err(e) => return Err(ApplyError::wrap(e, command, Some(patch_offset), output_offset)),
+        }
+
+        command += 1;
+    }
+
+    new.flush()
+}
+
+/// Runs the next command in `patch`, returning how many bytes it wrote, or
+/// `None` at the end of the patch.
+fn apply_command<PatchR: Read, OldRS: Read+Seek, NewW: Write>(
+    mut patch: PatchR,
+    sources: &mut [OldRS],
+    old_lens: &[u64],
+    new: &mut History<NewW>,
+    scratch: &mut Scratch,
+    empty: &mut EmptyCommands
+) -> io::Result<Option<u64>>
+{
+    let cmd = match Command::read_from(&mut patch)? {
+        Some(cmd) => cmd,
+        None => return Ok(None),
+    };
+
+    let len = cmd.bytewise_add_size.saturating_add(cmd.extra_append_size);
+    empty.check(len)?;
+
+    if cmd.source == SELF_SOURCE {
+        new.copy_within(cmd.old_offset, cmd.bytewise_add_size)?;
+    } else {
+        let source_count = sources.len();
+        let old = match sources.get_mut(cmd.source as usize) {
+            Some(old) => old,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Patch refers to source {}, but only {} were given", cmd.source, source_count))),
+        };
+
+        if cmd.bytewise_add_size > 0 {
+            check_old_range(cmd.old_offset, cmd.bytewise_add_size, old_lens[cmd.source as usize])?;
+        }
+
+        old.seek(SeekFrom::Start(cmd.old_offset))?;
+
+        read_paired_bufs_in(scratch, cmd.bytewise_add_size, old, &mut patch, |o, d| {
+            add_delta(o, d);
+            new.write_all(&o)
+        })?;
+    }
+
+    read_size_from_in(scratch, cmd.extra_append_size, &mut patch, |e| {
+        new.write_all(&e)
+    })?;
+
+    Ok(Some(len))
+}
+
+/// Passes writes through while remembering at least the last `WINDOW` bytes,
+/// for `SELF_SOURCE` copies.
+struct History<W> {
+    inner: W,
+    buf: Vec<u8>,
+    start: u64,
+}
+
+impl<W: Write> History<W> {
+    fn new(inner: W) -> History<W> {
+        History {
+            inner: inner,
+            buf: Vec::new(),
+            start: 0,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+
+    /// Appends `len` bytes copied from `offset` in the output.  The range may
+    /// run past the current end, in which case the copy repeats itself.
+    ///
+    /// `offset` must be within `WINDOW` bytes of the end: the copy reads from
+    /// a fixed distance behind the end as it grows, and trimming only ever
+    /// keeps that much.
+    fn copy_within(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if offset < self.start || offset >= self.end() || self.end() - offset > WINDOW as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Self-referential copy from offset {} is outside the output window", offset)));
+        }
+
+        let mut pos = offset;
+        let mut remaining = len;
+
+        while remaining > 0 {
+            let n = min(remaining, self.end() - pos) as usize;
+            let begin = (pos - self.start) as usize;
+
+            self.inner.write_all(&self.buf[begin .. begin + n])?;
+            for k in begin .. begin + n {
+                let b = self.buf[k];
+                self.buf.push(b);
+            }
+
+            pos += n as u64;
+            remaining -= n as u64;
+            self.trim();
+        }
+
+        Ok(())
+    }
+
+    fn trim(&mut self) {
+        if self.buf.len() > 2 * WINDOW {
+            let excess = self.buf.len() - WINDOW;
+            self.buf.drain(..excess);
+            self.start += excess as u64;
+        }
+    }
+}
+
+impl<W: Write> Write for History<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.buf.extend_from_slice(&buf[..n]);
+        self.trim();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use diff::Index;
+
+    #[test]
+    fn test_multi_source_roundtrip() {
+        let lib_a = b"first library: 0123456789abcdef shared code".to_vec();
+        let lib_b = b"second library: ghijklmnopqrstuv other code".to_vec();
+        let new = b"app 0123456789abcdef and ghijklmnopqrstuv, plus new bits";
+
+        let a = Index::compute(lib_a.clone());
+        let b = Index::compute(lib_b.clone());
+
+        let mut patch = Vec::new();
+        generate_full_patch(&[&a, &b], &new[..], &mut patch).unwrap();
+        assert!(MultiSource::is_match(&patch));
+
+        let mut sources = [Cursor::new(&lib_a[..]), Cursor::new(&lib_b[..])];
+        let mut computed = Vec::new();
+        apply_patch(&patch[..], &mut sources, &mut computed).unwrap();
+        assert_eq!(&new[..], &computed[..]);
+
+        let err = apply_patch(&patch[..], &mut sources[..1], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_apply_error_context() {
+        let mut patch = MultiSource::MAGIC.to_vec();
+        Command { source: 0, old_offset: 0, bytewise_add_size: 0, extra_append_size: 3 }.write_to(&mut patch).unwrap();
+        patch.extend_from_slice(b"abc");
+        let second = patch.len() as u64;
+        Command { source: 5, old_offset: 0, bytewise_add_size: 1, extra_append_size: 0 }.write_to(&mut patch).unwrap();
+
+        let err = apply_patch(&patch[..], &mut [Cursor::new(&b"old"[..])], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let context = ApplyError::find(&err).unwrap();
+        assert_eq!(context.command, 1);
+        assert_eq!(context.patch_offset, Some(second));
+        assert_eq!(context.output_offset, 3);
+    }
+
+    #[test]
+    fn test_self_referential_copies() {
+        let old = b"unrelated old contents".to_vec();
+
+        let mut new = b"a block that repeats; ".to_vec();
+        for _ in 0..50 {
+            new.extend_from_slice(b"a block that repeats; ");
+        }
+        new.extend_from_slice(b"tail");
+
+        let index = Index::compute(old.clone());
+        let mut patch = Vec::new();
+        generate_full_patch(&[&index], &new, &mut patch).unwrap();
+        assert!(patch.len() < new.len() / 4);
+
+        let mut computed = Vec::new();
+        apply_patch(&patch[..], &mut [Cursor::new(&old[..])], &mut computed).unwrap();
+        assert_eq!(new, computed);
+    }
+
+    /// A patch writing `2 * WINDOW` extra bytes, then copying `len` bytes from
+    /// `offset` in its own output.
+    fn long_self_copy(offset: u64, len: u64) -> Vec<u8> {
+        let mut patch = MultiSource::MAGIC.to_vec();
+        Command {
+            source: 0,
+            old_offset: 0,
+            bytewise_add_size: 0,
+            extra_append_size: 2 * WINDOW as u64,
+        }.write_to(&mut patch).unwrap();
+        patch.extend((0..2 * WINDOW).map(|i| (i % 251) as u8));
+        Command {
+            source: SELF_SOURCE,
+            old_offset: offset,
+            bytewise_add_size: len,
+            extra_append_size: 0,
+        }.write_to(&mut patch).unwrap();
+        patch
+    }
+
+    #[test]
+    fn test_self_copy_window() {
+        let end = 2 * WINDOW as u64;
+
+        // Reaching further back than the window is an error, even though
+        // those bytes happen to still be buffered.
+        let patch = long_self_copy(0, end + 1);
+        let err = apply_patch(&patch[..], &mut [Cursor::new(&b""[..])], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A copy from the edge of the window that outlives a trim.
+        let patch = long_self_copy(end - WINDOW as u64, end + 1);
+        let mut computed = Vec::new();
+        apply_patch(&patch[..], &mut [Cursor::new(&b""[..])], &mut computed).unwrap();
+        assert_eq!(computed.len() as u64, 2 * end + 1);
+        for (i, &b) in computed.iter().enumerate().skip(end as usize) {
+            assert_eq!(b, computed[i - WINDOW]);
+        }
+    }
+}
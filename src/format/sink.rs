@@ -0,0 +1,404 @@
+//! Storage for patch sections that have to be fully written before they can
+//! be emitted.
+//!
+//! The classic header records the compressed size of the command and delta
+//! sections, so those sections can't be streamed straight to the output.
+//! A `SectionSink` holds a section until the header is known, either in
+//! memory or in a temporary file on disk.
+
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub trait SectionSink: Write {
+    /// Number of bytes written to the sink so far.
+    fn len(&self) -> u64;
+
+    /// Copies everything written so far to `writer`.
+    fn copy_to<W: Write>(&mut self, writer: W) -> io::Result<()>;
+}
+
+impl SectionSink for Vec<u8> {
+    fn len(&self) -> u64 {
+        Vec::len(self) as u64
+    }
+
+    fn copy_to<W: Write>(&mut self, mut writer: W) -> io::Result<()> {
+        writer.write_all(self)
+    }
+}
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A section spilled to an anonymous file in the system temp directory (or
+/// another, with `in_dir`).
+/// The file is deleted when the `TempFileSink` is dropped.
+pub struct TempFileSink {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl TempFileSink {
+    pub fn new() -> io::Result<TempFileSink> {
+        TempFileSink::in_dir(&env::temp_dir())
+    }
+
+    /// Like `new`, with the file in `dir`.
+    pub fn in_dir(dir: &Path) -> io::Result<TempFileSink> {
+        loop {
+            let path = dir.join(format!("rsdiff-{}-{}.tmp",
+                process::id(),
+                TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)));
+
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok(TempFileSink {
+                    path: path,
+                    file: file,
+                    len: 0,
+                }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Write for TempFileSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl SectionSink for TempFileSink {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn copy_to<W: Write>(&mut self, mut writer: W) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let copied = io::copy(&mut (&mut self.file).take(self.len), &mut writer)?;
+
+        if copied != self.len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Temp file was truncated"));
+        }
+
+        self.file.seek(SeekFrom::End(0)).map(|_| ())
+    }
+}
+
+impl Drop for TempFileSink {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads back what was written, from wherever the last write or seek left
+/// off.
+impl Read for TempFileSink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for TempFileSink {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// Where bytes that don't fit in memory go: bsdiff sections with
+/// `PatchOptions::spill_to_temp_files`, and the intermediate files of
+/// `patch::apply_chain`.  `TempFiles` (in the system temp directory) unless
+/// told otherwise; `InMemory` for targets without a usable disk.
+pub trait Scratch: fmt::Debug + Send + Sync {
+    /// A new, empty file, deleted once it's dropped.
+    fn create(&self) -> io::Result<ScratchFile>;
+}
+
+/// Keeps scratch files in memory after all.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InMemory;
+
+impl Scratch for InMemory {
+    fn create(&self) -> io::Result<ScratchFile> {
+        Ok(ScratchFile::Memory(io::Cursor::new(Vec::new())))
+    }
+}
+
+/// Puts scratch files in a directory: the system temp directory, or one
+/// given to `in_dir` (a fast local disk, say).
+#[derive(Debug, Clone)]
+pub struct TempFiles {
+    dir: PathBuf,
+}
+
+impl TempFiles {
+    pub fn new() -> TempFiles {
+        TempFiles::in_dir(env::temp_dir())
+    }
+
+    pub fn in_dir<P: Into<PathBuf>>(dir: P) -> TempFiles {
+        TempFiles { dir: dir.into() }
+    }
+}
+
+impl Default for TempFiles {
+    fn default() -> TempFiles {
+        TempFiles::new()
+    }
+}
+
+impl Scratch for TempFiles {
+    fn create(&self) -> io::Result<ScratchFile> {
+        TempFileSink::in_dir(&self.dir).map(ScratchFile::File)
+    }
+}
+
+/// A file from a `Scratch`.  It reads back what was written, from wherever
+/// the last write or seek left off.
+pub enum ScratchFile {
+    Memory(io::Cursor<Vec<u8>>),
+    File(TempFileSink),
+}
+
+impl Write for ScratchFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ScratchFile::Memory(ref mut w) => w.write(buf),
+            ScratchFile::File(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ScratchFile::Memory(ref mut w) => w.flush(),
+            ScratchFile::File(ref mut w) => w.flush(),
+        }
+    }
+}
+
+impl Read for ScratchFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ScratchFile::Memory(ref mut r) => r.read(buf),
+            ScratchFile::File(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+impl Seek for ScratchFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            ScratchFile::Memory(ref mut r) => r.seek(pos),
+            ScratchFile::File(ref mut r) => r.seek(pos),
+        }
+    }
+}
+
+impl SectionSink for ScratchFile {
+    fn len(&self) -> u64 {
+        match *self {
+            ScratchFile::Memory(ref c) => c.get_ref().len() as u64,
+            ScratchFile::File(ref f) => f.len(),
+        }
+    }
+
+    fn copy_to<W: Write>(&mut self, mut writer: W) -> io::Result<()> {
+        match *self {
+            ScratchFile::Memory(ref c) => writer.write_all(c.get_ref()),
+            ScratchFile::File(ref mut f) => f.copy_to(writer),
+        }
+    }
+}
+
+/// Bytes kept in memory until there are more than `limit` of them, then
+/// moved to a file from `scratch`.  `into_reader` reads them back.
+pub struct SpillBuffer<'a> {
+    memory: Vec<u8>,
+    file: Option<ScratchFile>,
+    limit: usize,
+    scratch: &'a Scratch,
+}
+
+impl<'a> SpillBuffer<'a> {
+    pub fn new(limit: usize, scratch: &'a Scratch) -> SpillBuffer<'a> {
+        SpillBuffer {
+            memory: Vec::new(),
+            file: None,
+            limit: limit,
+            scratch: scratch,
+        }
+    }
+
+    /// Whether the bytes have moved to a scratch file.
+    pub fn spilled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Everything written, from the start.
+    pub fn into_reader(self) -> io::Result<ScratchFile> {
+        match self.file {
+            Some(mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(file)
+            }
+            None => Ok(ScratchFile::Memory(io::Cursor::new(self.memory))),
+        }
+    }
+}
+
+impl<'a> Write for SpillBuffer<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() && self.memory.len() + buf.len() > self.limit {
+            let mut file = self.scratch.create()?;
+            file.write_all(&self.memory)?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+
+        match self.file {
+            Some(ref mut file) => file.write(buf),
+            None => self.memory.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file {
+            Some(ref mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Passes writes through to `inner`, keeping track of how many bytes went by.
+pub struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter {
+            inner: inner,
+            count: 0,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes reads through to `inner`, counting the bytes read.
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> CountingReader<R> {
+        CountingReader {
+            inner: inner,
+            count: 0,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_file_sink_roundtrip() {
+        let mut sink = TempFileSink::new().unwrap();
+        sink.write_all(b"this is ").unwrap();
+        sink.write_all(b"a test").unwrap();
+
+        assert_eq!(sink.len(), 14);
+
+        let mut out = Vec::new();
+        sink.copy_to(&mut out).unwrap();
+        assert_eq!(&out[..], b"this is a test");
+
+        let path = sink.path.clone();
+        drop(sink);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_spill_buffer() {
+        let scratches: [&Scratch; 2] = [&TempFiles::new(), &InMemory];
+        for &limit in &[0, 5, 100] {
+            for &scratch in &scratches {
+                let mut buffer = SpillBuffer::new(limit, scratch);
+                buffer.write_all(b"this is ").unwrap();
+                buffer.write_all(b"a test").unwrap();
+                assert_eq!(buffer.spilled(), limit < 14);
+
+                let mut reader = buffer.into_reader().unwrap();
+                let mut out = Vec::new();
+                reader.read_to_end(&mut out).unwrap();
+                assert_eq!(&out[..], b"this is a test");
+
+                reader.seek(SeekFrom::Start(8)).unwrap();
+                out.clear();
+                reader.read_to_end(&mut out).unwrap();
+                assert_eq!(&out[..], b"a test");
+            }
+        }
+    }
+
+    #[test]
+    fn test_temp_files_in_dir() {
+        let dir = env::temp_dir().join(format!("rsdiff-test-scratch-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut file = TempFiles::in_dir(&dir).create().unwrap();
+        file.write_all(b"scratch").unwrap();
+        assert_eq!(file.len(), 7);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        drop(file);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! An optional envelope around a patch recording the size and SHA-1 of the
+//! old file it applies to, so that updaters can check the installed base
+//! version with `patch::verify_source` before downloading the patch or
+//! scheduling downtime.  Applying the patch checks it too, which costs a
+//! read of the old file before anything is written (or, for
+//! `patch::apply_sequential`, once the apply is done).
+//!
+//! Layout:
+//!
+//! ```text
+//! magic "RSDSRC01", old file size (u64, little-endian), old file SHA-1 (20 bytes), patch
+//! ```
+//!
+//! The wrapped patch may itself be normalized or block-hashed, but not
+//! wrapped in another source digest.
+
+use std::io::{self, Write};
+
+use sha1::Sha1;
+
+use format::{self, wire};
+
+pub const MAGIC: &'static [u8] = b"RSDSRC01";
+
+/// Bytes before the wrapped patch: all `patch::verify_source` needs.
+pub const HEADER_SIZE: usize = 8 + 8 + 20;
+
+pub fn is_match(patch: &[u8]) -> bool {
+    patch.starts_with(MAGIC)
+}
+
+/// The old file a patch was made against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceDigest {
+    pub old_size: u64,
+    pub digest: [u8; 20],
+}
+
+impl SourceDigest {
+    pub fn compute(old: &[u8]) -> SourceDigest {
+        let mut sha1 = Sha1::new();
+        sha1.update(old);
+
+        SourceDigest {
+            old_size: old.len() as u64,
+            digest: sha1.digest().bytes(),
+        }
+    }
+
+    /// Reads just the header, from the first `HEADER_SIZE` bytes of a
+    /// wrapped patch.
+    pub fn read_header(header: &[u8]) -> io::Result<SourceDigest> {
+        if !is_match(header) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad source digest magic"));
+        }
+        if header.len() < HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated source digest header"));
+        }
+
+        let mut digest = [0u8; 20];
+        digest.copy_from_slice(&header[16..HEADER_SIZE]);
+
+        Ok(SourceDigest {
+            old_size: wire::read_u64(&header[8..16]),
+            digest: digest,
+        })
+    }
+
+    /// Splits a wrapped patch into its source digest and the patch inside.
+    pub fn read(patch: &[u8]) -> io::Result<(SourceDigest, &[u8])> {
+        let source = SourceDigest::read_header(patch)?;
+        let inner = &patch[HEADER_SIZE..];
+        format::check_wrapped(patch, inner)?;
+        Ok((source, inner))
+    }
+
+    /// Writes `patch` wrapped with this source digest.
+    pub fn write_patch<W: Write>(&self, patch: &[u8], mut writer: W) -> io::Result<()> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..8].copy_from_slice(MAGIC);
+        wire::write_u64(&mut header[8..16], self.old_size);
+        header[16..].copy_from_slice(&self.digest);
+
+        writer.write_all(&header)?;
+        writer.write_all(patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use diff::{Differ, Index, PatchOptions};
+    use format::block_hashes::BlockHashes;
+    use patch::{apply_any, apply_from_slice, apply_sequential, can_apply, verify_source, SourceMismatch,
+                CAN_APPLY_BYTES};
+
+    const OLD: &'static [u8] = b"the quick brown fox jumps over the lazy dog, again and again and again";
+    const NEW: &'static [u8] = b"the quick brown cat jumps over the lazy dog, again and again, and then stops";
+
+    #[test]
+    fn test_verify_source() {
+        let index = Index::compute(OLD.to_vec());
+        let mut patch = Vec::new();
+        Differ::new(&index)
+            .options(PatchOptions::new().source_digest(true).block_hashes(16))
+            .run(NEW, &mut patch)
+            .unwrap();
+
+        assert!(is_match(&patch));
+        assert_eq!(SourceDigest::read(&patch).unwrap().0, SourceDigest::compute(OLD));
+        assert_eq!(can_apply(&patch[..CAN_APPLY_BYTES]), Ok(()));
+
+        verify_source(&patch[..HEADER_SIZE], OLD).unwrap();
+
+        let mut wrong = OLD.to_vec();
+        wrong[3] = b'!';
+        match verify_source(&patch, &wrong[..]) {
+            Err(SourceMismatch::WrongDigest) => {}
+            other => panic!("{:?}", other),
+        }
+        match verify_source(&patch, &OLD[1..]) {
+            Err(SourceMismatch::WrongSize { expected, actual }) =>
+                assert_eq!((expected, actual), (OLD.len() as u64, OLD.len() as u64 - 1)),
+            other => panic!("{:?}", other),
+        }
+        match verify_source(&patch[..HEADER_SIZE - 1], OLD) {
+            Err(SourceMismatch::Truncated { needed }) => assert_eq!(needed, HEADER_SIZE),
+            other => panic!("{:?}", other),
+        }
+
+        let (_, inner) = SourceDigest::read(&patch).unwrap();
+        match verify_source(inner, OLD) {
+            Err(SourceMismatch::Unrecorded) => {}
+            other => panic!("{:?}", other),
+        }
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(OLD), &mut out).unwrap();
+        assert_eq!(out, NEW);
+    }
+
+    #[test]
+    fn test_apply_checks_digest() {
+        let index = Index::compute(OLD.to_vec());
+        let mut patch = Vec::new();
+        Differ::new(&index)
+            .options(PatchOptions::new().source_digest(true))
+            .run(NEW, &mut patch)
+            .unwrap();
+
+        let mut wrong = OLD.to_vec();
+        wrong[3] = b'!';
+
+        let mut out = Vec::new();
+        let err = apply_any(&patch, Cursor::new(&wrong[..]), &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(out.is_empty());
+
+        let err = apply_from_slice(&patch, &wrong, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = apply_sequential(&patch, &wrong[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = apply_sequential(&patch, &[OLD, b"!"].concat()[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut out = Vec::new();
+        apply_sequential(&patch, OLD, &mut out).unwrap();
+        assert_eq!(out, NEW);
+        let mut out = Vec::new();
+        apply_from_slice(&patch, OLD, &mut out).unwrap();
+        assert_eq!(out, NEW);
+    }
+
+    #[test]
+    fn test_nested_envelopes() {
+        let index = Index::compute(OLD.to_vec());
+        let mut patch = Vec::new();
+        Differ::new(&index)
+            .options(PatchOptions::new().source_digest(true))
+            .run(NEW, &mut patch)
+            .unwrap();
+
+        // A digest wrapping another digest, however deep, is rejected
+        // rather than unwrapped again.
+        let mut nested = patch[..HEADER_SIZE].repeat(100_000);
+        nested.extend_from_slice(&patch);
+        let err = apply_any(&nested, Cursor::new(OLD), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = apply_from_slice(&nested, OLD, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(can_apply(&nested[..CAN_APPLY_BYTES]).is_err());
+
+        // So is one inside a block-hashed patch, since the digest has to be
+        // the outermost envelope.
+        let mut hashed = Vec::new();
+        BlockHashes::compute(NEW, 16).write_patch(&patch, &mut hashed).unwrap();
+        let err = apply_any(&hashed, Cursor::new(OLD), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // And a block-hashed patch can't be block-hashed again.
+        let (_, inner) = SourceDigest::read(&patch).unwrap();
+        let mut hashed = Vec::new();
+        BlockHashes::compute(NEW, 16).write_patch(inner, &mut hashed).unwrap();
+        apply_any(&hashed, Cursor::new(OLD), &mut Vec::new()).unwrap();
+        let mut twice = Vec::new();
+        BlockHashes::compute(NEW, 16).write_patch(&hashed, &mut twice).unwrap();
+        let err = apply_any(&twice, Cursor::new(OLD), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,379 @@
+//! Integer encodings shared by the patch formats and containers.
+//!
+//! Two encodings turn up on the wire:
+//!
+//! - fixed-width little-endian integers, used by every rsdiff format,
+//!   envelope and container (bundles, payloads, catalogs, journals, cached
+//!   indexes) for sizes, offsets and counts;
+//! - bsdiff's 8-byte sign-and-magnitude integers ("offtin" in the original
+//!   C), used for everything in a bsdiff header and command stream.
+//!
+//! Formats should go through these helpers rather than hand-rolling their
+//! own, so that edge cases (truncation, the bsdiff negative zero) are
+//! handled the same way everywhere.  Fixed-width integers come in two
+//! flavours: `read_u64` and friends work on a slice the caller has already
+//! bounds-checked, and `read_u64_from` and friends on a stream.
+//!
+//! LEB128 varints (with zigzag for signed values) are here too, for formats
+//! that want small numbers to stay small before compression, but none of
+//! the current formats use them: changing an existing format's encoding
+//! would break every patch already written with it.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+pub fn read_u16(buf: &[u8]) -> u16 {
+    LittleEndian::read_u16(buf)
+}
+
+pub fn write_u16(buf: &mut [u8], x: u16) {
+    LittleEndian::write_u16(buf, x)
+}
+
+pub fn read_u32(buf: &[u8]) -> u32 {
+    LittleEndian::read_u32(buf)
+}
+
+pub fn write_u32(buf: &mut [u8], x: u32) {
+    LittleEndian::write_u32(buf, x)
+}
+
+pub fn read_u64(buf: &[u8]) -> u64 {
+    LittleEndian::read_u64(buf)
+}
+
+pub fn write_u64(buf: &mut [u8], x: u64) {
+    LittleEndian::write_u64(buf, x)
+}
+
+/// Reads a little-endian `u64` from each 8-byte word of `buf`.
+pub fn read_u64s(buf: &[u8], out: &mut [u64]) {
+    for (x, word) in out.iter_mut().zip(buf.chunks(8)) {
+        *x = read_u64(word);
+    }
+}
+
+/// Writes `xs` as consecutive little-endian `u64`s at the start of `buf`.
+pub fn write_u64s(buf: &mut [u8], xs: &[u64]) {
+    for (word, &x) in buf.chunks_mut(8).zip(xs) {
+        write_u64(word, x);
+    }
+}
+
+pub fn read_u8_from<R: Read>(mut reader: R) -> io::Result<u8> {
+    reader.read_u8()
+}
+
+pub fn write_u8_to<W: Write>(mut writer: W, x: u8) -> io::Result<()> {
+    writer.write_u8(x)
+}
+
+pub fn read_u16_from<R: Read>(mut reader: R) -> io::Result<u16> {
+    reader.read_u16::<LittleEndian>()
+}
+
+pub fn write_u16_to<W: Write>(mut writer: W, x: u16) -> io::Result<()> {
+    writer.write_u16::<LittleEndian>(x)
+}
+
+pub fn read_u32_from<R: Read>(mut reader: R) -> io::Result<u32> {
+    reader.read_u32::<LittleEndian>()
+}
+
+pub fn write_u32_to<W: Write>(mut writer: W, x: u32) -> io::Result<()> {
+    writer.write_u32::<LittleEndian>(x)
+}
+
+/// Reads a little-endian `u64` from `reader`, failing with `UnexpectedEof`
+/// if the stream ends partway through it.
+pub fn read_u64_from<R: Read>(mut reader: R) -> io::Result<u64> {
+    reader.read_u64::<LittleEndian>()
+}
+
+pub fn write_u64_to<W: Write>(mut writer: W, x: u64) -> io::Result<()> {
+    writer.write_u64::<LittleEndian>(x)
+}
+
+/// Reads a bsdiff sign-and-magnitude integer from the first 8 bytes of
+/// `buf`: the low 63 bits of a little-endian word are the magnitude, and the
+/// top bit the sign.  "Negative zero" reads as 0.
+pub fn read_offset(buf: &[u8]) -> i64 {
+    let mut y = (buf[7] & 0x7F) as i64;
+
+    for i in 0..7 {
+        y = y * 256;
+        y += buf[6 - i] as i64;
+    }
+
+    if (buf[7] & 0x80) != 0 {
+        y = -y;
+    }
+
+    y
+}
+
+/// Writes `x` as a bsdiff sign-and-magnitude integer to the first 8 bytes
+/// of `buf`.  `i64::min_value()` has no such encoding: its magnitude needs
+/// all 64 bits.
+pub fn write_offset(buf: &mut [u8], x: i64) {
+    let mut y = if x < 0 { x.wrapping_neg() } else { x };
+
+    for i in 0..8 {
+        buf[i] = y as u8;
+        y -= buf[i] as i64;
+        y /= 256;
+    }
+
+    if x < 0 {
+        buf[7] |= 0x80;
+    }
+}
+
+/// Whether `buf` is the "negative zero" that `read_offset` accepts but
+/// `write_offset` never produces.
+pub fn is_negative_zero(buf: &[u8]) -> bool {
+    buf[7] == 0x80 && buf[..7].iter().all(|&b| b == 0)
+}
+
+/// Longest varint encoding of a `u64`.
+pub const MAX_VARINT_LEN: usize = 10;
+
+/// Encodes `x` as an LEB128 varint into `buf`, returning the number of bytes
+/// used.  `buf` must have room for `MAX_VARINT_LEN` bytes.
+pub fn encode_varint(buf: &mut [u8], mut x: u64) -> usize {
+    let mut n = 0;
+    while x >= 0x80 {
+        buf[n] = x as u8 | 0x80;
+        x >>= 7;
+        n += 1;
+    }
+    buf[n] = x as u8;
+    n + 1
+}
+
+/// Decodes an LEB128 varint from the start of `buf`, returning it and the
+/// number of bytes it took up.  `Ok(None)` means `buf` ends mid-varint;
+/// encodings that are overlong or don't fit in a `u64` are `InvalidData`.
+pub fn decode_varint(buf: &[u8]) -> io::Result<Option<(u64, usize)>> {
+    let mut x = 0u64;
+    for (i, &b) in buf.iter().enumerate().take(MAX_VARINT_LEN) {
+        if i == MAX_VARINT_LEN - 1 && b > 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Varint overflows a u64"));
+        }
+        x |= ((b & 0x7F) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            if b == 0 && i > 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Overlong varint"));
+            }
+            return Ok(Some((x, i + 1)));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn write_varint<W: Write>(mut writer: W, x: u64) -> io::Result<()> {
+    let mut buf = [0u8; MAX_VARINT_LEN];
+    let n = encode_varint(&mut buf, x);
+    writer.write_all(&buf[..n])
+}
+
+/// Reads one varint, failing with `UnexpectedEof` if the stream ends
+/// partway through it.
+pub fn read_varint<R: Read>(mut reader: R) -> io::Result<u64> {
+    let mut buf = [0u8; MAX_VARINT_LEN];
+    for n in 0..MAX_VARINT_LEN {
+        reader.read_exact(&mut buf[n..n + 1])?;
+        if buf[n] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    match decode_varint(&buf)? {
+        Some((x, _)) => Ok(x),
+        None => unreachable!(),
+    }
+}
+
+/// Maps signed values to unsigned ones so that small magnitudes of either
+/// sign make short varints: 0, -1, 1, -2, ... become 0, 1, 2, 3, ...
+pub fn zigzag(x: i64) -> u64 {
+    ((x << 1) ^ (x >> 63)) as u64
+}
+
+pub fn unzigzag(x: u64) -> i64 {
+    ((x >> 1) as i64) ^ -((x & 1) as i64)
+}
+
+pub fn write_signed_varint<W: Write>(writer: W, x: i64) -> io::Result<()> {
+    write_varint(writer, zigzag(x))
+}
+
+pub fn read_signed_varint<R: Read>(reader: R) -> io::Result<i64> {
+    read_varint(reader).map(unzigzag)
+}
+
+/// Fills `buf` with one fixed-size record, returning `false` on a clean end
+/// of stream before its first byte.  A stream that ends partway through the
+/// record is an `UnexpectedEof` error naming `what`.
+pub fn read_record<R: Read>(mut reader: R, buf: &mut [u8], what: &str) -> io::Result<bool> {
+    let mut p = 0;
+    while p < buf.len() {
+        // A read of 0 is taken as the end of the stream, which isn't
+        // strictly true of every reader (e.g. non-blocking sockets).
+        match reader.read(&mut buf[p..]) {
+            Ok(0) if p == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Truncated {}", what))),
+            Ok(size) => p += size,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn assert_identity_encoding(tests: &[i64]) {
+        for test in tests {
+            let mut buf = [0u8; 8];
+
+            write_offset(&mut buf, *test);
+            let result = read_offset(&buf);
+
+            assert_eq!(*test, result);
+        }
+    }
+
+    #[test]
+    fn test_read_write_offset_roundtrip() {
+        assert_identity_encoding(&[
+            0, 1, -1, 2, -2, 3, -3,
+            127, -127, 128, -128, 129, -129,
+            255, -255, 256, -256, 257, -257,
+            16383, -16383, 16384, -16384, 16385, -16385,
+            65535, -65535, 65536, -65536, 65537, -65537,
+            0x7ffffffffffffffe,
+            0x7fffffffffffffff,
+            // -0x8000000000000000 isn't representable; see `write_offset`.
+            -0x7fffffffffffffff,
+        ]);
+    }
+
+    #[test]
+    fn test_offset_layout() {
+        let mut buf = [0u8; 8];
+        write_offset(&mut buf, -0x0102);
+        assert_eq!(buf, [0x02, 0x01, 0, 0, 0, 0, 0, 0x80]);
+
+        assert!(!is_negative_zero(&buf));
+        assert!(is_negative_zero(&[0, 0, 0, 0, 0, 0, 0, 0x80]));
+        assert_eq!(read_offset(&[0, 0, 0, 0, 0, 0, 0, 0x80]), 0);
+    }
+
+    #[test]
+    fn test_fixed_width() {
+        let mut buf = [0u8; 24];
+        write_u64s(&mut buf, &[1, 0x0102, u64::max_value()]);
+        assert_eq!(&buf[..9], &[1, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+
+        let mut xs = [0u64; 3];
+        read_u64s(&buf, &mut xs);
+        assert_eq!(xs, [1, 0x0102, u64::max_value()]);
+
+        write_u32(&mut buf[..4], 0xdeadbeef);
+        assert_eq!(&buf[..4], &[0xef, 0xbe, 0xad, 0xde]);
+        assert_eq!(read_u32(&buf[..4]), 0xdeadbeef);
+
+        write_u16(&mut buf[..2], 0xbeef);
+        assert_eq!(&buf[..2], &[0xef, 0xbe]);
+        assert_eq!(read_u16(&buf[..2]), 0xbeef);
+    }
+
+    #[test]
+    fn test_fixed_width_streams() {
+        let mut buf = Vec::new();
+        write_u8_to(&mut buf, 7).unwrap();
+        write_u16_to(&mut buf, 0x0102).unwrap();
+        write_u32_to(&mut buf, 0x03040506).unwrap();
+        write_u64_to(&mut buf, 0x0708090a0b0c0d0e).unwrap();
+        assert_eq!(buf, [7, 0x02, 0x01, 0x06, 0x05, 0x04, 0x03,
+                         0x0e, 0x0d, 0x0c, 0x0b, 0x0a, 0x09, 0x08, 0x07]);
+
+        let mut r = Cursor::new(&buf[..]);
+        assert_eq!(read_u8_from(&mut r).unwrap(), 7);
+        assert_eq!(read_u16_from(&mut r).unwrap(), 0x0102);
+        assert_eq!(read_u32_from(&mut r).unwrap(), 0x03040506);
+        assert_eq!(read_u64_from(&mut r).unwrap(), 0x0708090a0b0c0d0e);
+        assert_eq!(read_u8_from(&mut r).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        assert_eq!(read_u64_from(Cursor::new(&buf[..7])).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        let values = [0, 1, 127, 128, 255, 300, 16383, 16384, 1 << 35, u64::max_value() - 1, u64::max_value()];
+
+        for &x in values.iter() {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, x).unwrap();
+            assert!(buf.len() <= MAX_VARINT_LEN);
+            assert_eq!(decode_varint(&buf).unwrap(), Some((x, buf.len())));
+            assert_eq!(read_varint(Cursor::new(&buf)).unwrap(), x);
+        }
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300).unwrap();
+        assert_eq!(buf, [0xAC, 0x02]);
+    }
+
+    #[test]
+    fn test_varint_errors() {
+        assert_eq!(decode_varint(&[]).unwrap(), None);
+        assert_eq!(decode_varint(&[0x80, 0x80]).unwrap(), None);
+        assert_eq!(read_varint(Cursor::new(&[0x80u8][..])).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        assert_eq!(decode_varint(&[0x80, 0x00]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let too_big = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02];
+        assert_eq!(decode_varint(&too_big).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let too_long = [0x80; 11];
+        assert_eq!(decode_varint(&too_long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_varint(Cursor::new(&too_long[..])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_signed_varint() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+
+        for &x in [0, 1, -1, 63, -64, 64, i64::max_value(), i64::min_value()].iter() {
+            assert_eq!(unzigzag(zigzag(x)), x);
+
+            let mut buf = Vec::new();
+            write_signed_varint(&mut buf, x).unwrap();
+            assert_eq!(read_signed_varint(Cursor::new(&buf)).unwrap(), x);
+        }
+    }
+
+    #[test]
+    fn test_read_record() {
+        let mut buf = [0u8; 4];
+        let mut r = Cursor::new(&[1u8, 2, 3, 4, 5, 6][..]);
+        assert!(read_record(&mut r, &mut buf, "thing").unwrap());
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        let err = read_record(&mut r, &mut buf, "thing").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(err.to_string(), "Truncated thing");
+
+        assert!(!read_record(&mut r, &mut buf, "thing").unwrap());
+    }
+}
@@ -0,0 +1,127 @@
+//! zstd's "patch from" mode: the new file compressed with the whole old
+//! file as the zstd dictionary, so anything it shares with the old file
+//! becomes a back-reference.
+//!
+//! The layout is the magic, the new file's size (u64), and a single zstd
+//! frame.  There are no commands to decode without the old file, so these
+//! patches can't be transcoded, recompressed or planned from steps.
+//!
+//! zstd only refers back as far as its window, which depends on the level
+//! (a few MiB by default, 8 MiB at `Compression::Best`), and the version
+//! of zstd we build against has no long-distance mode to raise it.  Old
+//! files much bigger than the window only match in their last few MiB;
+//! `diff::auto::generate_smallest` will usually pick another engine for
+//! them.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use zstd;
+
+use format::wire;
+
+pub const MAGIC: &'static [u8] = super::PATCH_FROM_MAGIC;
+pub const NAME: &'static str = "zstd_patch_from";
+
+const HEADER_SIZE: usize = 16;
+
+pub fn is_match(patch: &[u8]) -> bool {
+    patch.starts_with(MAGIC)
+}
+
+/// Writes a patch that turns `old` into `new`, compressed at zstd `level`.
+pub fn generate_patch<W: Write>(old: &[u8], new: &[u8], level: i32, mut patch: W) -> io::Result<()> {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..8].copy_from_slice(MAGIC);
+    wire::write_u64(&mut header[8..], new.len() as u64);
+    patch.write_all(&header)?;
+
+    let mut encoder = zstd::stream::Encoder::with_dictionary(patch, level, old)?;
+    encoder.write_all(new)?;
+    encoder.finish().map(|_| ())
+}
+
+/// Applies a patch to an old file that's already in memory.
+pub fn apply_patch_from_slice<W: Write>(patch: &[u8], old: &[u8], mut new: W) -> io::Result<()> {
+    let new_size = new_size(patch)?;
+
+    // The size is checked as the output is copied rather than trusted for
+    // an allocation.
+    let decoder = zstd::stream::Decoder::with_dictionary(&patch[HEADER_SIZE..], old)?;
+    let written = io::copy(&mut decoder.take(new_size.saturating_add(1)), &mut new)?;
+    if written > new_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Patch produces more than the {} bytes its header says", new_size)));
+    }
+    if written < new_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Patch produces {} bytes, but its header says {}", written, new_size)));
+    }
+
+    Ok(())
+}
+
+/// Applies a patch, reading the whole of `old` into memory first: zstd
+/// needs the dictionary up front.
+pub fn apply_patch<OldRS: Read + Seek, W: Write>(patch: &[u8], mut old: OldRS, new: W) -> io::Result<()> {
+    let mut data = Vec::new();
+    old.seek(SeekFrom::Start(0))?;
+    old.read_to_end(&mut data)?;
+    apply_patch_from_slice(patch, &data, new)
+}
+
+/// The size of the file `patch` produces, from its header.
+pub(crate) fn new_size(patch: &[u8]) -> io::Result<u64> {
+    if patch.len() < HEADER_SIZE || !is_match(patch) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a zstd patch-from patch"));
+    }
+    Ok(wire::read_u64(&patch[8..HEADER_SIZE]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use test_util::noise;
+
+    #[test]
+    fn test_roundtrip() {
+        let old = noise(1, 100000);
+        let mut new = old.clone();
+        new[500..600].copy_from_slice(&noise(2, 100));
+        new.extend_from_slice(b"some new bytes at the end");
+
+        let mut patch = Vec::new();
+        generate_patch(&old, &new, 3, &mut patch).unwrap();
+        assert!(is_match(&patch));
+        assert_eq!(new_size(&patch).unwrap(), new.len() as u64);
+        // The old file is a dictionary, not repeated in the patch.
+        assert!(patch.len() < 2000, "{}", patch.len());
+
+        let mut out = Vec::new();
+        apply_patch(&patch, Cursor::new(&old), &mut out).unwrap();
+        assert!(out == new);
+
+        // Against the wrong old file, the frame doesn't decode to `new`.
+        let mut out = Vec::new();
+        let wrong = noise(3, 100000);
+        assert!(apply_patch_from_slice(&patch, &wrong, &mut out).is_err() || out != new);
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let old = b"this is a test";
+        let mut patch = Vec::new();
+        generate_patch(old, b"this is a test too", 3, &mut patch).unwrap();
+
+        for &size in &[0u64, 17, 19, !0] {
+            let mut bad = patch.clone();
+            wire::write_u64(&mut bad[8..16], size);
+            let err = apply_patch_from_slice(&bad, old, &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", size);
+        }
+
+        let err = apply_patch_from_slice(&patch[..10], old, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,529 @@
+#[cfg(feature = "sha1")]
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Cursor};
+#[cfg(feature = "sha1")]
+use std::io::BufWriter;
+#[cfg(feature = "sha1")]
+use std::path::PathBuf;
+use std::collections::HashMap;
+#[cfg(feature = "sha1")]
+use std::process;
+use std::sync::Mutex;
+#[cfg(feature = "sha1")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "sha1")]
+use sha1::Sha1;
+
+use format::sink::CountingWriter;
+use lru::Lru;
+
+/// Storage for computed suffix arrays, keyed by the digest of the indexed
+/// data.
+pub trait Cache {
+    type Read: io::Read;
+    type Write: io::Write;
+
+    fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>>;
+    fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write>;
+
+    /// Called once everything has been written to a writer from
+    /// `get_writer`.  Until then, the entry must not be visible to `get` --
+    /// implementations that can't write atomically should write to a
+    /// temporary location and move it into place here.
+    fn commit(&self, _digest: &[u8; 20], _writer: Self::Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Drops the entry for `digest`, if there is one.
+    fn remove(&self, _digest: &[u8; 20]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, C: Cache> Cache for &'a C {
+    type Read = C::Read;
+    type Write = C::Write;
+
+    fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>> {
+        (**self).get(digest)
+    }
+
+    fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write> {
+        (**self).get_writer(digest)
+    }
+
+    fn commit(&self, digest: &[u8; 20], writer: Self::Write) -> io::Result<()> {
+        (**self).commit(digest, writer)
+    }
+
+    fn remove(&self, digest: &[u8; 20]) -> io::Result<()> {
+        (**self).remove(digest)
+    }
+}
+
+struct Sizes {
+    entries: Lru<[u8; 20], u64>,
+    total_bytes: u64,
+}
+
+/// Wraps another cache, evicting the least recently used entries once the
+/// entries written through it take up more than `max_bytes`.
+///
+/// Only entries committed through this wrapper count towards the limit; the
+/// most recently committed entry is never evicted, even if it alone is over
+/// the limit.
+pub struct BoundedCache<C> {
+    inner: C,
+    max_bytes: u64,
+    sizes: Mutex<Sizes>,
+}
+
+impl<C: Cache> BoundedCache<C> {
+    pub fn new(inner: C, max_bytes: u64) -> BoundedCache<C> {
+        BoundedCache {
+            inner: inner,
+            max_bytes: max_bytes,
+            sizes: Mutex::new(Sizes {
+                entries: Lru::new(),
+                total_bytes: 0,
+            }),
+        }
+    }
+
+    /// Total size of the entries currently tracked.
+    pub fn total_bytes(&self) -> u64 {
+        self.sizes.lock().unwrap().total_bytes
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Cache> Cache for BoundedCache<C> {
+    type Read = C::Read;
+    type Write = CountingWriter<C::Write>;
+
+    fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>> {
+        let res = self.inner.get(digest)?;
+
+        if res.is_some() {
+            self.sizes.lock().unwrap().entries.touch(digest);
+        }
+
+        Ok(res)
+    }
+
+    fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write> {
+        Ok(CountingWriter::new(self.inner.get_writer(digest)?))
+    }
+
+    fn commit(&self, digest: &[u8; 20], writer: Self::Write) -> io::Result<()> {
+        let size = writer.count();
+        self.inner.commit(digest, writer.into_inner())?;
+
+        let mut sizes = self.sizes.lock().unwrap();
+
+        if let Some(old_size) = sizes.entries.insert(*digest, size) {
+            sizes.total_bytes -= old_size;
+        }
+        sizes.total_bytes += size;
+
+        while sizes.total_bytes > self.max_bytes && sizes.entries.len() > 1 {
+            let (evicted, evicted_size) = sizes.entries.pop_oldest().unwrap();
+            sizes.total_bytes -= evicted_size;
+            self.inner.remove(&evicted)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, digest: &[u8; 20]) -> io::Result<()> {
+        self.inner.remove(digest)?;
+
+        let mut sizes = self.sizes.lock().unwrap();
+        if let Some(size) = sizes.entries.remove(digest) {
+            sizes.total_bytes -= size;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sha1")]
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "sha1")]
+fn hex(digest: &[u8; 20]) -> String {
+    let mut s = String::with_capacity(40);
+    for byte in digest.iter() {
+        s.push_str(&format!("{:02x}", byte));
+    }
+    s
+}
+
+/// A cache storing each entry as a file named after its digest in `dir`.
+///
+/// Entries are written to a temporary file, synced and renamed into place on
+/// commit, so readers never see a partially written entry.  Each file ends
+/// with a SHA-1 of its contents; entries that fail that check on read are
+/// deleted and treated as missing.
+#[cfg(feature = "sha1")]
+pub struct FsCache {
+    dir: PathBuf,
+}
+
+#[cfg(feature = "sha1")]
+impl FsCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<FsCache> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FsCache {
+            dir: dir
+        })
+    }
+
+    fn path(&self, digest: &[u8; 20]) -> PathBuf {
+        self.dir.join(hex(digest))
+    }
+}
+
+#[cfg(feature = "sha1")]
+pub struct FsCacheWriter {
+    file: Option<BufWriter<File>>,
+    temp_path: PathBuf,
+    sha1: Sha1,
+}
+
+#[cfg(feature = "sha1")]
+impl Write for FsCacheWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.as_mut().unwrap().write(buf)?;
+        self.sha1.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl Drop for FsCacheWriter {
+    fn drop(&mut self) {
+        // Never committed; don't leave the temp file lying around.
+        if self.file.is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl Cache for FsCache {
+    type Read = Cursor<Vec<u8>>;
+    type Write = FsCacheWriter;
+
+    fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>> {
+        let path = self.path(digest);
+
+        let mut contents = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => { file.read_to_end(&mut contents)?; }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let valid = contents.len() >= 20 && {
+            let (body, checksum) = contents.split_at(contents.len() - 20);
+            let mut sha1 = Sha1::new();
+            sha1.update(body);
+            &sha1.digest().bytes()[..] == checksum
+        };
+
+        if !valid {
+            let _ = fs::remove_file(&path);
+            return Ok(None);
+        }
+
+        let len = contents.len() - 20;
+        contents.truncate(len);
+
+        Ok(Some(Cursor::new(contents)))
+    }
+
+    fn get_writer(&self, digest: &[u8; 20]) -> io::Result<Self::Write> {
+        let temp_path = self.dir.join(format!("{}.{}-{}.tmp",
+            hex(digest),
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::SeqCst)));
+
+        let file = OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+
+        Ok(FsCacheWriter {
+            file: Some(BufWriter::new(file)),
+            temp_path: temp_path,
+            sha1: Sha1::new(),
+        })
+    }
+
+    fn commit(&self, digest: &[u8; 20], mut writer: Self::Write) -> io::Result<()> {
+        {
+            let file = writer.file.as_mut().unwrap();
+            file.write_all(&writer.sha1.digest().bytes())?;
+            file.flush()?;
+            file.get_ref().sync_all()?;
+        }
+
+        fs::rename(&writer.temp_path, self.path(digest))?;
+
+        writer.file = None;
+        Ok(())
+    }
+
+    fn remove(&self, digest: &[u8; 20]) -> io::Result<()> {
+        match fs::remove_file(self.path(digest)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+/// An in-memory cache, mostly useful for tests and long-running services.
+pub struct MemCache {
+    entries: Mutex<HashMap<[u8; 20], Vec<u8>>>,
+}
+
+impl MemCache {
+    pub fn new() -> MemCache {
+        MemCache {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for MemCache {
+    fn default() -> MemCache {
+        MemCache::new()
+    }
+}
+
+impl Cache for MemCache {
+    type Read = Cursor<Vec<u8>>;
+    type Write = Vec<u8>;
+
+    fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>> {
+        Ok(self.entries.lock().unwrap().get(digest).map(|e| Cursor::new(e.clone())))
+    }
+
+    fn get_writer(&self, _digest: &[u8; 20]) -> io::Result<Self::Write> {
+        Ok(Vec::new())
+    }
+
+    fn commit(&self, digest: &[u8; 20], writer: Self::Write) -> io::Result<()> {
+        self.entries.lock().unwrap().insert(*digest, writer);
+        Ok(())
+    }
+
+    fn remove(&self, digest: &[u8; 20]) -> io::Result<()> {
+        self.entries.lock().unwrap().remove(digest);
+        Ok(())
+    }
+}
+
+/// Adapts any key-value store to a `Cache`, given functions to load and store
+/// whole entries.  Entries are buffered in memory while being written and
+/// handed to `put` on commit.
+///
+/// ```ignore
+/// let cache = KvCache::new(
+///     |digest| redis_get(digest),
+///     |digest, value| redis_set(digest, value));
+/// ```
+pub struct KvCache<G, P> {
+    get: G,
+    put: P,
+}
+
+impl<G, P> KvCache<G, P>
+    where
+        G: Fn(&[u8; 20]) -> io::Result<Option<Vec<u8>>>,
+        P: Fn(&[u8; 20], Vec<u8>) -> io::Result<()>
+{
+    pub fn new(get: G, put: P) -> KvCache<G, P> {
+        KvCache {
+            get: get,
+            put: put,
+        }
+    }
+}
+
+impl<G, P> Cache for KvCache<G, P>
+    where
+        G: Fn(&[u8; 20]) -> io::Result<Option<Vec<u8>>>,
+        P: Fn(&[u8; 20], Vec<u8>) -> io::Result<()>
+{
+    type Read = Cursor<Vec<u8>>;
+    type Write = Vec<u8>;
+
+    fn get(&self, digest: &[u8; 20]) -> io::Result<Option<Self::Read>> {
+        Ok((self.get)(digest)?.map(Cursor::new))
+    }
+
+    fn get_writer(&self, _digest: &[u8; 20]) -> io::Result<Self::Write> {
+        Ok(Vec::new())
+    }
+
+    fn commit(&self, digest: &[u8; 20], writer: Self::Write) -> io::Result<()> {
+        (self.put)(digest, writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    fn put<C: Cache>(cache: &C, digest: &[u8; 20], size: usize) {
+        let mut w = cache.get_writer(digest).unwrap();
+        w.write_all(&vec![0u8; size]).unwrap();
+        cache.commit(digest, w).unwrap();
+    }
+
+    #[test]
+    fn test_bounded_cache_evicts_lru() {
+        let cache = BoundedCache::new(MemCache::new(), 100);
+
+        put(&cache, &[1; 20], 40);
+        put(&cache, &[2; 20], 40);
+        assert!(cache.get(&[1; 20]).unwrap().is_some());
+
+        // [2] is now the least recently used.
+        put(&cache, &[3; 20], 40);
+
+        assert!(cache.get(&[1; 20]).unwrap().is_some());
+        assert!(cache.get(&[2; 20]).unwrap().is_none());
+        assert!(cache.get(&[3; 20]).unwrap().is_some());
+        assert_eq!(cache.total_bytes(), 80);
+    }
+
+    #[cfg(feature = "sha1")]
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("rsdiff-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_fs_cache_roundtrip() {
+        let dir = temp_dir("fs-cache-roundtrip");
+        let cache = FsCache::new(&dir).unwrap();
+
+        assert!(cache.get(&[1; 20]).unwrap().is_none());
+
+        let mut w = cache.get_writer(&[1; 20]).unwrap();
+        w.write_all(b"this is a test").unwrap();
+        assert!(cache.get(&[1; 20]).unwrap().is_none());
+        cache.commit(&[1; 20], w).unwrap();
+
+        let mut contents = Vec::new();
+        cache.get(&[1; 20]).unwrap().unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(&contents[..], b"this is a test");
+
+        cache.remove(&[1; 20]).unwrap();
+        assert!(cache.get(&[1; 20]).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_fs_cache_detects_corruption() {
+        let dir = temp_dir("fs-cache-corruption");
+        let cache = FsCache::new(&dir).unwrap();
+
+        put(&cache, &[2; 20], 100);
+
+        let path = cache.path(&[2; 20]);
+        let mut contents = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        contents[10] ^= 0xff;
+        File::create(&path).unwrap().write_all(&contents).unwrap();
+
+        assert!(cache.get(&[2; 20]).unwrap().is_none());
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_fs_cache_abandoned_writer_cleans_up() {
+        let dir = temp_dir("fs-cache-abandoned");
+        let cache = FsCache::new(&dir).unwrap();
+
+        {
+            let mut w = cache.get_writer(&[3; 20]).unwrap();
+            w.write_all(b"partial").unwrap();
+        }
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_mem_cache_index_roundtrip() {
+        use index::Index;
+
+        let cache = MemCache::new();
+        let data = b"this is a test 12345678 test".to_vec();
+
+        let computed = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        let cached = Index::from_cache_or_compute(&cache, data).unwrap();
+        assert_eq!(computed.offsets, cached.offsets);
+    }
+
+    #[test]
+    fn test_kv_cache() {
+        let store: RefCell<HashMap<[u8; 20], Vec<u8>>> = RefCell::new(HashMap::new());
+
+        let cache = KvCache::new(
+            |digest: &[u8; 20]| Ok(store.borrow().get(digest).cloned()),
+            |digest: &[u8; 20], value| {
+                store.borrow_mut().insert(*digest, value);
+                Ok(())
+            });
+
+        put(&cache, &[4; 20], 10);
+
+        assert_eq!(store.borrow().get(&[4; 20]).map(|v| v.len()), Some(10));
+        assert!(cache.get(&[4; 20]).unwrap().is_some());
+        assert!(cache.get(&[5; 20]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_bounded_cache_uncommitted_invisible() {
+        let cache = BoundedCache::new(MemCache::new(), 100);
+
+        let mut w = cache.get_writer(&[1; 20]).unwrap();
+        w.write_all(b"partial").unwrap();
+        assert!(cache.get(&[1; 20]).unwrap().is_none());
+
+        cache.commit(&[1; 20], w).unwrap();
+        assert!(cache.get(&[1; 20]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,272 @@
+//! An FM-index over the old file.
+//!
+//! We keep the Burrows-Wheeler transform of the *reversed* old file, along
+//! with sampled occurrence counts and a sampled suffix array.  Backward search
+//! over the reversed text walks the query forwards, so each byte of the new
+//! file narrows the candidate range and we can stop as soon as it empties --
+//! which is exactly what `longest_match` needs.
+//!
+//! On top of the data itself this costs about 2.5 bytes per input byte,
+//! against a full `usize` per byte for the suffix array in `Index`.  The price
+//! is slower lookups, since counts between checkpoints are found by scanning.
+
+use std::mem;
+use std::ops::Range;
+
+use index::{Index, Matcher};
+
+/// Number of BWT rows between occurrence-count checkpoints.
+const OCC_RATE: usize = 512;
+
+/// Every text position that's a multiple of this has its suffix array entry
+/// stored.
+const SA_RATE: usize = 32;
+
+pub struct FmIndex {
+    data: Vec<u8>,
+
+    bwt: Vec<u8>,
+
+    // The row whose BWT entry is the (virtual) end-of-text marker.  `bwt`
+    // holds a zero there, which must not be counted.
+    primary: usize,
+
+    // Row at which the suffixes starting with each byte begin.
+    starts: Vec<usize>,
+
+    // Occurrence counts of every byte before each checkpoint row, 256 per
+    // checkpoint.
+    occ: Vec<u32>,
+
+    // One bit per row: is this row's suffix array entry stored in `samples`?
+    sampled: Vec<u64>,
+
+    // Number of set bits in `sampled` before each word.
+    sampled_rank: Vec<u32>,
+
+    samples: Vec<u32>,
+}
+
+impl FmIndex {
+    pub fn compute(data: Vec<u8>) -> FmIndex {
+        assert!(data.len() < u32::max_value() as usize);
+
+        let n = data.len();
+        let rows = n + 1;
+
+        let reversed = Index::compute(data.iter().rev().cloned().collect());
+
+        let mut bwt = Vec::with_capacity(rows);
+        let mut primary = 0;
+        let mut sampled = vec![0u64; (rows + 63) / 64];
+        let mut samples = Vec::with_capacity(rows / SA_RATE + 1);
+
+        for row in 0..rows {
+            // Row 0 is the empty suffix, which sorts before everything else.
+            let pos = if row == 0 { n } else { reversed.offsets[row - 1] };
+
+            if pos == 0 {
+                primary = row;
+                bwt.push(0);
+            } else {
+                bwt.push(reversed.data[pos - 1]);
+            }
+
+            if pos % SA_RATE == 0 {
+                sampled[row / 64] |= 1 << (row % 64);
+                samples.push(pos as u32);
+            }
+        }
+
+        let mut sampled_rank = Vec::with_capacity(sampled.len());
+        let mut total = 0;
+        for word in &sampled {
+            sampled_rank.push(total);
+            total += word.count_ones();
+        }
+
+        let mut counts = [0usize; 256];
+        for &b in &data {
+            counts[b as usize] += 1;
+        }
+
+        let mut starts = Vec::with_capacity(256);
+        let mut total = 1;
+        for c in 0..256 {
+            starts.push(total);
+            total += counts[c];
+        }
+
+        let mut occ = Vec::with_capacity((rows / OCC_RATE + 1) * 256);
+        let mut running = [0u32; 256];
+        for row in 0..rows {
+            if row % OCC_RATE == 0 {
+                occ.extend_from_slice(&running);
+            }
+            if row != primary {
+                running[bwt[row] as usize] += 1;
+            }
+        }
+        if rows % OCC_RATE == 0 {
+            occ.extend_from_slice(&running);
+        }
+
+        FmIndex {
+            data: data,
+            bwt: bwt,
+            primary: primary,
+            starts: starts,
+            occ: occ,
+            sampled: sampled,
+            sampled_rank: sampled_rank,
+            samples: samples,
+        }
+    }
+
+    /// Approximate heap memory held by the index, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.data.len()
+            + self.bwt.len()
+            + self.starts.len() * mem::size_of::<usize>()
+            + self.occ.len() * mem::size_of::<u32>()
+            + self.sampled.len() * mem::size_of::<u64>()
+            + self.sampled_rank.len() * mem::size_of::<u32>()
+            + self.samples.len() * mem::size_of::<u32>()
+    }
+
+    /// Number of occurrences of `c` in `bwt[..row]`.
+    fn occ(&self, c: u8, row: usize) -> usize {
+        let checkpoint = row / OCC_RATE;
+        let mut count = self.occ[checkpoint * 256 + c as usize] as usize;
+
+        for r in checkpoint * OCC_RATE .. row {
+            if self.bwt[r] == c && r != self.primary {
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    /// Maps a row to the row of the suffix one position earlier in the text.
+    fn lf(&self, row: usize) -> usize {
+        let c = self.bwt[row];
+        self.starts[c as usize] + self.occ(c, row)
+    }
+
+    /// Finds the position in the reversed text of the suffix at `row`.
+    fn locate(&self, mut row: usize) -> usize {
+        let mut steps = 0;
+
+        loop {
+            let word = self.sampled[row / 64];
+            let bit = 1u64 << (row % 64);
+
+            if word & bit != 0 {
+                let rank = self.sampled_rank[row / 64] + (word & (bit - 1)).count_ones();
+                return self.samples[rank as usize] as usize + steps;
+            }
+
+            row = self.lf(row);
+            steps += 1;
+        }
+    }
+}
+
+impl Matcher for FmIndex {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        let n = self.data.len();
+
+        let (mut lo, mut hi) = (0, n + 1);
+        let mut len = 0;
+
+        for &c in buf {
+            let start = self.starts[c as usize];
+            let next_lo = start + self.occ(c, lo);
+            let next_hi = start + self.occ(c, hi);
+
+            if next_lo >= next_hi {
+                break;
+            }
+
+            lo = next_lo;
+            hi = next_hi;
+            len += 1;
+        }
+
+        if len == 0 {
+            return 0 .. 0;
+        }
+
+        // Row `lo` holds the reversed match starting at `reversed_pos`, so in
+        // the original text the match ends `reversed_pos` bytes from the end.
+        let reversed_pos = self.locate(lo);
+        let start = n - reversed_pos - len;
+
+        start .. start + len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use format::bsdiff;
+
+    fn assert_same_match_lengths(old: &[u8], queries: &[&[u8]]) {
+        let index = Index::compute(old.to_vec());
+        let fm = FmIndex::compute(old.to_vec());
+
+        for query in queries {
+            let expected = index.longest_match(query);
+            let actual = fm.longest_match(query);
+
+            assert_eq!(expected.len(), actual.len());
+            assert_eq!(&old[actual.clone()], &query[..actual.len()]);
+        }
+    }
+
+    #[test]
+    fn test_fm_index_longest_match() {
+        assert_same_match_lengths(b"this is a test 12345678 test", &[
+            b"",
+            b"this is a test",
+            b"test",
+            b"12345678 test and more",
+            b"s a t",
+            b"zzz",
+            b" test",
+        ]);
+
+        assert_same_match_lengths(b"", &[b"", b"a"]);
+        assert_same_match_lengths(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", &[b"aaaa", b"aab", b"b"]);
+    }
+
+    #[test]
+    fn test_fm_index_long_input() {
+        let old = (0..5000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+
+        let queries = (0..50)
+            .map(|i| old[i * 97 .. i * 97 + 200].to_vec())
+            .collect::<Vec<_>>();
+
+        assert_same_match_lengths(&old, &queries.iter().map(|q| &q[..]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_fm_index_patch_roundtrip() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let patch = bsdiff::generate_full_patch(&FmIndex::compute(old.to_vec()), &new[..]);
+
+        let mut out = Vec::new();
+        bsdiff::apply_patch(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+
+        assert_eq!(&new[..], &out[..]);
+    }
+}
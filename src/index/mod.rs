@@ -0,0 +1,754 @@
+use std::io::{self, Read};
+#[cfg(feature = "sha1")]
+use std::io::{Write, BufReader};
+use std::cmp::{min, Ordering};
+use std::ops::Range;
+use std::mem;
+
+#[cfg(feature = "sha1")]
+use sha1::Sha1;
+
+#[cfg(feature = "sha1")]
+use format::wire;
+
+mod cache;
+mod fm;
+mod small;
+
+pub use self::cache::{Cache, BoundedCache, MemCache, KvCache};
+#[cfg(feature = "sha1")]
+pub use self::cache::{FsCache, FsCacheWriter};
+pub use self::fm::FmIndex;
+pub use self::small::{AutoIndex, SmallIndex, SMALL_INDEX_THRESHOLD};
+
+/// Version of the cached suffix array layout, recorded in each entry and
+/// mixed into its key.
+#[cfg(feature = "sha1")]
+const VERSION: u8 = 7;
+
+/// A cached suffix array starts with this, `VERSION`, the key it's stored
+/// under and the number of offsets.  The offsets follow as little-endian
+/// u64s, then a SHA-1 of everything before it.
+#[cfg(feature = "sha1")]
+const CACHE_MAGIC: &[u8] = b"RSDSA";
+
+#[cfg(feature = "sha1")]
+const CACHE_HEADER_SIZE: usize = 5 + 1 + 20 + 8;
+
+/// Offsets read or written at a time.
+#[cfg(feature = "sha1")]
+const CACHE_CHUNK: usize = 4096;
+
+/// The common interface of the old-file indexes used by `MatchIter`.
+pub trait Matcher {
+    /// The old file's contents.
+    fn data(&self) -> &[u8];
+
+    /// Finds the longest prefix of `buf` that occurs somewhere in the old
+    /// file, returning its location.
+    fn longest_match(&self, buf: &[u8]) -> Range<usize>;
+}
+
+/// A change to indexed data: `removed` bytes starting at `offset` are
+/// replaced with `inserted`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub offset: usize,
+    pub removed: usize,
+    pub inserted: Vec<u8>,
+}
+
+/// A suffix array over the old file.
+///
+/// Matching only ever reads the index, so it's `Send + Sync` and one index
+/// can be shared (e.g. behind an `Arc`) by any number of threads diffing
+/// against the same old file.
+pub struct Index {
+    pub data: Vec<u8>,
+    pub(crate) offsets: Vec<usize>,
+}
+
+impl Index {
+    #[cfg(feature = "sha1")]
+    pub fn from_cache_or_compute<C: Cache>(cache: C, data: Vec<u8>) -> io::Result<Index> {
+        let digest = digest_of(&data);
+        Index::from_cache_or_sort(cache, data, digest)
+    }
+
+    /// Like `from_cache_or_compute`, given the SHA-1 of `data`.
+    #[cfg(feature = "sha1")]
+    fn from_cache_or_sort<C: Cache>(cache: C, data: Vec<u8>, digest: [u8; 20]) -> io::Result<Index> {
+        enter_span!("Index::from_cache_or_compute");
+
+        let key = cache_key(&digest);
+
+        if let Some(r) = cache.get(&key)? {
+            match read_cached_offsets(r, &key, data.len()) {
+                Ok(offsets) => {
+                    info_event!("loaded index for {} bytes from cache", data.len());
+
+                    return Ok(Index {
+                        data: data,
+                        offsets: offsets,
+                    })
+                }
+                // Written by another version, or damaged: start over.
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::UnexpectedEof => {
+                    info_event!("discarding cached index: {}", e);
+                    cache.remove(&key)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let res = Index::compute(data);
+
+        let mut w = cache.get_writer(&key)?;
+        res.serialize_to(&key, &mut w)?;
+        cache.commit(&key, w)?;
+
+        info_event!("computed and cached index for {} bytes", res.data.len());
+
+        Ok(res)
+    }
+
+    pub fn compute(data: Vec<u8>) -> Index {
+        enter_span!("Index::compute");
+
+        let mut offsets = Vec::new();
+
+        for i in 0..data.len() as usize {
+            offsets.push(i);
+        }
+
+        sort_suffixes(&data, &mut offsets);
+
+        Index {
+            data: data,
+            offsets: offsets,
+        }
+    }
+
+    /// Like `compute`, also returning the SHA-1 of `data` (as recorded by
+    /// `format::source_digest`, and used by `service::IndexRegistry`).
+    #[cfg(feature = "sha1")]
+    pub fn compute_with_digest(data: Vec<u8>) -> (Index, [u8; 20]) {
+        let digest = digest_of(&data);
+        (Index::compute(data), digest)
+    }
+
+    /// Indexes everything `r` produces, for old files arriving from a pipe
+    /// or a network stream.
+    ///
+    /// This isn't incremental: the whole stream is read, 64 KiB at a time,
+    /// into the index's own buffer before any sorting starts, since where a
+    /// suffix sorts can depend on any of the bytes after it.  Memory isn't
+    /// bounded either, beyond not holding the data twice: with an accurate
+    /// `len_hint`, peak use is that of the finished index plus one chunk,
+    /// and a longer stream grows the buffers as a `Vec` would.
+    pub fn from_reader<R: Read>(mut r: R, len_hint: usize) -> io::Result<Index> {
+        enter_span!("Index::from_reader");
+
+        let mut data = Vec::with_capacity(len_hint);
+        let mut offsets = Vec::with_capacity(len_hint);
+        let mut chunk = vec![0u8; 64 * 1024];
+
+        loop {
+            let n = match r.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            let len = data.len();
+            data.extend_from_slice(&chunk[..n]);
+            offsets.extend(len..len + n);
+        }
+
+        sort_suffixes(&data, &mut offsets);
+
+        info_event!("indexed {} bytes from a reader", data.len());
+
+        Ok(Index {
+            data: data,
+            offsets: offsets,
+        })
+    }
+
+    /// Applies `edits` to the indexed data, updating the suffix array in place
+    /// of recomputing it from scratch.
+    ///
+    /// `edits` must be sorted by offset and must not overlap; all offsets refer
+    /// to the data as it was before the update.  Fails with `InvalidInput`,
+    /// leaving the index as it was, if they aren't.
+    ///
+    /// Only suffixes whose order can change are sorted again: those that
+    /// start in inserted bytes, and those that start shortly before an edit,
+    /// where the bytes up to the edit also occur somewhere else.  The rest
+    /// keep their order, so a few small edits cost a linear pass over the
+    /// array (every offset after an edit moves) plus work in proportion to
+    /// the edits, rather than a full sort.
+    pub fn update(&mut self, edits: &[Edit]) -> io::Result<()> {
+        let mut prev = 0;
+        for edit in edits {
+            if edit.offset < prev {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Edits must be sorted and not overlap"));
+            }
+            prev = match edit.offset.checked_add(edit.removed) {
+                Some(end) if end <= self.data.len() => end,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("Edit at {} runs past the end of the {}-byte data", edit.offset, self.data.len()))),
+            };
+        }
+
+        // For each edit, the old bytes whose suffixes leave the array: the
+        // ones it removes, and the unstable ones just before it.
+        let mut dropped = Vec::with_capacity(edits.len());
+        let mut prev = 0;
+        for edit in edits {
+            dropped.push(self.unstable_before(prev, edit.offset) .. edit.offset + edit.removed);
+            prev = edit.offset + edit.removed;
+        }
+
+        let mut data = Vec::with_capacity(self.data.len());
+        let mut shifts = Vec::with_capacity(edits.len() + 1);
+        let mut reinserted = Vec::new();
+        let mut prev = 0;
+        let mut shift = 0isize;
+        shifts.push(shift);
+
+        for (edit, dropped) in edits.iter().zip(&dropped) {
+            data.extend_from_slice(&self.data[prev..edit.offset]);
+            let start = (dropped.start as isize + shift) as usize;
+            data.extend_from_slice(&edit.inserted);
+            reinserted.extend(start .. data.len());
+
+            prev = edit.offset + edit.removed;
+            shift += edit.inserted.len() as isize - edit.removed as isize;
+            shifts.push(shift);
+        }
+
+        data.extend_from_slice(&self.data[prev..]);
+
+        // The suffixes that stay keep their order, at their new offsets.
+        let mut kept = Vec::with_capacity(self.offsets.len());
+        for &offset in &self.offsets {
+            // Number of edits whose dropped range ends at or before `offset`.
+            let k = match dropped.binary_search_by(|r| {
+                if r.end <= offset { Ordering::Less } else { Ordering::Greater }
+            }) {
+                Ok(k) | Err(k) => k,
+            };
+
+            if k == dropped.len() || offset < dropped[k].start {
+                kept.push((offset as isize + shifts[k]) as usize);
+            }
+        }
+
+        sort_suffixes(&data, &mut reinserted);
+
+        let mut offsets = Vec::with_capacity(data.len());
+        let mut rest = &kept[..];
+        for &offset in &reinserted {
+            let at = match rest.binary_search_by(|&v| data[v..].cmp(&data[offset..])) {
+                Ok(at) | Err(at) => at,
+            };
+            offsets.extend_from_slice(&rest[..at]);
+            offsets.push(offset);
+            rest = &rest[at..];
+        }
+        offsets.extend_from_slice(rest);
+
+        self.data = data;
+        self.offsets = offsets;
+        Ok(())
+    }
+
+    /// The first offset in `start..end` whose suffix's order could change
+    /// when the data from `end` on does.  That's any whose bytes up to `end`
+    /// occur elsewhere, since a suffix that shares them with another is
+    /// only told apart from it by the bytes that change.  Once those bytes
+    /// are unique, they are for every earlier offset too, so the answer is
+    /// found by bisection.
+    fn unstable_before(&self, start: usize, end: usize) -> usize {
+        let (mut lo, mut hi) = (start, end);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.occurrences(&self.data[mid..end]) > 1 {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        lo
+    }
+
+    /// How many suffixes start with `prefix`.
+    fn occurrences(&self, prefix: &[u8]) -> usize {
+        let bound = |after_equal: bool| {
+            match self.offsets.binary_search_by(|&v| {
+                let s = &self.data[v..min(v + prefix.len(), self.data.len())];
+                match s.cmp(prefix) {
+                    Ordering::Equal if after_equal => Ordering::Less,
+                    Ordering::Equal => Ordering::Greater,
+                    other => other,
+                }
+            }) {
+                Ok(i) | Err(i) => i,
+            }
+        };
+        bound(true) - bound(false)
+    }
+
+    #[cfg(feature = "sha1")]
+    fn serialize_to<W: Write>(&self, key: &[u8; 20], mut w: W) -> io::Result<()> {
+        let mut header = Vec::with_capacity(CACHE_HEADER_SIZE);
+        header.extend_from_slice(CACHE_MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(key);
+        wire::write_u64_to(&mut header, self.offsets.len() as u64)?;
+
+        let mut sha1 = Sha1::new();
+        sha1.update(&header);
+        w.write_all(&header)?;
+
+        let mut chunk = Vec::with_capacity(CACHE_CHUNK * 8);
+        for offsets in self.offsets.chunks(CACHE_CHUNK) {
+            chunk.clear();
+            for &offset in offsets {
+                wire::write_u64_to(&mut chunk, offset as u64)?;
+            }
+            sha1.update(&chunk);
+            w.write_all(&chunk)?;
+        }
+
+        w.write_all(&sha1.digest().bytes())
+    }
+
+    /// Approximate heap memory held by the index, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.data.len() + self.offsets.len() * mem::size_of::<usize>()
+    }
+}
+
+/// Collects an old file written to it piece by piece, hashing it on the
+/// way in, so the file is only read once for both its index and its SHA-1.
+///
+/// ```
+/// use std::io::{self, Write};
+/// use rsdiff::index::{IndexBuilder, MemCache};
+///
+/// let mut builder = IndexBuilder::new(0);
+/// io::copy(&mut &b"this is a test"[..], &mut builder).unwrap();
+/// let (index, digest) = builder.finish_cached(&MemCache::new()).unwrap();
+/// # let _ = (index, digest);
+/// ```
+#[cfg(feature = "sha1")]
+pub struct IndexBuilder {
+    data: Vec<u8>,
+    sha1: Sha1,
+}
+
+#[cfg(feature = "sha1")]
+impl IndexBuilder {
+    /// A builder expecting about `len_hint` bytes.
+    pub fn new(len_hint: usize) -> IndexBuilder {
+        IndexBuilder {
+            data: Vec::with_capacity(len_hint),
+            sha1: Sha1::new(),
+        }
+    }
+
+    /// The SHA-1 of everything written so far.
+    pub fn digest(&self) -> [u8; 20] {
+        self.sha1.digest().bytes()
+    }
+
+    /// Indexes everything written.
+    pub fn finish(self) -> (Index, [u8; 20]) {
+        let digest = self.sha1.digest().bytes();
+        (Index::compute(self.data), digest)
+    }
+
+    /// Like `finish`, loading the suffix array from `cache` if it's there
+    /// and storing it if not; see `Index::from_cache_or_compute`.
+    pub fn finish_cached<C: Cache>(self, cache: C) -> io::Result<(Index, [u8; 20])> {
+        let digest = self.sha1.digest().bytes();
+        Index::from_cache_or_sort(cache, self.data, digest).map(|index| (index, digest))
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl Write for IndexBuilder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sha1.update(buf);
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sha1")]
+fn digest_of(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    sha1.digest().bytes()
+}
+
+/// Reads a suffix array written by `Index::serialize_to` for `len` bytes of
+/// data.  Anything amiss (another version's layout, another file, a
+/// truncated or corrupted entry) is an `InvalidData` or `UnexpectedEof`
+/// error.
+#[cfg(feature = "sha1")]
+fn read_cached_offsets<R: Read>(r: R, key: &[u8; 20], len: usize) -> io::Result<Vec<usize>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Cached index: {}", msg));
+
+    let mut r = BufReader::new(r);
+    let mut header = [0u8; CACHE_HEADER_SIZE];
+    r.read_exact(&mut header)?;
+
+    if &header[..CACHE_MAGIC.len()] != CACHE_MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    if header[CACHE_MAGIC.len()] != VERSION {
+        return Err(invalid(&format!("version {}, expected {}", header[CACHE_MAGIC.len()], VERSION)));
+    }
+    if &header[CACHE_MAGIC.len() + 1 .. CACHE_MAGIC.len() + 21] != key {
+        return Err(invalid("for another file"));
+    }
+    if wire::read_u64(&header[CACHE_MAGIC.len() + 21 ..]) != len as u64 {
+        return Err(invalid("wrong length"));
+    }
+
+    let mut sha1 = Sha1::new();
+    sha1.update(&header);
+
+    let mut offsets = Vec::with_capacity(len);
+    let mut chunk = vec![0u8; CACHE_CHUNK * 8];
+    while offsets.len() < len {
+        let n = min(CACHE_CHUNK, len - offsets.len());
+        let chunk = &mut chunk[..n * 8];
+        r.read_exact(chunk)?;
+        sha1.update(chunk);
+
+        for bytes in chunk.chunks(8) {
+            let offset = wire::read_u64(bytes);
+            if offset >= len as u64 {
+                return Err(invalid("offset out of range"));
+            }
+            offsets.push(offset as usize);
+        }
+    }
+
+    let mut checksum = [0u8; 20];
+    r.read_exact(&mut checksum)?;
+    if checksum != sha1.digest().bytes() {
+        return Err(invalid("bad checksum"));
+    }
+
+    Ok(offsets)
+}
+
+/// The key a suffix array is cached under: the file's SHA-1, salted with
+/// `VERSION` so that changes to the layout don't load stale entries.
+#[cfg(feature = "sha1")]
+fn cache_key(digest: &[u8; 20]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(&[VERSION]);
+    sha1.update(digest);
+    sha1.digest().bytes()
+}
+
+impl Matcher for Index {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        let res = self.offsets.binary_search_by(|&v| {
+            let mut i = 0;
+            let v = &self.data[v..];
+            // println!("looking for {:?} in {:?} ",
+            //    ::std::str::from_utf8(buf).unwrap(),
+            //    ::std::str::from_utf8(v).unwrap());
+            let l = min(buf.len(), v.len());
+            while i < l {
+                if v[i] != buf[i] {
+                    return if v[i] < buf[i] {
+                        // println!("returning Less");
+                        Ordering::Less
+                    } else {
+                        // println!("returning Greater");
+                        Ordering::Greater
+                    };
+                }
+                i += 1;
+            }
+            if v.len() < buf.len() {
+                // println!("returning Less");
+                Ordering::Less
+            } else if v.len() > buf.len() {
+                // println!("returning Greater");
+                Ordering::Greater
+            } else {
+                // println!("returning Equal");
+                Ordering::Equal
+            }
+        });
+
+        // println!("found [{}] at {:?}", unsafe { str::from_utf8_unchecked(buf) }, res);
+
+        let (start, len) = match res {
+            Ok(index) => {
+                let start = self.offsets[index];
+                let len = longest_prefix(buf, &self.data[start..]);
+                (start, len)
+            }
+            Err(index) => {
+                let lower_start = if index > 0 {
+                    self.offsets[index - 1]
+                } else {
+                    self.data.len()
+                };
+
+                let upper_start = if index < self.offsets.len() {
+                    self.offsets[index]
+                } else {
+                    self.data.len()
+                };
+
+                let lower_len = longest_prefix(buf, &self.data[lower_start..]);
+                let upper_len = longest_prefix(buf, &self.data[upper_start..]);
+
+                if lower_len > upper_len {
+                    (lower_start, lower_len)
+                } else {
+                    (upper_start, upper_len)
+                }
+            }
+        };
+
+        start .. start + len
+    }
+}
+
+fn sort_suffixes(data: &[u8], offsets: &mut [usize]) {
+    offsets.sort_by(|a, b| {
+        let sa = &data[*a as usize..];
+        let sb = &data[*b as usize..];
+
+        let mut i = 0;
+        let l = min(sb.len(), sa.len());
+        while i < l {
+            if sa[i] != sb[i] {
+                return if sa[i] < sb[i] {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                };
+            }
+            i += 1;
+        }
+        if sa.len() < sb.len() {
+            Ordering::Less
+        } else if sa.len() > sb.len() {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    });
+}
+
+pub(crate) fn longest_prefix(a: &[u8], b: &[u8]) -> usize {
+    let mut i = 0;
+    let l = min(a.len(), b.len());
+    while i < l {
+        if a[i] != b[i] {
+            break;
+        }
+        i += 1;
+    }
+    return i;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::noise;
+
+    fn assert_update_matches_compute(old: &[u8], edits: &[Edit]) {
+        let mut updated = Index::compute(old.to_vec());
+        updated.update(edits).unwrap();
+
+        let computed = Index::compute(updated.data.clone());
+
+        assert_eq!(updated.offsets, computed.offsets);
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_index_builder() {
+        let data = (0..10000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+        let (computed, digest) = Index::compute_with_digest(data.clone());
+        assert_eq!(digest, ::format::source_digest::SourceDigest::compute(&data).digest);
+
+        let mut builder = IndexBuilder::new(data.len());
+        for chunk in data.chunks(777) {
+            builder.write_all(chunk).unwrap();
+        }
+        assert_eq!(builder.digest(), digest);
+        let (built, built_digest) = builder.finish();
+        assert_eq!(built_digest, digest);
+        assert_eq!(built.offsets, computed.offsets);
+
+        let cache = MemCache::new();
+        for _ in 0..2 {
+            let mut builder = IndexBuilder::new(0);
+            builder.write_all(&data).unwrap();
+            let (cached, cached_digest) = builder.finish_cached(&cache).unwrap();
+            assert_eq!(cached_digest, digest);
+            assert_eq!(cached.offsets, computed.offsets);
+            assert_eq!(cache.len(), 1);
+        }
+
+        // `from_cache_or_compute` shares the entry.
+        Index::from_cache_or_compute(&cache, data).unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_cached_index_recovers_from_damage() {
+        use std::cell::RefCell;
+        use std::collections::HashMap;
+
+        let store: RefCell<HashMap<[u8; 20], Vec<u8>>> = RefCell::new(HashMap::new());
+        let cache = KvCache::new(
+            |key: &[u8; 20]| Ok(store.borrow().get(key).cloned()),
+            |key: &[u8; 20], value| {
+                store.borrow_mut().insert(*key, value);
+                Ok(())
+            });
+
+        let data = (0..10000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+        let computed = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+        let entry = store.borrow().values().next().unwrap().clone();
+        assert_eq!(entry.len(), CACHE_HEADER_SIZE + data.len() * 8 + 20);
+
+        let damage: Vec<Box<Fn(&mut Vec<u8>)>> = vec![
+            Box::new(|e| e[CACHE_MAGIC.len()] = VERSION - 1),
+            Box::new(|e| e[0] ^= 1),
+            Box::new(|e| e[CACHE_HEADER_SIZE + 100] ^= 1),
+            Box::new(|e| { let len = e.len(); e.truncate(len - 100) }),
+            Box::new(|e| e.truncate(10)),
+        ];
+        for damage in &damage {
+            for value in store.borrow_mut().values_mut() {
+                damage(value);
+            }
+
+            let loaded = Index::from_cache_or_compute(&cache, data.clone()).unwrap();
+            assert_eq!(loaded.offsets, computed.offsets);
+            assert_eq!(store.borrow().values().next().unwrap(), &entry);
+        }
+    }
+
+    #[test]
+    fn test_index_update() {
+        let old = b"this is a test 12345678 test";
+
+        assert_update_matches_compute(old, &[]);
+
+        assert_update_matches_compute(old, &[
+            Edit { offset: 0, removed: 0, inserted: b"oh, ".to_vec() },
+        ]);
+
+        assert_update_matches_compute(old, &[
+            Edit { offset: 5, removed: 2, inserted: b"was".to_vec() },
+            Edit { offset: 15, removed: 8, inserted: Vec::new() },
+            Edit { offset: 28, removed: 0, inserted: b"s!".to_vec() },
+        ]);
+
+        assert_update_matches_compute(old, &[
+            Edit { offset: 0, removed: 28, inserted: b"something else".to_vec() },
+        ]);
+    }
+
+    #[test]
+    fn test_index_update_repeats() {
+        // Suffixes well before an edit whose order hangs on it: the runs
+        // before it occur elsewhere too.
+        let mut old = b"abcabcabcabcabcabcabcabc".to_vec();
+        old.extend_from_slice(&noise(2, 500));
+        old.extend_from_slice(b"abcabcabcabcabcabcabcabx");
+        let end = old.len();
+
+        for &offset in &[0, 10, 23, 24, 300, end - 1, end] {
+            for inserted in &[&b""[..], b"a", b"abcabd", b"\0\xff"] {
+                let removed = min(3, end - offset);
+                assert_update_matches_compute(&old, &[Edit { offset: offset, removed: removed, inserted: inserted.to_vec() }]);
+                assert_update_matches_compute(&old, &[Edit { offset: offset, removed: 0, inserted: inserted.to_vec() }]);
+            }
+        }
+
+        let data = noise(3, 20000).iter().map(|b| b % 3).collect::<Vec<_>>();
+        let edits = (1..40).map(|i| Edit {
+            offset: i * 500,
+            removed: i % 4,
+            inserted: noise(i as u32, i % 7).iter().map(|b| b % 3).collect(),
+        }).collect::<Vec<_>>();
+        assert_update_matches_compute(&data, &edits);
+    }
+
+    #[test]
+    fn test_index_update_invalid() {
+        let mut index = Index::compute(b"this is a test".to_vec());
+        let offsets = index.offsets.clone();
+
+        let unsorted = [
+            Edit { offset: 8, removed: 1, inserted: Vec::new() },
+            Edit { offset: 2, removed: 1, inserted: Vec::new() },
+        ];
+        let overlapping = [
+            Edit { offset: 2, removed: 4, inserted: Vec::new() },
+            Edit { offset: 5, removed: 1, inserted: Vec::new() },
+        ];
+        let out_of_range = [Edit { offset: 12, removed: 3, inserted: Vec::new() }];
+        let overflowing = [Edit { offset: 1, removed: !0, inserted: Vec::new() }];
+
+        for edits in &[&unsorted[..], &overlapping[..], &out_of_range[..], &overflowing[..]] {
+            assert_eq!(index.update(edits).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(&index.data[..], b"this is a test");
+            assert_eq!(index.offsets, offsets);
+        }
+    }
+
+    #[test]
+    fn test_index_update_data() {
+        let mut index = Index::compute(b"this is a test".to_vec());
+        index.update(&[
+            Edit { offset: 8, removed: 1, inserted: b"the".to_vec() },
+            Edit { offset: 14, removed: 0, inserted: b"!".to_vec() },
+        ]).unwrap();
+
+        assert_eq!(&index.data[..], b"this is the test!");
+    }
+
+    #[test]
+    fn test_index_from_reader() {
+        let data = noise(1, 200000);
+
+        // A read that comes up short mid-stream, and a hint that's too small.
+        let index = Index::from_reader(io::Cursor::new(&data[..]).take(150000).chain(&data[150000..]), 1000).unwrap();
+        let computed = Index::compute(data.clone());
+        assert_eq!(index.data, computed.data);
+        assert_eq!(index.offsets, computed.offsets);
+
+        let empty = Index::from_reader(io::empty(), 0).unwrap();
+        assert!(empty.data.is_empty());
+    }
+}
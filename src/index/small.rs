@@ -0,0 +1,258 @@
+//! A hash-chain index for small old files.
+//!
+//! For a file of a few KB, sorting its suffixes costs more than the whole
+//! diff that follows, and most of a patch service's time goes into that
+//! sort when it's generating thousands of patches for config files and the
+//! like.  `SmallIndex` instead chains together the positions that start
+//! with the same `GRAM` bytes (hashed, as in zlib), which takes one pass,
+//! and answers a lookup by walking the chain for the query's first `GRAM`
+//! bytes.  Chains get long on repetitive data, so it's only meant for small
+//! inputs: `AutoIndex` picks it below `SMALL_INDEX_THRESHOLD` bytes, and a
+//! suffix array above.
+
+use std::mem;
+use std::ops::Range;
+
+use index::{longest_prefix, Index, Matcher};
+
+/// Old files shorter than this get a `SmallIndex` from `AutoIndex::compute`.
+pub const SMALL_INDEX_THRESHOLD: usize = 4 * 1024;
+
+/// Bytes each position is hashed by.
+const GRAM: usize = 4;
+
+const HASH_BITS: u32 = 12;
+
+/// Ends a chain.
+const NONE: u32 = !0;
+
+/// Positions in the old file, chained together two ways: by the hash of the
+/// `GRAM` bytes starting there, for matches at least that long, and by the
+/// byte there, for the shorter ones.  Each chain runs from the first
+/// position to the last.
+pub struct SmallIndex {
+    data: Vec<u8>,
+
+    gram_heads: Vec<u32>,
+    gram_next: Vec<u32>,
+
+    byte_heads: Vec<u32>,
+    byte_next: Vec<u32>,
+}
+
+impl SmallIndex {
+    pub fn compute(data: Vec<u8>) -> SmallIndex {
+        assert!(data.len() < NONE as usize, "too big for a SmallIndex");
+
+        let mut gram_heads = vec![NONE; 1 << HASH_BITS];
+        let mut gram_next = vec![NONE; data.len()];
+        let mut byte_heads = vec![NONE; 256];
+        let mut byte_next = vec![NONE; data.len()];
+
+        // Filled in from the end, so that chains run forwards.
+        for i in (0..data.len()).rev() {
+            if i + GRAM <= data.len() {
+                let h = hash(&data[i..]);
+                gram_next[i] = gram_heads[h];
+                gram_heads[h] = i as u32;
+            }
+
+            let b = data[i] as usize;
+            byte_next[i] = byte_heads[b];
+            byte_heads[b] = i as u32;
+        }
+
+        SmallIndex {
+            data: data,
+            gram_heads: gram_heads,
+            gram_next: gram_next,
+            byte_heads: byte_heads,
+            byte_next: byte_next,
+        }
+    }
+
+    /// Roughly how many bytes the index takes up, including the data.
+    pub fn memory_usage(&self) -> usize {
+        let links = self.gram_heads.len() + self.gram_next.len() + self.byte_heads.len() + self.byte_next.len();
+        self.data.len() + links * mem::size_of::<u32>()
+    }
+
+    /// The longest match of at most `limit` bytes among the positions on the
+    /// chain starting at `head`, or an empty range if none match at all.
+    fn walk(&self, head: u32, next: &[u32], buf: &[u8], limit: usize) -> Range<usize> {
+        let buf = &buf[..limit];
+        let mut best = self.data.len()..self.data.len();
+
+        let mut pos = head;
+        while pos != NONE {
+            let start = pos as usize;
+            pos = next[start];
+
+            // Can't beat `best` unless it matches the byte after it.
+            let best_len = best.len();
+            if best_len > 0 && self.data.get(start + best_len) != buf.get(best_len) {
+                continue;
+            }
+
+            let len = longest_prefix(buf, &self.data[start..]);
+            if len > best_len {
+                best = start..start + len;
+                if len == limit {
+                    break;
+                }
+            }
+        }
+        best
+    }
+}
+
+fn hash(buf: &[u8]) -> usize {
+    let x = (buf[0] as u32) | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24;
+    (x.wrapping_mul(0x9E3779B1) >> (32 - HASH_BITS)) as usize
+}
+
+impl Matcher for SmallIndex {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        if buf.is_empty() {
+            return self.data.len()..self.data.len();
+        }
+
+        if buf.len() >= GRAM {
+            let m = self.walk(self.gram_heads[hash(buf)], &self.gram_next, buf, buf.len());
+            // Shorter matches may be hash collisions, and miss better ones.
+            if m.len() >= GRAM {
+                return m;
+            }
+        }
+
+        let limit = buf.len().min(GRAM - 1);
+        self.walk(self.byte_heads[buf[0] as usize], &self.byte_next, buf, limit)
+    }
+}
+
+/// A `SmallIndex` or a suffix array `Index`, whichever suits the size of
+/// the old file.
+pub enum AutoIndex {
+    Small(SmallIndex),
+    Full(Index),
+}
+
+impl AutoIndex {
+    pub fn compute(data: Vec<u8>) -> AutoIndex {
+        AutoIndex::with_threshold(data, SMALL_INDEX_THRESHOLD)
+    }
+
+    /// Builds a `SmallIndex` if `data` is shorter than `threshold` bytes,
+    /// and an `Index` otherwise.
+    pub fn with_threshold(data: Vec<u8>, threshold: usize) -> AutoIndex {
+        if data.len() < threshold {
+            AutoIndex::Small(SmallIndex::compute(data))
+        } else {
+            AutoIndex::Full(Index::compute(data))
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        match *self {
+            AutoIndex::Small(ref index) => index.memory_usage(),
+            AutoIndex::Full(ref index) => index.memory_usage(),
+        }
+    }
+}
+
+impl Matcher for AutoIndex {
+    fn data(&self) -> &[u8] {
+        match *self {
+            AutoIndex::Small(ref index) => index.data(),
+            AutoIndex::Full(ref index) => index.data(),
+        }
+    }
+
+    fn longest_match(&self, buf: &[u8]) -> Range<usize> {
+        match *self {
+            AutoIndex::Small(ref index) => index.longest_match(buf),
+            AutoIndex::Full(ref index) => index.longest_match(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use format::bsdiff;
+
+    fn assert_same_match_lengths(old: &[u8], queries: &[&[u8]]) {
+        let index = Index::compute(old.to_vec());
+        let small = SmallIndex::compute(old.to_vec());
+
+        for query in queries {
+            let expected = index.longest_match(query);
+            let actual = small.longest_match(query);
+
+            assert_eq!(expected.len(), actual.len(), "{:?}", query);
+            assert_eq!(&old[actual.clone()], &query[..actual.len()]);
+        }
+    }
+
+    #[test]
+    fn test_small_index_longest_match() {
+        assert_same_match_lengths(b"this is a test 12345678 test", &[
+            b"",
+            b"t",
+            b"te",
+            b"this is a test",
+            b"test",
+            b"12345678 test and more",
+            b"s a t",
+            b"zzz",
+            b" test",
+            b"tesz",
+            b"45z",
+        ]);
+
+        assert_same_match_lengths(b"", &[b"", b"a", b"abcdef"]);
+        assert_same_match_lengths(b"abc", &[b"abc", b"abcd", b"bc"]);
+        assert_same_match_lengths(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", &[b"aaaa", b"aab", b"b", b"aaaaaaab"]);
+    }
+
+    #[test]
+    fn test_small_index_long_input() {
+        let old = (0..3000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+
+        let queries = (0..25)
+            .map(|i| old[i * 97 .. i * 97 + 200].to_vec())
+            .collect::<Vec<_>>();
+
+        assert_same_match_lengths(&old, &queries.iter().map(|q| &q[..]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_auto_index() {
+        match AutoIndex::compute(b"small".to_vec()) {
+            AutoIndex::Small(_) => {}
+            AutoIndex::Full(_) => panic!("expected a SmallIndex"),
+        }
+        match AutoIndex::with_threshold(b"small".to_vec(), 5) {
+            AutoIndex::Full(_) => {}
+            AutoIndex::Small(_) => panic!("expected an Index"),
+        }
+    }
+
+    #[test]
+    fn test_small_index_patch_roundtrip() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let patch = bsdiff::generate_full_patch(&AutoIndex::compute(old.to_vec()), &new[..]);
+
+        let mut out = Vec::new();
+        bsdiff::apply_patch(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+
+        assert_eq!(&new[..], &out[..]);
+    }
+}
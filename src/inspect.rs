@@ -0,0 +1,695 @@
+//! Looking inside patches without applying them.
+
+use std::io::{self, Read, Write};
+use std::cmp::min;
+
+use format::PatchFormat;
+use format::bsdiff::{self, CommandReader, Header, Section};
+use format::linear_diff::{self, LinearDiff};
+#[cfg(feature = "zstd")]
+use format::linear_diff::LinearDiffZstd;
+#[cfg(feature = "zstd")]
+use format::zstd_patch_from;
+use format::multi_source::{self, MultiSource, SELF_SOURCE};
+
+/// Totals describing what a patch does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchSummary {
+    /// Name of the patch's format.
+    pub format: String,
+    pub patch_size: u64,
+    /// Size of the file the patch produces.
+    pub new_size: u64,
+    pub command_count: u64,
+    /// Bytes produced by adding deltas to the old file (or, for fill and
+    /// self-referential commands, without reading any patch data).
+    pub delta_bytes: u64,
+    /// Bytes copied literally from the patch.
+    pub extra_bytes: u64,
+}
+
+/// Decodes every command in `patch` and totals them up.
+pub fn summarize(patch: &[u8]) -> io::Result<PatchSummary> {
+    let mut summary = PatchSummary {
+        format: String::new(),
+        patch_size: patch.len() as u64,
+        new_size: 0,
+        command_count: 0,
+        delta_bytes: 0,
+        extra_bytes: 0,
+    };
+
+    #[cfg(feature = "zstd")]
+    {
+        if LinearDiffZstd::is_match(patch) {
+            summary.format = LinearDiffZstd::NAME.to_string();
+            summarize_linear(linear_body(patch)?, &mut summary)?;
+            return Ok(summary);
+        }
+
+        // Its matches are zstd back-references, which can't be told apart
+        // from literals without the old file, so there are no commands.
+        if zstd_patch_from::is_match(patch) {
+            summary.format = zstd_patch_from::NAME.to_string();
+            summary.new_size = zstd_patch_from::new_size(patch)?;
+            return Ok(summary);
+        }
+    }
+
+    if bsdiff::is_match(patch) {
+        summary.format = bsdiff::format_name(patch).to_string();
+
+        let header = Header::read(patch)?;
+        let commands = bsdiff_commands(patch, &header)?;
+
+        for cmd in CommandReader::new(Section::open(header.codecs.commands, commands)?) {
+            let cmd = cmd?;
+            summary.command_count += 1;
+            add(&mut summary.delta_bytes, cmd.bytewise_add_size)?;
+            add(&mut summary.extra_bytes, cmd.extra_append_size)?;
+        }
+
+        summary.new_size = header.new_file_size;
+    } else if LinearDiff::is_match(patch) {
+        summary.format = LinearDiff::NAME.to_string();
+        summarize_linear(linear_body(patch)?, &mut summary)?;
+    } else if MultiSource::is_match(patch) {
+        summary.format = MultiSource::NAME.to_string();
+        summarize_multi_source(&patch[MultiSource::MAGIC.len()..], &mut summary)?;
+    } else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown patch format"));
+    }
+
+    Ok(summary)
+}
+
+/// The compressed command section of a bsdiff patch.
+pub(crate) fn bsdiff_commands<'a>(patch: &'a [u8], header: &Header) -> io::Result<&'a [u8]> {
+    let start = header.encoded_size();
+    let end = start.checked_add(header.compressed_commands_size);
+    match end {
+        Some(end) if end <= patch.len() as u64 => Ok(&patch[start as usize..end as usize]),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Command section runs past the end of the patch")),
+    }
+}
+
+/// Adds a size from the patch to a running total, failing rather than
+/// wrapping on a corrupt patch.
+fn add(total: &mut u64, len: u64) -> io::Result<()> {
+    *total = total.checked_add(len).ok_or_else(overflow)?;
+    Ok(())
+}
+
+fn overflow() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Patch sizes or offsets overflow")
+}
+
+fn skip<R: Read>(mut r: R, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut r.by_ref().take(len), &mut io::sink())?;
+    if skipped < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Patch data ends mid-command"));
+    }
+    Ok(())
+}
+
+/// The body of a `LinearDiff` or `LinearDiffZstd` patch, decompressed.
+fn linear_body<'a>(patch: &'a [u8]) -> io::Result<Box<Read + 'a>> {
+    let body = &patch[LinearDiff::MAGIC.len()..];
+
+    #[cfg(feature = "zstd")]
+    {
+        if LinearDiffZstd::is_match(patch) {
+            return Ok(Box::new(::zstd::stream::Decoder::new(body)?));
+        }
+    }
+
+    Ok(Box::new(body))
+}
+
+fn summarize_linear<R: Read>(mut body: R, summary: &mut PatchSummary) -> io::Result<()> {
+    while let Some(cmd) = linear_diff::Command::read_from(&mut body)? {
+        summary.command_count += 1;
+
+        if cmd.fill_byte().is_some() {
+            add(&mut summary.delta_bytes, cmd.extra_append_size)?;
+        } else {
+            skip(&mut body, cmd.body_len()?)?;
+            add(&mut summary.delta_bytes, cmd.output_len()? - cmd.extra_append_size)?;
+            add(&mut summary.extra_bytes, cmd.extra_append_size)?;
+        }
+    }
+
+    summary.new_size = summary.delta_bytes;
+    add(&mut summary.new_size, summary.extra_bytes)?;
+    Ok(())
+}
+
+fn summarize_multi_source<R: Read>(mut body: R, summary: &mut PatchSummary) -> io::Result<()> {
+    while let Some(cmd) = multi_source::Command::read_from(&mut body)? {
+        summary.command_count += 1;
+
+        if cmd.source != SELF_SOURCE {
+            skip(&mut body, cmd.bytewise_add_size)?;
+        }
+        skip(&mut body, cmd.extra_append_size)?;
+
+        add(&mut summary.delta_bytes, cmd.bytewise_add_size)?;
+        add(&mut summary.extra_bytes, cmd.extra_append_size)?;
+    }
+
+    summary.new_size = summary.delta_bytes;
+    add(&mut summary.new_size, summary.extra_bytes)?;
+    Ok(())
+}
+
+/// How many bytes of each command's extra data `dump` keeps for preview.
+pub const EXTRA_PREVIEW: usize = 16;
+
+/// One decoded command, as reported by `dump`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CommandInfo {
+    /// Where this command's output starts in the new file.
+    pub new_offset: u64,
+    /// Where its delta bytes line up in the old file, if it reads from it.
+    pub old_offset: Option<u64>,
+    pub delta_len: u64,
+    pub extra_len: u64,
+    /// Hex of the first `EXTRA_PREVIEW` bytes of extra data.
+    pub extra_preview: String,
+}
+
+/// A separately-compressed part of a patch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SectionInfo {
+    pub name: String,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+}
+
+impl SectionInfo {
+    /// Uncompressed over compressed size; higher is better.
+    pub fn ratio(&self) -> f64 {
+        self.uncompressed_size as f64 / self.compressed_size.max(1) as f64
+    }
+}
+
+/// Everything `dump` found in a patch.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchDump {
+    pub summary: PatchSummary,
+    pub sections: Vec<SectionInfo>,
+    pub commands: Vec<CommandInfo>,
+}
+
+/// Decodes `patch` into its full command list, with section sizes and a
+/// preview of each command's extra data.
+pub fn dump(patch: &[u8]) -> io::Result<PatchDump> {
+    let summary = summarize(patch)?;
+    let mut commands = Vec::new();
+    let mut sections = Vec::new();
+
+    #[cfg(feature = "zstd")]
+    {
+        if zstd_patch_from::is_match(patch) {
+            sections.push(SectionInfo {
+                name: "frame".to_string(),
+                compressed_size: (patch.len() - zstd_patch_from::MAGIC.len() - 8) as u64,
+                uncompressed_size: summary.new_size,
+            });
+            return Ok(PatchDump {
+                summary: summary,
+                sections: sections,
+                commands: commands,
+            });
+        }
+    }
+
+    if bsdiff::is_match(patch) {
+        let header = Header::read(patch)?;
+        let command_data = bsdiff_commands(patch, &header)?;
+        let rest = &patch[header.encoded_size() as usize + command_data.len()..];
+
+        if header.compressed_delta_size > rest.len() as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Delta section runs past the end of the patch"));
+        }
+        let extra_data = &rest[header.compressed_delta_size as usize..];
+        let mut extra = Section::open(header.codecs.extra, extra_data)?;
+
+        let mut new_offset = 0;
+        let mut old_offset = 0i64;
+
+        for cmd in CommandReader::new(Section::open(header.codecs.commands, command_data)?) {
+            let cmd = cmd?;
+
+            commands.push(CommandInfo {
+                new_offset: new_offset,
+                old_offset: Some(old_offset as u64),
+                delta_len: cmd.bytewise_add_size,
+                extra_len: cmd.extra_append_size,
+                extra_preview: read_preview(&mut extra, cmd.extra_append_size)?,
+            });
+
+            add(&mut new_offset, cmd.bytewise_add_size)?;
+            add(&mut new_offset, cmd.extra_append_size)?;
+            if cmd.bytewise_add_size > i64::max_value() as u64 {
+                return Err(overflow());
+            }
+            old_offset = old_offset.checked_add(cmd.bytewise_add_size as i64)
+                .and_then(|offset| offset.checked_add(cmd.oldfile_seek_offset))
+                .ok_or_else(overflow)?;
+        }
+
+        sections.push(SectionInfo {
+            name: "commands".to_string(),
+            compressed_size: header.compressed_commands_size,
+            uncompressed_size: 24 * summary.command_count,
+        });
+        sections.push(SectionInfo {
+            name: "delta".to_string(),
+            compressed_size: header.compressed_delta_size,
+            uncompressed_size: summary.delta_bytes,
+        });
+        sections.push(SectionInfo {
+            name: "extra".to_string(),
+            compressed_size: extra_data.len() as u64,
+            uncompressed_size: summary.extra_bytes,
+        });
+    } else if MultiSource::is_match(patch) {
+        let body = &patch[MultiSource::MAGIC.len()..];
+        dump_multi_source(body, &mut commands)?;
+
+        sections.push(SectionInfo {
+            name: "body".to_string(),
+            compressed_size: body.len() as u64,
+            uncompressed_size: body.len() as u64,
+        });
+    } else {
+        // `summarize` only lets linear_diff patches, compressed or not,
+        // through to here.
+        let uncompressed_size = dump_linear(linear_body(patch)?, &mut commands)?;
+
+        sections.push(SectionInfo {
+            name: "body".to_string(),
+            compressed_size: (patch.len() - LinearDiff::MAGIC.len()) as u64,
+            uncompressed_size: uncompressed_size,
+        });
+    }
+
+    Ok(PatchDump {
+        summary: summary,
+        sections: sections,
+        commands: commands,
+    })
+}
+
+/// Returns the size of the decoded body.
+fn dump_linear<R: Read>(mut body: R, commands: &mut Vec<CommandInfo>) -> io::Result<u64> {
+    let mut new_offset = 0;
+    let mut body_size = 0;
+
+    while let Some(cmd) = linear_diff::Command::read_from(&mut body)? {
+        body_size += 24;
+
+        if let Some(byte) = cmd.fill_byte() {
+            commands.push(CommandInfo {
+                new_offset: new_offset,
+                old_offset: None,
+                delta_len: cmd.extra_append_size,
+                extra_len: 0,
+                extra_preview: hex(&[byte]),
+            });
+            add(&mut new_offset, cmd.extra_append_size)?;
+            continue;
+        }
+
+        skip(&mut body, cmd.delta_len())?;
+
+        commands.push(CommandInfo {
+            new_offset: new_offset,
+            old_offset: Some(cmd.old_offset),
+            delta_len: cmd.output_len()? - cmd.extra_append_size,
+            extra_len: cmd.extra_append_size,
+            extra_preview: read_preview(&mut body, cmd.extra_append_size)?,
+        });
+
+        add(&mut new_offset, cmd.output_len()?)?;
+        add(&mut body_size, cmd.body_len()?)?;
+    }
+
+    Ok(body_size)
+}
+
+fn dump_multi_source<R: Read>(mut body: R, commands: &mut Vec<CommandInfo>) -> io::Result<()> {
+    let mut new_offset = 0;
+
+    while let Some(cmd) = multi_source::Command::read_from(&mut body)? {
+        let old_offset = if cmd.source == SELF_SOURCE {
+            None
+        } else {
+            skip(&mut body, cmd.bytewise_add_size)?;
+            Some(cmd.old_offset)
+        };
+
+        commands.push(CommandInfo {
+            new_offset: new_offset,
+            old_offset: old_offset,
+            delta_len: cmd.bytewise_add_size,
+            extra_len: cmd.extra_append_size,
+            extra_preview: read_preview(&mut body, cmd.extra_append_size)?,
+        });
+
+        add(&mut new_offset, cmd.bytewise_add_size)?;
+        add(&mut new_offset, cmd.extra_append_size)?;
+    }
+
+    Ok(())
+}
+
+/// Reads `len` bytes of extra data, returning the hex of the first few.
+fn read_preview<R: Read>(mut r: R, len: u64) -> io::Result<String> {
+    let mut preview = Vec::new();
+    let want = min(len, EXTRA_PREVIEW as u64);
+    r.by_ref().take(want).read_to_end(&mut preview)?;
+
+    if (preview.len() as u64) < want {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Patch data ends mid-command"));
+    }
+
+    skip(r, len - want)?;
+    Ok(hex(&preview))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl PatchDump {
+    /// Writes the dump as a single JSON object, with each section's
+    /// `ratio` alongside its sizes.
+    #[cfg(feature = "json")]
+    pub fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let dump = JsonDump {
+            summary: &self.summary,
+            sections: self.sections.iter().map(|section| JsonSection { section: section, ratio: section.ratio() }).collect(),
+            commands: &self.commands,
+        };
+        serde_json::to_writer(&mut w, &dump)?;
+        writeln!(w)
+    }
+
+    /// Writes the dump as human-readable text, one command per line.
+    pub fn write_text<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let s = &self.summary;
+        writeln!(w, "format: {}", s.format)?;
+        writeln!(w, "patch size: {}, new size: {}", s.patch_size, s.new_size)?;
+        writeln!(w, "{} commands, {} delta bytes, {} extra bytes", s.command_count, s.delta_bytes, s.extra_bytes)?;
+
+        for section in &self.sections {
+            writeln!(w, "section {}: {} -> {} bytes ({:.2}x)",
+                section.name, section.uncompressed_size, section.compressed_size, section.ratio())?;
+        }
+
+        for cmd in &self.commands {
+            let old_offset = match cmd.old_offset {
+                Some(offset) => offset.to_string(),
+                None => "-".to_string(),
+            };
+            writeln!(w, "new {:>10} old {:>10} delta {:>8} extra {:>8} {}",
+                cmd.new_offset, old_offset, cmd.delta_len, cmd.extra_len, cmd.extra_preview)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct JsonDump<'a> {
+    summary: &'a PatchSummary,
+    sections: Vec<JsonSection<'a>>,
+    commands: &'a [CommandInfo],
+}
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct JsonSection<'a> {
+    #[serde(flatten)]
+    section: &'a SectionInfo,
+    ratio: f64,
+}
+
+/// A stretch of the new file where two patches encode things differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DivergentRegion {
+    pub new_start: u64,
+    pub new_end: u64,
+    pub a_commands: u64,
+    pub b_commands: u64,
+    pub a_extra_bytes: u64,
+    pub b_extra_bytes: u64,
+}
+
+/// How two patches for the same old/new pair differ, from `compare_patches`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchComparison {
+    pub a: PatchSummary,
+    pub b: PatchSummary,
+    /// Regions between the points where both command streams agree, in
+    /// new-file order.
+    pub regions: Vec<DivergentRegion>,
+}
+
+impl PatchComparison {
+    /// New-file offset where the command streams first disagree.
+    pub fn first_divergence(&self) -> Option<u64> {
+        self.regions.first().map(|r| r.new_start)
+    }
+
+    /// How many more extra bytes `b` carries than `a`.
+    pub fn extra_bytes_difference(&self) -> i64 {
+        self.b.extra_bytes as i64 - self.a.extra_bytes as i64
+    }
+}
+
+/// Lines up the command streams of two patches (in any formats) that turn
+/// the same old file into the same new file, to find where they diverge and
+/// which one spends more extra bytes there.  Useful for tracking down patch
+/// size regressions between versions.
+pub fn compare_patches(a: &[u8], b: &[u8]) -> io::Result<PatchComparison> {
+    let a = dump(a)?;
+    let b = dump(b)?;
+
+    let same = |x: &CommandInfo, y: &CommandInfo| {
+        x.new_offset == y.new_offset
+            && x.old_offset == y.old_offset
+            && x.delta_len == y.delta_len
+            && x.extra_len == y.extra_len
+    };
+
+    let offset = |cmds: &[CommandInfo], i: usize| {
+        cmds.get(i).map(|c| c.new_offset).unwrap_or(!0)
+    };
+
+    let (ac, bc) = (&a.commands[..], &b.commands[..]);
+    let (mut i, mut j) = (0, 0);
+    let mut regions = Vec::new();
+
+    while i < ac.len() || j < bc.len() {
+        if i < ac.len() && j < bc.len() && same(&ac[i], &bc[j]) {
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        let mut region = DivergentRegion {
+            new_start: min(offset(ac, i), offset(bc, j)),
+            new_end: 0,
+            a_commands: 0,
+            b_commands: 0,
+            a_extra_bytes: 0,
+            b_extra_bytes: 0,
+        };
+
+        // Consume commands from whichever side is behind until both sides
+        // start a command at the same place, agreeing on it.
+        loop {
+            let (ao, bo) = (offset(ac, i), offset(bc, j));
+
+            if i < ac.len() && ao <= bo {
+                region.a_commands += 1;
+                region.a_extra_bytes += ac[i].extra_len;
+                i += 1;
+            }
+            if j < bc.len() && bo <= ao {
+                region.b_commands += 1;
+                region.b_extra_bytes += bc[j].extra_len;
+                j += 1;
+            }
+
+            let done = i == ac.len() && j == bc.len();
+            if done || (i < ac.len() && j < bc.len() && same(&ac[i], &bc[j])) {
+                break;
+            }
+        }
+
+        region.new_end = if i == ac.len() && j == bc.len() {
+            ::std::cmp::max(a.summary.new_size, b.summary.new_size)
+        } else {
+            offset(ac, i)
+        };
+
+        regions.push(region);
+    }
+
+    Ok(PatchComparison {
+        a: a.summary,
+        b: b.summary,
+        regions: regions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::{Index, PatchOptions};
+    use format::bsdiff::{self, BsdiffCodecs};
+
+    #[test]
+    fn test_summarize() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let mut patches = vec![bsdiff::generate_full_patch(&index, &new[..])];
+
+        let mut patch = Vec::new();
+        let options = PatchOptions::default().codecs(BsdiffCodecs::CODECS);
+        bsdiff::write_full_patch(&index, &new[..], &options, &mut patch).unwrap();
+        patches.push(patch);
+
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new[..], &mut patch).unwrap();
+        patches.push(patch);
+
+        #[cfg(feature = "zstd")]
+        {
+            let mut patch = Vec::new();
+            linear_diff::generate_compressed_patch(&index, &new[..], 3, &mut patch).unwrap();
+            patches.push(patch);
+        }
+
+        let mut patch = Vec::new();
+        multi_source::generate_full_patch(&[&index], &new[..], &mut patch).unwrap();
+        patches.push(patch);
+
+        for patch in &patches {
+            let summary = summarize(patch).unwrap();
+            assert_eq!(summary.patch_size, patch.len() as u64);
+            assert_eq!(summary.new_size, new.len() as u64);
+            assert_eq!(summary.delta_bytes + summary.extra_bytes, new.len() as u64);
+            assert!(summary.command_count > 0);
+        }
+
+        assert!(summarize(b"NOTAPATCH").is_err());
+    }
+
+    #[test]
+    fn test_dump() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let bsdiff_patch = bsdiff::generate_full_patch(&index, &new[..]);
+        let mut linear_patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new[..], &mut linear_patch).unwrap();
+
+        for patch in &[bsdiff_patch, linear_patch] {
+            let dump = dump(patch).unwrap();
+
+            assert_eq!(dump.commands.len() as u64, dump.summary.command_count);
+            assert_eq!(dump.commands[0].new_offset, 0);
+            assert_eq!(dump.commands[0].old_offset, Some(0));
+
+            let last = dump.commands.last().unwrap();
+            assert_eq!(last.new_offset + last.delta_len + last.extra_len, new.len() as u64);
+
+            #[cfg(feature = "json")]
+            {
+                let mut json = Vec::new();
+                dump.write_json(&mut json).unwrap();
+                let value: ::serde_json::Value = ::serde_json::from_slice(&json).unwrap();
+                assert_eq!(value["summary"]["command_count"], dump.summary.command_count);
+                assert_eq!(value["sections"][0]["name"], dump.sections[0].name.as_str());
+                assert!(value["sections"][0]["ratio"].is_f64());
+                assert_eq!(value["commands"].as_array().unwrap().len(), dump.commands.len());
+            }
+
+            let mut text = Vec::new();
+            dump.write_text(&mut text).unwrap();
+            assert!(!text.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_dump_offset_overflow() {
+        use diff::{SectionCodec, SectionCodecs};
+        use format::bsdiff::Command;
+
+        // A seek to the very end of the old file's address space, then a
+        // delta past it.
+        let mut commands = Vec::new();
+        Command { bytewise_add_size: 0, extra_append_size: 1, oldfile_seek_offset: i64::max_value() }.write_to(&mut commands).unwrap();
+        Command { bytewise_add_size: 1, extra_append_size: 0, oldfile_seek_offset: 0 }.write_to(&mut commands).unwrap();
+
+        let mut header = Header::new(2, SectionCodecs::all(SectionCodec::None));
+        header.compressed_commands_size = commands.len() as u64;
+        header.compressed_delta_size = 1;
+
+        let mut patch = Vec::new();
+        header.write_to(&mut patch).unwrap();
+        patch.extend_from_slice(&commands);
+        patch.extend_from_slice(b"dx");
+
+        assert_eq!(summarize(&patch).unwrap().command_count, 2);
+        assert_eq!(dump(&patch).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_compare_patches() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let patch = bsdiff::generate_full_patch(&index, &new[..]);
+        let same = compare_patches(&patch, &patch).unwrap();
+        assert!(same.regions.is_empty());
+        assert_eq!(same.first_divergence(), None);
+        assert_eq!(same.extra_bytes_difference(), 0);
+
+        let idempotent = bsdiff::generate_idempotent_patch(&new[..]);
+        let cmp = compare_patches(&patch, &idempotent).unwrap();
+        assert_eq!(cmp.first_divergence(), Some(0));
+        assert_eq!(cmp.regions.len(), 1);
+        assert_eq!(cmp.regions[0].new_end, new.len() as u64);
+        assert_eq!(cmp.regions[0].b_extra_bytes, new.len() as u64);
+        assert!(cmp.extra_bytes_difference() > 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_summary_json_roundtrip() {
+        let index = Index::compute(b"this is a test".to_vec());
+        let patch = bsdiff::generate_full_patch(&index, b"this is really a test");
+        let summary = summarize(&patch).unwrap();
+
+        let json = ::serde_json::to_string(&summary).unwrap();
+        let back: PatchSummary = ::serde_json::from_str(&json).unwrap();
+        assert_eq!(summary, back);
+    }
+}
@@ -0,0 +1,293 @@
+//! Writing patched output to NOR flash through the `embedded-storage`
+//! traits, with the `embedded-storage` feature.
+//!
+//! NOR flash can only be programmed after it's erased, a whole sector
+//! (`ERASE_SIZE`) at a time, and only in aligned units of `WRITE_SIZE`.
+//! `NorFlashWriter` buffers a sector of output, erases the sector, then
+//! programs it in one go.
+//!
+//! An apply interrupted partway can leave the sector it was programming
+//! half written, and a half-programmed page can't be finished without
+//! erasing it again.  So `NorFlashWriter::resume` rewinds to the start of
+//! the sector holding the first unverified byte, and rewrites it whole.
+//! The apply is simply run again from the start: output before that
+//! sector, which is already on the flash, is discarded rather than written.
+//!
+//! This isn't a `no_std` applier.  The crate as a whole needs `std`, and
+//! `NorFlashWriter` is a `std::io::Write` that buffers a sector in a `Vec`,
+//! so it's for hosted targets driving flash through an `embedded-storage`
+//! implementation (an MTD device on embedded Linux, say, or a simulator in
+//! tests).  A bare-metal bootloader needs an applier and sink built on
+//! `core` alone, which doesn't exist yet.
+
+use std::io::{self, Write};
+use std::cmp::min;
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError};
+
+/// What erased NOR flash reads as, and what the last write unit is padded
+/// with.
+pub const ERASED: u8 = 0xFF;
+
+/// A `Write` that programs `flash` from `start`, which must be on a sector
+/// boundary.  Nothing is programmed until a sector's worth of output has
+/// arrived, or `finish` is called.
+pub struct NorFlashWriter<F: NorFlash> {
+    flash: F,
+    start: u32,
+
+    /// Output bytes to discard before programming, when resuming.
+    skip: u64,
+    /// Output of the sector being filled.
+    buf: Vec<u8>,
+    /// Bytes of output from `start` already programmed.
+    programmed: u64,
+    /// Set once programming fails, after which the sector is left as it is
+    /// for `resume` to deal with.
+    failed: bool,
+}
+
+impl<F: NorFlash> NorFlashWriter<F> {
+    pub fn new(flash: F, start: u32) -> io::Result<NorFlashWriter<F>> {
+        NorFlashWriter::resume(flash, start, 0)
+    }
+
+    /// A writer for an apply that was interrupted after `verified` bytes of
+    /// output were known to be on the flash (say, from a journal).  It
+    /// expects the whole output again, and programs it from the start of
+    /// the sector `verified` falls in.
+    pub fn resume(flash: F, start: u32, verified: u64) -> io::Result<NorFlashWriter<F>> {
+        if start as usize % F::ERASE_SIZE != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Flash start {:#x} isn't on a {}-byte sector boundary", start, F::ERASE_SIZE)));
+        }
+        if F::ERASE_SIZE % F::WRITE_SIZE != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Flash sectors aren't whole write units"));
+        }
+
+        let sector = F::ERASE_SIZE as u64;
+        let skip = verified / sector * sector;
+
+        Ok(NorFlashWriter {
+            flash: flash,
+            start: start,
+            skip: skip,
+            buf: Vec::with_capacity(F::ERASE_SIZE),
+            programmed: skip,
+            failed: false,
+        })
+    }
+
+    /// Bytes of output from `start` programmed so far, always whole sectors
+    /// until `finish`.
+    pub fn programmed(&self) -> u64 {
+        self.programmed
+    }
+
+    /// Programs whatever output is left, padded with `ERASED` to a whole
+    /// write unit, and returns the flash.
+    pub fn finish(mut self) -> io::Result<F> {
+        if self.skip > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+                "Output ended {} bytes short of where the interrupted apply got to", self.skip)));
+        }
+
+        let tail = self.buf.len() % F::WRITE_SIZE;
+        if tail > 0 {
+            let pad = F::WRITE_SIZE - tail;
+            self.buf.extend((0..pad).map(|_| ERASED));
+        }
+        self.program()?;
+        Ok(self.flash)
+    }
+
+    fn check_failed(&self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::new(io::ErrorKind::Other, "Flash programming already failed"));
+        }
+        Ok(())
+    }
+
+    /// Erases the next sector and programs `buf` into it.
+    fn program(&mut self) -> io::Result<()> {
+        self.check_failed()?;
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let offset = self.start as u64 + self.programmed;
+        if offset + F::ERASE_SIZE as u64 > self.flash.capacity() as u64 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "Patched output doesn't fit in the flash"));
+        }
+        let offset = offset as u32;
+
+        self.failed = true;
+        self.flash.erase(offset, offset + F::ERASE_SIZE as u32).map_err(flash_error)?;
+        self.flash.write(offset, &self.buf).map_err(flash_error)?;
+        self.failed = false;
+
+        self.programmed += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+fn flash_error<E: NorFlashError>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Flash error: {:?}", e.kind()))
+}
+
+impl<F: NorFlash> Write for NorFlashWriter<F> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.check_failed()?;
+        if self.skip > 0 {
+            let n = min(self.skip, data.len() as u64) as usize;
+            self.skip -= n as u64;
+            return Ok(n);
+        }
+
+        let n = min(data.len(), F::ERASE_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == F::ERASE_SIZE {
+            self.program()?;
+        }
+        Ok(n)
+    }
+
+    /// Does nothing: a sector can only be programmed once it's complete.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use embedded_storage::nor_flash::{check_erase, check_write, ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    use super::*;
+    use diff::Index;
+    use format::bsdiff;
+    use patch::apply_any;
+
+    /// Flash in memory, which fails a write that would program bits that
+    /// aren't erased, and can be made to fail partway through a write.
+    struct RamFlash {
+        data: Vec<u8>,
+        /// Bytes that can be programmed before writes start failing.
+        fail_after: Option<usize>,
+    }
+
+    #[derive(Debug)]
+    struct RamFlashError(NorFlashErrorKind);
+
+    impl NorFlashError for RamFlashError {
+        fn kind(&self) -> NorFlashErrorKind {
+            self.0
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = RamFlashError;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), RamFlashError> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 16;
+        const ERASE_SIZE: usize = 256;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), RamFlashError> {
+            check_erase(self, from, to).map_err(RamFlashError)?;
+            for b in &mut self.data[from as usize..to as usize] {
+                *b = ERASED;
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), RamFlashError> {
+            check_write(self, offset, bytes.len()).map_err(RamFlashError)?;
+            for (i, &b) in bytes.iter().enumerate() {
+                if self.fail_after == Some(0) {
+                    return Err(RamFlashError(NorFlashErrorKind::Other));
+                }
+                self.fail_after = self.fail_after.map(|n| n - 1);
+
+                let cell = &mut self.data[offset as usize + i];
+                if *cell != ERASED {
+                    return Err(RamFlashError(NorFlashErrorKind::Other));
+                }
+                *cell = b;
+            }
+            Ok(())
+        }
+    }
+
+    fn pair() -> (Vec<u8>, Vec<u8>) {
+        let old = (0..5000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<u8>>();
+        let mut new = old[1000..].to_vec();
+        new.extend_from_slice(b"some more at the end");
+        (old, new)
+    }
+
+    #[test]
+    fn test_nor_flash_writer() {
+        let (old, new) = pair();
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.clone()), &new);
+
+        let flash = RamFlash { data: vec![0u8; 8192], fail_after: None };
+        let mut writer = NorFlashWriter::new(flash, 1024).unwrap();
+        apply_any(&patch, Cursor::new(&old[..]), &mut writer).unwrap();
+        let flash = writer.finish().unwrap();
+
+        let end = 1024 + new.len();
+        assert!(flash.data[1024..end] == new[..]);
+        // Padded to a write unit with erased bytes; the rest of the last
+        // sector is erased too.
+        assert!(flash.data[end..1024 + 4096].iter().all(|&b| b == ERASED));
+        assert!(flash.data[..1024].iter().all(|&b| b == 0));
+
+        let flash = RamFlash { data: vec![0u8; 8192], fail_after: None };
+        assert_eq!(NorFlashWriter::new(flash, 100).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        let flash = RamFlash { data: vec![0u8; 2048], fail_after: None };
+        let mut writer = NorFlashWriter::new(flash, 0).unwrap();
+        let err = apply_any(&patch, Cursor::new(&old[..]), &mut writer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn test_nor_flash_resume() {
+        let (old, new) = pair();
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.clone()), &new);
+
+        // Power fails halfway through programming the fourth sector.
+        let flash = RamFlash { data: vec![0u8; 8192], fail_after: Some(3 * 256 + 100) };
+        let mut writer = NorFlashWriter::new(flash, 0).unwrap();
+        assert!(apply_any(&patch, Cursor::new(&old[..]), &mut writer).is_err());
+        assert_eq!(writer.programmed(), 3 * 256);
+        let mut flash = writer.flash;
+        assert!(flash.data[..3 * 256 + 100] == new[..3 * 256 + 100]);
+
+        // Say the journal got to partway into that sector.
+        flash.fail_after = None;
+        let mut writer = NorFlashWriter::resume(flash, 0, 3 * 256 + 50).unwrap();
+        assert_eq!(writer.programmed(), 3 * 256);
+        apply_any(&patch, Cursor::new(&old[..]), &mut writer).unwrap();
+        let flash = writer.finish().unwrap();
+
+        assert!(flash.data[..new.len()] == new[..]);
+    }
+}
@@ -0,0 +1,196 @@
+//! Output adapters for applying patches straight to storage.
+//!
+//! Raw partitions and the like only take writes of whole, aligned blocks,
+//! and some want a barrier (a flush) after each erase group, so that a power
+//! cut leaves every group either fully old or fully written.  The appliers
+//! write output in whatever pieces the patch happens to produce;
+//! `BlockDevice` regroups them.
+
+use std::io::{self, Write};
+use std::cmp::min;
+
+#[cfg(feature = "embedded-storage")]
+pub mod flash;
+pub mod seekable;
+
+/// A `Write` that passes data on to `inner` only as whole groups of
+/// `block_size`-byte blocks, each aligned to where `inner` started, and
+/// flushes `inner` after every group.
+///
+/// The last partial block is held back until `finish`, which pads it out to
+/// a whole block with `pad_byte` (zero unless set).  Dropping a
+/// `BlockDevice` without calling `finish` loses whatever's still buffered.
+pub struct BlockDevice<W: Write> {
+    inner: W,
+    block_size: usize,
+    group_blocks: usize,
+    pad_byte: u8,
+
+    /// Output not yet written to `inner`, always less than a group.
+    buf: Vec<u8>,
+    /// Bytes written to `inner` so far, always a whole number of groups
+    /// until `finish`.
+    written: u64,
+}
+
+impl<W: Write> BlockDevice<W> {
+    /// A device taking `block_size`-byte blocks, flushed after each one.
+    pub fn new(inner: W, block_size: usize) -> BlockDevice<W> {
+        assert!(block_size > 0, "block_size must be non-zero");
+
+        BlockDevice {
+            inner: inner,
+            block_size: block_size,
+            group_blocks: 1,
+            pad_byte: 0,
+            buf: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Writes `blocks` blocks at a time, with a barrier after each group
+    /// rather than each block.
+    pub fn erase_group(mut self, blocks: usize) -> BlockDevice<W> {
+        assert!(blocks > 0, "an erase group needs at least one block");
+        self.group_blocks = blocks;
+        self
+    }
+
+    /// The byte `finish` pads the last block with: often 0xFF for flash,
+    /// which reads back erased.
+    pub fn pad_byte(mut self, byte: u8) -> BlockDevice<W> {
+        self.pad_byte = byte;
+        self
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Bytes in each write to the underlying device.
+    pub fn group_size(&self) -> usize {
+        self.block_size * self.group_blocks
+    }
+
+    /// Bytes passed on to the underlying device so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Writes out everything buffered, padding the last block, and returns
+    /// the underlying device.
+    pub fn finish(mut self) -> io::Result<W> {
+        let tail = self.buf.len() % self.block_size;
+        if tail > 0 {
+            let pad = self.block_size - tail;
+            let byte = self.pad_byte;
+            self.buf.extend((0..pad).map(|_| byte));
+        }
+        self.write_blocks()?;
+        Ok(self.inner)
+    }
+
+    /// Writes the whole blocks in `buf` in one go, and flushes.
+    fn write_blocks(&mut self) -> io::Result<()> {
+        let len = self.buf.len() / self.block_size * self.block_size;
+        if len == 0 {
+            return Ok(());
+        }
+
+        self.inner.write_all(&self.buf[..len])?;
+        self.inner.flush()?;
+        self.written += len as u64;
+        self.buf.drain(..len);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BlockDevice<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let group = self.group_size();
+        if self.buf.capacity() < group {
+            self.buf.reserve_exact(group - self.buf.len());
+        }
+
+        let n = min(data.len(), group - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == group {
+            self.write_blocks()?;
+        }
+        Ok(n)
+    }
+
+    /// Flushes `inner` only.  Buffered output stays buffered until its group
+    /// fills up or `finish` is called: writing part of a group early would
+    /// leave that group half written if the power went.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records each write and flush it's given.
+    #[derive(Default)]
+    struct Recorder {
+        data: Vec<u8>,
+        writes: Vec<usize>,
+        flushes: Vec<usize>,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.data.extend_from_slice(data);
+            self.writes.push(data.len());
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.push(self.data.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_block_device_groups() {
+        let data = (0..10000u32).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut device = BlockDevice::new(Recorder::default(), 512).erase_group(4).pad_byte(0xFF);
+        for chunk in data.chunks(333) {
+            device.write_all(chunk).unwrap();
+        }
+        assert_eq!(device.written(), 8192);
+        let recorder = device.finish().unwrap();
+
+        assert_eq!(&recorder.data[..data.len()], &data[..]);
+        assert_eq!(recorder.data.len(), 10240);
+        assert!(recorder.data[data.len()..].iter().all(|&b| b == 0xFF));
+
+        // Whole groups, then the padded tail; a barrier after each.
+        assert_eq!(recorder.writes, vec![2048, 2048, 2048, 2048, 2048]);
+        assert_eq!(recorder.flushes, vec![2048, 4096, 6144, 8192, 10240]);
+    }
+
+    #[test]
+    fn test_block_device_flush() {
+        let mut device = BlockDevice::new(Recorder::default(), 100).erase_group(8);
+        device.write_all(&[1; 250]).unwrap();
+        device.flush().unwrap();
+        assert!(device.get_ref().writes.is_empty());
+        assert_eq!(device.get_ref().flushes, vec![0]);
+        assert_eq!(device.written(), 0);
+
+        let recorder = device.finish().unwrap();
+        assert_eq!(recorder.writes, vec![300]);
+        assert_eq!(&recorder.data[250..], &[0; 50][..]);
+
+        let empty = BlockDevice::new(Recorder::default(), 100).finish().unwrap();
+        assert!(empty.writes.is_empty());
+    }
+}
@@ -0,0 +1,369 @@
+//! Reading an old file that's stored compressed, without decompressing it
+//! to a temporary file first.
+//!
+//! The appliers want the old file as `Read + Seek`, and seek all over it.
+//! That only works with a compressed container that's split into
+//! independently compressed frames, with a table of where each frame
+//! starts: squashfs images, or zstd's seekable format, say.  A
+//! `SeekableDecoder` knows how to find and decompress one frame of such a
+//! container; `SeekableReader` turns it into `Read + Seek` over the
+//! decompressed bytes, keeping the last frame it decompressed.
+//!
+//! `ZstdSeekable` (with the `zstd` feature) reads zstd's seekable format,
+//! as written by zstd's contrib seekable library or `write_zstd_seekable`.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::cmp::min;
+
+#[cfg(feature = "zstd")]
+use std::io::Write;
+#[cfg(feature = "zstd")]
+use format::wire;
+
+/// A compressed container that can decompress the frame holding any
+/// decompressed offset on its own.
+pub trait SeekableDecoder {
+    /// Length of the decompressed contents.
+    fn decompressed_len(&self) -> u64;
+
+    /// Replaces `buf` with the decompressed frame holding `offset`, which is
+    /// less than `decompressed_len()`, and returns the decompressed offset
+    /// the frame starts at.
+    fn decode_frame_at(&mut self, offset: u64, buf: &mut Vec<u8>) -> io::Result<u64>;
+}
+
+/// The decompressed contents of a `SeekableDecoder`, as `Read + Seek`.
+pub struct SeekableReader<D: SeekableDecoder> {
+    decoder: D,
+    pos: u64,
+
+    /// The last frame decompressed, and the offset it starts at.
+    frame: Vec<u8>,
+    frame_start: u64,
+}
+
+impl<D: SeekableDecoder> SeekableReader<D> {
+    pub fn new(decoder: D) -> SeekableReader<D> {
+        SeekableReader {
+            decoder: decoder,
+            pos: 0,
+            frame: Vec::new(),
+            frame_start: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.decoder
+    }
+
+    pub fn into_inner(self) -> D {
+        self.decoder
+    }
+}
+
+impl<D: SeekableDecoder> Read for SeekableReader<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.decoder.decompressed_len() {
+            return Ok(0);
+        }
+
+        let in_frame = self.pos >= self.frame_start && self.pos < self.frame_start + self.frame.len() as u64;
+        if !in_frame {
+            self.frame_start = self.decoder.decode_frame_at(self.pos, &mut self.frame)?;
+            if self.pos < self.frame_start || self.pos >= self.frame_start + self.frame.len() as u64 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Decoder returned a frame that doesn't hold offset {}", self.pos)));
+            }
+        }
+
+        let start = (self.pos - self.frame_start) as usize;
+        let n = min(buf.len(), self.frame.len() - start);
+        buf[..n].copy_from_slice(&self.frame[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: SeekableDecoder> Seek for SeekableReader<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => checked_offset(self.decoder.decompressed_len(), offset),
+            SeekFrom::Current(offset) => checked_offset(self.pos, offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative offset")),
+        }
+    }
+}
+
+fn checked_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+/// Magic number of the skippable frame holding the seek table.
+#[cfg(feature = "zstd")]
+pub const ZSTD_SEEK_TABLE_MAGIC: u32 = 0x184D2A5E;
+
+/// Magic number ending the seek table.
+#[cfg(feature = "zstd")]
+pub const ZSTD_SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
+/// Length of the seek table footer: frame count, descriptor, magic.
+#[cfg(feature = "zstd")]
+const FOOTER_SIZE: usize = 9;
+
+/// A file in zstd's seekable format: a run of independent zstd frames, then
+/// a skippable frame holding a table of their compressed and decompressed
+/// sizes.
+///
+/// The table's optional per-frame checksums aren't checked; frames that
+/// carry a content checksum are still checked by zstd as they decompress.
+#[cfg(feature = "zstd")]
+pub struct ZstdSeekable<R> {
+    inner: R,
+    /// Compressed and decompressed start of each frame, with one more entry
+    /// for the end of the last.
+    compressed: Vec<u64>,
+    decompressed: Vec<u64>,
+    /// Buffer for one compressed frame.
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "zstd")]
+impl<R: Read + Seek> ZstdSeekable<R> {
+    /// Reads the seek table from the end of `inner`.
+    pub fn open(mut inner: R) -> io::Result<ZstdSeekable<R>> {
+        let end = inner.seek(SeekFrom::End(0))?;
+        if end < (8 + FOOTER_SIZE) as u64 {
+            return Err(bad_table("File too short for a seek table"));
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE];
+        inner.seek(SeekFrom::Start(end - FOOTER_SIZE as u64))?;
+        inner.read_exact(&mut footer)?;
+
+        if wire::read_u32(&footer[5..9]) != ZSTD_SEEKABLE_MAGIC {
+            return Err(bad_table("Bad seekable magic"));
+        }
+        let frames = wire::read_u32(&footer[0..4]) as u64;
+        // Bit 7 says whether there are checksums, bits 6-2 are reserved and
+        // bits 1-0 are unused.
+        let descriptor = footer[4];
+        if descriptor & 0x7c != 0 {
+            return Err(bad_table("Reserved bits set in the seek table descriptor"));
+        }
+        let entry_size = if descriptor & 0x80 != 0 { 12 } else { 8 };
+
+        let table_size = frames * entry_size + FOOTER_SIZE as u64;
+        if table_size + 8 > end {
+            return Err(bad_table("Seek table longer than the file"));
+        }
+        let table_start = end - table_size - 8;
+
+        let mut table = vec![0u8; (table_size + 8) as usize];
+        inner.seek(SeekFrom::Start(table_start))?;
+        inner.read_exact(&mut table)?;
+
+        if wire::read_u32(&table[0..4]) != ZSTD_SEEK_TABLE_MAGIC {
+            return Err(bad_table("Bad seek table frame magic"));
+        }
+        if wire::read_u32(&table[4..8]) as u64 != table_size {
+            return Err(bad_table("Seek table frame size doesn't match its frame count"));
+        }
+
+        let mut compressed = vec![0u64];
+        let mut decompressed = vec![0u64];
+        for entry in table[8..].chunks(entry_size as usize).take(frames as usize) {
+            let c = compressed[compressed.len() - 1] + wire::read_u32(&entry[0..4]) as u64;
+            let d = decompressed[decompressed.len() - 1] + wire::read_u32(&entry[4..8]) as u64;
+            compressed.push(c);
+            decompressed.push(d);
+        }
+        if compressed[compressed.len() - 1] != table_start {
+            return Err(bad_table("Frame sizes don't add up to the start of the seek table"));
+        }
+
+        Ok(ZstdSeekable {
+            inner: inner,
+            compressed: compressed,
+            decompressed: decompressed,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.compressed.len() - 1
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn bad_table(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(feature = "zstd")]
+impl<R: Read + Seek> SeekableDecoder for ZstdSeekable<R> {
+    fn decompressed_len(&self) -> u64 {
+        self.decompressed[self.decompressed.len() - 1]
+    }
+
+    fn decode_frame_at(&mut self, offset: u64, buf: &mut Vec<u8>) -> io::Result<u64> {
+        // The last frame starting at or before `offset`; empty frames are
+        // skipped over since the next one starts at the same place.
+        let index = match self.decompressed.binary_search(&offset) {
+            Ok(mut i) => {
+                while self.decompressed[i + 1] == offset {
+                    i += 1;
+                }
+                i
+            }
+            Err(i) => i - 1,
+        };
+
+        let compressed_len = (self.compressed[index + 1] - self.compressed[index]) as usize;
+        self.buf.resize(compressed_len, 0);
+        self.inner.seek(SeekFrom::Start(self.compressed[index]))?;
+        self.inner.read_exact(&mut self.buf)?;
+
+        // A byte more than the table says, so a frame that decompresses to
+        // far more than that is caught without decompressing all of it.
+        let expected = self.decompressed[index + 1] - self.decompressed[index];
+        buf.clear();
+        zstd::stream::Decoder::new(&self.buf[..])?.take(expected + 1).read_to_end(buf)?;
+        if buf.len() as u64 != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Frame {} doesn't decompress to the size in the seek table", index)));
+        }
+        Ok(self.decompressed[index])
+    }
+}
+
+/// Writes `data` in zstd's seekable format, in frames of `frame_size`
+/// decompressed bytes, without per-frame checksums in the seek table.
+#[cfg(feature = "zstd")]
+pub fn write_zstd_seekable<W: Write>(data: &[u8], frame_size: usize, level: i32, mut w: W) -> io::Result<()> {
+    assert!(frame_size > 0 && frame_size <= u32::max_value() as usize);
+
+    let mut table = Vec::new();
+    for chunk in data.chunks(frame_size) {
+        let frame = zstd::stream::encode_all(chunk, level)?;
+        w.write_all(&frame)?;
+
+        let mut entry = [0u8; 8];
+        wire::write_u32(&mut entry[0..4], frame.len() as u32);
+        wire::write_u32(&mut entry[4..8], chunk.len() as u32);
+        table.extend_from_slice(&entry);
+    }
+    let frames = (table.len() / 8) as u32;
+
+    let mut header = [0u8; 8];
+    wire::write_u32(&mut header[0..4], ZSTD_SEEK_TABLE_MAGIC);
+    wire::write_u32(&mut header[4..8], (table.len() + FOOTER_SIZE) as u32);
+    w.write_all(&header)?;
+    w.write_all(&table)?;
+
+    let mut footer = [0u8; FOOTER_SIZE];
+    wire::write_u32(&mut footer[0..4], frames);
+    wire::write_u32(&mut footer[5..9], ZSTD_SEEKABLE_MAGIC);
+    w.write_all(&footer)
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use diff::Index;
+    use format::bsdiff;
+    use patch::apply_any;
+
+    fn data() -> Vec<u8> {
+        (0..50000u32).map(|i| (i * 7919 % 251) as u8).collect()
+    }
+
+    fn open(compressed: &[u8]) -> io::Result<SeekableReader<ZstdSeekable<Cursor<&[u8]>>>> {
+        Ok(SeekableReader::new(ZstdSeekable::open(Cursor::new(compressed))?))
+    }
+
+    #[test]
+    fn test_zstd_seekable_read() {
+        let data = data();
+        let mut compressed = Vec::new();
+        write_zstd_seekable(&data, 4096, 3, &mut compressed).unwrap();
+
+        let mut reader = open(&compressed).unwrap();
+        assert_eq!(reader.get_ref().frame_count(), 13);
+        assert_eq!(reader.get_ref().decompressed_len(), data.len() as u64);
+
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert!(all == data);
+
+        // Reads spanning frames, backwards and forwards.
+        for &offset in &[49000u64, 4000, 0, 8191, 20000] {
+            let mut buf = vec![0u8; 1000];
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert!(buf[..] == data[offset as usize..offset as usize + 1000], "{}", offset);
+        }
+
+        assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), data.len() as u64 - 10);
+        assert_eq!(reader.read(&mut [0u8; 100]).unwrap(), 10);
+        assert_eq!(reader.read(&mut [0u8; 100]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_zstd_seekable_bad_table() {
+        let mut compressed = Vec::new();
+        write_zstd_seekable(&data(), 4096, 3, &mut compressed).unwrap();
+
+        let mut bad_magic = compressed.clone();
+        *bad_magic.last_mut().unwrap() ^= 1;
+        assert_eq!(open(&bad_magic).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let truncated = &compressed[100..];
+        assert_eq!(open(truncated).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // The unused descriptor bits may be set, the reserved ones not.
+        let descriptor = compressed.len() - 5;
+        let mut unused = compressed.clone();
+        unused[descriptor] = 0x03;
+        assert!(open(&unused).is_ok());
+        let mut reserved = compressed.clone();
+        reserved[descriptor] = 0x04;
+        assert_eq!(open(&reserved).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // The first frame decompresses to more than its entry says.
+        let first_entry = compressed.len() - FOOTER_SIZE - 13 * 8;
+        let mut short = compressed.clone();
+        wire::write_u32(&mut short[first_entry + 4..first_entry + 8], 10);
+        let mut reader = open(&short).unwrap();
+        let err = reader.read(&mut [0u8; 100]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_apply_against_zstd_seekable() {
+        let old = data();
+        let mut new = old[10000..40000].to_vec();
+        new.extend_from_slice(b"and then some");
+        new.extend_from_slice(&old[..5000]);
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.clone()), &new);
+
+        let mut compressed = Vec::new();
+        write_zstd_seekable(&old, 4096, 3, &mut compressed).unwrap();
+
+        let mut out = Vec::new();
+        apply_any(&patch, open(&compressed).unwrap(), &mut out).unwrap();
+        assert!(out == new);
+    }
+}
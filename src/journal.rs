@@ -0,0 +1,248 @@
+//! A sidecar file recording how far `patch::apply_to_path` got, so an apply
+//! cut short by a crash or power loss picks up where it left off instead of
+//! rewriting the whole new file (see `ApplyOptions::journal_interval`).
+//!
+//! Layout (integers little-endian):
+//!
+//! ```text
+//! magic "RSDJNL01", SHA-1 of the patch, output offset (u64),
+//! SHA-1 of the output up to the offset
+//! ```
+//!
+//! The offset only ever covers output that was synced to disk before the
+//! journal was written.  Resuming still decodes the patch from the start,
+//! since compressed sections can't be entered midway, but the bytes before
+//! the offset are checked against their SHA-1 and then skipped rather than
+//! written again.  Without a usable journal, a block-hashed patch resumes
+//! after the blocks that match their hashes.
+
+use std::cmp::min;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use sha1::Sha1;
+
+use format::block_hashes::{self, BlockHashes};
+use format::wire;
+use patch::write_atomically;
+
+pub const MAGIC: &'static [u8] = b"RSDJNL01";
+
+pub const SIZE: usize = 8 + 20 + 8 + 20;
+
+/// How far an earlier apply of a patch got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Journal {
+    pub patch_digest: [u8; 20],
+    /// Bytes of output safely on disk.
+    pub offset: u64,
+    /// SHA-1 of those bytes.
+    pub prefix_digest: [u8; 20],
+}
+
+impl Journal {
+    pub fn read(buf: &[u8]) -> io::Result<Journal> {
+        if !buf.starts_with(MAGIC) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad journal magic"));
+        }
+        if buf.len() != SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Journal is the wrong size"));
+        }
+
+        let mut journal = Journal {
+            patch_digest: [0; 20],
+            offset: wire::read_u64(&buf[28..36]),
+            prefix_digest: [0; 20],
+        };
+        journal.patch_digest.copy_from_slice(&buf[8..28]);
+        journal.prefix_digest.copy_from_slice(&buf[36..56]);
+        Ok(journal)
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut buf = [0u8; SIZE];
+        buf[..8].copy_from_slice(MAGIC);
+        buf[8..28].copy_from_slice(&self.patch_digest);
+        wire::write_u64(&mut buf[28..36], self.offset);
+        buf[36..56].copy_from_slice(&self.prefix_digest);
+        writer.write_all(&buf)
+    }
+}
+
+/// Where the journal for an apply to `path` goes.
+pub fn path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".journal");
+    path.with_file_name(name)
+}
+
+fn sha1_digest(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    sha1.digest().bytes()
+}
+
+/// Feeds the first `len` bytes of `file` to `sha1`, or returns false if
+/// there aren't that many.
+fn hash_prefix(file: &mut File, len: u64, sha1: &mut Sha1) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let want = min(left, buf.len() as u64) as usize;
+        match file.read(&mut buf[..want])? {
+            0 => return Ok(false),
+            n => {
+                sha1.update(&buf[..n]);
+                left -= n as u64;
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// How much of the partial output in `file` can be kept for `patch`, with
+/// the SHA-1 of those bytes so far.
+fn resume_point(patch: &[u8], patch_digest: &[u8; 20], file: &mut File, journal_path: &Path)
+    -> io::Result<(u64, Sha1)>
+{
+    let journal = fs::read(journal_path).ok().and_then(|buf| Journal::read(&buf).ok());
+    if let Some(journal) = journal {
+        if journal.patch_digest == *patch_digest {
+            let mut sha1 = Sha1::new();
+            if hash_prefix(file, journal.offset, &mut sha1)? && sha1.digest().bytes() == journal.prefix_digest {
+                return Ok((journal.offset, sha1));
+            }
+        }
+    }
+
+    if block_hashes::is_match(patch) {
+        let (hashes, _) = BlockHashes::read(patch)?;
+        file.seek(SeekFrom::Start(0))?;
+        let kept = hashes.verified_prefix(&mut *file)?;
+        let mut sha1 = Sha1::new();
+        if hash_prefix(file, kept, &mut sha1)? {
+            return Ok((kept, sha1));
+        }
+    }
+
+    Ok((0, Sha1::new()))
+}
+
+/// Writes output to the temporary file, skipping what's already there and
+/// writing a journal entry every `interval` bytes.
+pub(crate) struct JournalWriter {
+    file: File,
+    journal_path: PathBuf,
+    patch_digest: [u8; 20],
+    interval: u64,
+    /// Output bytes seen so far, including skipped ones.
+    pos: u64,
+    /// Bytes kept from an earlier attempt.
+    skip: u64,
+    next_checkpoint: u64,
+    /// SHA-1 of the output up to `pos`.
+    sha1: Sha1,
+}
+
+impl JournalWriter {
+    /// Opens `temp_path`, keeping whatever part of an earlier attempt at
+    /// `patch` the journal at `journal_path` (or the patch's block hashes)
+    /// vouches for.
+    pub(crate) fn open(patch: &[u8], temp_path: &Path, journal_path: &Path, interval: u64)
+        -> io::Result<JournalWriter>
+    {
+        let patch_digest = sha1_digest(patch);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(temp_path)?;
+        let (skip, sha1) = resume_point(patch, &patch_digest, &mut file, journal_path)?;
+        info_event!("resuming apply after {} bytes", skip);
+
+        file.set_len(skip)?;
+        file.seek(SeekFrom::Start(skip))?;
+
+        Ok(JournalWriter {
+            file: file,
+            journal_path: journal_path.to_path_buf(),
+            patch_digest: patch_digest,
+            interval: interval,
+            pos: 0,
+            skip: skip,
+            next_checkpoint: skip + interval,
+            sha1: sha1,
+        })
+    }
+
+    /// Bytes kept from an earlier attempt.
+    pub(crate) fn resumed_at(&self) -> u64 {
+        self.skip
+    }
+
+    pub(crate) fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    fn checkpoint(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        let journal = Journal {
+            patch_digest: self.patch_digest,
+            offset: self.pos,
+            prefix_digest: self.sha1.digest().bytes(),
+        };
+        write_atomically(&self.journal_path, None, |file| journal.write_to(file))?;
+        self.next_checkpoint = self.pos + self.interval;
+        Ok(())
+    }
+
+    /// Checks that the output covered what was kept, and cuts off anything
+    /// after it from an earlier, longer attempt.
+    pub(crate) fn finish(self) -> io::Result<File> {
+        if self.pos < self.skip {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Patch produces {} bytes, but an earlier apply wrote {}", self.pos, self.skip)));
+        }
+        self.file.set_len(self.pos)?;
+        Ok(self.file)
+    }
+}
+
+impl Write for JournalWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos < self.skip {
+            let n = min(buf.len() as u64, self.skip - self.pos) as usize;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+
+        let n = self.file.write(buf)?;
+        self.sha1.update(&buf[..n]);
+        self.pos += n as u64;
+
+        if self.pos >= self.next_checkpoint {
+            self.checkpoint()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_roundtrip() {
+        let journal = Journal { patch_digest: [1; 20], offset: 1 << 40, prefix_digest: [2; 20] };
+        let mut buf = Vec::new();
+        journal.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), SIZE);
+        assert_eq!(Journal::read(&buf).unwrap(), journal);
+
+        assert!(Journal::read(&buf[..SIZE - 1]).is_err());
+        buf[0] ^= 1;
+        assert!(Journal::read(&buf).is_err());
+    }
+}
@@ -1,9 +1,87 @@
+#![deny(unused_must_use)]
+
 extern crate byteorder;
+#[cfg(feature = "bzip2")]
 extern crate bzip2;
+#[cfg(feature = "zstd")]
 extern crate zstd;
+#[cfg(feature = "sha1")]
 extern crate sha1;
+#[cfg(unix)]
+extern crate libc;
+
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[cfg(feature = "embedded-storage")]
+extern crate embedded_storage;
+
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+#[cfg(any(feature = "json", all(test, feature = "serde")))]
+extern crate serde_json;
+
+#[cfg(feature = "ed25519")]
+extern crate ed25519_compact;
+
+#[macro_use]
+mod macros;
+#[cfg(test)]
+mod test_util;
+mod lru;
 
+pub mod concurrency;
 pub mod format;
+pub mod index;
 
 pub mod patch;
 pub mod diff;
+pub mod inspect;
+pub mod bundle;
+#[cfg(feature = "sha1")]
+pub mod catalog;
+pub mod io;
+pub mod normalize;
+#[cfg(feature = "sha1")]
+pub mod payload;
+#[cfg(feature = "sha1")]
+pub mod service;
+#[cfg(feature = "sha1")]
+pub mod journal;
+
+/// Compile-time check that the types meant to be shared between threads stay
+/// `Send + Sync`: a server can build one `Index` up front and match against
+/// it from a whole thread pool.  Nothing here has interior mutability except
+/// the caches, which lock internally.
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn check<T: Send + Sync>() {}
+
+    check::<index::Index>();
+    check::<index::FmIndex>();
+    check::<index::SmallIndex>();
+    check::<index::AutoIndex>();
+    check::<index::MemCache>();
+    #[cfg(feature = "sha1")]
+    check::<index::FsCache>();
+    check::<index::BoundedCache<index::MemCache>>();
+
+    check::<diff::DiffStat>();
+    check::<diff::SizeEstimate>();
+    check::<diff::PatchOptions>();
+    check::<diff::Compression>();
+    check::<diff::Differ<'static, index::Index>>();
+
+    check::<concurrency::ScopedThreads>();
+
+    check::<patch::BufferConfig>();
+    check::<patch::ApplyOptions>();
+    check::<patch::PatchApplier>();
+    check::<patch::ValidationReport>();
+    check::<patch::ApplyStats>();
+
+    #[cfg(feature = "sha1")]
+    check::<service::IndexRegistry>();
+}
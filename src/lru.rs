@@ -0,0 +1,162 @@
+//! A map that remembers the order its entries were last used in, for the
+//! caches that evict the least recently used.  Every operation is a hash
+//! lookup plus a few pointer updates in a doubly linked list threaded
+//! through a slab.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    /// Towards the least recently used end.
+    older: Option<usize>,
+    newer: Option<usize>,
+}
+
+pub(crate) struct Lru<K, V> {
+    slots: HashMap<K, usize>,
+    nodes: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>,
+    oldest: Option<usize>,
+    newest: Option<usize>,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub(crate) fn new() -> Lru<K, V> {
+        Lru {
+            slots: HashMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            oldest: None,
+            newest: None,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    #[cfg_attr(not(feature = "sha1"), allow(dead_code))]
+    pub(crate) fn contains(&self, key: &K) -> bool {
+        self.slots.contains_key(key)
+    }
+
+    /// The value for `key`, marking it as the most recently used.
+    pub(crate) fn touch(&mut self, key: &K) -> Option<&V> {
+        let slot = *self.slots.get(key)?;
+        self.unlink(slot);
+        self.push_newest(slot);
+        self.nodes[slot].as_ref().map(|node| &node.value)
+    }
+
+    /// Inserts `value` as the most recently used, returning the value it
+    /// replaces, if any.
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let old = self.remove(&key);
+
+        let node = Some(Node { key: key.clone(), value: value, older: None, newer: None });
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        self.slots.insert(key, slot);
+        self.push_newest(slot);
+        old
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let slot = self.slots.remove(key)?;
+        Some(self.take(slot).1)
+    }
+
+    /// Removes and returns the least recently used entry.
+    pub(crate) fn pop_oldest(&mut self) -> Option<(K, V)> {
+        let slot = self.oldest?;
+        let entry = self.take(slot);
+        self.slots.remove(&entry.0);
+        Some(entry)
+    }
+
+    fn take(&mut self, slot: usize) -> (K, V) {
+        self.unlink(slot);
+        self.free.push(slot);
+        let node = self.nodes[slot].take().expect("slot in use");
+        (node.key, node.value)
+    }
+
+    fn node(&mut self, slot: usize) -> &mut Node<K, V> {
+        self.nodes[slot].as_mut().expect("slot in use")
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (older, newer) = {
+            let node = self.node(slot);
+            (node.older.take(), node.newer.take())
+        };
+
+        match older {
+            Some(older) => self.node(older).newer = newer,
+            None => self.oldest = newer,
+        }
+        match newer {
+            Some(newer) => self.node(newer).older = older,
+            None => self.newest = older,
+        }
+    }
+
+    fn push_newest(&mut self, slot: usize) {
+        let newest = self.newest;
+        self.node(slot).older = newest;
+        match newest {
+            Some(newest) => self.node(newest).newer = Some(slot),
+            None => self.oldest = Some(slot),
+        }
+        self.newest = Some(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(lru: &mut Lru<u32, &'static str>) -> Vec<u32> {
+        let mut keys = Vec::new();
+        while let Some((key, _)) = lru.pop_oldest() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn test_lru_order() {
+        let mut lru = Lru::new();
+        for &key in &[1, 2, 3, 4] {
+            assert_eq!(lru.insert(key, "x"), None);
+        }
+
+        assert_eq!(lru.touch(&2), Some(&"x"));
+        assert_eq!(lru.touch(&9), None);
+        assert_eq!(lru.insert(3, "y"), Some("x"));
+        assert_eq!(lru.remove(&1), Some("x"));
+        assert_eq!(lru.remove(&1), None);
+        assert!(lru.contains(&4) && !lru.contains(&1));
+        assert_eq!(lru.len(), 3);
+
+        // Freed slots are reused without disturbing the order.
+        lru.insert(5, "z");
+        assert_eq!(drain(&mut lru), vec![4, 2, 3, 5]);
+        assert_eq!(lru.len(), 0);
+
+        lru.insert(6, "x");
+        lru.touch(&6);
+        assert_eq!(drain(&mut lru), vec![6]);
+    }
+}
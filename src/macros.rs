@@ -0,0 +1,51 @@
+//! Diagnostics, routed through `tracing` when the `tracing` feature is on
+//! and compiled out otherwise.
+//!
+//! The disabled versions still type-check their arguments, so turning the
+//! feature on can't break the build.
+
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($arg:tt)*) => { ::tracing::trace!($($arg)*) }
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! debug_event {
+    ($($arg:tt)*) => { ::tracing::debug!($($arg)*) }
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! info_event {
+    ($($arg:tt)*) => { ::tracing::info!($($arg)*) }
+}
+
+/// Enters a debug-level span named `$name` until the end of the enclosing
+/// block.
+#[cfg(feature = "tracing")]
+macro_rules! enter_span {
+    ($name:expr) => {
+        let _span = ::tracing::debug_span!($name).entered();
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($arg:tt)*) => { { if false { let _ = format_args!($($arg)*); } } }
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_event {
+    ($($arg:tt)*) => { { if false { let _ = format_args!($($arg)*); } } }
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! info_event {
+    ($($arg:tt)*) => { { if false { let _ = format_args!($($arg)*); } } }
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! enter_span {
+    ($name:expr) => {
+        let _ = $name;
+    }
+}
@@ -0,0 +1,417 @@
+//! Normalizing pre-passes, for inputs where a systematic difference between
+//! the old and new files would otherwise defeat matching.
+//!
+//! A `Normalizer` rewrites both files before diffing, and records in the
+//! patch what's needed to turn the normalized new file back into the real
+//! one.  The applier normalizes the old file the same way, applies the
+//! patch, then undoes the normalization.  `PointerMask` is the one that
+//! ships: memory snapshots taken under ASLR differ in every pointer, which
+//! it zeroes out.
+//!
+//! Layout (integers little-endian):
+//!
+//! ```text
+//! magic "RSDNRM01", description length (u32), inverse patch length (u64),
+//! patch length (u64), patch, description, inverse patch
+//! ```
+//!
+//! The wrapped patch comes first, so that its own header sits at a fixed
+//! offset, where `patch::can_apply` can find it.  It may be block-hashed,
+//! and the whole thing may be wrapped in a source digest.
+//!
+//! The new file's inverse isn't stored as is, but as a plain patch against
+//! the old file's, which the applier gets for free when it normalizes the
+//! old file.  What was masked mostly changes the same way throughout (every
+//! pointer slides by the same amount), so that patch is tiny where the raw
+//! inverse would dwarf everything else.
+
+use std::io::{self, Write};
+
+use diff::{Differ, Index, PatchOptions};
+use diff::auto::Engine;
+#[cfg(feature = "sha1")]
+use format::source_digest::SourceDigest;
+use format::{self, wire};
+use patch::apply_from_slice;
+
+pub const MAGIC: &'static [u8] = b"RSDNRM01";
+
+/// Bytes before the wrapped patch.
+pub const HEADER_SIZE: usize = 8 + 4 + 8 + 8;
+
+pub fn is_match(patch: &[u8]) -> bool {
+    patch.starts_with(MAGIC)
+}
+
+/// A reversible rewrite applied to both files before diffing.
+pub trait Normalizer {
+    /// Identifies the normalizer and its settings.  It's recorded in the
+    /// patch, and `apply_with` refuses a normalizer that describes itself
+    /// differently.
+    fn describe(&self) -> Vec<u8>;
+
+    /// Rewrites `data` in place, returning what `denormalize` needs to undo
+    /// it.
+    fn normalize(&self, data: &mut [u8]) -> Vec<u8>;
+
+    /// Undoes `normalize` on `data`, given what it returned.  Fails with
+    /// `InvalidData` if `inverse` doesn't fit `data`.
+    fn denormalize(&self, data: &mut [u8], inverse: &[u8]) -> io::Result<()>;
+}
+
+const POINTER_MASK_NAME: &'static [u8] = b"pointer-mask";
+
+/// Zeroes every 8-byte aligned, little-endian value in `start..end`: with
+/// the range set to cover the heap (or all mapped memory), that's the
+/// pointers of a memory snapshot, along with the odd integer that happens to
+/// look like one.
+///
+/// The inverse holds 16 bytes for each value zeroed in the new file: its
+/// word index and the original value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PointerMask {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl PointerMask {
+    pub fn new(start: u64, end: u64) -> PointerMask {
+        PointerMask {
+            start: start,
+            end: end,
+        }
+    }
+
+    /// The `PointerMask` that `describe` returned `description`, if any.
+    pub fn from_description(description: &[u8]) -> Option<PointerMask> {
+        let n = POINTER_MASK_NAME.len();
+        if description.len() != n + 16 || &description[..n] != POINTER_MASK_NAME {
+            return None;
+        }
+        Some(PointerMask::new(
+            wire::read_u64(&description[n..n + 8]),
+            wire::read_u64(&description[n + 8..])))
+    }
+}
+
+impl Normalizer for PointerMask {
+    fn describe(&self) -> Vec<u8> {
+        let mut description = POINTER_MASK_NAME.to_vec();
+        let mut buf = [0u8; 16];
+        wire::write_u64(&mut buf[..8], self.start);
+        wire::write_u64(&mut buf[8..], self.end);
+        description.extend_from_slice(&buf);
+        description
+    }
+
+    fn normalize(&self, data: &mut [u8]) -> Vec<u8> {
+        let mut inverse = Vec::new();
+        let mut entry = [0u8; 16];
+
+        for (i, word) in data.chunks_mut(8).enumerate() {
+            if word.len() < 8 {
+                break;
+            }
+
+            let value = wire::read_u64(word);
+            if value >= self.start && value < self.end && value != 0 {
+                wire::write_u64(&mut entry[..8], i as u64);
+                wire::write_u64(&mut entry[8..], value);
+                inverse.extend_from_slice(&entry);
+
+                for b in word.iter_mut() {
+                    *b = 0;
+                }
+            }
+        }
+
+        inverse
+    }
+
+    fn denormalize(&self, data: &mut [u8], inverse: &[u8]) -> io::Result<()> {
+        if inverse.len() % 16 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad pointer mask inverse length"));
+        }
+
+        for entry in inverse.chunks(16) {
+            let index = wire::read_u64(&entry[..8]);
+            let value = wire::read_u64(&entry[8..]);
+
+            let end = index.checked_mul(8).and_then(|offset| offset.checked_add(8));
+            let word = match end {
+                Some(end) if end <= data.len() as u64 => &mut data[end as usize - 8..end as usize],
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Masked pointer {} is past the end of the output", index))),
+            };
+
+            if word.iter().any(|&b| b != 0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Masked pointer {} wasn't zero in the output", index)));
+            }
+            wire::write_u64(word, value);
+        }
+
+        Ok(())
+    }
+}
+
+/// Normalizes `old` and `new` and diffs them with `engine`, writing the
+/// patch wrapped with what's needed to undo the normalization.  Block hashes
+/// in `options` are of the normalized new file; a source digest is of the
+/// real old one, and wraps the whole lot.
+pub fn write_patch<N, W>(normalizer: &N, old: &[u8], new: &[u8], engine: Engine, options: &PatchOptions, mut writer: W)
+    -> io::Result<()>
+    where
+        N: Normalizer,
+        W: Write
+{
+    let mut normalized_old = old.to_vec();
+    let old_inverse = normalizer.normalize(&mut normalized_old);
+    let mut new = new.to_vec();
+    let inverse = normalizer.normalize(&mut new);
+
+    // Compressed the same way as the patch, so it needs no other features.
+    let mut inverse_patch = Vec::new();
+    Differ::new(&Index::compute(old_inverse))
+        .options(PatchOptions::new().compression(options.compression))
+        .run(&inverse, &mut inverse_patch)?;
+
+    // A source digest has to be of the real old file, so it goes outside.
+    #[allow(unused_mut)]
+    let mut inner_options = options.clone();
+    #[cfg(feature = "sha1")]
+    {
+        inner_options.source_digest = false;
+    }
+
+    let index = Index::compute(normalized_old);
+    let mut patch = Vec::new();
+    Differ::new(&index)
+        .options(inner_options)
+        .format(engine)
+        .run(&new, &mut patch)?;
+
+    let description = normalizer.describe();
+
+    let mut header = [0u8; HEADER_SIZE];
+    header[..8].copy_from_slice(MAGIC);
+    wire::write_u32(&mut header[8..12], description.len() as u32);
+    wire::write_u64(&mut header[12..20], inverse_patch.len() as u64);
+    wire::write_u64(&mut header[20..28], patch.len() as u64);
+
+    let mut wrapped = Vec::with_capacity(HEADER_SIZE + patch.len() + description.len() + inverse_patch.len());
+    wrapped.extend_from_slice(&header);
+    wrapped.extend_from_slice(&patch);
+    wrapped.extend_from_slice(&description);
+    wrapped.extend_from_slice(&inverse_patch);
+
+    #[cfg(feature = "sha1")]
+    {
+        if options.source_digest {
+            return SourceDigest::compute(old).write_patch(&wrapped, writer);
+        }
+    }
+
+    writer.write_all(&wrapped)
+}
+
+/// The parts of a normalized patch: the patch inside, the normalizer's
+/// description, and the patch from the old file's inverse to the new one's.
+pub fn read(patch: &[u8]) -> io::Result<(&[u8], &[u8], &[u8])> {
+    if !is_match(patch) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad normalizer magic"));
+    }
+    if patch.len() < HEADER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated normalizer header"));
+    }
+
+    let description_len = wire::read_u32(&patch[8..12]) as u64;
+    let inverse_len = wire::read_u64(&patch[12..20]);
+    let patch_len = wire::read_u64(&patch[20..28]);
+
+    let rest = &patch[HEADER_SIZE..];
+    let expected = patch_len.checked_add(description_len).and_then(|n| n.checked_add(inverse_len));
+    match expected {
+        Some(expected) if expected == rest.len() as u64 => {}
+        Some(expected) if expected > rest.len() as u64 =>
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated normalized patch")),
+        Some(_) =>
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Trailing bytes after normalized patch")),
+        None =>
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad normalizer header sizes")),
+    }
+
+    let (inner, rest) = rest.split_at(patch_len as usize);
+    format::check_wrapped(patch, inner)?;
+    let (description, inverse_patch) = rest.split_at(description_len as usize);
+    if format::is_envelope(inverse_patch) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Normalizer inverse patch is wrapped"));
+    }
+    Ok((inner, description, inverse_patch))
+}
+
+/// Applies a normalized patch to `old` with `normalizer`, which must be set
+/// up as it was when the patch was made.
+pub fn apply_with<N: Normalizer, W: Write>(normalizer: &N, patch: &[u8], old: &[u8], mut new: W) -> io::Result<()> {
+    let (inner, description, inverse_patch) = read(patch)?;
+    if description != &normalizer.describe()[..] {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Patch was made with a different normalizer, or different settings"));
+    }
+
+    let mut old = old.to_vec();
+    let old_inverse = normalizer.normalize(&mut old);
+    let mut inverse = Vec::new();
+    apply_from_slice(inverse_patch, &old_inverse, &mut inverse)?;
+
+    let mut out = Vec::new();
+    apply_from_slice(inner, &old, &mut out)?;
+    normalizer.denormalize(&mut out, &inverse)?;
+    new.write_all(&out)
+}
+
+/// Applies a normalized patch made with one of the normalizers that ship
+/// with rsdiff.  Others need `apply_with`.  `patch::apply_any` comes here
+/// for normalized patches, reading the whole old file into memory.
+pub fn apply<W: Write>(patch: &[u8], old: &[u8], new: W) -> io::Result<()> {
+    let (_, description, _) = read(patch)?;
+    match PointerMask::from_description(description) {
+        Some(mask) => apply_with(&mask, patch, old, new),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Patch needs a custom normalizer; apply it with normalize::apply_with")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::*;
+    use diff::{Differ, Index, PatchOptions};
+    use diff::auto::Engine;
+    use patch::{apply_any, can_apply, CAN_APPLY_BYTES};
+
+    /// A fake heap: records of two pointers, a counter and some text, with
+    /// the pointers all relative to `base`.
+    fn snapshot(base: u64, records: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 8];
+        for i in 0..records {
+            wire::write_u64(&mut buf, base + ((i * 7919) % records) as u64 * 32);
+            data.extend_from_slice(&buf);
+            wire::write_u64(&mut buf, base + ((i * 104729) % records) as u64 * 32 + 8);
+            data.extend_from_slice(&buf);
+            wire::write_u64(&mut buf, i as u64);
+            data.extend_from_slice(&buf);
+            data.extend_from_slice(b"heap obj");
+        }
+        data
+    }
+
+    #[test]
+    fn test_pointer_mask_roundtrip() {
+        let mask = PointerMask::new(0x7f00_0000_0000, 0x7fff_ffff_ffff);
+        assert_eq!(PointerMask::from_description(&mask.describe()), Some(mask));
+
+        let original = snapshot(0x7f12_3456_0000, 100);
+        let mut data = original.clone();
+        let inverse = mask.normalize(&mut data);
+        assert_eq!(inverse.len(), 200 * 16);
+        assert_eq!(data, snapshot(0, 100).iter().enumerate()
+            .map(|(i, &b)| if i % 32 < 16 { 0 } else { b })
+            .collect::<Vec<_>>());
+
+        mask.denormalize(&mut data, &inverse).unwrap();
+        assert_eq!(data, original);
+
+        assert!(mask.denormalize(&mut data, &inverse).is_err());
+        assert!(mask.denormalize(&mut data[..8], &inverse).is_err());
+
+        // Word indexes whose offset doesn't fit in a u64.
+        for &index in &[!0 / 8, !0] {
+            let mut entry = [0u8; 16];
+            wire::write_u64(&mut entry[..8], index);
+            let err = mask.denormalize(&mut data, &entry).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_normalized_patch() {
+        let mask = PointerMask::new(0x7f00_0000_0000, 0x7fff_ffff_ffff);
+        let old = snapshot(0x7f12_3456_0000, 500);
+        let mut new = snapshot(0x7fab_cdef_0000, 500);
+        new[32 * 250 + 16..32 * 250 + 24].copy_from_slice(b"CHANGED!");
+
+        let mut patch = Vec::new();
+        write_patch(&mask, &old, &new, Engine::Bsdiff, &PatchOptions::default(), &mut patch).unwrap();
+        assert_eq!(can_apply(&patch[..CAN_APPLY_BYTES]), Ok(()));
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(out, new);
+
+        // Every pointer moved, so a plain patch has to carry them all, while
+        // the normalized one only has to say how far they slid.
+        let mut plain = Vec::new();
+        Differ::new(&Index::compute(old.clone())).run(&new, &mut plain).unwrap();
+        assert!(patch.len() < plain.len(), "{} vs {}", patch.len(), plain.len());
+
+        // The inverse has an entry for each of them, but only its changes
+        // from the old file's are stored.
+        let (_, _, inverse_patch) = read(&patch).unwrap();
+        assert!(inverse_patch.len() * 20 < 1000 * 16, "{}", inverse_patch.len());
+
+        let other = PointerMask::new(0, 1);
+        let err = apply_with(&other, &patch, &old, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_normalized_patch_roundtrip() {
+        let mask = PointerMask::new(0x7f00_0000_0000, 0x7fff_ffff_ffff);
+        let old = snapshot(0x7f12_3456_0000, 300);
+        let mut new = snapshot(0x7fab_cdef_0000, 400);
+        new[32 * 100..32 * 101].copy_from_slice(&[0x55; 32]);
+        // A pointer that's only in the new file, and one that's gone.
+        wire::write_u64(&mut new[32 * 200 + 16..32 * 200 + 24], 0x7fab_cdef_1234);
+        wire::write_u64(&mut new[32 * 250..32 * 250 + 8], 0);
+
+        #[allow(unused_mut)]
+        let mut options = vec![PatchOptions::default()];
+        #[cfg(feature = "sha1")]
+        options.push(PatchOptions::default().source_digest(true).block_hashes(1024));
+
+        for options in &options {
+            for &engine in ::diff::auto::ALL_ENGINES {
+                let mut patch = Vec::new();
+                write_patch(&mask, &old, &new, engine, options, &mut patch).unwrap();
+
+                let mut out = Vec::new();
+                apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+                assert!(out == new, "{:?}", engine);
+
+                let mut out = Vec::new();
+                apply_from_slice(&patch, &old, &mut out).unwrap();
+                assert!(out == new, "{:?}", engine);
+
+                if is_match(&patch) {
+                    let mut out = Vec::new();
+                    apply_with(&mask, &patch, &old, &mut out).unwrap();
+                    assert!(out == new, "{:?}", engine);
+                }
+            }
+        }
+
+        let mut patch = Vec::new();
+        write_patch(&mask, &old, &new, Engine::Bsdiff, &PatchOptions::default(), &mut patch).unwrap();
+
+        // The inverse patch can't be an envelope itself.
+        let end = patch.len();
+        let inverse_len = wire::read_u64(&patch[12..20]) as usize;
+        let mut wrapped = patch.clone();
+        wrapped[end - inverse_len..end - inverse_len + 8].copy_from_slice(MAGIC);
+        let err = apply_with(&mask, &wrapped, &old, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
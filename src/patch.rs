@@ -1,108 +1,3152 @@
-use std::io::{self, Read, Write, Seek, Cursor};
-use std::cmp::min;
+use std::io::{self, Read, Write, Seek, SeekFrom, Cursor, BufReader};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::cmp::{min, max};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::error;
+use std::fmt;
 
-use bzip2::bufread::BzDecoder;
+#[cfg(feature = "sha1")]
+use sha1::Sha1;
 
 use format::bsdiff::{
     Command,
     CommandReader,
     Header,
 };
+use format::{self, bsdiff, linear_diff, FeatureSet, Format, PatchFormat};
+use format::linear_diff::LinearDiff;
+#[cfg(feature = "zstd")]
+use format::linear_diff::LinearDiffZstd;
+#[cfg(feature = "zstd")]
+use format::zstd_patch_from;
+#[cfg(feature = "sha1")]
+use format::block_hashes::{self, BlockHashes};
+#[cfg(feature = "sha1")]
+use format::source_digest::{self, SourceDigest};
+use format::multi_source::{self, MultiSource};
+use format::sink::{self, CountingWriter, ScratchFile, SpillBuffer, TempFiles};
+use format::wire;
+use diff::{record_seek, Compression, PatchOptions};
+use diff::auto::{check_delta_mode, Engine};
+#[cfg(feature = "zstd")]
+use diff::auto::{patch_from_needs_old, zstd_level};
+use normalize;
+use io::BlockDevice;
+use concurrency::{self, Executor};
+#[cfg(feature = "sha1")]
+use journal::{self, JournalWriter};
 
-pub fn read_paired_bufs<F, R0: Read, R1: Read>(
-    mut size: u64,
-    mut r0: R0,
-    mut r1: R1,
-    mut f: F
+/// Applies `patch` to `old`, picking the format based on the patch's magic
+/// bytes.  Fails with `InvalidData` if the magic isn't one we know about.
+pub fn apply_any<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    apply_any_with_buffers(patch, old, new, &BufferConfig::default())
+}
+
+/// Like `apply_any`, with scratch buffers sized by `config` for the formats
+/// that support it.
+pub fn apply_any_with_buffers<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW, config: &BufferConfig)
+    -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    apply_any_in(patch, old, new, config, None, 0, &mut ())
+}
+
+/// Dispatches on the patch's magic, decoding in `mode`, or in the format's
+/// own `DECODE_MODE` if that's `None`.  Envelopes are unwrapped first: a
+/// source digest is checked against the whole old file (with
+/// `verify_source`, before anything is written), a normalized patch
+/// goes to `normalize::apply`, and block-hashed output is checked block by
+/// block.  Bsdiff sections are decompressed on up to `threads` background
+/// threads; see `bsdiff::apply_patch_with_threads`.  Bsdiff and linear_diff
+/// commands are reported to `observer` as they're applied.
+fn apply_any_in<OldRS, NewW>(
+    patch: &[u8],
+    mut old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: Option<DecodeMode>,
+    threads: usize,
+    observer: &mut ApplyObserver
 ) -> io::Result<()>
-    where F: FnMut(&mut [u8], &mut [u8]) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
 {
-    let mut buf0 = [0u8; 1024];
-    let mut buf1 = [0u8; 1024];
+    #[cfg(feature = "sha1")]
+    {
+        if source_digest::is_match(patch) {
+            let (_, inner) = SourceDigest::read(patch)?;
+            let start = old.seek(SeekFrom::Current(0))?;
+            old.seek(SeekFrom::Start(0))?;
+            verify_source(patch, &mut old)?;
+            old.seek(SeekFrom::Start(start))?;
+            return apply_any_in(inner, old, new, config, mode, threads, observer);
+        }
+    }
 
-    let (mut p0, mut p1) = (0, 0);
-    let mut base = 0;
+    if normalize::is_match(patch) {
+        let mut data = Vec::new();
+        old.seek(SeekFrom::Start(0))?;
+        old.read_to_end(&mut data)?;
+        return normalize::apply(patch, &data, new);
+    }
 
-    while size > 0 {
-        println!("base {} size {}", base, size);
-        let avail = min(buf0.len() as u64, size) as usize;
-        println!("avail {} p0 {}", avail, p0);
-        if p0 < avail {
-            let s0 = r0.read(&mut buf0[p0..avail])?;
-            p0 += s0;
-            if s0 == 0 {
-                println!("got s0 0");
-                break;
-            }
-            println!("s0 {} p0 {}", s0, p0);
+    #[cfg(feature = "sha1")]
+    {
+        if block_hashes::is_match(patch) {
+            let (hashes, inner) = BlockHashes::read(patch)?;
+            let mut new = hashes.verifier(new);
+            apply_format_in(inner, old, &mut new, config, mode, threads, observer)?;
+            new.finish()?;
+            return Ok(());
         }
+    }
 
-        let avail = min(buf1.len() as u64, size) as usize;
-        println!("avail {} p1 {}", avail, p1);
-        if p1 < avail {
-            let s1 = r1.read(&mut buf1[p1..avail])?;
-            p1 += s1;
-            if s1 == 0 {
-                println!("got s1 0");
-                break;
+    apply_format_in(patch, old, new, config, mode, threads, observer)
+}
+
+fn apply_format_in<OldRS, NewW>(
+    patch: &[u8],
+    old: OldRS,
+    new: NewW,
+    config: &BufferConfig,
+    mode: Option<DecodeMode>,
+    threads: usize,
+    observer: &mut ApplyObserver
+) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    let format = Format::detect(patch).ok_or_else(|| unknown_format(patch))?;
+    let mode = mode.unwrap_or_else(|| format.default_mode(patch));
+    match format {
+        Format::Bsdiff => bsdiff::apply_patch_observed(patch, old, new, config, mode, threads, observer),
+        Format::LinearDiff => linear_diff::apply_patch_observed(patch, old, new, config, mode, observer),
+        #[cfg(feature = "zstd")]
+        Format::LinearDiffZstd => linear_diff::apply_compressed_patch_observed(patch, old, new, config, mode, observer),
+        Format::MultiSource => multi_source::apply_patch_with_mode(patch, &mut [old], new, mode),
+        #[cfg(feature = "zstd")]
+        Format::ZstdPatchFrom => zstd_patch_from::apply_patch(patch, old, new),
+    }
+}
+
+/// Intermediate files in `apply_chain` bigger than this go to temporary
+/// files instead of memory.
+pub const CHAIN_MEMORY_LIMIT: usize = 64 << 20;
+
+/// Applies `patches` one after another, starting from `old`: each patch's
+/// output is the next one's old file, and the last one's goes to `new`.
+/// Intermediate files are kept in memory up to `CHAIN_MEMORY_LIMIT` bytes,
+/// and in temporary files (`TempFiles::new()`) past that, which are deleted
+/// as soon as the next patch is done with them.  Each patch is read into memory in turn.
+///
+/// With no patches at all, `old` is copied to `new`.
+pub fn apply_chain<I, P, OldRS, NewW>(patches: I, old: OldRS, new: NewW) -> io::Result<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: Read,
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    apply_chain_with_limit(patches, old, new, CHAIN_MEMORY_LIMIT, &TempFiles::new())
+}
+
+/// Like `apply_chain`, keeping intermediate files of up to `memory_limit`
+/// bytes in memory and bigger ones in `scratch`.  At most two are around at
+/// once.
+pub fn apply_chain_with_limit<I, P, OldRS, NewW>(patches: I, mut old: OldRS, mut new: NewW, memory_limit: usize,
+    scratch: &sink::Scratch) -> io::Result<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: Read,
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    let mut patches = patches.into_iter().peekable();
+    let mut intermediate: Option<ScratchFile> = None;
+    let mut step = 0;
+
+    while let Some(mut patch_reader) = patches.next() {
+        let mut patch = Vec::new();
+        patch_reader.read_to_end(&mut patch)?;
+
+        let last = patches.peek().is_none();
+        let res = match (intermediate.take(), last) {
+            (None, true) => apply_any(&patch, &mut old, &mut new),
+            (Some(reader), true) => apply_any(&patch, reader, &mut new),
+            (prev, false) => {
+                let mut out = SpillBuffer::new(memory_limit, scratch);
+                let res = match prev {
+                    None => apply_any(&patch, &mut old, &mut out),
+                    Some(reader) => apply_any(&patch, reader, &mut out),
+                };
+                res.and_then(|()| {
+                    intermediate = Some(out.into_reader()?);
+                    Ok(())
+                })
             }
-            println!("s1 {} p1 {}", s1, p1);
+        };
+        res.map_err(|e| io::Error::new(e.kind(), format!("Patch {} of the chain: {}", step, e)))?;
+
+        if last {
+            return Ok(());
         }
+        step += 1;
+    }
 
-        let pmin = min(p0, p1);
+    // No patches: the old file is the new one.
+    old.seek(SeekFrom::Start(0))?;
+    io::copy(&mut old, &mut new).map(|_| ())
+}
 
-        f(&mut buf0[base..pmin], &mut buf1[base..pmin])?;
+/// Like `apply_any`, for an old file that can only be read front to back,
+/// such as a pipe or an entry in a tar stream.  Seeks forward are done by
+/// reading and discarding; a patch that seeks backwards fails at the command
+/// that does.  Patches written with `PatchOptions::sequential_old_access`
+/// never do.
+///
+/// Formats that need the old file's length up front, like `LinearDiff`,
+/// aren't supported.
+pub fn apply_sequential<OldR: Read, NewW: Write>(patch: &[u8], old: OldR, new: NewW) -> io::Result<()> {
+    apply_sequential_with_window(patch, old, new, 0)
+}
 
-        if p0 < p1 {
-            for i in pmin..p1 {
-                buf1[i - pmin] = buf1[i];
+/// Like `apply_sequential`, but keeps the last `window` bytes read from `old`
+/// in memory, so the patch may seek back that far.
+pub fn apply_sequential_with_window<OldR: Read, NewW: Write>(patch: &[u8], old: OldR, new: NewW, window: usize)
+    -> io::Result<()>
+{
+    #[cfg(feature = "sha1")]
+    {
+        // The old file can't be read twice, so a source digest is checked
+        // against what the apply reads, and the rest of the file, afterwards.
+        if source_digest::is_match(patch) {
+            let (expected, inner) = SourceDigest::read(patch)?;
+            let mut contents = CountingWriter::new(DigestWriter::new(io::sink()));
+            {
+                let mut old = TeeReader::new(old, &mut contents);
+                apply_any(inner, SequentialOld::new(&mut old, window), new)?;
+                io::copy(&mut old, &mut io::sink())?;
             }
-        } else if p1 < p0 {
-            for i in pmin..p0 {
-                buf0[i - pmin] = buf0[i];
+            return check_source(&expected, contents).map_err(io::Error::from);
+        }
+    }
+
+    apply_any(patch, SequentialOld::new(old, window), new)
+}
+
+/// Reads through `inner`, writing a copy of everything read to `copy`.
+#[cfg(feature = "sha1")]
+struct TeeReader<R, W> {
+    inner: R,
+    copy: W,
+}
+
+#[cfg(feature = "sha1")]
+impl<R: Read, W: Write> TeeReader<R, W> {
+    fn new(inner: R, copy: W) -> TeeReader<R, W> {
+        TeeReader {
+            inner: inner,
+            copy: copy,
+        }
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// Applies `patch` (in any format `apply_any` takes) to a raw block device,
+/// such as a partition, through `device`, and returns the device once the
+/// output is all written, its last block padded.  The appliers buffer a
+/// whole erase group of output before handing it on, so that writes reach
+/// the device in the fewest batches.
+pub fn apply_to_block_device<OldRS, W>(patch: &[u8], old: OldRS, mut device: BlockDevice<W>) -> io::Result<W>
+    where
+        OldRS: Read+Seek,
+        W: Write
+{
+    let config = BufferConfig::default().output_buffer_size(device.group_size());
+    apply_any_with_buffers(patch, old, &mut device, &config)?;
+    device.finish()
+}
+
+/// An old file read front to back, with the last `window` bytes kept around
+/// for seeking back into.  Seeks forward skip over bytes.
+pub(crate) struct SequentialOld<R: Read> {
+    inner: R,
+    /// Bytes read from `inner` so far.
+    pos: u64,
+    /// Where the next read starts; at most `pos`.
+    cursor: u64,
+    /// The bytes just before `pos`, up to twice `window` of them so they
+    /// aren't shuffled down on every read.
+    history: Vec<u8>,
+    window: usize,
+}
+
+impl<R: Read> SequentialOld<R> {
+    pub(crate) fn new(inner: R, window: usize) -> SequentialOld<R> {
+        SequentialOld {
+            inner: inner,
+            pos: 0,
+            cursor: 0,
+            history: Vec::new(),
+            window: window,
+        }
+    }
+
+    fn remember(&mut self, bytes: &[u8]) {
+        if self.window == 0 {
+            return;
+        }
+        self.history.extend_from_slice(bytes);
+        if self.history.len() > 2 * self.window {
+            let excess = self.history.len() - self.window;
+            self.history.drain(..excess);
+        }
+    }
+
+    /// Reads and remembers `count` bytes from `inner`.
+    fn skip(&mut self, count: u64) -> io::Result<()> {
+        let skipped = if self.window == 0 {
+            io::copy(&mut self.inner.by_ref().take(count), &mut io::sink())?
+        } else {
+            let mut buf = [0u8; 8192];
+            let mut skipped = 0;
+            while skipped < count {
+                let want = min(buf.len() as u64, count - skipped) as usize;
+                let n = self.inner.read(&mut buf[..want])?;
+                if n == 0 {
+                    break;
+                }
+                self.remember(&buf[..n]);
+                skipped += n as u64;
             }
+            skipped
+        };
+        self.pos += skipped;
+        self.cursor = self.pos;
+        if skipped < count {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Old file is too short for the patch"));
         }
+        Ok(())
+    }
+}
 
-        p0 -= pmin;
-        p1 -= pmin;
+impl<R: Read> Read for SequentialOld<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cursor < self.pos {
+            let behind = (self.pos - self.cursor) as usize;
+            let n = min(buf.len(), behind);
+            let start = self.history.len() - behind;
+            buf[..n].copy_from_slice(&self.history[start..start + n]);
+            self.cursor += n as u64;
+            return Ok(n);
+        }
 
-        let processed = pmin - base;
+        let n = self.inner.read(buf)?;
+        self.remember(&buf[..n]);
+        self.pos += n as u64;
+        self.cursor = self.pos;
+        Ok(n)
+    }
+}
 
-        println!("size {} processed {}", size, processed);
+impl<R: Read> Seek for SequentialOld<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) if offset >= 0 => self.cursor.checked_add(offset as u64),
+            SeekFrom::Current(offset) => self.cursor.checked_sub(offset.wrapping_neg() as u64),
+            SeekFrom::End(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "The old file's length isn't known when it's read front to back")),
+        };
 
-        size -= processed as u64;
-        base = 0;
+        let kept = min(self.window, self.history.len()) as u64;
+        match target {
+            Some(target) if target > self.pos => self.skip(target - self.pos)?,
+            Some(target) if target >= self.pos - kept => self.cursor = target,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Patch seeks further back than the {} bytes kept of an old file read front to back",
+                self.window))),
+        }
+        Ok(self.cursor)
     }
+}
 
-    Ok(())
+/// Like `apply_any`, for an old file that's already in memory.  Deltas are
+/// added straight from `old` rather than copied out through `Read+Seek`.
+pub fn apply_from_slice<NewW: Write>(patch: &[u8], old: &[u8], new: NewW) -> io::Result<()> {
+    apply_from_slice_in(patch, old, new, None)
 }
 
-pub fn read_size_from<F, R: Read>(mut size: u64, mut r: R, mut f: F) -> io::Result<()>
-    where F: FnMut(&mut [u8]) -> io::Result<()>
+fn apply_from_slice_in<NewW: Write>(patch: &[u8], old: &[u8], new: NewW, mode: Option<DecodeMode>) -> io::Result<()> {
+    #[cfg(feature = "sha1")]
+    {
+        if source_digest::is_match(patch) {
+            let (_, inner) = SourceDigest::read(patch)?;
+            verify_source(patch, old)?;
+            return apply_from_slice_in(inner, old, new, mode);
+        }
+    }
+
+    if normalize::is_match(patch) {
+        return normalize::apply(patch, old, new);
+    }
+
+    #[cfg(feature = "sha1")]
+    {
+        if block_hashes::is_match(patch) {
+            let (hashes, inner) = BlockHashes::read(patch)?;
+            let mut new = hashes.verifier(new);
+            apply_format_from_slice_in(inner, old, &mut new, mode)?;
+            new.finish()?;
+            return Ok(());
+        }
+    }
+
+    apply_format_from_slice_in(patch, old, new, mode)
+}
+
+fn apply_format_from_slice_in<NewW: Write>(patch: &[u8], old: &[u8], new: NewW, mode: Option<DecodeMode>)
+    -> io::Result<()>
 {
-    let mut buf = [0u8; 1024];
+    let format = Format::detect(patch).ok_or_else(|| unknown_format(patch))?;
+    let mode = mode.unwrap_or_else(|| format.default_mode(patch));
+    match format {
+        Format::Bsdiff => bsdiff::apply_patch_from_slice_with_mode(patch, old, new, mode),
+        Format::LinearDiff => linear_diff::apply_patch_from_slice_with_mode(patch, old, new, mode),
+        #[cfg(feature = "zstd")]
+        Format::LinearDiffZstd => linear_diff::apply_compressed_patch_from_slice_with_mode(patch, old, new, mode),
+        Format::MultiSource => multi_source::apply_patch_with_mode(patch, &mut [Cursor::new(old)], new, mode),
+        #[cfg(feature = "zstd")]
+        Format::ZstdPatchFrom => zstd_patch_from::apply_patch_from_slice(patch, old, new),
+    }
+}
 
-    let mut p = 0;
-    let mut base = 0;
+/// How much slack to allow in a patch's encoding.
+///
+/// None of this affects what a well-formed patch produces.  `Strict` rejects
+/// anything our own writers never emit: trailing bytes after the last
+/// section, offsets encoded in more than one way (bsdiff's sign-magnitude
+/// "negative zero", or negative sizes), and commands that produce no output
+/// (bar a patch made of just one, as earlier versions wrote empty patches).
+/// `Lenient` lets those through, for patches from sloppier third-party
+/// generators.
+///
+/// `Strict` is the default for every format but `Bsdiff`, whose patches come
+/// from all sorts of tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeMode {
+    Strict,
+    Lenient,
+}
 
-    while size > 0 {
-        let avail = min(buf.len() as u64, size) as usize;
-        if p < avail {
-            let s = r.read(&mut buf[p..avail])?;
-            assert!(s <= avail - p);
-            p += s;
+impl DecodeMode {
+    pub fn is_strict(self) -> bool {
+        self == DecodeMode::Strict
+    }
+
+    /// Fails in strict mode with `InvalidData` and the given message.
+    pub fn reject(self, msg: &str) -> io::Result<()> {
+        if self.is_strict() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} (strict decoding)", msg)));
         }
+        Ok(())
+    }
+}
 
-        if p == 0 {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+/// `DecodeMode::Strict`'s check on commands that produce no output.  Earlier
+/// versions wrote empty identity and idempotent patches as a single such
+/// command, so one is let through as long as nothing follows it.
+pub(crate) struct EmptyCommands {
+    mode: DecodeMode,
+    format: &'static str,
+    seen: u64,
+    first_empty: bool,
+}
+
+impl EmptyCommands {
+    pub(crate) fn new(mode: DecodeMode, format: &'static str) -> EmptyCommands {
+        EmptyCommands {
+            mode: mode,
+            format: format,
+            seen: 0,
+            first_empty: false,
         }
+    }
 
-        f(&mut buf[base..p])?;
+    /// Checks the next command, which produces `output_len` bytes.
+    pub(crate) fn check(&mut self, output_len: u64) -> io::Result<()> {
+        if self.first_empty || (output_len == 0 && self.seen > 0) {
+            self.mode.reject(&format!("{} command produces no output", self.format))?;
+        }
+        self.first_empty = output_len == 0 && self.seen == 0;
+        self.seen += 1;
+        Ok(())
+    }
+}
 
-        base = 0;
-        size -= p as u64;
-        p = 0;
+/// Passes writes through to `inner`, hashing everything written with SHA-1.
+#[cfg(feature = "sha1")]
+pub struct DigestWriter<W> {
+    inner: W,
+    sha1: Sha1,
+}
+
+#[cfg(feature = "sha1")]
+impl<W: Write> DigestWriter<W> {
+    pub fn new(inner: W) -> DigestWriter<W> {
+        DigestWriter {
+            inner: inner,
+            sha1: Sha1::new(),
+        }
+    }
+
+    /// SHA-1 of the bytes written so far.
+    pub fn digest(&self) -> [u8; 20] {
+        self.sha1.digest().bytes()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.sha1.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Like `apply_any`, but also returns the SHA-1 of the output, computed as
+/// it's written so there's no need to read the new file back.
+#[cfg(feature = "sha1")]
+pub fn apply_with_digest<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW) -> io::Result<[u8; 20]>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    let mut new = DigestWriter::new(new);
+    apply_any(patch, old, &mut new)?;
+    Ok(new.digest())
+}
+
+/// Like `apply_any`, but fails with `InvalidData` if the output's SHA-1
+/// isn't `expected`.  The output has already been written by then, so
+/// callers should treat it as garbage on error.
+#[cfg(feature = "sha1")]
+pub fn apply_and_verify<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW, expected: &[u8; 20]) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    let digest = apply_with_digest(patch, old, new)?;
+    check_digest(&digest, expected)
+}
+
+/// Applies patches in any supported format; the counterpart of
+/// `diff::Differ`, and the main entry point for patching.
+///
+/// ```
+/// use std::io::Cursor;
+/// use rsdiff::diff::{Differ, Index};
+/// use rsdiff::patch::{PatchApplier, BufferConfig};
+///
+/// let old = b"this is a test";
+/// let mut patch = Vec::new();
+/// Differ::new(&Index::compute(old.to_vec()))
+///     .run(b"this is really a test", &mut patch)
+///     .unwrap();
+///
+/// let mut new = Vec::new();
+/// PatchApplier::new()
+///     .buffers(BufferConfig::new(4096))
+///     .apply(&patch, Cursor::new(&old[..]), &mut new)
+///     .unwrap();
+///
+/// assert_eq!(&new[..], b"this is really a test");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PatchApplier {
+    buffers: BufferConfig,
+    #[cfg(feature = "sha1")]
+    expected_digest: Option<[u8; 20]>,
+    decode_mode: Option<DecodeMode>,
+    threads: usize,
+}
+
+impl PatchApplier {
+    pub fn new() -> PatchApplier {
+        PatchApplier::default()
+    }
+
+    pub fn buffers(mut self, buffers: BufferConfig) -> PatchApplier {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Fail with `InvalidData` unless the output's SHA-1 is `digest`.
+    #[cfg(feature = "sha1")]
+    pub fn expected_digest(mut self, digest: [u8; 20]) -> PatchApplier {
+        self.expected_digest = Some(digest);
+        self
+    }
+
+    /// Decode in `mode` rather than each format's `DECODE_MODE`.
+    pub fn decode_mode(mut self, mode: DecodeMode) -> PatchApplier {
+        self.decode_mode = Some(mode);
+        self
+    }
+
+    /// Decompress bsdiff sections on up to `threads` background threads;
+    /// see `ApplyOptions::threads`.
+    pub fn threads(mut self, threads: usize) -> PatchApplier {
+        self.threads = threads;
+        self
+    }
+
+    pub fn apply<OldRS, NewW>(&self, patch: &[u8], old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        self.apply_observed(patch, old, new, &mut ())
+    }
+
+    /// Like `apply`, reporting each command to `observer` as it's applied.
+    /// Only bsdiff and linear_diff patches (inside any envelope) have
+    /// commands to report; other formats apply without a word.
+    pub fn apply_observed<OldRS, NewW>(&self, patch: &[u8], old: OldRS, new: NewW, observer: &mut ApplyObserver)
+        -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        #[cfg(feature = "sha1")]
+        {
+            if let Some(ref expected) = self.expected_digest {
+                let mut new = DigestWriter::new(new);
+                apply_any_in(patch, old, &mut new, &self.buffers, self.decode_mode, self.threads, observer)?;
+                return check_digest(&new.digest(), expected);
+            }
+        }
+
+        apply_any_in(patch, old, new, &self.buffers, self.decode_mode, self.threads, observer)
+    }
+
+    /// Like `apply`, for an old file that's already in memory.
+    pub fn apply_from_slice<NewW: Write>(&self, patch: &[u8], old: &[u8], new: NewW) -> io::Result<()> {
+        #[cfg(feature = "sha1")]
+        {
+            if let Some(ref expected) = self.expected_digest {
+                let mut new = DigestWriter::new(new);
+                apply_from_slice_in(patch, old, &mut new, self.decode_mode)?;
+                return check_digest(&new.digest(), expected);
+            }
+        }
+
+        apply_from_slice_in(patch, old, new, self.decode_mode)
+    }
+
+    /// Atomically replaces `new_path` with the result of patching `old_path`;
+    /// see `apply_to_path`.
+    pub fn apply_to_path<P: AsRef<Path>, Q: AsRef<Path>>(&self, patch: &[u8], old_path: P, new_path: Q)
+        -> io::Result<()>
+    {
+        let options = ApplyOptions {
+            #[cfg(feature = "sha1")]
+            expected_digest: self.expected_digest,
+            threads: self.threads,
+            ..ApplyOptions::default()
+        };
+        apply_to_path(patch, old_path, new_path, &options)
+    }
+}
+
+/// What one command did, as reported to an `ApplyObserver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CommandEvent {
+    /// Output bytes made by adding a delta to old bytes.
+    pub added: u64,
+    /// Output bytes copied unchanged from the old file.
+    pub copied: u64,
+    /// Output bytes taken straight from the patch, fills included.
+    pub extra: u64,
+    /// How far the old file position jumped, since the last command that
+    /// read from it, to reach this command's old bytes; negative for a jump
+    /// backwards.  `None` if the command reads nothing from the old file.
+    pub seek: Option<i64>,
+}
+
+/// Receives an event for every command applied, so that devices can report
+/// how patches behave in the field.  See `PatchApplier::apply_observed`.
+pub trait ApplyObserver {
+    /// Command number `index` has been applied.
+    fn command(&mut self, index: u64, event: &CommandEvent);
+}
+
+/// Observes nothing.
+impl ApplyObserver for () {
+    fn command(&mut self, _: u64, _: &CommandEvent) {}
+}
+
+/// An `ApplyObserver` that tallies up the events.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyStats {
+    pub commands: u64,
+    pub added: u64,
+    pub copied: u64,
+    pub extra: u64,
+
+    /// How many seeks covered each distance: entry 0 counts seeks of 0 bytes,
+    /// entry `k` those of `2^(k-1)` up to `2^k - 1` bytes, either way.
+    pub seek_histogram: Vec<u64>,
+}
+
+impl ApplyStats {
+    pub fn new() -> ApplyStats {
+        ApplyStats::default()
+    }
+
+    /// Output bytes accounted for so far.
+    pub fn output_len(&self) -> u64 {
+        self.added + self.copied + self.extra
+    }
+}
+
+impl ApplyObserver for ApplyStats {
+    fn command(&mut self, _: u64, event: &CommandEvent) {
+        self.commands += 1;
+        self.added += event.added;
+        self.copied += event.copied;
+        self.extra += event.extra;
+
+        if let Some(seek) = event.seek {
+            record_seek(&mut self.seek_histogram, seek);
+        }
+    }
+}
+
+#[cfg(feature = "sha1")]
+fn check_digest(digest: &[u8; 20], expected: &[u8; 20]) -> io::Result<()> {
+    if digest != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Patched output doesn't match the expected digest"));
     }
 
     Ok(())
 }
+
+/// Options for `apply_to_path`.
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// SHA-1 the new file must have; if it doesn't, nothing is replaced.
+    #[cfg(feature = "sha1")]
+    pub expected_digest: Option<[u8; 20]>,
+
+    /// Background threads for decompressing bsdiff sections, so the main
+    /// thread only adds, copies and writes: 0 (the default) decompresses
+    /// everything inline, 1 moves the delta section to its own thread, and
+    /// 2 or more the extra section too.  Other formats ignore it.
+    pub threads: usize,
+
+    /// Worker threads writing the new file out of order, with positioned
+    /// writes into a file preallocated to its full size; 0 (the default)
+    /// writes it front to back.  The whole patch is decoded up front to plan
+    /// each command's output offset, so this takes memory for the decoded
+    /// delta and extra bytes.  Only plain bsdiff and linear_diff patches are
+    /// applied this way; anything else falls back to writing in order.
+    pub parallel_output: usize,
+
+    /// Runs the `parallel_output` workers; see `concurrency`.
+    pub executor: Option<Arc<Executor>>,
+
+    /// Bytes of output between journal entries; 0 (the default) keeps no
+    /// journal.  With one, output is synced and its progress recorded in a
+    /// `.journal` file next to the new file every this many bytes, and an
+    /// apply interrupted by a crash resumes from the last entry; see
+    /// `journal`.  Takes precedence over `parallel_output`.
+    ///
+    /// So that the next attempt can find it, the partial output goes to a
+    /// fixed `.tmp` file next to the new file, rather than one with a name
+    /// of its own: two journaled applies to the same path mustn't run at
+    /// once.
+    #[cfg(feature = "sha1")]
+    pub journal_interval: u64,
+
+    /// Lets large copies of old bytes be done by the filesystem: on Linux,
+    /// each copy of at least `CLONE_MIN` bytes is first tried as a reflink
+    /// (`FICLONERANGE`), which shares the blocks on copy-on-write
+    /// filesystems, then with `copy_file_range`, before falling back to
+    /// reading and writing.  The patch is applied as for `parallel_output`,
+    /// with at least one worker, so only plain bsdiff and linear_diff patches
+    /// benefit, and the whole patch is decoded into memory first.  Other
+    /// platforms ignore it.
+    pub allow_clone: bool,
+}
+
+impl ApplyOptions {
+    pub fn new() -> ApplyOptions {
+        ApplyOptions::default()
+    }
+
+    #[cfg(feature = "sha1")]
+    pub fn expected_digest(mut self, digest: [u8; 20]) -> ApplyOptions {
+        self.expected_digest = Some(digest);
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> ApplyOptions {
+        self.threads = threads;
+        self
+    }
+
+    pub fn parallel_output(mut self, workers: usize) -> ApplyOptions {
+        self.parallel_output = workers;
+        self
+    }
+
+    pub fn executor(mut self, executor: Arc<Executor>) -> ApplyOptions {
+        self.executor = Some(executor);
+        self
+    }
+
+    pub fn allow_clone(mut self, allow: bool) -> ApplyOptions {
+        self.allow_clone = allow;
+        self
+    }
+
+    #[cfg(feature = "sha1")]
+    pub fn journal_interval(mut self, bytes: u64) -> ApplyOptions {
+        self.journal_interval = bytes;
+        self
+    }
+}
+
+/// Applies `patch` to the file at `old_path`, atomically replacing
+/// `new_path` with the result.
+///
+/// The output goes to a temporary file next to `new_path`, which is fsynced,
+/// checked against `options.expected_digest` and only then renamed into
+/// place.  On any failure the temporary file is removed and whatever was at
+/// `new_path` is left untouched.  Each apply gets a temporary file of its
+/// own (except with `options.journal_interval`), so applies racing to the
+/// same `new_path` can't corrupt each other: the last to finish wins.
+pub fn apply_to_path<P: AsRef<Path>, Q: AsRef<Path>>(
+    patch: &[u8],
+    old_path: P,
+    new_path: Q,
+    options: &ApplyOptions
+) -> io::Result<()> {
+    replace_with_patched(patch, old_path.as_ref(), new_path.as_ref(), options, None)
+}
+
+/// Applies `patch` to the file at `path`, replacing it with the new version.
+///
+/// There's no in-place patch format yet, so this always goes through a
+/// temporary file and a rename, as `apply_to_path` does; the old file's
+/// permissions carry over to the new one.  If anything fails, the file at
+/// `path` is left as it was.
+pub fn upgrade_in_path<P: AsRef<Path>>(patch: &[u8], path: P, options: &ApplyOptions) -> io::Result<()> {
+    let path = path.as_ref();
+    let permissions = fs::metadata(path)?.permissions();
+    replace_with_patched(patch, path, path, options, Some(permissions))
+}
+
+fn replace_with_patched(
+    patch: &[u8],
+    old_path: &Path,
+    new_path: &Path,
+    options: &ApplyOptions,
+    permissions: Option<fs::Permissions>
+) -> io::Result<()> {
+    #[cfg(feature = "sha1")]
+    {
+        if options.journal_interval > 0 {
+            return apply_journaled(patch, old_path, new_path, options, permissions);
+        }
+    }
+
+    #[cfg(any(unix, windows))]
+    {
+        let has_steps = (bsdiff::is_match(patch) && bsdiff::has_steps(patch)) || linear_diff::is_match(patch);
+        if (options.parallel_output > 0 || options.allow_clone) && has_steps {
+            let old = File::open(old_path)?;
+            return write_atomically(new_path, permissions, |file| apply_parallel(patch, &old, file, options));
+        }
+    }
+
+    // An identity patch is a plain copy, which the kernel can do between
+    // the files themselves; see `copy_old_prefix`.
+    #[cfg(feature = "sha1")]
+    let checks_digest = options.expected_digest.is_some();
+    #[cfg(not(feature = "sha1"))]
+    let checks_digest = false;
+    if bsdiff::is_match(patch) && !checks_digest {
+        if let Some(len) = bsdiff::identity_len(patch, bsdiff::default_mode(patch))? {
+            let old = File::open(old_path)?;
+            return write_atomically(new_path, permissions, |file| copy_old_prefix(&old, file, len));
+        }
+    }
+
+    let old = File::open(old_path)?;
+    let size = preallocation_size(patch, &old)?;
+    let old = BufReader::new(old);
+    write_atomically(new_path, permissions, |file| {
+        if let Some(size) = size {
+            preallocate(file, size)?;
+        }
+        apply_with_options(patch, old, &mut *file, options)?;
+
+        // Don't leave preallocated bytes behind a patch that came up short.
+        let end = file.seek(SeekFrom::Current(0))?;
+        file.set_len(end)
+    })
+}
+
+/// Size of the file `patch` produces, if it says up front.  Only bsdiff
+/// headers and block hash envelopes do.
+pub(crate) fn declared_new_size(patch: &[u8]) -> Option<u64> {
+    #[cfg(feature = "sha1")]
+    {
+        if source_digest::is_match(patch) {
+            return SourceDigest::read(patch).ok().and_then(|(_, inner)| declared_new_size(inner));
+        }
+
+        if block_hashes::is_match(patch) {
+            return BlockHashes::read(patch).ok().map(|(hashes, _)| hashes.new_size);
+        }
+    }
+
+    if bsdiff::is_match(patch) {
+        Header::read(patch).ok().map(|header| header.new_file_size)
+    } else {
+        None
+    }
+}
+
+/// How many times the size of the old file and the patch together a
+/// patch's declared new size is believed for preallocation.
+const PREALLOCATE_FACTOR: u64 = 4;
+
+/// Bytes of output to preallocate for applying `patch` to `old`: the new
+/// size the patch declares, capped at `PREALLOCATE_FACTOR` times the old
+/// file and the patch together, since a bad header could claim anything.
+/// Output past the cap just grows the file as it's written.
+fn preallocation_size(patch: &[u8], old: &File) -> io::Result<Option<u64>> {
+    let size = match declared_new_size(patch) {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+
+    let inputs = old.metadata()?.len().saturating_add(patch.len() as u64);
+    Ok(Some(min(size, inputs.saturating_mul(PREALLOCATE_FACTOR))))
+}
+
+/// Extends the empty `file` to `size` bytes, reserving the disk space where
+/// the platform can, so a full disk fails the apply before it starts rather
+/// than most of the way through, and the file goes down in one piece.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if size == 0 || size > libc::off_t::max_value() as u64 {
+        return file.set_len(size);
+    }
+
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, size as libc::off_t) } {
+        0 => Ok(()),
+        // The filesystem can't reserve space; settle for the size.
+        libc::EINVAL | libc::EOPNOTSUPP => file.set_len(size),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// On Windows, `set_len` (`SetFileInformationByHandle` with the end of the
+/// file) allocates the space too; elsewhere it may only make a sparse file.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    file.set_len(size)
+}
+
+/// Bytes each `apply_parallel` worker reads and writes at a time.
+#[cfg(any(unix, windows))]
+const PARALLEL_CHUNK: usize = 1 << 20;
+
+/// Applies a bsdiff or linear_diff `patch` for `ApplyOptions::parallel_output`
+/// or `allow_clone`: decodes it into steps, works out where each one's output
+/// goes, then has the workers take steps in turn and write them straight to
+/// their place in `new`.
+#[cfg(any(unix, windows))]
+fn apply_parallel(patch: &[u8], old: &File, new: &mut File, options: &ApplyOptions) -> io::Result<()> {
+    let steps = if bsdiff::is_match(patch) {
+        bsdiff::read_steps(patch)?
+    } else {
+        linear_diff::read_steps(patch)?
+    };
+
+    let mut offsets = Vec::with_capacity(steps.len());
+    let mut size = 0u64;
+    for step in &steps {
+        offsets.push(size);
+        size = size.checked_add(step.output_len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Patch output length overflows"))?;
+    }
+    preallocate(new, size)?;
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    {
+        let new: &File = new;
+        let executor = concurrency::executor(&options.executor);
+        let workers = concurrency::limit(max(options.parallel_output, 1));
+        concurrency::run(&*executor, workers, |_| -> io::Result<()> {
+            let mut buf = vec![0u8; PARALLEL_CHUNK];
+            while !failed.load(atomic::Ordering::Relaxed) {
+                let i = next.fetch_add(1, atomic::Ordering::Relaxed);
+                if i >= steps.len() {
+                    break;
+                }
+                if let Err(e) = write_step(&steps[i], offsets[i], old, new, options.allow_clone, &mut buf) {
+                    failed.store(true, atomic::Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }).into_iter().collect::<io::Result<()>>()?;
+    }
+
+    #[cfg(feature = "sha1")]
+    {
+        if let Some(ref expected) = options.expected_digest {
+            new.seek(SeekFrom::Start(0))?;
+            let mut digest = DigestWriter::new(io::sink());
+            io::copy(new, &mut digest)?;
+            check_digest(&digest.digest(), expected)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the output of `step` to `new` at `offset`, using `buf` for old
+/// bytes.  With `clone`, large copies are left to `clone_range` if it can.
+#[cfg(any(unix, windows))]
+fn write_step(step: &Step, offset: u64, old: &File, new: &File, clone: bool, buf: &mut [u8]) -> io::Result<()> {
+    match *step {
+        Step::Delta { old_offset, ref delta } => {
+            let mut pos = 0;
+            for d in delta.chunks(buf.len()) {
+                let o = &mut buf[..d.len()];
+                read_exact_at(old, o, old_offset + pos)?;
+                add_delta(o, d);
+                write_all_at(new, o, offset + pos)?;
+                pos += d.len() as u64;
+            }
+        }
+        Step::Copy { old_offset, len } => {
+            if clone && len >= CLONE_MIN && clone_range(old, new, old_offset, offset, len)? {
+                return Ok(());
+            }
+            let mut pos = 0;
+            while pos < len {
+                let n = min(len - pos, buf.len() as u64) as usize;
+                let o = &mut buf[..n];
+                read_exact_at(old, o, old_offset + pos)?;
+                write_all_at(new, o, offset + pos)?;
+                pos += o.len() as u64;
+            }
+        }
+        Step::Extra(ref extra) => write_all_at(new, extra, offset)?,
+        // The preallocated file already holds zeros.
+        Step::Fill { byte: 0, .. } => {}
+        Step::Fill { byte, len } => {
+            let n = min(len, buf.len() as u64) as usize;
+            let fill = &mut buf[..n];
+            for b in fill.iter_mut() {
+                *b = byte;
+            }
+            let mut pos = 0;
+            while pos < len {
+                let n = min(len - pos, fill.len() as u64) as usize;
+                write_all_at(new, &fill[..n], offset + pos)?;
+                pos += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies shorter than this aren't worth a system call of their own.
+#[cfg(any(unix, windows))]
+pub const CLONE_MIN: u64 = 64 * 1024;
+
+/// Has the kernel make `len` bytes of `new` from `offset` the same as `old`
+/// from `old_offset`: as a reflink where the filesystem supports it and the
+/// range is block-aligned, otherwise with `copy_file_range`.  Returns false
+/// if neither works between these files, possibly after copying part of the
+/// range, which the caller then writes over in full.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn clone_range(old: &File, new: &File, old_offset: u64, offset: u64, len: u64) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let range = libc::file_clone_range {
+        src_fd: old.as_raw_fd() as i64,
+        src_offset: old_offset,
+        src_length: len,
+        dest_offset: offset,
+    };
+    if unsafe { libc::ioctl(new.as_raw_fd(), libc::FICLONERANGE, &range) } == 0 {
+        return Ok(true);
+    }
+
+    let mut done = 0u64;
+    while done < len {
+        let mut off_in = (old_offset + done) as libc::loff_t;
+        let mut off_out = (offset + done) as libc::loff_t;
+        let n = unsafe {
+            libc::copy_file_range(old.as_raw_fd(), &mut off_in, new.as_raw_fd(), &mut off_out,
+                min(len - done, 1 << 30) as usize, 0)
+        };
+        match n {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Old file is too short for the patch")),
+            n if n > 0 => done += n as u64,
+            _ => {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EINTR) => {}
+                    // Not supported by this kernel, or between these files.
+                    Some(libc::ENOSYS) | Some(libc::EXDEV) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) =>
+                        return Ok(false),
+                    _ => return Err(err),
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(all(any(unix, windows), not(any(target_os = "linux", target_os = "android"))))]
+fn clone_range(_old: &File, _new: &File, _old_offset: u64, _offset: u64, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Old file is too short for the patch")),
+            Ok(n) => {
+                let rest = buf;
+                buf = &mut rest[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Has `write` fill a temporary file next to `path`, then syncs it and
+/// renames it over `path`, so `path` only ever holds the old contents or
+/// all of the new.  If anything fails, the temporary file is removed.
+///
+/// The temporary file gets a name of its own, so two writers racing to
+/// replace the same `path` (or a stray file from a crashed one) can't
+/// clobber each other's output; the last rename wins.
+pub(crate) fn write_atomically<F>(path: &Path, permissions: Option<fs::Permissions>, write: F) -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()>
+{
+    let (file, temp_path) = create_temp_next_to(path)?;
+
+    let res = write_synced(file, permissions, write)
+        .and_then(|()| fs::rename(&temp_path, path));
+
+    if res.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return res;
+    }
+
+    sync_parent(path);
+    Ok(())
+}
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates a new, empty file in the same directory as `path` (so it can be
+/// renamed over it), named after it plus the process id and a counter.  It's
+/// opened for reading too, so the output can be checked against a digest.
+fn create_temp_next_to(path: &Path) -> io::Result<(File, PathBuf)> {
+    let name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+
+    loop {
+        let mut temp_name = name.clone();
+        temp_name.push(format!(".{}-{}.tmp",
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, atomic::Ordering::SeqCst)));
+        let temp_path = path.with_file_name(temp_name);
+
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&temp_path) {
+            Ok(file) => return Ok((file, temp_path)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Makes a rename to `path` durable.  Directories can't be opened for this
+/// on every platform, so it's best-effort.
+fn sync_parent(path: &Path) {
+    if let Some(dir) = path.parent() {
+        let _ = File::open(dir).and_then(|dir| dir.sync_all());
+    }
+}
+
+/// `replace_with_patched` keeping a journal, for
+/// `ApplyOptions::journal_interval`.  A failed attempt leaves its temporary
+/// file and journal behind for the next one to resume from, unless the
+/// patch itself was at fault.
+#[cfg(feature = "sha1")]
+fn apply_journaled(
+    patch: &[u8],
+    old_path: &Path,
+    new_path: &Path,
+    options: &ApplyOptions,
+    permissions: Option<fs::Permissions>
+) -> io::Result<()> {
+    let temp_path = temp_path_for(new_path);
+    let journal_path = journal::path_for(new_path);
+
+    let res = (|| {
+        let old = File::open(old_path)?;
+        let size = preallocation_size(patch, &old)?;
+        let old = BufReader::new(old);
+        let mut new = JournalWriter::open(patch, &temp_path, &journal_path, options.journal_interval)?;
+        if new.resumed_at() == 0 {
+            if let Some(size) = size {
+                preallocate(new.file(), size)?;
+            }
+        }
+
+        apply_with_options(patch, old, &mut new, options)?;
+
+        // Set last, so that a read-only file can still be resumed.
+        let file = new.finish()?;
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, new_path)
+    })();
+
+    match res {
+        Ok(()) => {
+            let _ = fs::remove_file(&journal_path);
+            sync_parent(new_path);
+            Ok(())
+        }
+        Err(e) => {
+            if e.kind() == io::ErrorKind::InvalidData {
+                let _ = fs::remove_file(&temp_path);
+                let _ = fs::remove_file(&journal_path);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// The temporary file a journaled apply to `path` writes to.  Unlike
+/// `write_atomically`'s, its name is fixed, so that the next attempt can
+/// find it and resume; the journal next to it records how far it got.
+#[cfg(feature = "sha1")]
+fn temp_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn write_synced<F>(mut file: File, permissions: Option<fs::Permissions>, write: F) -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()>
+{
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions)?;
+    }
+
+    write(&mut file)?;
+    file.sync_all()
+}
+
+/// Applies `patch`, checking the output against `options.expected_digest`.
+#[cfg(feature = "sha1")]
+fn apply_with_options<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW, options: &ApplyOptions) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    let config = BufferConfig::default();
+    match options.expected_digest {
+        Some(ref expected) => {
+            let mut new = DigestWriter::new(new);
+            apply_any_in(patch, old, &mut new, &config, None, options.threads, &mut ())?;
+            check_digest(&new.digest(), expected)
+        }
+        None => apply_any_in(patch, old, new, &config, None, options.threads, &mut ()),
+    }
+}
+
+#[cfg(not(feature = "sha1"))]
+fn apply_with_options<OldRS, NewW>(patch: &[u8], old: OldRS, new: NewW, options: &ApplyOptions) -> io::Result<()>
+    where
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    apply_any_in(patch, old, new, &BufferConfig::default(), None, options.threads, &mut ())
+}
+
+/// What `validate` learned about a patch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Name of the patch's format.
+    pub format: &'static str,
+    /// Size of the file the patch produces.
+    pub new_size: u64,
+}
+
+/// Dry-runs `patch` against `old` without writing anything, so updaters can
+/// check a patch before committing to an install.
+///
+/// Every command is decoded and every old-file range is checked against
+/// `old`; for formats that declare the new file's size, the output size is
+/// checked against it.  Corrupt compressed sections are caught by their
+/// decoders' own checksums.
+pub fn validate(patch: &[u8], old: &[u8]) -> io::Result<ValidationReport> {
+    let format = match format_name(patch) {
+        Some(format) => format,
+        None => return Err(unknown_format(patch)),
+    };
+
+    let mut out = CountingWriter::new(io::sink());
+    apply_from_slice(patch, old, &mut out)?;
+    let new_size = out.count();
+
+    if bsdiff::is_match(patch) {
+        let expected = Header::read(patch)?.new_file_size;
+        if expected != new_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Patch declares a {} byte new file but produces {} bytes", expected, new_size)));
+        }
+    }
+
+    Ok(ValidationReport {
+        format: format,
+        new_size: new_size,
+    })
+}
+
+/// Re-encodes `patch` in the format `engine` writes, with the compression
+/// and bsdiff section codecs from `options`, keeping every command as it
+/// is.  Neither the old nor the new file is needed, so a whole archive of
+/// patches can be moved to another format.  Source digest and block hash
+/// envelopes are carried over.  `Engine::ZstdPatchFrom` needs the old file,
+/// so transcoding to it fails with `InvalidInput`.
+///
+/// Delta runs that are all zeros become copies where the target format has
+/// them, and fills become extra bytes where it doesn't; otherwise the output
+/// is what the input says, so transcoding doesn't find better matches.
+pub fn transcode<W: Write>(patch: &[u8], output: W, engine: Engine, options: &PatchOptions) -> io::Result<()> {
+    #[cfg(feature = "sha1")]
+    {
+        if source_digest::is_match(patch) {
+            let (digest, inner) = SourceDigest::read(patch)?;
+            let mut transcoded = Vec::new();
+            transcode(inner, &mut transcoded, engine, options)?;
+            return digest.write_patch(&transcoded, output);
+        }
+
+        if block_hashes::is_match(patch) {
+            let (hashes, inner) = BlockHashes::read(patch)?;
+            let mut transcoded = Vec::new();
+            transcode(inner, &mut transcoded, engine, options)?;
+            return hashes.write_patch(&transcoded, output);
+        }
+    }
+
+    let steps = if bsdiff::is_match(patch) {
+        bsdiff::read_steps(patch)?
+    } else if linear_diff::is_match(patch) {
+        linear_diff::read_steps(patch)?
+    } else if let Some(name) = format_name(patch) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't transcode {} patches", name)));
+    } else {
+        return Err(unknown_format(patch));
+    };
+
+    check_delta_mode(engine, options)?;
+    let new_len = steps_output_len(&steps)?;
+    let steps = steps.iter().map(Ok);
+    match engine {
+        Engine::Bsdiff => bsdiff::write_steps(steps, new_len, options, output),
+        #[cfg(feature = "zstd")]
+        Engine::LinearDiffZstd if options.compression == Compression::None =>
+            linear_diff::write_steps(steps, new_len, output),
+        #[cfg(feature = "zstd")]
+        Engine::LinearDiffZstd =>
+            linear_diff::write_compressed_steps(steps, new_len, zstd_level(options.compression), output),
+        #[cfg(feature = "zstd")]
+        Engine::ZstdPatchFrom => Err(patch_from_needs_old()),
+    }
+}
+
+/// Rewrites `patch` in the same format with its compressed sections
+/// recompressed at `level`, say to squeeze patches made quickly on a build
+/// machine before they're published.  Codecs stay as they are; even
+/// `Compression::None` only picks the fastest level (`transcode` can change
+/// codecs).  Uncompressed formats are copied unchanged.  Source digest and
+/// block hash envelopes are carried over.
+pub fn recompress<W: Write>(patch: &[u8], mut output: W, level: Compression) -> io::Result<()> {
+    #[cfg(feature = "sha1")]
+    {
+        if source_digest::is_match(patch) {
+            let (digest, inner) = SourceDigest::read(patch)?;
+            let mut recompressed = Vec::new();
+            recompress(inner, &mut recompressed, level)?;
+            return digest.write_patch(&recompressed, output);
+        }
+
+        if block_hashes::is_match(patch) {
+            let (hashes, inner) = BlockHashes::read(patch)?;
+            let mut recompressed = Vec::new();
+            recompress(inner, &mut recompressed, level)?;
+            return hashes.write_patch(&recompressed, output);
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    {
+        if LinearDiffZstd::is_match(patch) {
+            return linear_diff::recompress(patch, zstd_level(level), output);
+        }
+    }
+
+    if bsdiff::is_match(patch) {
+        bsdiff::recompress(patch, level, output)
+    } else if LinearDiff::is_match(patch) || MultiSource::is_match(patch) {
+        output.write_all(patch)
+    } else if let Some(name) = format_name(patch) {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't recompress {} patches", name)))
+    } else {
+        Err(unknown_format(patch))
+    }
+}
+
+/// One command of a patch, in terms every format can express, for
+/// `transcode`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// `delta` added to the old file's bytes from `old_offset`.
+    Delta { old_offset: u64, delta: Vec<u8> },
+    /// `len` bytes of the old file from `old_offset`, unchanged.
+    Copy { old_offset: u64, len: u64 },
+    Extra(Vec<u8>),
+    /// `len` copies of `byte`.
+    Fill { byte: u8, len: u64 },
+}
+
+impl Step {
+    pub(crate) fn output_len(&self) -> u64 {
+        match *self {
+            Step::Delta { ref delta, .. } => delta.len() as u64,
+            Step::Copy { len, .. } => len,
+            Step::Extra(ref extra) => extra.len() as u64,
+            Step::Fill { len, .. } => len,
+        }
+    }
+}
+
+/// The total output of `steps`, failing with `InvalidData` if it doesn't
+/// fit in a `u64`.
+pub(crate) fn steps_output_len(steps: &[Step]) -> io::Result<u64> {
+    steps.iter().try_fold(0u64, |total, step| total.checked_add(step.output_len()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Patch output length overflows"))
+}
+
+/// Reads exactly `len` bytes of `name` from `section`, growing the buffer
+/// only as data actually arrives.
+pub(crate) fn read_step_bytes<R: Read>(section: R, len: u64, name: &str) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    section.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Patch {} ends early", name)));
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn format_name(patch: &[u8]) -> Option<&'static str> {
+    #[cfg(feature = "sha1")]
+    {
+        if source_digest::is_match(patch) {
+            return SourceDigest::read(patch).ok().and_then(|(_, inner)| format_name(inner));
+        }
+    }
+
+    if normalize::is_match(patch) {
+        return normalize::read(patch).ok().and_then(|(inner, _, _)| format_name(inner));
+    }
+
+    #[cfg(feature = "sha1")]
+    {
+        if block_hashes::is_match(patch) {
+            return BlockHashes::read(patch).ok().and_then(|(_, inner)| format_name(inner));
+        }
+    }
+
+    Format::detect(patch).map(|format| format.name(patch))
+}
+
+fn unknown_format(patch: &[u8]) -> io::Error {
+    let magic = &patch[..min(patch.len(), 8)];
+
+    if let Some(feature) = format::missing_feature(patch) {
+        return io::Error::new(io::ErrorKind::InvalidData, format!(
+            "Patch format (magic {:?}) needs the `{}` feature, which this build of rsdiff leaves out",
+            magic, feature));
+    }
+
+    io::Error::new(io::ErrorKind::InvalidData, format!(
+        "Unknown patch format (magic {:?}); expected one of {}",
+        magic, format::known_names().join(", ")))
+}
+
+/// Bytes from the start of a patch that are always enough for `can_apply`:
+/// the largest format header, inside a source digest, a normalizer and a
+/// block hash envelope.
+pub const CAN_APPLY_BYTES: usize = 36 + 28 + 28 + 40;
+
+/// Why `can_apply` turned a patch down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unsupported {
+    /// Too little of the patch to tell; `needed` bytes would do.
+    Truncated { needed: usize },
+    /// The magic isn't one this version of rsdiff knows, or the header uses
+    /// bits it doesn't understand.
+    UnknownFormat,
+    /// The header names a section codec this version of rsdiff doesn't know.
+    UnknownCodec(u8),
+    /// The format is known, but needs features this build leaves out.
+    MissingFeatures(FeatureSet),
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Unsupported::Truncated { needed } =>
+                write!(f, "Need at least {} bytes of the patch to check it", needed),
+            Unsupported::UnknownFormat =>
+                write!(f, "Patch is in a format this version of rsdiff doesn't know"),
+            Unsupported::UnknownCodec(byte) =>
+                write!(f, "Patch uses unknown section codec {}", byte),
+            Unsupported::MissingFeatures(ref missing) =>
+                write!(f, "Patch needs the `{}` feature(s), which this build of rsdiff leaves out",
+                    missing.names().join("`, `")),
+        }
+    }
+}
+
+impl error::Error for Unsupported {
+    fn description(&self) -> &str {
+        "patch not supported by this build"
+    }
+}
+
+impl From<Unsupported> for io::Error {
+    fn from(err: Unsupported) -> io::Error {
+        let kind = match err {
+            Unsupported::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Checks whether this build of rsdiff can apply a patch, given only the
+/// start of it (`CAN_APPLY_BYTES` is always enough).  Updaters can call this
+/// before downloading the rest of a patch, and fall back to fetching the
+/// whole new file if it fails.
+///
+/// Only the format and codecs are checked; a patch that passes can still
+/// turn out to be corrupt.
+pub fn can_apply(header: &[u8]) -> Result<(), Unsupported> {
+    let missing = format::required_features(header)?.difference(&FeatureSet::available());
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Unsupported::MissingFeatures(missing))
+    }
+}
+
+/// Why `verify_source` turned down an old file.
+#[cfg(feature = "sha1")]
+#[derive(Debug)]
+pub enum SourceMismatch {
+    /// The patch doesn't record its source: it isn't wrapped with
+    /// `format::source_digest`.
+    Unrecorded,
+    /// Too little of the patch to read the digest; `needed` bytes would do.
+    Truncated { needed: usize },
+    /// The old file is the wrong size, so certainly the wrong version.
+    WrongSize { expected: u64, actual: u64 },
+    /// The old file is the right size, but its contents differ.
+    WrongDigest,
+    /// Reading the old file failed.
+    Io(io::Error),
+}
+
+#[cfg(feature = "sha1")]
+impl fmt::Display for SourceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourceMismatch::Unrecorded =>
+                write!(f, "Patch doesn't record a source digest"),
+            SourceMismatch::Truncated { needed } =>
+                write!(f, "Need at least {} bytes of the patch to read its source digest", needed),
+            SourceMismatch::WrongSize { expected, actual } =>
+                write!(f, "Old file is {} bytes, but the patch was made against {} bytes", actual, expected),
+            SourceMismatch::WrongDigest =>
+                write!(f, "Old file doesn't match the patch's source digest"),
+            SourceMismatch::Io(ref err) =>
+                write!(f, "Error reading old file: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl error::Error for SourceMismatch {
+    fn description(&self) -> &str {
+        "old file doesn't match the patch's source"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            SourceMismatch::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl From<SourceMismatch> for io::Error {
+    fn from(err: SourceMismatch) -> io::Error {
+        match err {
+            SourceMismatch::Io(err) => err,
+            SourceMismatch::Truncated { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+            _ => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Checks that `old` is the file a patch was made against, reading it once
+/// and comparing with the source digest at the start of the patch.  Only
+/// the first `source_digest::HEADER_SIZE` bytes of the patch are needed.
+///
+/// Run before applying, this tells "the wrong base version is installed"
+/// apart from "the patch is corrupt", which otherwise fail alike partway
+/// through an apply.
+#[cfg(feature = "sha1")]
+pub fn verify_source<R: Read>(patch_header: &[u8], mut old: R) -> Result<(), SourceMismatch> {
+    if !source_digest::is_match(patch_header) {
+        return Err(SourceMismatch::Unrecorded);
+    }
+    if patch_header.len() < source_digest::HEADER_SIZE {
+        return Err(SourceMismatch::Truncated { needed: source_digest::HEADER_SIZE });
+    }
+    let expected = SourceDigest::read_header(patch_header).map_err(SourceMismatch::Io)?;
+
+    let mut contents = CountingWriter::new(DigestWriter::new(io::sink()));
+    io::copy(&mut old, &mut contents).map_err(SourceMismatch::Io)?;
+    check_source(&expected, contents)
+}
+
+/// Compares the size and SHA-1 of an old file, as counted and hashed into
+/// `contents`, with `expected`.
+#[cfg(feature = "sha1")]
+fn check_source(expected: &SourceDigest, contents: CountingWriter<DigestWriter<io::Sink>>) -> Result<(), SourceMismatch> {
+    if contents.count() != expected.old_size {
+        return Err(SourceMismatch::WrongSize { expected: expected.old_size, actual: contents.count() });
+    }
+    if contents.into_inner().digest() != expected.digest {
+        return Err(SourceMismatch::WrongDigest);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sha1")]
+const SEGMENT_MAGIC: &'static [u8] = b"RSDSEG01";
+
+/// Bytes added to each segment's payload by its framing: magic, index,
+/// count, payload length and a trailing SHA-1.
+#[cfg(feature = "sha1")]
+pub const SEGMENT_OVERHEAD: usize = 8 + 4 + 4 + 8 + 20;
+
+/// One piece of a patch that has been `split` for delivery in bounded-size
+/// downloads.
+#[cfg(feature = "sha1")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub index: u32,
+    pub count: u32,
+    pub payload: Vec<u8>,
+}
+
+#[cfg(feature = "sha1")]
+impl Segment {
+    /// Size of the framed segment as written by `write_to`.
+    pub fn framed_len(&self) -> usize {
+        self.payload.len() + SEGMENT_OVERHEAD
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut header = [0u8; 24];
+        header[..8].copy_from_slice(SEGMENT_MAGIC);
+        wire::write_u32(&mut header[8..12], self.index);
+        wire::write_u32(&mut header[12..16], self.count);
+        wire::write_u64(&mut header[16..24], self.payload.len() as u64);
+
+        let mut sha1 = Sha1::new();
+        sha1.update(&header);
+        sha1.update(&self.payload);
+
+        w.write_all(&header)?;
+        w.write_all(&self.payload)?;
+        w.write_all(&sha1.digest().bytes())
+    }
+
+    /// Reads a framed segment, failing with `InvalidData` if its magic or
+    /// checksum don't match.
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<Segment> {
+        let mut header = [0u8; 24];
+        r.read_exact(&mut header)?;
+
+        if &header[..8] != SEGMENT_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad patch segment magic"));
+        }
+
+        let index = wire::read_u32(&header[8..12]);
+        let count = wire::read_u32(&header[12..16]);
+        let len = wire::read_u64(&header[16..24]);
+
+        let mut payload = Vec::new();
+        r.by_ref().take(len).read_to_end(&mut payload)?;
+        if (payload.len() as u64) < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated patch segment"));
+        }
+
+        let mut digest = [0u8; 20];
+        r.read_exact(&mut digest)?;
+
+        let mut sha1 = Sha1::new();
+        sha1.update(&header);
+        sha1.update(&payload);
+
+        if sha1.digest().bytes() != digest {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Checksum mismatch in patch segment {}", index)));
+        }
+
+        Ok(Segment {
+            index: index,
+            count: count,
+            payload: payload,
+        })
+    }
+}
+
+/// Splits `patch` into segments whose framed size is at most `max_segment`
+/// bytes.
+///
+/// Panics if `max_segment` leaves no room for a payload.
+#[cfg(feature = "sha1")]
+pub fn split(patch: &[u8], max_segment: usize) -> Vec<Segment> {
+    assert!(max_segment > SEGMENT_OVERHEAD, "max_segment too small for segment framing");
+
+    let chunks = patch.chunks(max_segment - SEGMENT_OVERHEAD).collect::<Vec<_>>();
+    let count = chunks.len() as u32;
+
+    if chunks.is_empty() {
+        return vec![Segment { index: 0, count: 1, payload: Vec::new() }];
+    }
+
+    chunks.into_iter().enumerate().map(|(i, chunk)| {
+        Segment {
+            index: i as u32,
+            count: count,
+            payload: chunk.to_vec(),
+        }
+    }).collect()
+}
+
+/// Reassembles the segments produced by `split`, which must arrive in order,
+/// and applies the result with `apply_any`.
+#[cfg(feature = "sha1")]
+pub fn apply_segments<I, OldRS, NewW>(segments: I, old: OldRS, new: NewW) -> io::Result<()>
+    where
+        I: IntoIterator<Item=Segment>,
+        OldRS: Read+Seek,
+        NewW: Write
+{
+    let mut patch = Vec::new();
+    let mut expected_count = None;
+    let mut next_index = 0;
+
+    for segment in segments {
+        let count = *expected_count.get_or_insert(segment.count);
+
+        if segment.count != count || segment.index != next_index {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Expected patch segment {} of {}, got {} of {}",
+                next_index, count, segment.index, segment.count)));
+        }
+
+        patch.extend_from_slice(&segment.payload);
+        next_index += 1;
+    }
+
+    if Some(next_index) != expected_count {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+            "Got {} patch segments, expected {}", next_index, expected_count.unwrap_or(1))));
+    }
+
+    apply_any(&patch, old, new)
+}
+
+/// Where applying a patch went wrong.
+///
+/// The appliers return plain `io::Error`s, keeping the original error kind;
+/// when the failure happened inside a command, the error carries one of
+/// these, which `ApplyError::find` digs out.
+#[derive(Debug)]
+pub struct ApplyError {
+    /// Index of the failing command, counting from zero.
+    pub command: u64,
+    /// Offset of the failing command within the patch.  Only known for
+    /// uncompressed formats.
+    pub patch_offset: Option<u64>,
+    /// Offset in the new file where the failing command's output begins.
+    pub output_offset: u64,
+    pub cause: io::Error,
+}
+
+impl ApplyError {
+    pub fn wrap(cause: io::Error, command: u64, patch_offset: Option<u64>, output_offset: u64) -> io::Error {
+        io::Error::new(cause.kind(), ApplyError {
+            command: command,
+            patch_offset: patch_offset,
+            output_offset: output_offset,
+            cause: cause,
+        })
+    }
+
+    /// The context attached to `err`, if it came from inside a command.
+    pub fn find(err: &io::Error) -> Option<&ApplyError> {
+        err.get_ref().and_then(|e| e.downcast_ref::<ApplyError>())
+    }
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (in command {}, output offset {}", self.cause, self.command, self.output_offset)?;
+        if let Some(offset) = self.patch_offset {
+            write!(f, ", patch offset {}", offset)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl error::Error for ApplyError {
+    fn description(&self) -> &str {
+        "error applying patch command"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.cause)
+    }
+}
+
+/// Default size of the scratch buffers used while applying patches.
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Controls the size of the scratch buffers used while applying patches.
+///
+/// Bigger buffers mean fewer, larger reads from the decompressors and the
+/// old file; embedded users may want to go much smaller than the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferConfig {
+    pub buffer_size: usize,
+    /// Capacity of the `BufWriter` placed in front of the output, so that the
+    /// many small delta and extra chunks reach it as a few large writes.
+    pub output_buffer_size: usize,
+}
+
+impl BufferConfig {
+    pub fn new(buffer_size: usize) -> BufferConfig {
+        assert!(buffer_size > 0);
+        BufferConfig {
+            buffer_size: buffer_size,
+            output_buffer_size: buffer_size,
+        }
+    }
+
+    pub fn output_buffer_size(mut self, output_buffer_size: usize) -> BufferConfig {
+        self.output_buffer_size = output_buffer_size;
+        self
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> BufferConfig {
+        BufferConfig::new(BUFFER_SIZE)
+    }
+}
+
+/// Scratch buffers, allocated once and reused across commands.
+pub struct Scratch {
+    buf0: Vec<u8>,
+    buf1: Vec<u8>,
+}
+
+impl Scratch {
+    pub fn new(config: &BufferConfig) -> Scratch {
+        Scratch {
+            buf0: vec![0u8; config.buffer_size],
+            buf1: vec![0u8; config.buffer_size],
+        }
+    }
+}
+
+/// Adds `delta` to `buf`, bytewise and wrapping.
+///
+/// Works eight bytes at a time: the low seven bits of every byte are added
+/// in one go (they can't carry out of their byte), then the high bits are
+/// patched in with an xor.
+pub fn add_delta(buf: &mut [u8], delta: &[u8]) {
+    assert_eq!(buf.len(), delta.len());
+
+    const HIGH: u64 = 0x8080808080808080;
+
+    let words = buf.len() / 8 * 8;
+
+    for (b, d) in buf[..words].chunks_mut(8).zip(delta[..words].chunks(8)) {
+        let x = wire::read_u64(b);
+        let y = wire::read_u64(d);
+        let sum = (x & !HIGH).wrapping_add(y & !HIGH) ^ ((x ^ y) & HIGH);
+        wire::write_u64(b, sum);
+    }
+
+    for i in words..buf.len() {
+        buf[i] = buf[i].wrapping_add(delta[i]);
+    }
+}
+
+pub fn read_paired_bufs<F, R0: Read, R1: Read>(size: u64, r0: R0, r1: R1, f: F) -> io::Result<()>
+    where F: FnMut(&mut [u8], &mut [u8]) -> io::Result<()>
+{
+    read_paired_bufs_in(&mut Scratch::new(&BufferConfig::default()), size, r0, r1, f)
+}
+
+pub fn read_paired_bufs_in<F, R0: Read, R1: Read>(
+    scratch: &mut Scratch,
+    mut size: u64,
+    mut r0: R0,
+    mut r1: R1,
+    mut f: F
+) -> io::Result<()>
+    where F: FnMut(&mut [u8], &mut [u8]) -> io::Result<()>
+{
+    let buf0 = &mut scratch.buf0[..];
+    let buf1 = &mut scratch.buf1[..];
+
+    let (mut p0, mut p1) = (0, 0);
+    let mut base = 0;
+
+    while size > 0 {
+        let avail = min(buf0.len() as u64, size) as usize;
+        if p0 < avail {
+            let s0 = r0.read(&mut buf0[p0..avail])?;
+            p0 += s0;
+            if s0 == 0 {
+                trace_event!("first reader hit EOF with {} bytes left", size);
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+            }
+        }
+
+        let avail = min(buf1.len() as u64, size) as usize;
+        if p1 < avail {
+            let s1 = r1.read(&mut buf1[p1..avail])?;
+            p1 += s1;
+            if s1 == 0 {
+                trace_event!("second reader hit EOF with {} bytes left", size);
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+            }
+        }
+
+        let pmin = min(p0, p1);
+
+        f(&mut buf0[base..pmin], &mut buf1[base..pmin])?;
+
+        if p0 < p1 {
+            for i in pmin..p1 {
+                buf1[i - pmin] = buf1[i];
+            }
+        } else if p1 < p0 {
+            for i in pmin..p0 {
+                buf0[i - pmin] = buf0[i];
+            }
+        }
+
+        p0 -= pmin;
+        p1 -= pmin;
+
+        let processed = pmin - base;
+
+        size -= processed as u64;
+        base = 0;
+    }
+
+    Ok(())
+}
+
+pub fn read_size_from<F, R: Read>(size: u64, r: R, f: F) -> io::Result<()>
+    where F: FnMut(&mut [u8]) -> io::Result<()>
+{
+    read_size_from_in(&mut Scratch::new(&BufferConfig::default()), size, r, f)
+}
+
+pub fn read_size_from_in<F, R: Read>(scratch: &mut Scratch, mut size: u64, mut r: R, mut f: F) -> io::Result<()>
+    where F: FnMut(&mut [u8]) -> io::Result<()>
+{
+    let buf = &mut scratch.buf0[..];
+
+    let mut p = 0;
+    let mut base = 0;
+
+    while size > 0 {
+        let avail = min(buf.len() as u64, size) as usize;
+        if p < avail {
+            let s = r.read(&mut buf[p..avail])?;
+            assert!(s <= avail - p);
+            p += s;
+        }
+
+        if p == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+        }
+
+        f(&mut buf[base..p])?;
+
+        base = 0;
+        size -= p as u64;
+        p = 0;
+    }
+
+    Ok(())
+}
+
+/// Reads `old.len()` bytes of delta from `delta`, adds `old` to them and
+/// passes the resulting new bytes to `f` a chunk at a time.
+pub fn add_delta_from_slice<F, R: Read>(scratch: &mut Scratch, old: &[u8], delta: R, mut f: F) -> io::Result<()>
+    where F: FnMut(&mut [u8]) -> io::Result<()>
+{
+    let mut pos = 0;
+    read_size_from_in(scratch, old.len() as u64, delta, |d| {
+        add_delta(d, &old[pos..pos + d.len()]);
+        pos += d.len();
+        f(d)
+    })
+}
+
+/// The `size` bytes of `old` starting at `offset`, or an error if a patch
+/// asks for bytes past the end of the old file.
+pub fn old_slice(old: &[u8], offset: u64, size: u64) -> io::Result<&[u8]> {
+    check_old_range(offset, size, old.len() as u64)?;
+    Ok(&old[offset as usize..(offset + size) as usize])
+}
+
+/// Fails with `InvalidData` unless `size` bytes at `offset` fit within an
+/// old file `old_len` bytes long.
+pub fn check_old_range(offset: u64, size: u64, old_len: u64) -> io::Result<()> {
+    match offset.checked_add(size) {
+        Some(end) if end <= old_len => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "patch reads {} bytes at offset {} of the old file, which is only {} bytes long",
+            size, offset, old_len))),
+    }
+}
+
+/// The length of `old`, found by seeking to its end and back to the start.
+pub fn old_len<S: Seek>(mut old: S) -> io::Result<u64> {
+    let len = old.seek(SeekFrom::End(0))?;
+    old.seek(SeekFrom::Start(0))?;
+    Ok(len)
+}
+
+/// Copies the first `len` bytes of `old` to `new`, for an identity patch.
+/// Between two `File`s on Linux, `io::copy` leaves this to the kernel
+/// (`copy_file_range`), which can share the blocks on filesystems that
+/// support it rather than copying them.
+pub(crate) fn copy_old_prefix<OldRS: Read+Seek, NewW: Write>(mut old: OldRS, mut new: NewW, len: u64) -> io::Result<()> {
+    old.seek(SeekFrom::Start(0))?;
+    let copied = io::copy(&mut (&mut old).take(len), &mut new)?;
+    if copied < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+            "Patch copies {} bytes, but the old file has only {}", len, copied)));
+    }
+    new.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::*;
+    use diff::{Compression, Differ, Index, PatchOptions};
+    use test_util::noise;
+    use format::{bsdiff, linear_diff};
+    #[cfg(feature = "bzip2")]
+    use format::bsdiff::Bsdiff;
+
+    #[test]
+    fn test_apply_any_bsdiff() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.to_vec()), &new[..]);
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+
+        assert_eq!(&new[..], &out[..]);
+    }
+
+    #[test]
+    fn test_apply_any_linear_diff() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&Index::compute(old.to_vec()), &new[..], &mut patch).unwrap();
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+
+        assert_eq!(&new[..], &out[..]);
+    }
+
+    #[test]
+    fn test_apply_observed() {
+        let a = noise(3, 5000);
+        let b = (0..5000).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>();
+        let old = [&a[..], &b[..]].concat();
+        let mut new = [&b[..], &b"something new"[..], &a[..]].concat();
+        new[100] ^= 1;
+
+        let index = Index::compute(old.clone());
+        let mut bsdiff_patch = Vec::new();
+        bsdiff::write_full_patch(&index, &new, &PatchOptions::default(), &mut bsdiff_patch).unwrap();
+        let mut linear_patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new, &mut linear_patch).unwrap();
+
+        struct Indices(Vec<u64>);
+        impl ApplyObserver for Indices {
+            fn command(&mut self, index: u64, _: &CommandEvent) {
+                self.0.push(index);
+            }
+        }
+
+        for patch in &[bsdiff_patch, linear_patch] {
+            let mut stats = ApplyStats::new();
+            let mut out = Vec::new();
+            PatchApplier::new().apply_observed(patch, Cursor::new(&old[..]), &mut out, &mut stats).unwrap();
+            assert_eq!(out, new);
+
+            assert!(stats.commands > 1);
+            assert_eq!(stats.output_len(), new.len() as u64);
+            assert!(stats.added + stats.copied >= 9900, "{:?}", stats);
+            assert!(stats.seek_histogram.iter().sum::<u64>() <= stats.commands);
+            // Going back for `a` is a seek of at least 5000 bytes.
+            assert!(stats.seek_histogram.len() > 13, "{:?}", stats);
+
+            let mut indices = Indices(Vec::new());
+            PatchApplier::new().apply_observed(patch, Cursor::new(&old[..]), &mut Vec::new(), &mut indices).unwrap();
+            assert_eq!(indices.0, (0..stats.commands).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_transcode() {
+        let a = noise(13, 20000);
+        let old = [&a[..], &[0u8; 3000][..]].concat();
+        let mut new = [&a[12000..], &[7u8; 500][..], &b"new bytes"[..], &a[..12000]].concat();
+        new[100] ^= 1;
+        let index = Index::compute(old.clone());
+
+        let mut bsdiff_patch = Vec::new();
+        bsdiff::write_full_patch(&index, &new, &PatchOptions::default(), &mut bsdiff_patch).unwrap();
+        let mut linear_patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new, &mut linear_patch).unwrap();
+
+        let plain = PatchOptions::default().compression(Compression::None);
+        let mut targets = vec![(Engine::Bsdiff, PatchOptions::default()), (Engine::Bsdiff, plain.clone())];
+        #[cfg(feature = "zstd")]
+        {
+            targets.push((Engine::LinearDiffZstd, PatchOptions::default()));
+            targets.push((Engine::LinearDiffZstd, plain.clone()));
+        }
+
+        for patch in &[&bsdiff_patch, &linear_patch] {
+            for &(engine, ref options) in &targets {
+                let mut transcoded = Vec::new();
+                transcode(patch, &mut transcoded, engine, options).unwrap();
+
+                let mut out = Vec::new();
+                apply_any(&transcoded, Cursor::new(&old[..]), &mut out).unwrap();
+                assert!(out == new, "{:?} to {:?}", format_name(patch), engine);
+
+                // And back again.
+                let mut back = Vec::new();
+                transcode(&transcoded, &mut back, Engine::Bsdiff, &PatchOptions::default()).unwrap();
+                let mut out = Vec::new();
+                apply_any(&back, Cursor::new(&old[..]), &mut out).unwrap();
+                assert!(out == new);
+            }
+        }
+
+        assert!(transcode(b"not a patch at all", &mut Vec::new(), Engine::Bsdiff, &PatchOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_transcode_overflowing_length() {
+        // Two fills that are each fine, but add up to more than a u64 holds.
+        let mut patch = linear_diff::LinearDiff::MAGIC.to_vec();
+        linear_diff::Command::fill(1, 1 << 63).write_to(&mut patch).unwrap();
+        linear_diff::Command::fill(2, 1 << 63).write_to(&mut patch).unwrap();
+
+        let plain = PatchOptions::default().compression(Compression::None);
+        let err = transcode(&patch, &mut Vec::new(), Engine::Bsdiff, &plain).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        #[cfg(feature = "zstd")]
+        {
+            let err = transcode(&patch, &mut Vec::new(), Engine::LinearDiffZstd, &plain).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_transcode_overflowing_offset() {
+        // Copies from beyond where a bsdiff seek can reach.
+        for &(offset, len) in &[(1u64 << 63, 10u64), (i64::max_value() as u64 - 5, 10)] {
+            let mut patch = linear_diff::LinearDiff::MAGIC.to_vec();
+            linear_diff::Command::copy(offset, len, 0).write_to(&mut patch).unwrap();
+
+            let plain = PatchOptions::default().compression(Compression::None);
+            let err = transcode(&patch, &mut Vec::new(), Engine::Bsdiff, &plain).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_recompress() {
+        let old = noise(17, 50000);
+        let mut new = old[10000..].to_vec();
+        for i in 0..2000 {
+            new.extend_from_slice(format!("line {}\n", i % 300).as_bytes());
+        }
+        let index = Index::compute(old.clone());
+
+        let mut patches = Vec::new();
+        let fastest = PatchOptions::default().compression(Compression::Fastest);
+        let mut patch = Vec::new();
+        bsdiff::write_full_patch(&index, &new, &fastest, &mut patch).unwrap();
+        patches.push(patch);
+        #[cfg(feature = "zstd")]
+        {
+            let mut patch = Vec::new();
+            ::diff::auto::write_with(Engine::LinearDiffZstd, &index, &new, &fastest, &mut patch).unwrap();
+            patches.push(patch);
+        }
+
+        for patch in &patches {
+            let mut recompressed = Vec::new();
+            recompress(patch, &mut recompressed, Compression::Best).unwrap();
+            assert_eq!(format_name(&recompressed), format_name(patch));
+
+            let mut out = Vec::new();
+            apply_any(&recompressed, Cursor::new(&old[..]), &mut out).unwrap();
+            assert!(out == new);
+        }
+
+        let mut linear_patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new, &mut linear_patch).unwrap();
+        let mut recompressed = Vec::new();
+        recompress(&linear_patch, &mut recompressed, Compression::Best).unwrap();
+        assert!(recompressed == linear_patch);
+    }
+
+    #[cfg(feature = "sha1")]
+    #[test]
+    fn test_transcode_keeps_envelopes() {
+        let old = (0..10000).map(|i| (i * 13 % 251) as u8).collect::<Vec<u8>>();
+        let mut new = old.clone();
+        new[5000] ^= 0xff;
+        let index = Index::compute(old.clone());
+
+        let mut patch = Vec::new();
+        Differ::new(&index).options(PatchOptions::default().source_digest(true).block_hashes(4096))
+            .run(&new, &mut patch).unwrap();
+
+        let mut transcoded = Vec::new();
+        transcode(&patch, &mut transcoded, Engine::Bsdiff, &PatchOptions::default().compression(Compression::None))
+            .unwrap();
+        assert!(source_digest::is_match(&transcoded));
+        assert!(verify_source(&transcoded, &old[..]).is_ok());
+
+        let mut out = Vec::new();
+        apply_any(&transcoded, Cursor::new(&old[..]), &mut out).unwrap();
+        assert!(out == new);
+    }
+
+    #[test]
+    fn test_apply_sequential() {
+        let a = noise(5, 5000);
+        let b = (0..5000).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>();
+        let old = [&a[..], &b[..]].concat();
+        let index = Index::compute(old.clone());
+
+        // Edits in place only ever seek forward.
+        let mut new = old.clone();
+        new[7000] ^= 1;
+        let mut patch = Vec::new();
+        bsdiff::write_full_patch(&index, &new, &PatchOptions::default(), &mut patch).unwrap();
+        let mut out = Vec::new();
+        apply_sequential(&patch, &old[..], &mut out).unwrap();
+        assert_eq!(out, new);
+
+        // Moving `a` after `b` has to go back for it, unless the patch was
+        // written to read the old file in order.
+        let new = [&b[..], &a[..]].concat();
+        let mut patch = Vec::new();
+        bsdiff::write_full_patch(&index, &new, &PatchOptions::default(), &mut patch).unwrap();
+        let err = apply_sequential(&patch, &old[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut patch = Vec::new();
+        let options = PatchOptions::default().sequential_old_access(true);
+        bsdiff::write_full_patch(&index, &new, &options, &mut patch).unwrap();
+        let mut out = Vec::new();
+        apply_sequential(&patch, &old[..], &mut out).unwrap();
+        assert_eq!(out, new);
+
+        // A short old file is caught while skipping.
+        assert!(apply_sequential(&patch, &old[..4000], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_apply_chain() {
+        let v1 = noise(3, 20000);
+        let mut v2 = v1.clone();
+        v2[100] ^= 1;
+        v2.extend_from_slice(b"appended in v2");
+        let mut v3 = [&v2[10000..], &v2[..10000]].concat();
+        v3[5000] ^= 0xff;
+
+        let diff = |old: &[u8], new: &[u8]| {
+            let mut patch = Vec::new();
+            bsdiff::write_full_patch(&Index::compute(old.to_vec()), new, &PatchOptions::default(), &mut patch).unwrap();
+            patch
+        };
+        let p12 = diff(&v1, &v2);
+        let p23 = diff(&v2, &v3);
+
+        for &limit in &[0, CHAIN_MEMORY_LIMIT] {
+            let mut out = Vec::new();
+            apply_chain_with_limit(vec![&p12[..], &p23[..]], Cursor::new(&v1), &mut out, limit, &TempFiles::new()).unwrap();
+            assert_eq!(out, v3);
+        }
+
+        let mut out = Vec::new();
+        apply_chain(vec![&p12[..]], Cursor::new(&v1), &mut out).unwrap();
+        assert_eq!(out, v2);
+
+        let mut out = Vec::new();
+        apply_chain(Vec::<&[u8]>::new(), Cursor::new(&v1), &mut out).unwrap();
+        assert_eq!(out, v1);
+
+        // Out of order, the second patch gets the wrong old file.
+        assert!(apply_chain(vec![&p23[..], &p12[..]], Cursor::new(&v1), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_apply_to_block_device() {
+        use io::BlockDevice;
+
+        let old = (0..30000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<u8>>();
+        let mut new = old[10000..].to_vec();
+        new.extend_from_slice(b"and a little more");
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.clone()), &new);
+
+        let device = BlockDevice::new(Vec::new(), 4096).erase_group(2).pad_byte(0xFF);
+        let out = apply_to_block_device(&patch, Cursor::new(&old[..]), device).unwrap();
+
+        assert_eq!(out.len() % 4096, 0);
+        assert_eq!(out.len() - new.len(), 4096 - new.len() % 4096);
+        assert!(&out[..new.len()] == &new[..]);
+        assert!(out[new.len()..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_apply_sequential_with_window() {
+        let old = noise(9, 20000);
+        let index = Index::compute(old.clone());
+
+        // Swapping two neighbouring 3000-byte blocks seeks back 6000 bytes.
+        let new = [&old[..10000], &old[13000..16000], &old[10000..13000], &old[16000..]].concat();
+        let mut patch = Vec::new();
+        bsdiff::write_full_patch(&index, &new, &PatchOptions::default(), &mut patch).unwrap();
+
+        assert!(apply_sequential(&patch, &old[..], &mut Vec::new()).is_err());
+        assert!(apply_sequential_with_window(&patch, &old[..], &mut Vec::new(), 1000).is_err());
+
+        for &window in &[7000, 8192, 1 << 20] {
+            let mut out = Vec::new();
+            apply_sequential_with_window(&patch, &old[..], &mut out, window).unwrap();
+            assert!(out == new, "window of {}", window);
+        }
+    }
+
+    #[test]
+    fn test_add_delta() {
+        for len in 0..40 {
+            let old = (0..len).map(|i| (i * 37 + 200) as u8).collect::<Vec<_>>();
+            let delta = (0..len).map(|i| (i * 91 + 100) as u8).collect::<Vec<_>>();
+
+            let mut fast = old.clone();
+            add_delta(&mut fast, &delta);
+
+            let slow = old.iter().zip(&delta).map(|(o, d)| o.wrapping_add(*d)).collect::<Vec<_>>();
+
+            assert_eq!(fast, slow);
+        }
+    }
+
+    #[test]
+    fn test_tiny_buffers() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+        let config = BufferConfig::new(3).output_buffer_size(5);
+
+        let patch = bsdiff::generate_full_patch(&index, &new[..]);
+        let mut out = Vec::new();
+        bsdiff::apply_patch_with_buffers(&patch, Cursor::new(&old[..]), &mut out, &config).unwrap();
+        assert_eq!(&new[..], &out[..]);
+
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new[..], &mut patch).unwrap();
+        let mut out = Vec::new();
+        linear_diff::apply_patch_with_buffers(&patch[..], Cursor::new(&old[..]), &mut out, &config).unwrap();
+        assert_eq!(&new[..], &out[..]);
+    }
+
+    #[test]
+    fn test_apply_from_slice() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let mut patches = vec![bsdiff::generate_full_patch(&index, &new[..])];
+
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new[..], &mut patch).unwrap();
+        patches.push(patch);
+
+        #[cfg(feature = "zstd")]
+        {
+            let mut patch = Vec::new();
+            linear_diff::generate_compressed_patch(&index, &new[..], 3, &mut patch).unwrap();
+            patches.push(patch);
+        }
+
+        for patch in &patches {
+            let mut out = Vec::new();
+            apply_from_slice(patch, &old[..], &mut out).unwrap();
+            assert_eq!(&new[..], &out[..]);
+        }
+
+        let err = apply_from_slice(&patches[1], &old[..10], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_segments() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.to_vec()), &new[..]);
+
+        let max_segment = SEGMENT_OVERHEAD + 16;
+        let segments = split(&patch, max_segment);
+        assert!(segments.len() > 1);
+
+        let mut framed = Vec::new();
+        for segment in &segments {
+            let mut buf = Vec::new();
+            segment.write_to(&mut buf).unwrap();
+            assert!(buf.len() <= max_segment);
+            assert_eq!(buf.len(), segment.framed_len());
+            framed.push(buf);
+        }
+
+        let read = framed.iter()
+            .map(|buf| Segment::read_from(&buf[..]).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(read, segments);
+
+        let mut out = Vec::new();
+        apply_segments(read, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(&new[..], &out[..]);
+
+        let mut corrupt = framed[0].clone();
+        corrupt[30] ^= 1;
+        let err = Segment::read_from(&corrupt[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut reordered = segments.clone();
+        reordered.swap(0, 1);
+        assert!(apply_segments(reordered, Cursor::new(&old[..]), &mut Vec::new()).is_err());
+
+        let missing = segments[..segments.len() - 1].to_vec();
+        assert!(apply_segments(missing, Cursor::new(&old[..]), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "bzip2")]
+    fn test_validate() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let patch = bsdiff::generate_full_patch(&index, &new[..]);
+        assert_eq!(validate(&patch, &old[..]).unwrap(), ValidationReport {
+            format: Bsdiff::NAME,
+            new_size: new.len() as u64,
+        });
+
+        assert!(validate(&patch, &old[..4]).is_err());
+        assert!(validate(&patch[..patch.len() - 16], &old[..]).is_err());
+
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new[..], &mut patch).unwrap();
+        assert_eq!(validate(&patch, &old[..]).unwrap().format, LinearDiff::NAME);
+        assert!(validate(&patch, &old[..4]).is_err());
+
+        assert!(validate(b"NOTAPATCH", &old[..]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_apply_with_digest() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.to_vec()), &new[..]);
+
+        let mut sha1 = Sha1::new();
+        sha1.update(new);
+        let expected = sha1.digest().bytes();
+
+        let mut out = Vec::new();
+        let digest = apply_with_digest(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(digest, expected);
+        assert_eq!(&new[..], &out[..]);
+
+        apply_and_verify(&patch, Cursor::new(&old[..]), &mut Vec::new(), &expected).unwrap();
+
+        let err = apply_and_verify(&patch, Cursor::new(&old[..]), &mut Vec::new(), &[0; 20]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("rsdiff-test-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The names of the files in `dir`, sorted, to check that an apply left
+    /// no temporary files behind.
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_apply_to_path() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.to_vec()), &new[..]);
+
+        let dir = temp_dir("apply-to-path");
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        fs::write(&old_path, &old[..]).unwrap();
+        fs::write(&new_path, b"previous").unwrap();
+
+        let err = apply_to_path(&patch, &old_path, &new_path, &ApplyOptions::new().expected_digest([0; 20]));
+        assert!(err.is_err());
+        assert_eq!(fs::read(&new_path).unwrap(), b"previous");
+        assert_eq!(dir_entries(&dir), ["new", "old"]);
+
+        apply_to_path(&patch, &old_path, &new_path, &ApplyOptions::new()).unwrap();
+        assert_eq!(fs::read(&new_path).unwrap(), &new[..]);
+
+        // Some other updater's temporary file is left alone, and applies
+        // racing to the same file don't trample each other's output.
+        let other_temp = dir.join("new.tmp");
+        fs::write(&other_temp, b"someone else's").unwrap();
+        let threads = (0..4).map(|_| {
+            let (patch, old_path, new_path) = (patch.clone(), old_path.clone(), new_path.clone());
+            ::std::thread::spawn(move || apply_to_path(&patch, &old_path, &new_path, &ApplyOptions::new()))
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        assert_eq!(fs::read(&new_path).unwrap(), &new[..]);
+        assert_eq!(fs::read(&other_temp).unwrap(), b"someone else's");
+        assert_eq!(dir_entries(&dir), ["new", "new.tmp", "old"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply_identity_patch() {
+        let old = noise(3, 30000);
+        let len = 20000;
+
+        let mut with_codecs = Vec::new();
+        bsdiff::BsdiffCodecs::write_identity_patch(len, &mut with_codecs).unwrap();
+
+        for patch in &[bsdiff::generate_identity_patch(len), with_codecs] {
+            assert_eq!(bsdiff::identity_len(patch, DecodeMode::Strict).unwrap(), Some(len));
+
+            let mut stats = ApplyStats::new();
+            let mut out = Vec::new();
+            PatchApplier::new().apply_observed(patch, Cursor::new(&old[..]), &mut out, &mut stats).unwrap();
+            assert!(out[..] == old[..len as usize]);
+            assert_eq!((stats.commands, stats.added), (1, len));
+
+            let err = apply_any(patch, Cursor::new(&old[..1000]), &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+
+        // Anything that changes a byte isn't an identity patch.
+        let mut new = old[..len as usize].to_vec();
+        new[len as usize - 1] ^= 1;
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.clone()), &new);
+        assert_eq!(bsdiff::identity_len(&patch, DecodeMode::Strict).unwrap(), None);
+
+        let dir = temp_dir("identity");
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        fs::write(&old_path, &old).unwrap();
+
+        let patch = bsdiff::generate_identity_patch(len);
+        apply_to_path(&patch, &old_path, &new_path, &ApplyOptions::new()).unwrap();
+        assert!(fs::read(&new_path).unwrap()[..] == old[..len as usize]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply_to_path_preallocates() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+        let patch = bsdiff::generate_full_patch(&index, &new[..]);
+        assert_eq!(declared_new_size(&patch), Some(new.len() as u64));
+
+        let mut linear = Vec::new();
+        linear_diff::generate_full_patch(&index, &new[..], &mut linear).unwrap();
+        assert_eq!(declared_new_size(&linear), None);
+
+        let dir = temp_dir("preallocate");
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        fs::write(&old_path, &old[..]).unwrap();
+        fs::write(&new_path, b"previous").unwrap();
+
+        for patch in &[&patch, &linear] {
+            apply_to_path(patch, &old_path, &new_path, &ApplyOptions::new()).unwrap();
+            assert_eq!(fs::read(&new_path).unwrap(), &new[..]);
+        }
+
+        // No disk has room for this, but the header isn't believed that
+        // far: the apply goes ahead, and fails on the patch, not the disk.
+        let mut huge = patch.clone();
+        bsdiff::write_offset(&mut huge[24..32], 1 << 62);
+        let old_file = File::open(&old_path).unwrap();
+        let cap = (old.len() + huge.len()) as u64 * PREALLOCATE_FACTOR;
+        assert_eq!(preallocation_size(&huge, &old_file).unwrap(), Some(cap));
+        assert_eq!(preallocation_size(&patch, &old_file).unwrap(), Some(new.len() as u64));
+        assert_eq!(preallocation_size(&linear, &old_file).unwrap(), None);
+
+        let err = apply_to_path(&huge, &old_path, &new_path, &ApplyOptions::new()).unwrap_err();
+        assert!(err.raw_os_error().is_none(), "{}", err);
+        assert_eq!(fs::read(&new_path).unwrap(), &new[..]);
+        assert_eq!(dir_entries(&dir), ["new", "old"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_apply_to_path_journal() {
+        let old = noise(3, 200000);
+        let mut new = old.clone();
+        new[1000] ^= 1;
+        new[150000] ^= 1;
+        new.extend_from_slice(b"appended");
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.clone()), &new);
+
+        let dir = temp_dir("journal");
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        let journal_path = journal::path_for(&new_path);
+        let options = ApplyOptions::new().journal_interval(4096);
+
+        // The "crash": an old file that runs out halfway through.
+        fs::write(&old_path, &old[..100000]).unwrap();
+        assert!(apply_to_path(&patch, &old_path, &new_path, &options).is_err());
+        let offset = journal::Journal::read(&fs::read(&journal_path).unwrap()).unwrap().offset;
+        assert!(offset > 0 && offset <= 100000, "{}", offset);
+        assert!(!new_path.exists());
+
+        fs::write(&old_path, &old).unwrap();
+        apply_to_path(&patch, &old_path, &new_path, &options).unwrap();
+        assert!(fs::read(&new_path).unwrap() == new);
+        assert!(!journal_path.exists());
+        assert_eq!(dir_entries(&dir), ["new", "old"]);
+
+        // Output damaged since the journal was written is rewritten.
+        fs::write(&old_path, &old[..100000]).unwrap();
+        assert!(apply_to_path(&patch, &old_path, &new_path, &options).is_err());
+        let temp_path = temp_path_for(&new_path);
+        let mut partial = fs::read(&temp_path).unwrap();
+        partial[10] ^= 1;
+        fs::write(&temp_path, &partial).unwrap();
+
+        let mut sha1 = Sha1::new();
+        sha1.update(&new);
+        fs::write(&old_path, &old).unwrap();
+        apply_to_path(&patch, &old_path, &new_path, &options.expected_digest(sha1.digest().bytes())).unwrap();
+        assert!(fs::read(&new_path).unwrap() == new);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply_to_path_parallel_output() {
+        let old = noise(7, 300000);
+        let mut new = old[100000..].to_vec();
+        new.extend_from_slice(&[0; 5000]);
+        new.extend_from_slice(&old[..150000]);
+        new[1234] ^= 0x40;
+        new[250000] ^= 0x01;
+
+        let index = Index::compute(old.clone());
+        let dir = temp_dir("parallel-output");
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        fs::write(&old_path, &old).unwrap();
+
+        let mut linear = Vec::new();
+        linear_diff::generate_full_patch(&index, &new, &mut linear).unwrap();
+        for patch in &[bsdiff::generate_full_patch(&index, &new), linear] {
+            for &workers in &[1, 4] {
+                fs::write(&new_path, b"previous").unwrap();
+                apply_to_path(patch, &old_path, &new_path, &ApplyOptions::new().parallel_output(workers)).unwrap();
+                assert!(fs::read(&new_path).unwrap() == new);
+            }
+        }
+
+        // The finished file is read back for the digest check.
+        #[cfg(feature = "sha1")]
+        {
+            let mut sha1 = Sha1::new();
+            sha1.update(&new);
+            let patch = bsdiff::generate_full_patch(&index, &new);
+
+            fs::write(&new_path, b"previous").unwrap();
+            let options = ApplyOptions::new().parallel_output(4);
+            assert!(apply_to_path(&patch, &old_path, &new_path, &options.clone().expected_digest([0; 20])).is_err());
+            assert_eq!(fs::read(&new_path).unwrap(), b"previous");
+            assert_eq!(dir_entries(&dir), ["new", "old"]);
+
+            apply_to_path(&patch, &old_path, &new_path, &options.expected_digest(sha1.digest().bytes())).unwrap();
+            assert!(fs::read(&new_path).unwrap() == new);
+        }
+
+        // An old file too short for the patch leaves the new file alone.
+        fs::write(&new_path, b"previous").unwrap();
+        fs::write(&old_path, &old[..1000]).unwrap();
+        let patch = bsdiff::generate_full_patch(&index, &new);
+        assert!(apply_to_path(&patch, &old_path, &new_path, &ApplyOptions::new().parallel_output(4)).is_err());
+        assert_eq!(fs::read(&new_path).unwrap(), b"previous");
+        assert_eq!(dir_entries(&dir), ["new", "old"]);
+
+        // So does one whose output adds up to more than a u64 holds, with
+        // or without cloning.
+        let mut patch = linear_diff::LinearDiff::MAGIC.to_vec();
+        linear_diff::Command::fill(1, 1 << 63).write_to(&mut patch).unwrap();
+        linear_diff::Command::fill(2, 1 << 63).write_to(&mut patch).unwrap();
+        for options in &[ApplyOptions::new().parallel_output(4), ApplyOptions::new().allow_clone(true)] {
+            let err = apply_to_path(&patch, &old_path, &new_path, options).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(fs::read(&new_path).unwrap(), b"previous");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply_to_path_allow_clone() {
+        let old = noise(11, 300000);
+        // One block-aligned copy, one that isn't, and a short one.
+        let mut new = old[131072..262144].to_vec();
+        new.extend_from_slice(b"in between");
+        new.extend_from_slice(&old[1000..201000]);
+        new.extend_from_slice(&old[290000..291000]);
+
+        let index = Index::compute(old.clone());
+        let dir = temp_dir("allow-clone");
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        fs::write(&old_path, &old).unwrap();
+
+        let mut linear = Vec::new();
+        linear_diff::generate_full_patch(&index, &new, &mut linear).unwrap();
+        let cases = [
+            (bsdiff::generate_full_patch(&index, &new), &new[..]),
+            (linear, &new[..]),
+            (bsdiff::generate_identity_patch(250000), &old[..250000]),
+        ];
+        for &(ref patch, expected) in &cases {
+            fs::write(&new_path, b"previous").unwrap();
+            apply_to_path(patch, &old_path, &new_path, &ApplyOptions::new().allow_clone(true)).unwrap();
+            assert!(fs::read(&new_path).unwrap() == expected);
+        }
+
+        // Cloned output goes through the same digest check.
+        #[cfg(feature = "sha1")]
+        {
+            let mut sha1 = Sha1::new();
+            sha1.update(&new);
+            let patch = &cases[0].0;
+
+            fs::write(&new_path, b"previous").unwrap();
+            let options = ApplyOptions::new().allow_clone(true);
+            assert!(apply_to_path(patch, &old_path, &new_path, &options.clone().expected_digest([0; 20])).is_err());
+            assert_eq!(fs::read(&new_path).unwrap(), b"previous");
+            assert_eq!(dir_entries(&dir), ["new", "old"]);
+
+            apply_to_path(patch, &old_path, &new_path, &options.expected_digest(sha1.digest().bytes())).unwrap();
+            assert!(fs::read(&new_path).unwrap() == new);
+        }
+
+        // An old file too short for a copy leaves the new file alone.
+        fs::write(&new_path, b"previous").unwrap();
+        fs::write(&old_path, &old[..150000]).unwrap();
+        let patch = bsdiff::generate_full_patch(&index, &new);
+        assert!(apply_to_path(&patch, &old_path, &new_path, &ApplyOptions::new().allow_clone(true)).is_err());
+        assert_eq!(fs::read(&new_path).unwrap(), b"previous");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_in_path() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.to_vec()), &new[..]);
+
+        let dir = temp_dir("upgrade-in-path");
+        let path = dir.join("file");
+        fs::write(&path, &old[..]).unwrap();
+
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+
+        assert!(upgrade_in_path(b"garbage", &path, &ApplyOptions::new()).is_err());
+        assert_eq!(fs::read(&path).unwrap(), &old[..]);
+
+        upgrade_in_path(&patch, &path, &ApplyOptions::new()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), &new[..]);
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sha1")]
+    fn test_patch_applier() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.to_vec()), &new[..]);
+
+        let mut sha1 = Sha1::new();
+        sha1.update(new);
+        let expected = sha1.digest().bytes();
+
+        let applier = PatchApplier::new()
+            .buffers(BufferConfig::new(5))
+            .expected_digest(expected);
+
+        let mut out = Vec::new();
+        applier.apply(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(&new[..], &out[..]);
+
+        let mut out = Vec::new();
+        applier.apply_from_slice(&patch, &old[..], &mut out).unwrap();
+        assert_eq!(&new[..], &out[..]);
+
+        let err = PatchApplier::new()
+            .expected_digest([0; 20])
+            .apply(&patch, Cursor::new(&old[..]), &mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_apply_error_context() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new[..], &mut patch).unwrap();
+
+        // Cut the patch off partway through its second command.
+        let first = linear_diff::Command::read_from(&patch[8..]).unwrap().unwrap();
+        let first_len = first.body_len().unwrap();
+        let second = 8 + 24 + first_len as usize;
+
+        let err = apply_any(&patch[..second + 10], Cursor::new(&old[..]), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let context = ApplyError::find(&err).unwrap();
+        assert_eq!(context.command, 1);
+        assert_eq!(context.patch_offset, Some(second as u64));
+        assert_eq!(context.output_offset, first_len);
+        assert!(err.to_string().contains("command 1"));
+
+        let patch = bsdiff::generate_full_patch(&index, &new[..]);
+        let err = apply_any(&patch, Cursor::new(&old[..4]), &mut Vec::new()).unwrap_err();
+        let context = ApplyError::find(&err).unwrap();
+        assert_eq!(context.command, 0);
+        assert_eq!(context.patch_offset, None);
+        assert_eq!(context.output_offset, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "bzip2")]
+    fn test_decode_modes() {
+        let old = b"this is a test 12345678 test";
+        let new = b"this is really a cool uftu 12345678 uftu";
+        let index = Index::compute(old.to_vec());
+
+        let strict = PatchApplier::new().decode_mode(DecodeMode::Strict);
+        let lenient = PatchApplier::new().decode_mode(DecodeMode::Lenient);
+
+        // Trailing junk after a bsdiff patch is let through by default.
+        let mut patch = bsdiff::generate_full_patch(&index, &new[..]);
+        strict.apply(&patch, Cursor::new(&old[..]), &mut Vec::new()).unwrap();
+        patch.extend_from_slice(b"junk");
+
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(&new[..], &out[..]);
+
+        let err = strict.apply(&patch, Cursor::new(&old[..]), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = strict.apply_from_slice(&patch, &old[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // An empty command in a linear_diff patch isn't, unless asked.
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &new[..], &mut patch).unwrap();
+        let patch = [&patch[..8], &[0u8; 24][..], &patch[8..]].concat();
+
+        // It's caught when the next command shows it isn't a whole patch.
+        let err = apply_any(&patch, Cursor::new(&old[..]), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(ApplyError::find(&err).unwrap().command, 1);
+
+        let mut out = Vec::new();
+        lenient.apply(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(&new[..], &out[..]);
+
+        let mut out = Vec::new();
+        lenient.apply_from_slice(&patch, &old[..], &mut out).unwrap();
+        assert_eq!(&new[..], &out[..]);
+    }
+
+    #[test]
+    fn test_empty_patches_are_strict() {
+        let strict = PatchApplier::new().decode_mode(DecodeMode::Strict);
+
+        let mut patches = vec![
+            bsdiff::generate_identity_patch(0),
+            bsdiff::generate_idempotent_patch(b""),
+        ];
+
+        for i in 0..4 {
+            let mut patch = Vec::new();
+            match i {
+                0 => LinearDiff::write_identity_patch(0, &mut patch),
+                #[cfg(feature = "zstd")]
+                1 => LinearDiffZstd::write_idempotent_patch(b"", &mut patch),
+                #[cfg(not(feature = "zstd"))]
+                1 => LinearDiff::write_idempotent_patch(b"", &mut patch),
+                2 => MultiSource::write_identity_patch(0, &mut patch),
+                _ => MultiSource::write_idempotent_patch(b"", &mut patch),
+            }.unwrap();
+            patches.push(patch);
+        }
+
+        // Earlier versions wrote them as a single empty command.
+        patches.push([b"LINDIF01", &[0u8; 24][..]].concat());
+        patches.push([LinearDiff::MAGIC, &[0u8; 24][..]].concat());
+        patches.push([MultiSource::MAGIC, &[0u8; 32][..]].concat());
+
+        for patch in &patches {
+            let mut out = Vec::new();
+            strict.apply(patch, Cursor::new(&b"old"[..]), &mut out).unwrap();
+            assert!(out.is_empty());
+            strict.apply_from_slice(patch, b"old", &mut out).unwrap();
+            assert!(out.is_empty());
+        }
+
+        let mut commands = bsdiff::CommandReader::with_mode(&[0u8; 24][..], DecodeMode::Strict);
+        assert!(commands.next().unwrap().is_ok());
+        assert!(commands.next().is_none());
+
+        // But not followed by anything else.
+        let patch = [LinearDiff::MAGIC, &[0u8; 48][..]].concat();
+        assert!(strict.apply(&patch, Cursor::new(&b"old"[..]), &mut Vec::new()).is_err());
+        let patch = [MultiSource::MAGIC, &[0u8; 64][..]].concat();
+        assert!(strict.apply(&patch, Cursor::new(&b"old"[..]), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_apply_any_unknown_magic() {
+        for patch in &[&b""[..], b"BSDIFF", b"NOTAPATCH0000000"] {
+            let mut out = Vec::new();
+            let err = apply_any(patch, Cursor::new(&b"old"[..]), &mut out).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_can_apply() {
+        let patch = bsdiff::generate_identity_patch(10);
+        assert_eq!(can_apply(&patch[..min(patch.len(), CAN_APPLY_BYTES)]), Ok(()));
+        let header = Header::read(&patch).unwrap();
+        assert!(header.required_features().difference(&FeatureSet::available()).is_empty());
+
+        let mut patch = Vec::new();
+        LinearDiff::write_identity_patch(10, &mut patch).unwrap();
+        assert_eq!(can_apply(&patch), Ok(()));
+
+        assert_eq!(can_apply(b"BSD"), Err(Unsupported::Truncated { needed: 8 }));
+        assert_eq!(can_apply(b"NOTAPATCH0000000"), Err(Unsupported::UnknownFormat));
+
+        // A bare BSDIFFC1 header with zstd-compressed commands.
+        let mut header = b"BSDIFFC1".to_vec();
+        header.extend_from_slice(&[0; 24]);
+        header.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(can_apply(&header[..39]), Err(Unsupported::Truncated { needed: 40 }));
+
+        let zstd = FeatureSet { zstd: true, ..FeatureSet::default() };
+        if cfg!(feature = "zstd") {
+            assert_eq!(can_apply(&header), Ok(()));
+        } else {
+            assert_eq!(can_apply(&header), Err(Unsupported::MissingFeatures(zstd)));
+        }
+
+        header[33] = 9;
+        assert_eq!(can_apply(&header), Err(Unsupported::UnknownCodec(9)));
+
+        header[33] = 0;
+        header[36] = 1;
+        assert_eq!(can_apply(&header), Err(Unsupported::UnknownFormat));
+
+        let err: io::Error = Unsupported::MissingFeatures(zstd).into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
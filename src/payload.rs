@@ -0,0 +1,538 @@
+//! Update payloads for whole systems, in the style of A/B OTA payloads: a
+//! manifest listing each partition's old and new size and SHA-1, and the
+//! operations that build the new partition, followed by the data those
+//! operations carry.
+//!
+//! Layout:
+//!
+//! ```text
+//! magic "RSDPAY01", manifest length (u64), manifest, data
+//! ```
+//!
+//! All integers are little-endian.  The manifest is a partition count
+//! (u32), then for each partition:
+//!
+//! ```text
+//! name length (u16), name (UTF-8),
+//! old size (u64), has old digest (u8), old digest (20 bytes, if present),
+//! new size (u64), new digest (20 bytes),
+//! operation count (u32), operations
+//! ```
+//!
+//! Each operation is a kind byte followed by two u64s; see `Operation`.
+//! Operations write the new partition front to back, in order.
+
+use std::io::{self, Read, Write, Seek, SeekFrom};
+
+use sha1::Sha1;
+
+use diff::{Index, PatchOptions, write_fill};
+use diff::auto::{self, ALL_ENGINES};
+use format::sink::CountingWriter;
+use format::wire;
+use patch::{apply_any, DigestWriter};
+
+pub const MAGIC: &'static [u8] = b"RSDPAY01";
+
+/// How one stretch of a new partition is produced.  Data offsets are into
+/// the payload's data section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Operation {
+    /// Copies `len` bytes of the old partition, starting at `offset`.
+    SourceCopy { offset: u64, len: u64 },
+    /// Writes `data_len` bytes of data verbatim.
+    Replace { data_offset: u64, data_len: u64 },
+    /// Applies the patch in `data_len` bytes of data, in any format
+    /// `apply_any` understands, to the whole old partition.
+    Diff { data_offset: u64, data_len: u64 },
+    /// Writes `len` zeros.
+    Zero { len: u64 },
+}
+
+const SOURCE_COPY: u8 = 0;
+const REPLACE: u8 = 1;
+const DIFF: u8 = 2;
+const ZERO: u8 = 3;
+
+impl Operation {
+    fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let (kind, a, b) = match *self {
+            Operation::SourceCopy { offset, len } => (SOURCE_COPY, offset, len),
+            Operation::Replace { data_offset, data_len } => (REPLACE, data_offset, data_len),
+            Operation::Diff { data_offset, data_len } => (DIFF, data_offset, data_len),
+            Operation::Zero { len } => (ZERO, 0, len),
+        };
+        wire::write_u8_to(&mut w, kind)?;
+        wire::write_u64_to(&mut w, a)?;
+        wire::write_u64_to(&mut w, b)
+    }
+
+    fn read_from<R: Read>(mut r: R) -> io::Result<Operation> {
+        let kind = wire::read_u8_from(&mut r)?;
+        let a = wire::read_u64_from(&mut r)?;
+        let b = wire::read_u64_from(&mut r)?;
+        Ok(match kind {
+            SOURCE_COPY => Operation::SourceCopy { offset: a, len: b },
+            REPLACE => Operation::Replace { data_offset: a, data_len: b },
+            DIFF => Operation::Diff { data_offset: a, data_len: b },
+            ZERO => Operation::Zero { len: b },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Unknown payload operation {}", kind))),
+        })
+    }
+
+    /// The stretch of the data section this operation reads, if any.
+    fn data_range(&self) -> Option<(u64, u64)> {
+        match *self {
+            Operation::Replace { data_offset, data_len } |
+            Operation::Diff { data_offset, data_len } => Some((data_offset, data_len)),
+            _ => None,
+        }
+    }
+}
+
+/// Everything the manifest records about one partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PartitionInfo {
+    pub name: String,
+    pub old_size: u64,
+    /// SHA-1 of the old partition, or `None` for a partition written from
+    /// scratch, whose old contents don't matter.
+    pub old_digest: Option<[u8; 20]>,
+    pub new_size: u64,
+    pub new_digest: [u8; 20],
+    pub operations: Vec<Operation>,
+}
+
+impl PartitionInfo {
+    fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        wire::write_u16_to(&mut w, self.name.len() as u16)?;
+        w.write_all(self.name.as_bytes())?;
+
+        wire::write_u64_to(&mut w, self.old_size)?;
+        match self.old_digest {
+            Some(ref digest) => {
+                wire::write_u8_to(&mut w, 1)?;
+                w.write_all(digest)?;
+            }
+            None => wire::write_u8_to(&mut w, 0)?,
+        }
+
+        wire::write_u64_to(&mut w, self.new_size)?;
+        w.write_all(&self.new_digest)?;
+
+        wire::write_u32_to(&mut w, self.operations.len() as u32)?;
+        for op in &self.operations {
+            op.write_to(&mut w)?;
+        }
+        Ok(())
+    }
+
+    fn read_from<R: Read>(mut r: R) -> io::Result<PartitionInfo> {
+        let name_len = wire::read_u16_from(&mut r)?;
+        let mut name = Vec::new();
+        r.by_ref().take(name_len as u64).read_to_end(&mut name)?;
+        if name.len() < name_len as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated payload manifest"));
+        }
+        let name = String::from_utf8(name).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Partition name isn't UTF-8")
+        })?;
+
+        let old_size = wire::read_u64_from(&mut r)?;
+        let old_digest = match wire::read_u8_from(&mut r)? {
+            0 => None,
+            1 => {
+                let mut digest = [0u8; 20];
+                r.read_exact(&mut digest)?;
+                Some(digest)
+            }
+            flag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Bad old digest flag {} for partition {:?}", flag, name))),
+        };
+
+        let new_size = wire::read_u64_from(&mut r)?;
+        let mut new_digest = [0u8; 20];
+        r.read_exact(&mut new_digest)?;
+
+        // Don't trust the count for the allocation; a bad one just runs out
+        // of manifest.
+        let count = wire::read_u32_from(&mut r)?;
+        let mut operations = Vec::new();
+        for _ in 0..count {
+            operations.push(Operation::read_from(&mut r)?);
+        }
+
+        Ok(PartitionInfo {
+            name: name,
+            old_size: old_size,
+            old_digest: old_digest,
+            new_size: new_size,
+            new_digest: new_digest,
+            operations: operations,
+        })
+    }
+}
+
+/// The table of contents of a payload.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Manifest {
+    pub partitions: Vec<PartitionInfo>,
+}
+
+impl Manifest {
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        wire::write_u32_to(&mut w, self.partitions.len() as u32)?;
+        for partition in &self.partitions {
+            partition.write_to(&mut w)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<Manifest> {
+        let count = wire::read_u32_from(&mut r)?;
+        let mut partitions = Vec::new();
+        for _ in 0..count {
+            partitions.push(PartitionInfo::read_from(&mut r)?);
+        }
+        Ok(Manifest { partitions: partitions })
+    }
+
+    pub fn partition(&self, name: &str) -> Option<&PartitionInfo> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+}
+
+fn sha1_digest(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    sha1.digest().bytes()
+}
+
+/// Builds a payload one partition at a time, diffing each against its old
+/// contents with the usual engines.
+#[derive(Debug, Default)]
+pub struct PayloadWriter {
+    manifest: Manifest,
+    data: Vec<u8>,
+    options: PatchOptions,
+}
+
+impl PayloadWriter {
+    pub fn new() -> PayloadWriter {
+        PayloadWriter::default()
+    }
+
+    /// Options for the patches of later `diff_partition` calls.
+    pub fn options(mut self, options: PatchOptions) -> PayloadWriter {
+        self.options = options;
+        self
+    }
+
+    fn check_name(&self, name: &str) -> io::Result<()> {
+        if name.len() > u16::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Partition name is {} bytes long", name.len())));
+        }
+        if self.manifest.partition(name).is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Duplicate partition {:?}", name)));
+        }
+        Ok(())
+    }
+
+    fn push_data(&mut self, data: &[u8]) -> (u64, u64) {
+        let offset = self.data.len() as u64;
+        self.data.extend_from_slice(data);
+        (offset, data.len() as u64)
+    }
+
+    /// Adds a partition that goes from `old` to `new`.  An unchanged
+    /// partition is a single copy; anything else gets the smallest patch
+    /// from `auto::generate_smallest`.
+    pub fn diff_partition(&mut self, name: &str, old: &[u8], new: &[u8]) -> io::Result<()> {
+        self.check_name(name)?;
+
+        let operations = if old == new {
+            vec![Operation::SourceCopy { offset: 0, len: new.len() as u64 }]
+        } else {
+            let index = Index::compute(old.to_vec());
+            let (_, patch) = auto::generate_smallest(ALL_ENGINES, &index, new, &self.options)?;
+            let (data_offset, data_len) = self.push_data(&patch);
+            vec![Operation::Diff { data_offset: data_offset, data_len: data_len }]
+        };
+
+        self.manifest.partitions.push(PartitionInfo {
+            name: name.to_string(),
+            old_size: old.len() as u64,
+            old_digest: Some(sha1_digest(old)),
+            new_size: new.len() as u64,
+            new_digest: sha1_digest(new),
+            operations: operations,
+        });
+        Ok(())
+    }
+
+    /// Adds a partition written from scratch with `new`, whatever was there
+    /// before.  Blocks of zeros are stored as `Zero` operations.
+    pub fn full_partition(&mut self, name: &str, new: &[u8]) -> io::Result<()> {
+        self.check_name(name)?;
+
+        let mut operations: Vec<Operation> = Vec::new();
+        for chunk in new.chunks(4096) {
+            let op = if chunk.iter().all(|&b| b == 0) {
+                match operations.last_mut() {
+                    Some(&mut Operation::Zero { ref mut len }) => {
+                        *len += chunk.len() as u64;
+                        continue;
+                    }
+                    _ => Operation::Zero { len: chunk.len() as u64 },
+                }
+            } else {
+                let (data_offset, data_len) = self.push_data(chunk);
+                match operations.last_mut() {
+                    Some(&mut Operation::Replace { data_len: ref mut len, .. }) => {
+                        // Data is pushed in order, so this extends the last run.
+                        *len += data_len;
+                        continue;
+                    }
+                    _ => Operation::Replace { data_offset: data_offset, data_len: data_len },
+                }
+            };
+            operations.push(op);
+        }
+
+        self.manifest.partitions.push(PartitionInfo {
+            name: name.to_string(),
+            old_size: 0,
+            old_digest: None,
+            new_size: new.len() as u64,
+            new_digest: sha1_digest(new),
+            operations: operations,
+        });
+        Ok(())
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut manifest = Vec::new();
+        self.manifest.write_to(&mut manifest)?;
+
+        w.write_all(MAGIC)?;
+        wire::write_u64_to(&mut w, manifest.len() as u64)?;
+        w.write_all(&manifest)?;
+        w.write_all(&self.data)
+    }
+
+    /// Like `write_to`, into a fresh buffer.
+    pub fn build(&self) -> io::Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        self.write_to(&mut payload)?;
+        Ok(payload)
+    }
+}
+
+/// A payload parsed from memory.  The manifest is decoded up front and
+/// checked against the size of the data section; partitions are applied
+/// one at a time with `apply_partition`.
+#[derive(Debug)]
+pub struct PayloadReader<'a> {
+    manifest: Manifest,
+    data: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    pub fn parse(payload: &'a [u8]) -> io::Result<PayloadReader<'a>> {
+        if !payload.starts_with(MAGIC) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad payload magic"));
+        }
+
+        let mut rest = &payload[MAGIC.len()..];
+        let manifest_len = wire::read_u64_from(&mut rest)?;
+        if manifest_len > rest.len() as u64 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated payload manifest"));
+        }
+        let (mut manifest_bytes, data) = rest.split_at(manifest_len as usize);
+
+        let manifest = Manifest::read_from(&mut manifest_bytes)?;
+        if !manifest_bytes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Trailing bytes in payload manifest"));
+        }
+
+        for (i, partition) in manifest.partitions.iter().enumerate() {
+            if manifest.partitions[..i].iter().any(|p| p.name == partition.name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Duplicate partition {:?}", partition.name)));
+            }
+
+            for op in &partition.operations {
+                if let Some((offset, len)) = op.data_range() {
+                    if offset.checked_add(len).map_or(true, |end| end > data.len() as u64) {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+                            "Operation for partition {:?} runs past the end of the payload", partition.name)));
+                    }
+                }
+            }
+        }
+
+        Ok(PayloadReader {
+            manifest: manifest,
+            data: data,
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    fn partition(&self, name: &str) -> io::Result<&PartitionInfo> {
+        self.manifest.partition(name).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No partition {:?} in payload", name))
+        })
+    }
+
+    fn data_for(&self, offset: u64, len: u64) -> &'a [u8] {
+        // Checked against the data section in `parse`.
+        &self.data[offset as usize .. (offset + len) as usize]
+    }
+
+    /// Checks that `old` holds what partition `name` expects to update,
+    /// leaving it positioned at the start.  Partitions written from scratch
+    /// accept anything.
+    pub fn verify_old<OldRS: Read+Seek>(&self, name: &str, mut old: OldRS) -> io::Result<()> {
+        let partition = self.partition(name)?;
+        let expected = match partition.old_digest {
+            Some(ref digest) => digest,
+            None => return Ok(()),
+        };
+
+        old.seek(SeekFrom::Start(0))?;
+        let mut contents = CountingWriter::new(DigestWriter::new(io::sink()));
+        io::copy(&mut old, &mut contents)?;
+        old.seek(SeekFrom::Start(0))?;
+
+        if contents.count() != partition.old_size || contents.into_inner().digest() != *expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Partition {:?} doesn't hold the expected old contents", name)));
+        }
+        Ok(())
+    }
+
+    /// Builds partition `name` from `old` into `new`: checks the old
+    /// contents, runs the operations, then checks the new size and digest.
+    /// On error, whatever was written to `new` should be discarded.
+    pub fn apply_partition<OldRS, NewW>(&self, name: &str, mut old: OldRS, new: NewW) -> io::Result<()>
+        where
+            OldRS: Read+Seek,
+            NewW: Write
+    {
+        self.verify_old(name, &mut old)?;
+        let partition = self.partition(name)?;
+
+        let mut new = CountingWriter::new(DigestWriter::new(new));
+        for op in &partition.operations {
+            match *op {
+                Operation::SourceCopy { offset, len } => {
+                    old.seek(SeekFrom::Start(offset))?;
+                    let copied = io::copy(&mut (&mut old).take(len), &mut new)?;
+                    if copied < len {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+                            "Copy for partition {:?} runs past the end of the old partition", name)));
+                    }
+                }
+                Operation::Replace { data_offset, data_len } =>
+                    new.write_all(self.data_for(data_offset, data_len))?,
+                Operation::Diff { data_offset, data_len } => {
+                    old.seek(SeekFrom::Start(0))?;
+                    apply_any(self.data_for(data_offset, data_len), &mut old, &mut new)?;
+                }
+                Operation::Zero { len } =>
+                    write_fill(&mut new, 0, len)?,
+            }
+        }
+        new.flush()?;
+
+        let size = new.count();
+        if size != partition.new_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Partition {:?} came out {} bytes long, expected {}", name, size, partition.new_size)));
+        }
+        if new.into_inner().digest() != partition.new_digest {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Digest mismatch for partition {:?}", name)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::*;
+
+    fn apply(reader: &PayloadReader, name: &str, old: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        reader.apply_partition(name, Cursor::new(old), &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_payload_roundtrip() {
+        let system_old = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        let mut system_new = system_old.clone();
+        system_new[5000..5010].copy_from_slice(b"new code!!");
+
+        let mut boot = vec![0u8; 16384];
+        boot[..5].copy_from_slice(b"kern!");
+        boot[9000..9004].copy_from_slice(b"tail");
+
+        let mut writer = PayloadWriter::new();
+        writer.diff_partition("system", &system_old, &system_new).unwrap();
+        writer.diff_partition("vendor", b"unchanged", b"unchanged").unwrap();
+        writer.full_partition("boot", &boot).unwrap();
+        assert!(writer.diff_partition("boot", b"", b"").is_err());
+
+        let payload = writer.build().unwrap();
+        let reader = PayloadReader::parse(&payload).unwrap();
+        assert_eq!(reader.manifest(), writer.manifest());
+
+        assert_eq!(apply(&reader, "system", &system_old).unwrap(), system_new);
+        assert_eq!(apply(&reader, "vendor", b"unchanged").unwrap(), b"unchanged");
+        assert_eq!(apply(&reader, "boot", b"whatever was there").unwrap(), boot);
+
+        let ops = &reader.manifest().partition("boot").unwrap().operations;
+        assert_eq!(ops.iter().filter(|op| match **op { Operation::Zero { .. } => true, _ => false }).count(), 2);
+
+        // The wrong old partition is caught before anything is written.
+        let mut out = Vec::new();
+        let err = reader.apply_partition("vendor", Cursor::new(&b"changed!!"[..]), &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(out.is_empty());
+
+        assert_eq!(apply(&reader, "cache", b"").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_payload_corruption() {
+        let mut writer = PayloadWriter::new();
+        writer.full_partition("boot", b"some boot image").unwrap();
+        let payload = writer.build().unwrap();
+
+        for len in 0..payload.len() {
+            assert!(PayloadReader::parse(&payload[..len]).is_err());
+        }
+
+        // A flipped data byte gets through parsing, but not the digest check.
+        let mut corrupt = payload.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let reader = PayloadReader::parse(&corrupt).unwrap();
+        assert_eq!(apply(&reader, "boot", b"").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
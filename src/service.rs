@@ -0,0 +1,234 @@
+//! Pieces for long-running patch generation services, which diff many new
+//! files against a handful of popular old ones.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use sha1::Sha1;
+
+use diff::{Differ, Index, PatchOptions};
+use diff::auto::Engine;
+use lru::Lru;
+
+/// What `IndexRegistry::diff_against` fails with when asked for a source
+/// that isn't registered (or has been evicted).  It comes wrapped in an
+/// `io::Error` of kind `NotFound`; `UnknownSource::find` digs it out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownSource {
+    pub digest: [u8; 20],
+}
+
+impl UnknownSource {
+    /// The `UnknownSource` inside `err`, if that's what it is.
+    pub fn find(err: &io::Error) -> Option<&UnknownSource> {
+        err.get_ref().and_then(|e| e.downcast_ref::<UnknownSource>())
+    }
+}
+
+impl fmt::Display for UnknownSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No index registered for source ")?;
+        for byte in &self.digest {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for UnknownSource {
+    fn description(&self) -> &str {
+        "unknown patch source"
+    }
+}
+
+struct Entries {
+    entries: Lru<[u8; 20], Arc<Index>>,
+    total_bytes: u64,
+}
+
+/// Holds the indexes of recently used old files, keyed by the SHA-1 of the
+/// old file (the digest `format::source_digest` records).
+///
+/// Once there are more than `max_entries` indexes, or they take up more than
+/// `max_bytes` (by `Index::memory_usage`), the least recently used are
+/// dropped.  The most recently registered index is never dropped, even if it
+/// alone is over the budget.  Indexes are handed out as `Arc`s, so one that's
+/// dropped while a diff is still using it lives until the diff finishes.
+pub struct IndexRegistry {
+    max_entries: usize,
+    max_bytes: u64,
+    options: PatchOptions,
+    engine: Engine,
+    state: Mutex<Entries>,
+}
+
+impl IndexRegistry {
+    pub fn new(max_entries: usize, max_bytes: u64) -> IndexRegistry {
+        IndexRegistry {
+            max_entries: max_entries,
+            max_bytes: max_bytes,
+            options: PatchOptions::default(),
+            engine: Engine::Bsdiff,
+            state: Mutex::new(Entries {
+                entries: Lru::new(),
+                total_bytes: 0,
+            }),
+        }
+    }
+
+    /// Options for the patches `diff_against` generates.
+    pub fn options(mut self, options: PatchOptions) -> IndexRegistry {
+        self.options = options;
+        self
+    }
+
+    /// Which patch format `diff_against` produces.  Defaults to
+    /// `Engine::Bsdiff`.
+    pub fn format(mut self, engine: Engine) -> IndexRegistry {
+        self.engine = engine;
+        self
+    }
+
+    /// Indexes `old` and registers it, returning its digest.  Registering a
+    /// source that's already there just marks it as recently used.
+    pub fn register(&self, old: Vec<u8>) -> [u8; 20] {
+        let digest = digest_of(&old);
+        if self.get(&digest).is_none() {
+            self.insert(digest, Index::compute(old));
+        }
+        digest
+    }
+
+    /// Registers an index that's already been built (say, loaded from a
+    /// `Cache`), returning the digest of its data.
+    pub fn register_index(&self, index: Index) -> [u8; 20] {
+        let digest = digest_of(&index.data);
+        self.insert(digest, index);
+        digest
+    }
+
+    fn insert(&self, digest: [u8; 20], index: Index) {
+        let size = index.memory_usage() as u64;
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(old) = state.entries.insert(digest, Arc::new(index)) {
+            state.total_bytes -= old.memory_usage() as u64;
+        }
+        state.total_bytes += size;
+
+        while (state.entries.len() > self.max_entries || state.total_bytes > self.max_bytes)
+            && state.entries.len() > 1
+        {
+            let (_, evicted) = state.entries.pop_oldest().unwrap();
+            state.total_bytes -= evicted.memory_usage() as u64;
+        }
+    }
+
+    /// The index for `digest`, marking it as recently used.
+    pub fn get(&self, digest: &[u8; 20]) -> Option<Arc<Index>> {
+        self.state.lock().unwrap().entries.touch(digest).cloned()
+    }
+
+    pub fn contains(&self, digest: &[u8; 20]) -> bool {
+        self.state.lock().unwrap().entries.contains(digest)
+    }
+
+    /// Drops the index for `digest`, if there is one.
+    pub fn remove(&self, digest: &[u8; 20]) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(index) = state.entries.remove(digest) {
+            state.total_bytes -= index.memory_usage() as u64;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Memory taken up by the registered indexes, by `Index::memory_usage`.
+    pub fn total_bytes(&self) -> u64 {
+        self.state.lock().unwrap().total_bytes
+    }
+
+    /// Generates a patch from the source registered as `digest` to `new`.
+    /// Fails with `NotFound` and an `UnknownSource` if there's no such
+    /// source.  The registry isn't locked while diffing.
+    pub fn diff_against(&self, digest: &[u8; 20], new: &[u8]) -> io::Result<Vec<u8>> {
+        let index = match self.get(digest) {
+            Some(index) => index,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, UnknownSource { digest: *digest })),
+        };
+
+        let mut patch = Vec::new();
+        Differ::new(&*index)
+            .options(self.options.clone())
+            .format(self.engine)
+            .run(new, &mut patch)?;
+        Ok(patch)
+    }
+}
+
+fn digest_of(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    sha1.digest().bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::*;
+    use patch::apply_any;
+
+    #[test]
+    fn test_registry_diff_against() {
+        let registry = IndexRegistry::new(4, 1 << 20);
+        let old = b"this is a test 12345678 test".to_vec();
+        let new = b"this is really a cool uftu 12345678 uftu";
+
+        let digest = registry.register(old.clone());
+        assert_eq!(registry.register(old.clone()), digest);
+        assert_eq!(registry.len(), 1);
+
+        let patch = registry.diff_against(&digest, new).unwrap();
+        let mut out = Vec::new();
+        apply_any(&patch, Cursor::new(&old[..]), &mut out).unwrap();
+        assert_eq!(&out[..], &new[..]);
+
+        let err = registry.diff_against(&[0; 20], new).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(UnknownSource::find(&err), Some(&UnknownSource { digest: [0; 20] }));
+    }
+
+    #[test]
+    fn test_registry_eviction() {
+        let registry = IndexRegistry::new(2, 1 << 20);
+        let a = registry.register(b"first old file".to_vec());
+        let b = registry.register(b"second old file".to_vec());
+
+        // Touching `a` makes `b` the one to go.
+        assert!(registry.get(&a).is_some());
+        let c = registry.register(b"third old file".to_vec());
+        assert!(registry.contains(&a));
+        assert!(!registry.contains(&b));
+        assert!(registry.contains(&c));
+
+        registry.remove(&a);
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.total_bytes(), registry.get(&c).unwrap().memory_usage() as u64);
+
+        // Over the memory budget, only the newest survives.
+        let registry = IndexRegistry::new(10, 1);
+        registry.register(b"first old file".to_vec());
+        let d = registry.register(b"another old file".to_vec());
+        assert_eq!(registry.len(), 1);
+        assert!(registry.contains(&d));
+    }
+}
@@ -0,0 +1,31 @@
+//! Helpers shared by the unit tests.
+
+use std::cmp::min;
+use std::io::{self, Read};
+
+/// `len` bytes of deterministic pseudo-random data, from the classic
+/// `rand()` LCG seeded with `seed`.
+///
+/// Nothing in it matches itself, so it stands in for compressed or
+/// executable data; the same seed always gives the same bytes.
+pub fn noise(seed: u32, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect()
+}
+
+/// Reads from `inner` at most `max` bytes at a time, as a pipe or socket
+/// might.
+pub struct ShortReads<R> {
+    pub inner: R,
+    pub max: usize,
+}
+
+impl<R: Read> Read for ShortReads<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(buf.len(), self.max);
+        self.inner.read(&mut buf[..len])
+    }
+}
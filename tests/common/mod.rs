@@ -0,0 +1,12 @@
+//! Helpers shared by the integration tests.
+
+/// `len` bytes of deterministic pseudo-random data, from the classic
+/// `rand()` LCG seeded with `seed`.
+#[allow(dead_code)]
+pub fn noise(seed: u32, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect()
+}
@@ -0,0 +1,201 @@
+//! Fault injection: applying a patch through readers and writers that fail
+//! or return short counts at chosen byte offsets.
+//!
+//! Whatever happens, apply must either produce the whole new file or return
+//! an error, and anything it did write must be a prefix of the new file.
+//! Since nothing is resumable yet, recovering from a failure means applying
+//! again from the start, which must then succeed.
+
+extern crate rsdiff;
+
+use std::cmp::min;
+use std::io::{self, Cursor, Read, Write, Seek, SeekFrom};
+
+use rsdiff::diff::Index;
+use rsdiff::format::{bsdiff, linear_diff};
+use rsdiff::patch::apply_any;
+
+/// Wraps a reader, failing every read from `fail_at` onwards and returning
+/// at most `max_read` bytes per call before that.
+struct FlakyReader<R> {
+    inner: R,
+    pos: u64,
+    fail_at: u64,
+    max_read: usize,
+}
+
+impl<R> FlakyReader<R> {
+    fn new(inner: R, fail_at: u64, max_read: usize) -> FlakyReader<R> {
+        FlakyReader {
+            inner: inner,
+            pos: 0,
+            fail_at: fail_at,
+            max_read: max_read,
+        }
+    }
+}
+
+impl<R: Read> Read for FlakyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.fail_at && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Other, "injected read failure"));
+        }
+
+        let allowed = min(self.fail_at - self.pos, self.max_read as u64) as usize;
+        let len = min(buf.len(), allowed);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for FlakyReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Collects writes, accepting at most `max_write` bytes per call and failing
+/// every write once `fail_at` bytes have been accepted.
+struct FlakyWriter {
+    written: Vec<u8>,
+    fail_at: usize,
+    max_write: usize,
+}
+
+impl FlakyWriter {
+    fn new(fail_at: usize, max_write: usize) -> FlakyWriter {
+        FlakyWriter {
+            written: Vec::new(),
+            fail_at: fail_at,
+            max_write: max_write,
+        }
+    }
+}
+
+impl Write for FlakyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written.len() >= self.fail_at && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Other, "injected write failure"));
+        }
+
+        let n = min(buf.len(), min(self.fail_at - self.written.len(), self.max_write));
+        self.written.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn test_data() -> (Vec<u8>, Vec<u8>) {
+    let old = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+
+    let mut new = old.clone();
+    new.splice(100..100, b"inserted text".iter().cloned());
+    new.drain(1000..1200);
+    for b in &mut new[2000..2100] {
+        *b = b.wrapping_add(1);
+    }
+    new.extend_from_slice(b"and a brand new tail");
+
+    (old, new)
+}
+
+fn patches(old: &[u8], new: &[u8]) -> Vec<(&'static str, Vec<u8>)> {
+    let index = Index::compute(old.to_vec());
+
+    let mut linear = Vec::new();
+    linear_diff::generate_full_patch(&index, new, &mut linear).unwrap();
+
+    let mut compressed = Vec::new();
+    linear_diff::generate_compressed_patch(&index, new, 3, &mut compressed).unwrap();
+
+    vec![
+        ("bsdiff", bsdiff::generate_full_patch(&index, new)),
+        ("linear_diff", linear),
+        ("linear_diff+zstd", compressed),
+    ]
+}
+
+fn fail_points(len: usize) -> Vec<usize> {
+    vec![0, 1, 7, len / 3, len / 2, len - 1]
+}
+
+fn assert_prefix(name: &str, written: &[u8], new: &[u8]) {
+    assert!(written.len() <= new.len() && written == &new[..written.len()],
+        "{}: wrote {} bytes that aren't a prefix of the new file", name, written.len());
+}
+
+#[test]
+fn test_short_reads_and_writes() {
+    let (old, new) = test_data();
+
+    for (name, patch) in patches(&old, &new) {
+        for &max in &[1, 3, 1000] {
+            let old = FlakyReader::new(Cursor::new(&old[..]), !0, max);
+            let mut out = FlakyWriter::new(!0, max);
+
+            apply_any(&patch, old, &mut out).unwrap();
+            assert!(out.written == new, "{}: short I/O of {} bytes changed the output", name, max);
+        }
+    }
+}
+
+#[test]
+fn test_write_failures() {
+    let (old, new) = test_data();
+
+    for (name, patch) in patches(&old, &new) {
+        for fail_at in fail_points(new.len()) {
+            let mut out = FlakyWriter::new(fail_at, 100);
+            assert!(apply_any(&patch, Cursor::new(&old[..]), &mut out).is_err(),
+                "{}: write failure at {} went unreported", name, fail_at);
+
+            assert!(out.written.len() <= fail_at);
+            assert_prefix(name, &out.written, &new);
+
+            // Starting over with a working writer recovers.
+            let mut retry = Vec::new();
+            apply_any(&patch, Cursor::new(&old[..]), &mut retry).unwrap();
+            assert!(retry == new);
+        }
+    }
+}
+
+#[test]
+fn test_old_file_read_failures() {
+    let (old, new) = test_data();
+
+    for (name, patch) in patches(&old, &new) {
+        for fail_at in fail_points(old.len()) {
+            let mut out = Vec::new();
+            let res = apply_any(&patch, FlakyReader::new(Cursor::new(&old[..]), fail_at as u64, 100), &mut out);
+
+            // A failing old file may or may not be reached, depending on
+            // where the patch reads from; either way, no garbage comes out.
+            match res {
+                Ok(()) => assert!(out == new, "{}: read failure at {} corrupted the output", name, fail_at),
+                Err(_) => assert_prefix(name, &out, &new),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_truncated_patches() {
+    let (old, new) = test_data();
+
+    for (name, patch) in patches(&old, &new) {
+        for cut in fail_points(patch.len()) {
+            let mut out = Vec::new();
+            if apply_any(&patch[..cut], Cursor::new(&old[..]), &mut out).is_ok() {
+                assert!(out == new, "{}: patch cut at {} applied to the wrong output", name, cut);
+            } else {
+                assert_prefix(name, &out, &new);
+            }
+        }
+    }
+}
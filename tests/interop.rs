@@ -0,0 +1,106 @@
+//! Compatibility with the reference C implementation of bsdiff.
+//!
+//! The patches in `diffs/` were generated by Colin Percival's bsdiff 4.3.
+//! We must apply them and reproduce the original files byte-for-byte.
+//!
+//! Conversely, the patches in `tests/golden/interop/` are ones we wrote
+//! and the reference bspatch applied.  We must keep writing them
+//! byte-for-byte.
+
+extern crate rsdiff;
+
+mod common;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+use rsdiff::diff::Index;
+use rsdiff::format::bsdiff;
+
+fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+fn assert_reference_patch_applies(old: &str, new: &str) {
+    let new_data = load(format!("tests/{}", new)).unwrap();
+    let patch = load(format!("diffs/{}-to-{}.diff", old, new)).unwrap();
+
+    let mut out = Vec::with_capacity(new_data.len());
+    bsdiff::apply_patch(&patch, File::open(format!("tests/{}", old)).unwrap(), &mut out).unwrap();
+
+    assert!(out == new_data, "{} -> {} didn't reproduce the new file", old, new);
+}
+
+#[test]
+fn test_reference_patches_apply() {
+    for &(old, new) in &[
+        ("avian_linux", "avian_linux"),
+        ("avian_linux", "avian_pr_linux"),
+        ("avian_pr_linux", "avian_linux"),
+        ("avian_pr_linux", "avian_pr_linux"),
+    ] {
+        assert_reference_patch_applies(old, new);
+    }
+}
+
+#[test]
+fn test_reference_patches_parse() {
+    // We don't have the mac binaries these were generated from, but their
+    // headers must still parse and account for the whole file.
+    for name in &[
+        "bsdiff_mac-to-bsdiff_mac",
+        "bsdiff_mac-to-bspatch_mac",
+        "bspatch_mac-to-bsdiff_mac",
+        "bspatch_mac-to-bspatch_mac",
+    ] {
+        let patch = load(format!("diffs/{}.diff", name)).unwrap();
+        let header = bsdiff::Header::read(&patch).unwrap();
+
+        assert!(32 + header.compressed_commands_size + header.compressed_delta_size <= patch.len() as u64);
+    }
+}
+
+/// Inputs to the patches in `tests/golden/interop/`.
+fn golden_cases() -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+    let old = common::noise(7, 4000);
+    let mut new = old[1000..].to_vec();
+    new.extend_from_slice(b"a run of bytes that only the new file has");
+    new.extend_from_slice(&old[..1000]);
+    for i in (50..new.len()).step_by(300) {
+        new[i] = new[i].wrapping_add(1);
+    }
+
+    vec![
+        ("text", b"this is a test 12345678 test".to_vec(), b"this is really a cool uftu 12345678 uftu".to_vec()),
+        ("empty-new", b"anything".to_vec(), Vec::new()),
+        ("moved-halves", old, new),
+    ]
+}
+
+#[test]
+fn test_our_patches_match_golden() {
+    // Each golden patch was applied with the reference bspatch 4.3 when it
+    // was recorded, so writing the same bytes means the reference bspatch
+    // still applies what we write.  Set `RSDIFF_UPDATE_GOLDEN=1` to rewrite
+    // them after a deliberate change, and check the new ones against the
+    // reference bspatch before committing them.
+    for (name, old, new) in golden_cases() {
+        let path = format!("tests/golden/interop/{}.bsdiff", name);
+        let patch = bsdiff::generate_full_patch(&Index::compute(old.clone()), &new);
+
+        if env::var_os("RSDIFF_UPDATE_GOLDEN").is_some() {
+            File::create(&path).unwrap().write_all(&patch).unwrap();
+        }
+
+        let golden = load(&path).unwrap();
+        assert!(patch == golden, "{} differs from the golden patch", name);
+
+        let mut out = Vec::new();
+        bsdiff::apply_patch(&golden, Cursor::new(&old), &mut out).unwrap();
+        assert!(out == new, "the golden {} patch doesn't reproduce the new file", name);
+    }
+}
@@ -0,0 +1,191 @@
+//! Property-based round-trip tests: for arbitrary pairs of related inputs,
+//! every format and compression setting must reproduce the new file exactly.
+
+extern crate quickcheck;
+extern crate rsdiff;
+
+use std::cmp::min;
+use std::io::Cursor;
+
+use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+use rsdiff::diff::{Index, Differ, PatchOptions, Compression, MatchOptions, Effort};
+use rsdiff::format::linear_diff;
+use rsdiff::patch::apply_any;
+
+/// An old file, and a new file derived from it by a handful of random edits.
+#[derive(Debug, Clone)]
+struct RelatedPair {
+    old: Vec<u8>,
+    new: Vec<u8>,
+}
+
+fn random_bytes<G: Gen>(g: &mut G, len: usize, alphabet: u8) -> Vec<u8> {
+    (0..len).map(|_| g.gen_range(0, alphabet)).collect()
+}
+
+fn random_range<G: Gen>(g: &mut G, len: usize) -> (usize, usize) {
+    let begin = g.gen_range(0, len + 1);
+    let end = g.gen_range(begin, min(len, begin + 64) + 1);
+    (begin, end)
+}
+
+impl Arbitrary for RelatedPair {
+    fn arbitrary<G: Gen>(g: &mut G) -> RelatedPair {
+        let size = g.size();
+
+        // A small alphabet makes long matches (and so interesting commands)
+        // much more likely.
+        let alphabet = if g.gen() { 4 } else { 255 };
+        let len = g.gen_range(0, size * 8 + 1);
+        let old = random_bytes(g, len, alphabet);
+
+        let mut new = old.clone();
+
+        for _ in 0..g.gen_range(0, 8) {
+            let (begin, end) = random_range(g, new.len());
+
+            match g.gen_range(0, 5) {
+                // Insert fresh bytes.
+                0 => {
+                    let n = g.gen_range(0, 32);
+                    let inserted = random_bytes(g, n, alphabet);
+                    new.splice(begin..begin, inserted);
+                }
+                // Delete a range.
+                1 => {
+                    new.drain(begin..end);
+                }
+                // Move a range somewhere else.
+                2 => {
+                    let moved = new.drain(begin..end).collect::<Vec<_>>();
+                    let to = g.gen_range(0, new.len() + 1);
+                    new.splice(to..to, moved);
+                }
+                // Duplicate a range.
+                3 => {
+                    let copy = new[begin..end].to_vec();
+                    let to = g.gen_range(0, new.len() + 1);
+                    new.splice(to..to, copy);
+                }
+                // Tweak bytes in place, the way bsdiff's delta stream likes.
+                _ => {
+                    for b in &mut new[begin..end] {
+                        *b = b.wrapping_add(g.gen_range(0, 3));
+                    }
+                }
+            }
+        }
+
+        RelatedPair {
+            old: old,
+            new: new,
+        }
+    }
+}
+
+fn applies_to(patch: &[u8], pair: &RelatedPair) -> bool {
+    let mut out = Vec::new();
+    apply_any(patch, Cursor::new(&pair.old[..]), &mut out).unwrap();
+    out == pair.new
+}
+
+fn check(prop: fn(RelatedPair) -> bool) {
+    QuickCheck::new().tests(50).quickcheck(prop);
+}
+
+#[test]
+fn test_bsdiff_roundtrip() {
+    fn prop(pair: RelatedPair) -> bool {
+        let index = Index::compute(pair.old.clone());
+
+        [Compression::None, Compression::Fastest, Compression::Best].iter().all(|&compression| {
+            let mut patch = Vec::new();
+            Differ::new(&index)
+                .options(PatchOptions::new().compression(compression))
+                .write_to(&pair.new, &mut patch)
+                .unwrap();
+            applies_to(&patch, &pair)
+        })
+    }
+    check(prop);
+}
+
+#[test]
+fn test_bsdiff_seekable_roundtrip() {
+    fn prop(pair: RelatedPair) -> bool {
+        let index = Index::compute(pair.old.clone());
+
+        let mut patch = Cursor::new(Vec::new());
+        Differ::new(&index).write_to_seekable(&pair.new, &mut patch).unwrap();
+        applies_to(patch.get_ref(), &pair)
+    }
+    check(prop);
+}
+
+#[test]
+fn test_linear_diff_roundtrip() {
+    fn prop(pair: RelatedPair) -> bool {
+        let index = Index::compute(pair.old.clone());
+
+        let mut patch = Vec::new();
+        linear_diff::generate_full_patch(&index, &pair.new, &mut patch).unwrap();
+        applies_to(&patch, &pair)
+    }
+    check(prop);
+}
+
+#[test]
+fn test_linear_diff_zstd_roundtrip() {
+    fn prop(pair: RelatedPair) -> bool {
+        let index = Index::compute(pair.old.clone());
+
+        let mut patch = Vec::new();
+        linear_diff::generate_compressed_patch(&index, &pair.new, 3, &mut patch).unwrap();
+        applies_to(&patch, &pair)
+    }
+    check(prop);
+}
+
+#[test]
+fn test_mismatch_budget_roundtrip() {
+    fn prop(pair: RelatedPair) -> bool {
+        let index = Index::compute(pair.old.clone());
+
+        [0, 1, 64].iter().all(|&budget| {
+            let matching = MatchOptions::new().mismatch_budget(budget);
+
+            let mut bsdiff = Vec::new();
+            Differ::new(&index)
+                .options(PatchOptions::new().matching(matching))
+                .write_to(&pair.new, &mut bsdiff)
+                .unwrap();
+
+            let mut linear = Vec::new();
+            linear_diff::generate_full_patch_with(&index, &pair.new, matching, &mut linear).unwrap();
+
+            applies_to(&bsdiff, &pair) && applies_to(&linear, &pair)
+        })
+    }
+    check(prop);
+}
+
+#[test]
+fn test_optimal_roundtrip() {
+    fn prop(pair: RelatedPair) -> bool {
+        let index = Index::compute(pair.old.clone());
+        let matching = MatchOptions::new().effort(Effort::Optimal);
+
+        let mut bsdiff = Vec::new();
+        Differ::new(&index)
+            .options(PatchOptions::new().matching(matching))
+            .write_to(&pair.new, &mut bsdiff)
+            .unwrap();
+
+        let mut linear = Vec::new();
+        linear_diff::generate_full_patch_with(&index, &pair.new, matching, &mut linear).unwrap();
+
+        applies_to(&bsdiff, &pair) && applies_to(&linear, &pair)
+    }
+    check(prop);
+}
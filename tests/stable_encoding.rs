@@ -0,0 +1,118 @@
+//! Serialized indexes and patches must come out byte-for-byte the same on
+//! every host, whatever its endianness or word size: every field is a
+//! fixed-width little-endian integer.  An index cached on an x86 server has
+//! to load on an ARM (or big-endian) device.
+//!
+//! The files in `tests/golden/` were written on a little-endian 64-bit host.
+//! Running these tests anywhere else checks both that we write the same
+//! bytes and that we read them back.  Set `RSDIFF_UPDATE_GOLDEN=1` to
+//! rewrite them after a deliberate format change.
+
+extern crate rsdiff;
+
+mod common;
+
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+
+use rsdiff::diff::{Compression, Index, Matcher, PatchOptions};
+use rsdiff::format::{bsdiff, linear_diff};
+use rsdiff::index::KvCache;
+use rsdiff::patch;
+
+fn old_file() -> Vec<u8> {
+    common::noise(1, 1000)
+}
+
+fn new_file() -> Vec<u8> {
+    let old = old_file();
+    let mut new = old[600..].to_vec();
+    new.extend_from_slice(b"inserted between the two halves");
+    new.extend_from_slice(&old[..600]);
+    new[700] ^= 0x55;
+    new
+}
+
+fn check_golden(name: &str, actual: &[u8]) {
+    let path = format!("tests/golden/{}", name);
+
+    if env::var_os("RSDIFF_UPDATE_GOLDEN").is_some() {
+        File::create(&path).unwrap().write_all(actual).unwrap();
+        return;
+    }
+
+    let mut expected = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut expected).unwrap();
+    assert!(actual == &expected[..], "{} differs from the golden file", name);
+}
+
+fn load_golden(name: &str) -> Vec<u8> {
+    let mut contents = Vec::new();
+    File::open(format!("tests/golden/{}", name)).unwrap().read_to_end(&mut contents).unwrap();
+    contents
+}
+
+#[test]
+fn test_cached_index_encoding() {
+    let entry = RefCell::new(None);
+    let cache = KvCache::new(
+        |_: &[u8; 20]| Ok(None),
+        |key: &[u8; 20], value| {
+            *entry.borrow_mut() = Some((*key, value));
+            Ok(())
+        });
+    Index::from_cache_or_compute(&cache, old_file()).unwrap();
+
+    let (key, entry) = entry.into_inner().unwrap();
+    check_golden("index.cache", &entry);
+
+    // The offset count, spelled out.
+    assert_eq!(&entry[26..34], &[0xe8, 0x03, 0, 0, 0, 0, 0, 0]);
+
+    // The golden entry loads as is, without being recomputed.
+    let golden = load_golden("index.cache");
+    let stored = Cell::new(false);
+    let cache = KvCache::new(
+        |k: &[u8; 20]| Ok(if *k == key { Some(golden.clone()) } else { None }),
+        |_: &[u8; 20], _| {
+            stored.set(true);
+            Ok(())
+        });
+    let loaded = Index::from_cache_or_compute(&cache, old_file()).unwrap();
+    assert!(!stored.get(), "the golden index was recomputed");
+
+    let computed = Index::compute(old_file());
+    let new = new_file();
+    for start in (0..new.len()).step_by(37) {
+        assert_eq!(loaded.longest_match(&new[start..]), computed.longest_match(&new[start..]));
+    }
+}
+
+#[test]
+fn test_bsdiff_encoding() {
+    let (old, new) = (old_file(), new_file());
+
+    let mut patch = Vec::new();
+    let options = PatchOptions::new().compression(Compression::None);
+    bsdiff::write_full_patch(&Index::compute(old.clone()), &new, &options, &mut patch).unwrap();
+    check_golden("uncompressed.bsdiff", &patch);
+
+    let mut out = Vec::new();
+    patch::apply_any(&load_golden("uncompressed.bsdiff"), Cursor::new(&old), &mut out).unwrap();
+    assert!(out == new);
+}
+
+#[test]
+fn test_linear_diff_encoding() {
+    let (old, new) = (old_file(), new_file());
+
+    let mut patch = Vec::new();
+    linear_diff::generate_full_patch(&Index::compute(old.clone()), &new, &mut patch).unwrap();
+    check_golden("uncompressed.linear_diff", &patch);
+
+    let mut out = Vec::new();
+    patch::apply_any(&load_golden("uncompressed.linear_diff"), Cursor::new(&old), &mut out).unwrap();
+    assert!(out == new);
+}
@@ -0,0 +1,135 @@
+//! Conformance vectors for the uncompressed linear_diff format.
+//!
+//! Each `tests/vectors/<name>.patch` is a hand-written patch against
+//! `tests/vectors/old`; the table below says what applying it must do.
+//! Valid patches come with the expected output in `<name>.new`.  The files
+//! double as a spec for other implementations of the format:
+//!
+//! ```text
+//! magic "LINDIF02" (or "LINDIF01", which has no copy commands)
+//! then, until the end of the patch, commands of three little-endian u64s:
+//!   old_offset, bytewise_add_size, extra_append_size
+//! each followed by its body:
+//!   - old_offset == 2^64-1: a fill, `extra_append_size` copies of the low
+//!     byte of `bytewise_add_size`, no body;
+//!   - top bit of bytewise_add_size set: a copy of the rest of it from
+//!     `old_offset` in the old file, then `extra_append_size` extra bytes
+//!     (an error in a "LINDIF01" patch);
+//!   - otherwise `bytewise_add_size` delta bytes, added bytewise (mod 256)
+//!     to the old file from `old_offset`, then `extra_append_size` extra
+//!     bytes.
+//! ```
+//!
+//! A command whose lengths add up to more than 2^64-1 is an error.  A patch
+//! that ends cleanly between commands is complete; one that ends anywhere
+//! else is truncated.  In strict decoding, which is the default, a
+//! command producing no output is an error, unless it's the only command:
+//! that's how earlier versions wrote empty patches.
+//!
+//! Errors inside a command carry an `ApplyError` giving the command's index
+//! and its offset in the patch; errors in the magic don't.
+
+extern crate rsdiff;
+
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+
+use rsdiff::format::linear_diff;
+use rsdiff::patch::{ApplyError, BufferConfig, DecodeMode};
+
+enum Expect {
+    /// Applies cleanly, producing `<name>.new`.
+    Output,
+    /// Fails with `kind`; `at` is the failing command's index and offset in
+    /// the patch, for errors inside a command.
+    Error { kind: io::ErrorKind, at: Option<(u64, u64)> },
+}
+
+use Expect::*;
+use io::ErrorKind::{InvalidData, UnexpectedEof};
+
+const VECTORS: &'static [(&'static str, DecodeMode, Expect)] = &[
+    ("empty", DecodeMode::Strict, Output),
+    ("extra", DecodeMode::Strict, Output),
+    ("delta", DecodeMode::Strict, Output),
+    ("copy", DecodeMode::Strict, Output),
+    ("fill", DecodeMode::Strict, Output),
+    ("mixed", DecodeMode::Strict, Output),
+    ("zero-length-command", DecodeMode::Lenient, Output),
+    ("legacy-empty", DecodeMode::Strict, Output),
+
+    ("bad-magic", DecodeMode::Strict, Error { kind: InvalidData, at: None }),
+    ("truncated-magic", DecodeMode::Strict, Error { kind: UnexpectedEof, at: None }),
+    ("truncated-command", DecodeMode::Strict, Error { kind: UnexpectedEof, at: Some((0, 8)) }),
+    ("truncated-delta", DecodeMode::Strict, Error { kind: UnexpectedEof, at: Some((0, 8)) }),
+    ("truncated-extra", DecodeMode::Strict, Error { kind: UnexpectedEof, at: Some((0, 8)) }),
+    ("truncated-second-command", DecodeMode::Strict, Error { kind: UnexpectedEof, at: Some((1, 34)) }),
+    ("overlong-delta", DecodeMode::Strict, Error { kind: InvalidData, at: Some((0, 8)) }),
+    ("overlong-copy", DecodeMode::Strict, Error { kind: InvalidData, at: Some((0, 8)) }),
+    ("overflowing-copy", DecodeMode::Strict, Error { kind: InvalidData, at: Some((0, 8)) }),
+    ("overflowing-length", DecodeMode::Strict, Error { kind: InvalidData, at: Some((0, 8)) }),
+    ("copy-in-v1", DecodeMode::Strict, Error { kind: InvalidData, at: Some((0, 8)) }),
+    ("zero-length-command", DecodeMode::Strict, Error { kind: InvalidData, at: Some((1, 32)) }),
+];
+
+fn read_vector(name: &str) -> Vec<u8> {
+    let mut contents = Vec::new();
+    File::open(format!("tests/vectors/{}", name)).unwrap().read_to_end(&mut contents).unwrap();
+    contents
+}
+
+fn check(name: &str, how: &str, expect: &Expect, result: io::Result<()>, new: &[u8]) {
+    match (expect, result) {
+        (&Output, Ok(())) => {
+            assert!(new == &read_vector(&format!("{}.new", name))[..], "{} ({}): wrong output", name, how);
+        }
+        (&Output, Err(e)) => panic!("{} ({}): unexpected error: {}", name, how, e),
+        (&Error { .. }, Ok(())) => panic!("{} ({}): applied, but should have failed", name, how),
+        (&Error { kind, at }, Err(e)) => {
+            assert_eq!(e.kind(), kind, "{} ({}): {}", name, how, e);
+            let found = ApplyError::find(&e).map(|ctx| (ctx.command, ctx.patch_offset.unwrap()));
+            assert_eq!(found, at, "{} ({}): {}", name, how, e);
+        }
+    }
+}
+
+#[test]
+fn test_vectors() {
+    let old = read_vector("old");
+
+    for &(name, mode, ref expect) in VECTORS {
+        let patch = read_vector(&format!("{}.patch", name));
+
+        let mut new = Vec::new();
+        let result = linear_diff::apply_patch_with_mode(
+            &patch[..], Cursor::new(&old[..]), &mut new, &BufferConfig::default(), mode);
+        check(name, "streamed", expect, result, &new);
+
+        let mut new = Vec::new();
+        let result = linear_diff::apply_patch_from_slice_with_mode(&patch[..], &old, &mut new, mode);
+        check(name, "from slice", expect, result, &new);
+    }
+}
+
+/// Every file in `tests/vectors/` is in the table, so none is silently
+/// skipped.
+#[test]
+fn test_vectors_are_all_listed() {
+    let listed: BTreeSet<String> = VECTORS.iter()
+        .flat_map(|&(name, _, ref expect)| {
+            let mut files = vec![format!("{}.patch", name)];
+            if let Output = *expect {
+                files.push(format!("{}.new", name));
+            }
+            files
+        })
+        .chain(Some("old".to_string()))
+        .collect();
+
+    let present: BTreeSet<String> = fs::read_dir("tests/vectors").unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+
+    assert_eq!(listed, present);
+}